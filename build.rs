@@ -36,6 +36,35 @@ fn main() {
         .shared_flag(true)
         .compile("libklipper");
 
+    // Klipper's step generation, for the kinematics tests to check against, with steps
+    // kept as they come rather than compressed
+    if env::var_os("CARGO_FEATURE_KLIPPER_STEPS").is_some() {
+        let sources = [
+            "klipper/klippy/chelper/pyhelper.c",
+            "klipper/klippy/chelper/itersolve.c",
+            "klipper/klippy/chelper/trapq.c",
+            "klipper/klippy/chelper/kin_cartesian.c",
+            "klipper/klippy/chelper/kin_corexy.c",
+            "klipper/klippy/chelper/kin_corexz.c",
+            "klipper/klippy/chelper/kin_delta.c",
+            "klipper/klippy/chelper/kin_extruder.c",
+            "src/ffi/stepcapture.c",
+        ];
+        // what klippy only declares in python, declared here and checked against them
+        let header = "src/ffi/chelper.h";
+        let mut build = cc::Build::new();
+        if build.get_compiler().is_like_msvc() {
+            build.flag(&format!("/FI{header}"));
+        } else {
+            build.flag("-include").flag(header);
+        }
+        build.files(&sources).include(".").compile("klippersteps");
+        for source in sources {
+            cargo_emit::rerun_if_changed!(source);
+        }
+        cargo_emit::rerun_if_changed!(header);
+    }
+
     // For all the random C stuff that just needs a little linker fix-up
     let bindings = bindgen::builder()
         .header("wrapper.h")
@@ -0,0 +1,704 @@
+//! Bed mesh leveling: probe a grid of bed heights, then follow them while moving. A port
+//! of klipper's `bed_mesh.py`
+//!
+//! Moves get split wherever the bed changes height enough along the way, and the
+//! correction tapers off between `fade_start` and `fade_end`, so that well up a print
+//! the layers are flat again
+
+pub mod zmesh;
+
+use std::collections::BTreeMap;
+
+use crate::homing::{HomingContext, HomingError};
+use crate::kinematics::Kinematics;
+use crate::mcu::McuLink;
+use crate::planner::{MoveSegment, MoveTransform};
+use crate::probe::{Probe, ProbeError, ProbePoints};
+use crate::trapq::Coord;
+use crate::units::{Millimeters, MillimetersPerSecond};
+use zmesh::{Algorithm, MeshParams, ZMesh};
+
+/// Profile `BED_MESH_CALIBRATE` saves to
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[derive(thiserror::Error, Debug)]
+pub enum BedMeshError {
+    #[error("bed_mesh: probe_count must be at least 3. Configured Probe Count: {0}, {1}")]
+    ProbeCount(usize, usize),
+    #[error(
+        "bed_mesh: cannot exceed a probe_count of 6 when using lagrange interpolation. \
+         Configured Probe Count: {0}, {1}"
+    )]
+    LagrangeProbeCount(usize, usize),
+    #[error(
+        "bed_mesh: invalid probe_count option when using bicubic interpolation. \
+         Combination of 3 points on one axis with more than 6 on another is not permitted. \
+         Configured Probe Count: {0}, {1}"
+    )]
+    BicubicProbeCount(usize, usize),
+    #[error("bed_mesh: min/max points too close together")]
+    PointsTooClose,
+    #[error("bed_mesh: probed heights don't match probe_count")]
+    MatrixShape,
+    #[error(
+        "bed_mesh: ERROR, fade_target lies outside of mesh z range\n\
+         min: {min:.4}, max: {max:.4}, fade_target: {target:.4}"
+    )]
+    FadeTargetOutOfRange { target: f64, min: f64, max: f64 },
+    #[error(
+        "bed_mesh: Mesh extends outside of the fade range. \
+         fade distance: {fade_dist:.2} mesh min: {min:.4} mesh max: {max:.4}"
+    )]
+    OutsideFadeRange { fade_dist: f64, min: f64, max: f64 },
+    #[error("Unable to save to profile [{0}], the bed has not been probed")]
+    NotProbed(String),
+    #[error("bed_mesh: Unknown profile [{0}]")]
+    UnknownProfile(String),
+    #[error(transparent)]
+    Probe(#[from] ProbeError),
+    #[error(transparent)]
+    Homing(#[from] HomingError),
+}
+
+/// A `[bed_mesh]` section
+#[derive(Clone, Debug, PartialEq)]
+pub struct BedMeshConfig {
+    /// Corners of the grid, as probe positions rather than nozzle ones
+    pub mesh_min: (f64, f64),
+    pub mesh_max: (f64, f64),
+    /// Points to probe along X and Y
+    pub probe_count: (usize, usize),
+    /// Points to fill in between each pair of probed ones along X and Y
+    pub mesh_pps: (usize, usize),
+    pub algorithm: Algorithm,
+    pub bicubic_tension: f64,
+    /// Travel speed between points
+    pub speed: MillimetersPerSecond,
+    /// Height to travel between points at
+    pub horizontal_move_z: Millimeters,
+    /// Height the correction starts tapering off at
+    pub fade_start: Millimeters,
+    /// Height it's gone by. Fading's off unless this is above `fade_start`
+    pub fade_end: Millimeters,
+    /// What's left once faded, the mesh's average height unless given
+    pub fade_target: Option<Millimeters>,
+    /// Change in correction along a move that's worth a new segment
+    pub split_delta_z: Millimeters,
+    /// How often along a move to check the correction
+    pub move_check_distance: Millimeters,
+}
+
+impl BedMeshConfig {
+    /// Klipper's defaults for everything but the grid's corners
+    pub fn new(mesh_min: (f64, f64), mesh_max: (f64, f64)) -> Self {
+        Self {
+            mesh_min,
+            mesh_max,
+            probe_count: (3, 3),
+            mesh_pps: (2, 2),
+            algorithm: Algorithm::Lagrange,
+            bicubic_tension: 0.2,
+            speed: MillimetersPerSecond(50.0),
+            horizontal_move_z: Millimeters(5.0),
+            fade_start: Millimeters(1.0),
+            fade_end: Millimeters(0.0),
+            fade_target: None,
+            split_delta_z: Millimeters(0.025),
+            move_check_distance: Millimeters(5.0),
+        }
+    }
+
+    /// Distance between probed points along X and Y, floored to the hundredth
+    fn point_spacing(&self) -> Result<(f64, f64), BedMeshError> {
+        let (x_count, y_count) = self.probe_count;
+        if x_count.min(y_count) < 3 {
+            return Err(BedMeshError::ProbeCount(x_count, y_count));
+        }
+        let spacing = |min: f64, max: f64, count: usize| {
+            ((max - min) / (count - 1) as f64 * 100.0).floor() / 100.0
+        };
+        let x_dist = spacing(self.mesh_min.0, self.mesh_max.0, x_count);
+        let y_dist = spacing(self.mesh_min.1, self.mesh_max.1, y_count);
+        if x_dist < 1.0 || y_dist < 1.0 {
+            return Err(BedMeshError::PointsTooClose);
+        }
+        Ok((x_dist, y_dist))
+    }
+
+    /// Points to probe, in order: back and forth along X, working up Y
+    pub fn probe_points(&self) -> Result<Vec<(f64, f64)>, BedMeshError> {
+        let (x_dist, y_dist) = self.point_spacing()?;
+        let (x_count, y_count) = self.probe_count;
+        let (min_x, min_y) = self.mesh_min;
+        let max_x = min_x + x_dist * (x_count - 1) as f64;
+        let points = (0..y_count).flat_map(|j| {
+            let y = min_y + y_dist * j as f64;
+            (0..x_count).map(move |i| match j % 2 {
+                0 => (min_x + x_dist * i as f64, y),
+                _ => (max_x - x_dist * i as f64, y),
+            })
+        });
+        Ok(points.collect())
+    }
+
+    /// The grid [`BedMeshConfig::probe_points`] covers
+    pub fn mesh_params(&self) -> Result<MeshParams, BedMeshError> {
+        let (x_dist, y_dist) = self.point_spacing()?;
+        let (x_count, y_count) = self.probe_count;
+        let mut params = MeshParams {
+            min_x: self.mesh_min.0,
+            max_x: self.mesh_min.0 + x_dist * (x_count - 1) as f64,
+            min_y: self.mesh_min.1,
+            max_y: self.mesh_min.1 + y_dist * (y_count - 1) as f64,
+            x_count,
+            y_count,
+            mesh_x_pps: self.mesh_pps.0,
+            mesh_y_pps: self.mesh_pps.1,
+            algo: self.algorithm,
+            tension: self.bicubic_tension,
+        };
+        params.verify()?;
+        Ok(params)
+    }
+}
+
+pub struct BedMesh {
+    config: BedMeshConfig,
+    /// Mesh being followed, if any
+    mesh: Option<ZMesh>,
+    /// Correction left once faded out
+    fade_target: f64,
+    profiles: BTreeMap<String, ZMesh>,
+}
+
+impl BedMesh {
+    pub fn new(config: BedMeshConfig) -> Result<Self, BedMeshError> {
+        config.mesh_params()?;
+        Ok(Self {
+            config,
+            mesh: None,
+            fade_target: 0.0,
+            profiles: BTreeMap::new(),
+        })
+    }
+
+    pub fn config(&self) -> &BedMeshConfig {
+        &self.config
+    }
+
+    pub fn mesh(&self) -> Option<&ZMesh> {
+        self.mesh.as_ref()
+    }
+
+    pub fn fade_target(&self) -> Millimeters {
+        Millimeters(self.fade_target)
+    }
+
+    /// Where fading starts and ends, unless it's off
+    fn fade(&self) -> Option<(f64, f64)> {
+        let (start, end) = (self.config.fade_start.0, self.config.fade_end.0);
+        (end > start).then_some((start, end))
+    }
+
+    /// Follow `mesh` from now on, or with `None` stop following any, like `BED_MESH_CLEAR`
+    pub fn set_mesh(&mut self, mesh: Option<ZMesh>) -> Result<(), BedMeshError> {
+        self.mesh = None;
+        self.fade_target = 0.0;
+        let Some(mesh) = mesh else {
+            return Ok(());
+        };
+        if let Some((start, end)) = self.fade() {
+            let (min, max) = mesh.z_range();
+            let target = match self.config.fade_target {
+                None => mesh.z_average(),
+                Some(Millimeters(target)) if target != 0.0 && !(min..=max).contains(&target) => {
+                    return Err(BedMeshError::FadeTargetOutOfRange { target, min, max });
+                }
+                Some(Millimeters(target)) => target,
+            };
+            let fade_dist = end - start;
+            if fade_dist <= min.abs().max(max.abs()) {
+                return Err(BedMeshError::OutsideFadeRange {
+                    fade_dist,
+                    min,
+                    max,
+                });
+            }
+            self.fade_target = target;
+        }
+        self.mesh = Some(mesh);
+        Ok(())
+    }
+
+    /// Names of every saved profile, in order
+    pub fn profiles(&self) -> impl Iterator<Item = &str> {
+        self.profiles.keys().map(String::as_str)
+    }
+
+    pub fn profile(&self, name: &str) -> Option<&ZMesh> {
+        self.profiles.get(name)
+    }
+
+    /// `BED_MESH_PROFILE SAVE`: keep the current mesh under `name`
+    pub fn save_profile(&mut self, name: &str) -> Result<(), BedMeshError> {
+        let mesh = self
+            .mesh
+            .clone()
+            .ok_or_else(|| BedMeshError::NotProbed(name.into()))?;
+        self.profiles.insert(name.into(), mesh);
+        Ok(())
+    }
+
+    /// `BED_MESH_PROFILE LOAD`: follow the mesh saved under `name`
+    pub fn load_profile(&mut self, name: &str) -> Result<(), BedMeshError> {
+        let mesh = self
+            .profiles
+            .get(name)
+            .cloned()
+            .ok_or_else(|| BedMeshError::UnknownProfile(name.into()))?;
+        self.set_mesh(Some(mesh))
+    }
+
+    /// `BED_MESH_PROFILE REMOVE`
+    pub fn remove_profile(&mut self, name: &str) -> Result<(), BedMeshError> {
+        self.profiles
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| BedMeshError::UnknownProfile(name.into()))
+    }
+
+    /// `BED_MESH_CALIBRATE`: probe every point, then follow the resulting mesh and save
+    /// it as the default profile
+    pub fn calibrate<K: Kinematics<Position = Coord>, L: McuLink>(
+        &mut self,
+        ctx: &mut HomingContext<K, L>,
+        probe: &mut Probe,
+    ) -> Result<(), BedMeshError> {
+        self.set_mesh(None)?;
+        let params = self.config.mesh_params()?;
+        let probe_points = ProbePoints {
+            points: self.config.probe_points()?,
+            horizontal_move_z: self.config.horizontal_move_z,
+            speed: self.config.speed,
+            use_offsets: true,
+        };
+        probe.begin(ctx)?;
+        let positions = probe_points.run(ctx, probe);
+        probe.end(ctx)?;
+        let z_offset = probe.config().z_offset.0;
+        let heights = positions?.into_iter().map(|pos| pos.z - z_offset);
+
+        // points went back and forth, the mesh wants every row in increasing X
+        let x_count = params.x_count;
+        let mut probed = vec![vec![0.0; x_count]; params.y_count];
+        for (i, z) in heights.enumerate() {
+            let (row, col) = (i / x_count, i % x_count);
+            let col = if row % 2 == 0 { col } else { x_count - 1 - col };
+            probed[row][col] = z;
+        }
+        self.set_mesh(Some(ZMesh::new(params, probed)?))?;
+        self.save_profile(DEFAULT_PROFILE)
+    }
+
+    /// How much of the mesh's correction applies at requested height `z`
+    fn z_factor(&self, z: f64) -> f64 {
+        match self.fade() {
+            Some((_, end)) if z >= end => 0.0,
+            Some((start, end)) if z >= start => (end - z) / (end - start),
+            _ => 1.0,
+        }
+    }
+}
+
+impl MoveTransform for BedMesh {
+    fn split_move(&self, from: Coord, to: Coord) -> Vec<MoveSegment> {
+        let factor = self.z_factor(to.z);
+        let lift = |pos: Coord, z_offset: f64| Coord::new(pos.x, pos.y, pos.z + z_offset);
+        let mesh = match &self.mesh {
+            Some(mesh) if factor > 0.0 => mesh,
+            _ => {
+                return vec![MoveSegment {
+                    end_pos: lift(to, self.fade_target),
+                    fraction: 1.0,
+                }]
+            }
+        };
+        let z_offset_at =
+            |pos: Coord| factor * (mesh.calc_z(pos.x, pos.y) - self.fade_target) + self.fade_target;
+        let start = self.requested_position(from);
+        let axes_d = to - start;
+        let move_d = axes_d.norm();
+        let mut segments = Vec::new();
+        if axes_d.x.abs() > 1e-10 || axes_d.y.abs() > 1e-10 {
+            let check_d = self.config.move_check_distance.0;
+            let mut z_offset = z_offset_at(start);
+            let mut checked = check_d;
+            while checked < move_d {
+                let fraction = checked / move_d;
+                let pos = start + axes_d * fraction;
+                let next_z_offset = z_offset_at(pos);
+                if (next_z_offset - z_offset).abs() >= self.config.split_delta_z.0 {
+                    z_offset = next_z_offset;
+                    segments.push(MoveSegment {
+                        end_pos: lift(pos, z_offset),
+                        fraction,
+                    });
+                }
+                checked += check_d;
+            }
+        }
+        segments.push(MoveSegment {
+            end_pos: lift(to, z_offset_at(to)),
+            fraction: 1.0,
+        });
+        segments
+    }
+
+    fn requested_position(&self, pos: Coord) -> Coord {
+        let Some(mesh) = &self.mesh else {
+            return Coord::new(pos.x, pos.y, pos.z - self.fade_target);
+        };
+        let max_adj = mesh.calc_z(pos.x, pos.y);
+        let z_adj = max_adj - self.fade_target;
+        // the requested height isn't known yet, so work the fade out backwards from the
+        // toolhead's
+        let factor = match self.fade() {
+            Some((_, end)) if pos.z.min(pos.z - max_adj) >= end => 0.0,
+            Some((start, end)) if pos.z.max(pos.z - max_adj) >= start => {
+                let factor = (end + self.fade_target - pos.z) / (end - start - z_adj);
+                factor.clamp(0.0, 1.0)
+            }
+            _ => 1.0,
+        };
+        Coord::new(pos.x, pos.y, pos.z - (factor * z_adj + self.fade_target))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::homing::HomingEndstop;
+    use crate::kinematics::cartesian::{Cartesian, CartesianConfig};
+    use crate::kinematics::{Axis, RailConfig};
+    use crate::mcu::endstop::Endstop;
+    use crate::mcu::fake::FakeLink;
+    use crate::mcu::pin::PinRef;
+    use crate::planner::{MoveChecks, Planner, PlannerConfig};
+    use crate::probe::{z_steppers, ProbeConfig, SwitchProbe};
+    use crate::stepcompress::StepCompressor;
+    use crate::testutils;
+    use crate::units::{Hertz, MillimetersPerSecondSquared, Seconds};
+
+    const FREQ: Hertz = Hertz(1_000_000.0);
+    const XY_STEP: f64 = 0.0125;
+    const Z_STEP: f64 = 0.0025;
+    /// Where the toolhead gets declared to be
+    const START: Coord = Coord::new(100.0, 100.0, 10.0);
+
+    fn tilted(x: f64, y: f64) -> f64 {
+        0.1 + 0.002 * x - 0.001 * y
+    }
+
+    /// A mesh straight from `surface`, no interpolation to blur it
+    fn mesh_of(surface: fn(f64, f64) -> f64) -> ZMesh {
+        let params = MeshParams {
+            min_x: 0.0,
+            max_x: 200.0,
+            min_y: 0.0,
+            max_y: 200.0,
+            x_count: 3,
+            y_count: 3,
+            mesh_x_pps: 0,
+            mesh_y_pps: 0,
+            algo: Algorithm::Direct,
+            tension: 0.2,
+        };
+        let probed = (0..3)
+            .map(|j| {
+                (0..3)
+                    .map(|i| surface(i as f64 * 100.0, j as f64 * 100.0))
+                    .collect()
+            })
+            .collect();
+        ZMesh::new(params, probed).unwrap()
+    }
+
+    fn cartesian() -> Cartesian {
+        let rail = |step: f64, max: f64| {
+            RailConfig::new(
+                Millimeters(step),
+                Millimeters(0.0),
+                Millimeters(max),
+                Millimeters(0.0),
+            )
+            .unwrap()
+        };
+        Cartesian::new(
+            CartesianConfig {
+                x: rail(XY_STEP, 200.0),
+                y: rail(XY_STEP, 200.0),
+                z: rail(Z_STEP, 150.0),
+                max_z_velocity: MillimetersPerSecond(10.0),
+                max_z_accel: MillimetersPerSecondSquared(100.0),
+            },
+            FREQ,
+        )
+    }
+
+    fn faded_config() -> BedMeshConfig {
+        let mut config = BedMeshConfig::new((0.0, 0.0), (200.0, 200.0));
+        config.fade_start = Millimeters(1.0);
+        config.fade_end = Millimeters(10.0);
+        config
+    }
+
+    #[test]
+    fn test_probe_points() {
+        let mut config = BedMeshConfig::new((10.0, 20.0), (190.0, 120.0));
+        config.probe_count = (3, 4);
+        let points = config.probe_points().unwrap();
+        // Y spacing floors 33.333 to 33.33, the last row ends up a little short
+        let expected = [
+            (10.0, 20.0),
+            (100.0, 20.0),
+            (190.0, 20.0),
+            (190.0, 53.33),
+            (100.0, 53.33),
+            (10.0, 53.33),
+            (10.0, 86.66),
+            (100.0, 86.66),
+            (190.0, 86.66),
+            (190.0, 119.99),
+            (100.0, 119.99),
+            (10.0, 119.99),
+        ];
+        assert_eq!(points.len(), expected.len());
+        for (point, expected) in points.iter().zip(expected) {
+            assert!((point.0 - expected.0).abs() < 1e-9 && (point.1 - expected.1).abs() < 1e-9);
+        }
+        let params = config.mesh_params().unwrap();
+        assert!((params.max_y - 119.99).abs() < 1e-9);
+
+        config.mesh_max = (11.5, 120.0);
+        assert!(matches!(
+            config.probe_points(),
+            Err(BedMeshError::PointsTooClose)
+        ));
+        config.probe_count = (2, 4);
+        assert!(matches!(
+            BedMesh::new(config),
+            Err(BedMeshError::ProbeCount(2, 4))
+        ));
+    }
+
+    #[test]
+    fn test_split_move() {
+        let mut bed_mesh = BedMesh::new(faded_config()).unwrap();
+        let mesh = mesh_of(tilted);
+        bed_mesh.set_mesh(Some(mesh.clone())).unwrap();
+        let target = bed_mesh.fade_target().0;
+        assert_eq!(target, mesh.z_average());
+
+        // below fade_start the toolhead follows the bed exactly, in steps small enough
+        let from = Coord::new(10.0, 20.0, 0.5 + tilted(10.0, 20.0));
+        let to = Coord::new(190.0, 20.0, 0.5);
+        assert_eq!(
+            bed_mesh.requested_position(from),
+            Coord::new(10.0, 20.0, 0.5)
+        );
+        let segments = bed_mesh.split_move(from, to);
+        assert_eq!(segments.len(), 12);
+        let mut prev = from;
+        for segment in &segments {
+            let pos = segment.end_pos;
+            assert!((pos.z - 0.5 - tilted(pos.x, pos.y)).abs() < 1e-9);
+            assert!((pos.x - (10.0 + 180.0 * segment.fraction)).abs() < 1e-9);
+            assert!((pos.z - prev.z).abs() >= 0.025 - 1e-9);
+            assert!((bed_mesh.requested_position(pos).z - 0.5).abs() < 1e-9);
+            prev = pos;
+        }
+        assert_eq!(segments.last().unwrap().fraction, 1.0);
+
+        // halfway through the fade, half the correction
+        let to = Coord::new(150.0, 50.0, 5.5);
+        let segments = bed_mesh.split_move(from, to);
+        let end = segments.last().unwrap().end_pos;
+        let expected = 5.5 + 0.5 * (tilted(150.0, 50.0) - target) + target;
+        assert!((end.z - expected).abs() < 1e-9);
+        assert!((bed_mesh.requested_position(end).z - 5.5).abs() < 1e-9);
+
+        // past it, only the fade target is left, and there's nothing to split
+        let to = Coord::new(150.0, 50.0, 12.0);
+        let segments = bed_mesh.split_move(end, to);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].end_pos.z, 12.0 + target);
+        assert_eq!(bed_mesh.requested_position(segments[0].end_pos), to);
+
+        // straight up and down needs no splitting either
+        let from = Coord::new(150.0, 50.0, 0.2 + tilted(150.0, 50.0));
+        let segments = bed_mesh.split_move(from, Coord::new(150.0, 50.0, 0.4));
+        assert_eq!(segments.len(), 1);
+
+        // the planner extrudes evenly along the segments
+        let mut planner = Planner::new(PlannerConfig::new(
+            MillimetersPerSecond(300.0),
+            MillimetersPerSecondSquared(3000.0),
+        ));
+        let mut kin = cartesian();
+        kin.set_position(from, Axis::XYZ);
+        planner.set_position(from);
+        let extruder = testutils::extruder(planner.config());
+        let checks = MoveChecks {
+            kin: &kin,
+            extruder: &extruder,
+            can_extrude: true,
+        };
+        let to = Coord::new(50.0, 150.0, 0.2);
+        planner
+            .transformed_move(
+                &checks,
+                &bed_mesh,
+                to,
+                Millimeters(4.0),
+                MillimetersPerSecond(100.0),
+            )
+            .unwrap();
+        planner.flush();
+        assert_eq!(planner.e_position(), Millimeters(4.0));
+        assert!((planner.position().z - 0.2 - tilted(50.0, 150.0)).abs() < 1e-9);
+        assert!((bed_mesh.requested_position(planner.position()).z - 0.2).abs() < 1e-9);
+
+        // without a mesh, moves go straight through
+        bed_mesh.set_mesh(None).unwrap();
+        let segments = bed_mesh.split_move(from, to);
+        assert_eq!(
+            segments,
+            [MoveSegment {
+                end_pos: to,
+                fraction: 1.0
+            }]
+        );
+    }
+
+    #[test]
+    fn test_profiles() {
+        let mut bed_mesh = BedMesh::new(faded_config()).unwrap();
+        let err = bed_mesh.save_profile("pei");
+        assert!(matches!(err, Err(BedMeshError::NotProbed(name)) if name == "pei"));
+
+        bed_mesh.set_mesh(Some(mesh_of(tilted))).unwrap();
+        bed_mesh.save_profile("pei").unwrap();
+        bed_mesh.set_mesh(Some(mesh_of(|x, _| x * 0.001))).unwrap();
+        bed_mesh.save_profile("glass").unwrap();
+        assert_eq!(bed_mesh.profiles().collect::<Vec<_>>(), ["glass", "pei"]);
+
+        bed_mesh.load_profile("pei").unwrap();
+        assert_eq!(bed_mesh.mesh(), Some(&mesh_of(tilted)));
+        bed_mesh.remove_profile("pei").unwrap();
+        let err = bed_mesh.load_profile("pei");
+        assert!(matches!(err, Err(BedMeshError::UnknownProfile(_))));
+        assert!(bed_mesh.remove_profile("pei").is_err());
+
+        // the mesh has to fit within the fade, and the target within the mesh
+        let err = bed_mesh.set_mesh(Some(mesh_of(|x, _| x * 0.05)));
+        assert!(matches!(err, Err(BedMeshError::OutsideFadeRange { .. })));
+        assert!(bed_mesh.mesh().is_none());
+        let mut config = faded_config();
+        config.fade_target = Some(Millimeters(1.0));
+        let mut bed_mesh = BedMesh::new(config).unwrap();
+        let err = bed_mesh.set_mesh(Some(mesh_of(tilted)));
+        assert!(matches!(
+            err,
+            Err(BedMeshError::FadeTargetOutOfRange { .. })
+        ));
+
+        // with fading off there's no target either
+        let mut bed_mesh = BedMesh::new(BedMeshConfig::new((0.0, 0.0), (200.0, 200.0))).unwrap();
+        bed_mesh.set_mesh(Some(mesh_of(tilted))).unwrap();
+        assert_eq!(bed_mesh.fade_target(), Millimeters(0.0));
+        let to = Coord::new(100.0, 100.0, 50.0);
+        let segments = bed_mesh.split_move(to, to);
+        assert!((segments[0].end_pos.z - 50.0 - tilted(100.0, 100.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_calibrate() {
+        let mut kin = cartesian();
+        let mut planner = Planner::new(PlannerConfig::new(
+            MillimetersPerSecond(300.0),
+            MillimetersPerSecondSquared(3000.0),
+        ));
+        let mut link = FakeLink::new(&["mcu"], FREQ);
+        let mut axes = Vec::new();
+        for (i, step) in [XY_STEP, XY_STEP, Z_STEP].into_iter().enumerate() {
+            let mcu = link.mcu("mcu").unwrap();
+            let oid = mcu.create_oid();
+            let sc = StepCompressor::new(oid, FREQ, Seconds(0.000_025), false);
+            kin.steppers_mut()[i].set_stepcompress(mcu, sc);
+            let steps = link.fake("mcu").stepper_position(oid);
+            axes.push(move || steps.get() as f64 * step);
+        }
+        let nozzle = Rc::new(move || START + Coord::new(axes[0](), axes[1](), axes[2]()));
+
+        // the probe trips with the nozzle 1mm above the bed under the probe
+        let probe_config = ProbeConfig::new(Millimeters(-10.0), Millimeters(5.0), Millimeters(1.0));
+        let offsets = Coord::new(-10.0, 5.0, 1.0);
+        let pin = PinRef {
+            mcu: "mcu".into(),
+            pin: "PB7".into(),
+        };
+        let endstop = Endstop::new(link.mcu("mcu").unwrap(), pin, false, true);
+        let at = nozzle.clone();
+        link.fake("mcu").set_pin("PB7", move || {
+            let pos = at();
+            pos.z <= tilted(pos.x + offsets.x, pos.y + offsets.y) + offsets.z
+        });
+        let steppers = z_steppers(&kin);
+        let endstop = HomingEndstop::new(&mut link, &kin, "probe", endstop, steppers).unwrap();
+        let mut probe = Probe::new(probe_config, endstop, Box::new(SwitchProbe));
+
+        let mut config = BedMeshConfig::new((20.0, 20.0), (180.0, 180.0));
+        config.horizontal_move_z = Millimeters(3.0);
+        config.fade_end = Millimeters(10.0);
+        let mut bed_mesh = BedMesh::new(config).unwrap();
+        let mut ctx = HomingContext {
+            kin: &mut kin,
+            planner: &mut planner,
+            link: &mut link,
+        };
+        ctx.set_position(START, Axis::XYZ).unwrap();
+        bed_mesh.calibrate(&mut ctx, &mut probe).unwrap();
+
+        let mesh = bed_mesh.mesh().unwrap();
+        assert_eq!(bed_mesh.profile(DEFAULT_PROFILE), Some(mesh));
+        assert_eq!(mesh.params().min_x, 20.0);
+        for (x, y) in [(20.0, 20.0), (33.3, 150.2), (100.0, 100.0), (177.7, 61.0)] {
+            let z = mesh.calc_z(x, y);
+            assert!((z - tilted(x, y)).abs() < 2.0 * Z_STEP, "{z} at {x},{y}");
+        }
+
+        // and printing just above the bed follows it
+        let extruder = testutils::extruder(ctx.planner.config());
+        for (x, y) in [(40.0, 60.0), (160.0, 170.0), (90.0, 30.0)] {
+            let to = Coord::new(x, y, 0.2);
+            let checks = MoveChecks {
+                kin: &*ctx.kin,
+                extruder: &extruder,
+                can_extrude: true,
+            };
+            ctx.planner
+                .transformed_move(
+                    &checks,
+                    &bed_mesh,
+                    to,
+                    Millimeters(0.0),
+                    MillimetersPerSecond(50.0),
+                )
+                .unwrap();
+            let pos = ctx.planner.position();
+            assert!((pos.z - 0.2 - tilted(x, y)).abs() < 2.0 * Z_STEP, "{pos:?}");
+        }
+    }
+}
@@ -0,0 +1,397 @@
+//! A probed grid of bed heights, filled in to a finer grid and interpolated between,
+//! klipper's `ZMesh`
+
+use super::BedMeshError;
+
+/// How the points between probed ones get filled in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Algorithm {
+    /// Nothing to fill in, used when there are no points between probes
+    Direct,
+    #[default]
+    Lagrange,
+    Bicubic,
+}
+
+/// Everything about a mesh but its heights
+#[derive(Clone, Debug, PartialEq)]
+pub struct MeshParams {
+    pub min_x: f64,
+    pub max_x: f64,
+    pub min_y: f64,
+    pub max_y: f64,
+    /// Probed points along X
+    pub x_count: usize,
+    pub y_count: usize,
+    /// Points filled in between each pair of probed points along X
+    pub mesh_x_pps: usize,
+    pub mesh_y_pps: usize,
+    pub algo: Algorithm,
+    /// Bicubic tension, how tightly the spline hugs straight lines between points
+    pub tension: f64,
+}
+
+impl MeshParams {
+    /// Check the algorithm suits the probe count, switching to whatever klipper would
+    /// switch to when it doesn't quite
+    pub fn verify(&mut self) -> Result<(), BedMeshError> {
+        let max_count = self.x_count.max(self.y_count);
+        let min_count = self.x_count.min(self.y_count);
+        if min_count < 3 {
+            return Err(BedMeshError::ProbeCount(self.x_count, self.y_count));
+        }
+        if self.mesh_x_pps.max(self.mesh_y_pps) == 0 {
+            self.algo = Algorithm::Direct;
+        } else if self.algo == Algorithm::Lagrange && max_count > 6 {
+            // lagrange oscillates with any more than 6 samples
+            return Err(BedMeshError::LagrangeProbeCount(self.x_count, self.y_count));
+        } else if self.algo == Algorithm::Bicubic && min_count < 4 {
+            if max_count > 6 {
+                return Err(BedMeshError::BicubicProbeCount(self.x_count, self.y_count));
+            }
+            self.algo = Algorithm::Lagrange;
+        }
+        Ok(())
+    }
+}
+
+/// Hermite spline through `p1` and `p2`, taking its slopes from `p0` and `p3`, at `t`
+fn cardinal_spline([p0, p1, p2, p3]: [f64; 4], t: f64, tension: f64) -> f64 {
+    let (t2, t3) = (t * t, t * t * t);
+    let m1 = tension * (p2 - p0);
+    let m2 = tension * (p3 - p1);
+    p1 * (2.0 * t3 - 3.0 * t2 + 1.0)
+        + p2 * (-2.0 * t3 + 3.0 * t2)
+        + m1 * (t3 - 2.0 * t2 + t)
+        + m2 * (t3 - t2)
+}
+
+/// Polynomial through every `(pts[i], z(i))`, at `c`
+fn lagrange(pts: &[f64], c: f64, z: impl Fn(usize) -> f64) -> f64 {
+    (0..pts.len())
+        .map(|i| {
+            let (n, d) = (0..pts.len())
+                .filter(|&j| j != i)
+                .fold((1.0, 1.0), |(n, d), j| {
+                    (n * (c - pts[j]), d * (pts[i] - pts[j]))
+                });
+            z(i) * n / d
+        })
+        .sum()
+}
+
+/// Bicubic control points around `i` on a line of `count` points with probed ones every
+/// `mult`, and how far between the middle two `i` is
+fn spline_ctl_pts(
+    line: impl Fn(usize) -> f64,
+    i: usize,
+    count: usize,
+    mult: usize,
+) -> ([f64; 4], f64) {
+    let last_pt = count - 1 - mult;
+    let t = (i % mult) as f64 / mult as f64;
+    if i < mult {
+        ([line(0), line(0), line(mult), line(2 * mult)], t)
+    } else if i > last_pt {
+        let pts = [
+            line(last_pt - mult),
+            line(last_pt),
+            line(last_pt + mult),
+            line(last_pt + mult),
+        ];
+        (pts, (i - last_pt) as f64 / mult as f64)
+    } else {
+        let start = i - i % mult;
+        let pts = [
+            line(start - mult),
+            line(start),
+            line(start + mult),
+            line(start + 2 * mult),
+        ];
+        (pts, t)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZMesh {
+    params: MeshParams,
+    /// Heights as probed, by row along Y then column along X
+    probed: Vec<Vec<f64>>,
+    /// Probed heights with the gaps filled in
+    mesh: Vec<Vec<f64>>,
+    x_count: usize,
+    y_count: usize,
+    x_dist: f64,
+    y_dist: f64,
+}
+
+impl ZMesh {
+    /// Mesh from `probed` heights, a row for each probed Y of a height for each probed X
+    pub fn new(mut params: MeshParams, probed: Vec<Vec<f64>>) -> Result<Self, BedMeshError> {
+        params.verify()?;
+        if probed.len() != params.y_count || probed.iter().any(|row| row.len() != params.x_count) {
+            return Err(BedMeshError::MatrixShape);
+        }
+        let x_count = (params.x_count - 1) * params.mesh_x_pps + params.x_count;
+        let y_count = (params.y_count - 1) * params.mesh_y_pps + params.y_count;
+        let mut zmesh = Self {
+            x_dist: (params.max_x - params.min_x) / (x_count - 1) as f64,
+            y_dist: (params.max_y - params.min_y) / (y_count - 1) as f64,
+            x_count,
+            y_count,
+            mesh: Vec::new(),
+            probed,
+            params,
+        };
+        zmesh.mesh = match zmesh.params.algo {
+            Algorithm::Direct => zmesh.probed.clone(),
+            Algorithm::Lagrange => zmesh.sample_lagrange(),
+            Algorithm::Bicubic => zmesh.sample_bicubic(),
+        };
+        Ok(zmesh)
+    }
+
+    pub fn params(&self) -> &MeshParams {
+        &self.params
+    }
+
+    pub fn probed_matrix(&self) -> &[Vec<f64>] {
+        &self.probed
+    }
+
+    pub fn mesh_matrix(&self) -> &[Vec<f64>] {
+        &self.mesh
+    }
+
+    fn x_mult(&self) -> usize {
+        self.params.mesh_x_pps + 1
+    }
+
+    fn y_mult(&self) -> usize {
+        self.params.mesh_y_pps + 1
+    }
+
+    fn x_coordinate(&self, index: usize) -> f64 {
+        self.params.min_x + self.x_dist * index as f64
+    }
+
+    fn y_coordinate(&self, index: usize) -> f64 {
+        self.params.min_y + self.y_dist * index as f64
+    }
+
+    /// Lowest and highest points of the mesh
+    pub fn z_range(&self) -> (f64, f64) {
+        self.mesh
+            .iter()
+            .flatten()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &z| {
+                (min.min(z), max.max(z))
+            })
+    }
+
+    /// Average height of the mesh, to the nearest hundredth like klipper
+    pub fn z_average(&self) -> f64 {
+        let count = self.x_count * self.y_count;
+        let avg = self.mesh.iter().flatten().sum::<f64>() / count as f64;
+        (avg * 100.0).round() / 100.0
+    }
+
+    /// Bed height at `x`, `y`, by straight lines between the mesh's points. Off the mesh
+    /// it's level with the nearest edge
+    pub fn calc_z(&self, x: f64, y: f64) -> f64 {
+        let (tx, xi) = self.linear_index(x, self.params.min_x, self.x_dist, self.x_count);
+        let (ty, yi) = self.linear_index(y, self.params.min_y, self.y_dist, self.y_count);
+        let lerp = |t: f64, a: f64, b: f64| a + t * (b - a);
+        let z0 = lerp(tx, self.mesh[yi][xi], self.mesh[yi][xi + 1]);
+        let z1 = lerp(tx, self.mesh[yi + 1][xi], self.mesh[yi + 1][xi + 1]);
+        lerp(ty, z0, z1)
+    }
+
+    /// Index of the mesh point before `coord`, and how far on towards the next one it is
+    fn linear_index(&self, coord: f64, min: f64, dist: f64, count: usize) -> (f64, usize) {
+        let idx = ((coord - min) / dist)
+            .floor()
+            .clamp(0.0, (count - 2) as f64) as usize;
+        let t = (coord - (min + dist * idx as f64)) / dist;
+        (t.clamp(0.0, 1.0), idx)
+    }
+
+    /// Fine mesh with the probed points in place and zeros everywhere else
+    fn spread_probed(&self) -> Vec<Vec<f64>> {
+        let (x_mult, y_mult) = (self.x_mult(), self.y_mult());
+        (0..self.y_count)
+            .map(|j| {
+                (0..self.x_count)
+                    .map(|i| match (i % x_mult, j % y_mult) {
+                        (0, 0) => self.probed[j / y_mult][i / x_mult],
+                        _ => 0.0,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn sample_lagrange(&self) -> Vec<Vec<f64>> {
+        let (x_mult, y_mult) = (self.x_mult(), self.y_mult());
+        let mut mesh = self.spread_probed();
+        let xpts: Vec<_> = (0..self.params.x_count)
+            .map(|i| self.x_coordinate(i * x_mult))
+            .collect();
+        let ypts: Vec<_> = (0..self.params.y_count)
+            .map(|j| self.y_coordinate(j * y_mult))
+            .collect();
+        // along X first, on rows that were probed
+        for j in (0..self.y_count).step_by(y_mult) {
+            for i in (0..self.x_count).filter(|i| i % x_mult != 0) {
+                let x = self.x_coordinate(i);
+                mesh[j][i] = lagrange(&xpts, x, |k| mesh[j][k * x_mult]);
+            }
+        }
+        // then along Y, every column now being complete on those rows
+        for j in (0..self.y_count).filter(|j| j % y_mult != 0) {
+            let y = self.y_coordinate(j);
+            mesh[j] = (0..self.x_count)
+                .map(|i| lagrange(&ypts, y, |k| mesh[k * y_mult][i]))
+                .collect();
+        }
+        mesh
+    }
+
+    fn sample_bicubic(&self) -> Vec<Vec<f64>> {
+        let (x_mult, y_mult) = (self.x_mult(), self.y_mult());
+        let tension = self.params.tension;
+        let mut mesh = self.spread_probed();
+        for j in (0..self.y_count).step_by(y_mult) {
+            for i in (0..self.x_count).filter(|i| i % x_mult != 0) {
+                let (pts, t) = spline_ctl_pts(|k| mesh[j][k], i, self.x_count, x_mult);
+                mesh[j][i] = cardinal_spline(pts, t, tension);
+            }
+        }
+        for j in (0..self.y_count).filter(|j| j % y_mult != 0) {
+            mesh[j] = (0..self.x_count)
+                .map(|i| {
+                    let (pts, t) = spline_ctl_pts(|k| mesh[k][i], j, self.y_count, y_mult);
+                    cardinal_spline(pts, t, tension)
+                })
+                .collect();
+        }
+        mesh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid(count: usize, pps: usize, algo: Algorithm) -> MeshParams {
+        MeshParams {
+            min_x: 10.0,
+            max_x: 190.0,
+            min_y: 20.0,
+            max_y: 180.0,
+            x_count: count,
+            y_count: count,
+            mesh_x_pps: pps,
+            mesh_y_pps: pps,
+            algo,
+            tension: 0.2,
+        }
+    }
+
+    /// Probe `surface` at every point of the grid
+    fn probe(params: &MeshParams, surface: impl Fn(f64, f64) -> f64) -> Vec<Vec<f64>> {
+        let at = |min: f64, max: f64, count: usize, i: usize| {
+            min + (max - min) * i as f64 / (count - 1) as f64
+        };
+        (0..params.y_count)
+            .map(|j| {
+                let y = at(params.min_y, params.max_y, params.y_count, j);
+                (0..params.x_count)
+                    .map(|i| surface(at(params.min_x, params.max_x, params.x_count, i), y))
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Points all over the mesh, and some way off it
+    fn points() -> impl Iterator<Item = (f64, f64)> {
+        (0..=20).flat_map(|i| (0..=20).map(move |j| (i as f64 * 10.0, j as f64 * 9.5 + 5.0)))
+    }
+
+    #[test]
+    fn test_plane() {
+        let plane = |x: f64, y: f64| 0.002 * x - 0.001 * y + 0.1;
+        for (count, pps, algo) in [
+            (3, 2, Algorithm::Lagrange),
+            (5, 3, Algorithm::Lagrange),
+            (3, 0, Algorithm::Direct),
+        ] {
+            let params = grid(count, pps, algo);
+            let mesh = ZMesh::new(params.clone(), probe(&params, plane)).unwrap();
+            assert_eq!(mesh.mesh_matrix().len(), (count - 1) * pps + count);
+            for (x, y) in points() {
+                // off the mesh, it's whatever the nearest edge is
+                let expected = plane(x.clamp(10.0, 190.0), y.clamp(20.0, 180.0));
+                let z = mesh.calc_z(x, y);
+                assert!((z - expected).abs() < 1e-9, "{algo:?} at {x},{y}: {z}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_curved_surfaces() {
+        // lagrange through 5 points is exact for a quadratic, so only the straight lines
+        // between mesh points are off
+        let bowl = |x: f64, y: f64| 0.000_01 * ((x - 100.0).powi(2) + (y - 90.0).powi(2));
+        let params = grid(5, 3, Algorithm::Lagrange);
+        let mesh = ZMesh::new(params.clone(), probe(&params, bowl)).unwrap();
+        for (j, row) in mesh.mesh_matrix().iter().enumerate() {
+            for (i, &z) in row.iter().enumerate() {
+                let expected = bowl(mesh.x_coordinate(i), mesh.y_coordinate(j));
+                assert!((z - expected).abs() < 1e-9);
+            }
+        }
+        for (x, y) in points().filter(|&(x, y)| (10.0..=190.0).contains(&x) && y >= 20.0) {
+            let y = y.min(180.0);
+            assert!((mesh.calc_z(x, y) - bowl(x, y)).abs() < 0.001);
+        }
+
+        // bicubic goes through every probed point, and follows a gentle wave closely
+        let wave = |x: f64, y: f64| 0.05 * (x / 40.0).sin() + 0.03 * (y / 50.0).cos();
+        let params = grid(6, 2, Algorithm::Bicubic);
+        let mesh = ZMesh::new(params.clone(), probe(&params, wave)).unwrap();
+        for (j, row) in mesh.probed_matrix().iter().enumerate() {
+            for (i, &z) in row.iter().enumerate() {
+                assert_eq!(mesh.mesh_matrix()[j * 3][i * 3], z);
+            }
+        }
+        for (x, y) in points().filter(|&(x, y)| (10.0..=190.0).contains(&x) && y >= 20.0) {
+            let y = y.min(180.0);
+            assert!((mesh.calc_z(x, y) - wave(x, y)).abs() < 0.01, "at {x},{y}");
+        }
+        let (min, max) = mesh.z_range();
+        assert!(min >= -0.09 && max <= 0.09);
+    }
+
+    #[test]
+    fn test_algorithm_limits() {
+        let flat = |_, _| 0.0;
+        let params = grid(7, 2, Algorithm::Lagrange);
+        let err = ZMesh::new(params.clone(), probe(&params, flat));
+        assert!(matches!(err, Err(BedMeshError::LagrangeProbeCount(7, 7))));
+
+        let mut narrow = params.clone();
+        narrow.algo = Algorithm::Bicubic;
+        narrow.x_count = 3;
+        let err = ZMesh::new(narrow.clone(), probe(&narrow, flat));
+        assert!(matches!(err, Err(BedMeshError::BicubicProbeCount(3, 7))));
+
+        // bicubic needs 4 points a side, with fewer lagrange does instead
+        narrow.y_count = 5;
+        let mesh = ZMesh::new(narrow.clone(), probe(&narrow, flat)).unwrap();
+        assert_eq!(mesh.params().algo, Algorithm::Lagrange);
+
+        let err = ZMesh::new(narrow, vec![vec![0.0; 3]; 4]);
+        assert!(matches!(err, Err(BedMeshError::MatrixShape)));
+    }
+}
@@ -0,0 +1,127 @@
+//! Fit delta geometry to probed bed heights, klipper's `delta_calibrate.py`
+//!
+//! Each probe records which step every carriage was on when it touched the bed. Those
+//! step counts don't depend on the geometry, so the best geometry is the one that puts
+//! every touch at the same height
+
+use crate::kinematics::delta::DeltaConfig;
+use crate::mathutil;
+use crate::trapq::Coord;
+use crate::units::Millimeters;
+
+/// Returned for geometry the arms can't be assembled in, so the search steers clear
+const IMPOSSIBLE_ERROR: f64 = 9_999_999_999_999.9;
+/// How far out each of the six outer probes sits, as a fraction of the radius
+const SCATTER: [f64; 6] = [0.95, 0.90, 0.85, 0.70, 0.75, 0.80];
+
+/// Where to probe: the centre and then six points spiralling in, towards and between
+/// each tower
+pub fn probe_points(radius: Millimeters) -> Vec<(f64, f64)> {
+    let outer = SCATTER.iter().enumerate().map(|(i, scatter)| {
+        let (sin, cos) = (90.0 + 60.0 * i as f64).to_radians().sin_cos();
+        (cos * radius.0 * scatter, sin * radius.0 * scatter)
+    });
+    std::iter::once((0.0, 0.0)).chain(outer).collect()
+}
+
+/// What gets adjusted: radius, the angles of towers A and B, and all three endstops.
+/// Tower C's angle stays put, otherwise the whole printer could spin
+fn adjusted(config: &DeltaConfig, params: &[f64]) -> DeltaConfig {
+    let mut config = config.clone();
+    config.radius = Millimeters(params[0]);
+    config.towers[0].angle = params[1];
+    config.towers[1].angle = params[2];
+    for (tower, &endstop) in config.towers.iter_mut().zip(&params[3..]) {
+        tower.position_endstop = Millimeters(endstop);
+    }
+    config
+}
+
+/// Geometry that best explains where the probe hit the bed. `probed` holds the effector
+/// positions `config` put each trigger at, after taking off the probe's z offset, so on
+/// a perfectly calibrated printer every z would be zero
+pub fn calibrate(config: &DeltaConfig, probed: &[Coord]) -> DeltaConfig {
+    let stable: Vec<_> = probed.iter().map(|&p| config.stable_position(p)).collect();
+    let [a, b, c] = &config.towers;
+    let mut params = [
+        config.radius.0,
+        a.angle,
+        b.angle,
+        a.position_endstop.0,
+        b.position_endstop.0,
+        c.position_endstop.0,
+    ];
+    mathutil::coordinate_descent(&mut params, |params| {
+        let candidate = adjusted(config, params);
+        stable
+            .iter()
+            .map(|&s| match candidate.position_from_stable(s) {
+                Some(pos) => pos.z * pos.z,
+                None => IMPOSSIBLE_ERROR,
+            })
+            .sum()
+    });
+    adjusted(config, &params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::planner::PlannerConfig;
+    use crate::units::{MillimetersPerSecond, MillimetersPerSecondSquared};
+
+    #[test]
+    fn test_probe_points() {
+        let points = probe_points(Millimeters(100.0));
+        assert_eq!(points.len(), 7);
+        assert_eq!(points[0], (0.0, 0.0));
+        // straight towards tower C first
+        assert!(points[1].0.abs() < 1e-9 && (points[1].1 - 95.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_calibrate() {
+        let nominal = DeltaConfig::new(
+            Millimeters(140.0),
+            Millimeters(250.0),
+            Millimeters(300.0),
+            Millimeters(0.01),
+            &PlannerConfig::new(
+                MillimetersPerSecond(300.0),
+                MillimetersPerSecondSquared(3000.0),
+            ),
+        );
+        // how the printer was actually put together
+        let mut actual = nominal.clone();
+        actual.radius = Millimeters(141.3);
+        actual.towers[0].angle = 210.6;
+        actual.towers[1].angle = 329.5;
+        actual.towers[0].position_endstop = Millimeters(300.8);
+        actual.towers[1].position_endstop = Millimeters(299.4);
+        actual.towers[2].position_endstop = Millimeters(300.3);
+
+        // heights the nominal geometry reads with the effector really on the bed
+        let measure = |config: &DeltaConfig, points: &[(f64, f64)]| -> Vec<Coord> {
+            points
+                .iter()
+                .map(|&(x, y)| {
+                    let stable = actual.stable_position(Coord::new(x, y, 0.0));
+                    config.position_from_stable(stable).unwrap()
+                })
+                .collect()
+        };
+        let worst = |probed: &[Coord]| probed.iter().fold(0.0_f64, |m, p| m.max(p.z.abs()));
+
+        let probed = measure(&nominal, &probe_points(Millimeters(100.0)));
+        assert!(worst(&probed) > 0.5);
+        let calibrated = calibrate(&nominal, &probed);
+
+        // flat everywhere now, not just where it was probed
+        let check: Vec<_> = (-4..=4)
+            .flat_map(|i| (-4..=4).map(move |j| (i as f64 * 15.0, j as f64 * 15.0)))
+            .collect();
+        assert!(worst(&measure(&nominal, &check)) > 0.5);
+        let after = worst(&measure(&calibrated, &check));
+        assert!(after < 0.01, "still {after}mm out");
+    }
+}
@@ -0,0 +1,21 @@
+#ifndef CHELPER_H
+#define CHELPER_H
+
+// Kinematics from klippy/chelper that only have declarations in its __init__.py. Every
+// chelper source is built with this included first, so these have to agree with the
+// checked out klipper or it won't compile
+
+struct stepper_kinematics;
+
+struct stepper_kinematics *cartesian_stepper_alloc(char axis);
+struct stepper_kinematics *corexy_stepper_alloc(char type);
+struct stepper_kinematics *corexz_stepper_alloc(char type);
+struct stepper_kinematics *delta_stepper_alloc(double arm2, double tower_x
+                                               , double tower_y);
+struct stepper_kinematics *extruder_stepper_alloc(void);
+void extruder_set_pressure_advance(struct stepper_kinematics *sk
+                                   , double print_time
+                                   , double pressure_advance
+                                   , double smooth_time);
+
+#endif // chelper.h
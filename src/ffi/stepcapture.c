@@ -0,0 +1,85 @@
+// Stands in for klipper's stepcompress.c under itersolve.c, keeping every step as
+// generated instead of compressing them into queue_step commands for an MCU
+
+#include <stdlib.h> // calloc
+#include "klipper/klippy/chelper/stepcompress.h" // stepcompress_append
+#include "stepcapture.h" // struct captured_step
+
+struct stepcompress {
+    struct captured_step *steps;
+    int count, capacity;
+    // Direction of the last step, -1 before there's been one
+    int sdir;
+};
+
+struct stepcompress *
+stepcapture_alloc(void)
+{
+    struct stepcompress *sc = calloc(1, sizeof(*sc));
+    if (sc)
+        sc->sdir = -1;
+    return sc;
+}
+
+void
+stepcapture_free(struct stepcompress *sc)
+{
+    if (!sc)
+        return;
+    free(sc->steps);
+    free(sc);
+}
+
+int
+stepcapture_count(struct stepcompress *sc)
+{
+    return sc->count;
+}
+
+struct captured_step *
+stepcapture_steps(struct stepcompress *sc)
+{
+    return sc->steps;
+}
+
+// Stepper kinematics come from a plain malloc, klippy frees them the same way
+void
+stepcapture_free_kinematics(struct stepper_kinematics *sk)
+{
+    free(sk);
+}
+
+// Note a step at step_time into the move starting at print_time
+int
+stepcompress_append(struct stepcompress *sc, int sdir
+                    , double print_time, double step_time)
+{
+    if (sc->count == sc->capacity) {
+        int capacity = sc->capacity ? sc->capacity * 2 : 1024;
+        struct captured_step *steps = realloc(
+            sc->steps, capacity * sizeof(*steps));
+        if (!steps)
+            return -1;
+        sc->steps = steps;
+        sc->capacity = capacity;
+    }
+    struct captured_step *s = &sc->steps[sc->count++];
+    s->time = print_time + step_time;
+    s->dir = sdir;
+    sc->sdir = sdir;
+    return 0;
+}
+
+// itersolve picks up from the direction it last stepped in
+int
+stepcompress_get_step_dir(struct stepcompress *sc)
+{
+    return sc->sdir;
+}
+
+// Nothing is queued, so there's nothing to commit
+int
+stepcompress_commit(struct stepcompress *sc)
+{
+    return 0;
+}
@@ -0,0 +1,20 @@
+#ifndef STEPCAPTURE_H
+#define STEPCAPTURE_H
+
+#include <stdint.h> // uint8_t
+
+struct stepcompress;
+struct stepper_kinematics;
+
+struct captured_step {
+    double time;
+    uint8_t dir;
+};
+
+struct stepcompress *stepcapture_alloc(void);
+void stepcapture_free(struct stepcompress *sc);
+int stepcapture_count(struct stepcompress *sc);
+struct captured_step *stepcapture_steps(struct stepcompress *sc);
+void stepcapture_free_kinematics(struct stepper_kinematics *sk);
+
+#endif // stepcapture.h
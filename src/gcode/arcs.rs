@@ -0,0 +1,334 @@
+//! `G2`/`G3` arcs, cut into straight `G1` moves about as long as the configured resolution.
+//! Klipper's `gcode_arcs.py`
+
+use std::f64::consts::TAU;
+
+use super::{GCodeError, GCodeMove, MoveContext, MoveParams};
+use crate::kinematics::{Axis, Kinematics};
+use crate::trapq::Coord;
+use crate::units::Millimeters;
+
+/// Plane arcs go round in, as picked by `G17`, `G18` or `G19`. The axis left over moves
+/// along steadily, making a helix
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ArcPlane {
+    #[default]
+    XY,
+    XZ,
+    YZ,
+}
+
+impl ArcPlane {
+    /// The two axes going round, then the helical one
+    fn axes(self) -> [Axis; 3] {
+        match self {
+            ArcPlane::XY => [Axis::X, Axis::Y, Axis::Z],
+            ArcPlane::XZ => [Axis::X, Axis::Z, Axis::Y],
+            ArcPlane::YZ => [Axis::Y, Axis::Z, Axis::X],
+        }
+    }
+}
+
+/// `G2`/`G3` parameters, as given. The centre is at offsets `i`, `j` and `k` from the
+/// current position, along whichever two of X, Y and Z make up the plane
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ArcParams {
+    pub x: Option<f64>,
+    pub y: Option<f64>,
+    pub z: Option<f64>,
+    pub e: Option<f64>,
+    pub i: Option<f64>,
+    pub j: Option<f64>,
+    pub k: Option<f64>,
+    /// In mm/min
+    pub feedrate: Option<f64>,
+}
+
+pub struct GCodeArcs {
+    /// Length of each straight piece, give or take
+    resolution: Millimeters,
+    plane: ArcPlane,
+}
+
+impl GCodeArcs {
+    /// A `[gcode_arcs]` section
+    pub fn new(resolution: Millimeters) -> Result<Self, GCodeError> {
+        if resolution <= Millimeters(0.0) {
+            return Err(GCodeError::InvalidParameter("resolution"));
+        }
+        Ok(Self {
+            resolution,
+            plane: ArcPlane::default(),
+        })
+    }
+
+    pub fn plane(&self) -> ArcPlane {
+        self.plane
+    }
+
+    /// `G17`, `G18` or `G19`
+    pub fn set_plane(&mut self, plane: ArcPlane) {
+        self.plane = plane;
+    }
+
+    /// `G2` if `clockwise`, otherwise `G3`: a `G1` to each point along the arc, with
+    /// extrusion spread evenly between them
+    pub fn arc_move<K: Kinematics>(
+        &self,
+        gcode: &mut GCodeMove,
+        ctx: &mut MoveContext<K>,
+        params: &ArcParams,
+        clockwise: bool,
+    ) -> Result<(), GCodeError> {
+        if !gcode.absolute_coordinates() {
+            return Err(GCodeError::RelativeArc);
+        }
+        let current = gcode.position();
+        let target = Coord::new(
+            params.x.unwrap_or(current.x),
+            params.y.unwrap_or(current.y),
+            params.z.unwrap_or(current.z),
+        );
+        let offset = match self.plane {
+            ArcPlane::XY => (params.i, params.j),
+            ArcPlane::XZ => (params.i, params.k),
+            ArcPlane::YZ => (params.j, params.k),
+        };
+        let offset = (offset.0.unwrap_or(0.0), offset.1.unwrap_or(0.0));
+        if offset == (0.0, 0.0) {
+            return Err(GCodeError::MissingArcOffsets);
+        }
+        let coords = self.plan_arc(current, target, offset, clockwise);
+
+        let absolute_extrude = gcode.absolute_extrude();
+        let mut e_base = if absolute_extrude {
+            gcode.e_position().0
+        } else {
+            0.0
+        };
+        let e_per_move = params.e.map_or(0.0, |e| (e - e_base) / coords.len() as f64);
+        for coord in coords {
+            let mut e = None;
+            if e_per_move != 0.0 {
+                e = Some(e_base + e_per_move);
+                if absolute_extrude {
+                    e_base += e_per_move;
+                }
+            }
+            let to = MoveParams {
+                x: Some(coord.x),
+                y: Some(coord.y),
+                z: Some(coord.z),
+                e,
+                feedrate: params.feedrate,
+            };
+            gcode.linear_move(ctx, &to)?;
+        }
+        Ok(())
+    }
+
+    /// Points along the arc from `current` to `target` round the centre at `offset` from
+    /// `current`, ending at `target`. Going back to where it started makes a full circle
+    fn plan_arc(
+        &self,
+        current: Coord,
+        target: Coord,
+        (offset_a, offset_b): (f64, f64),
+        clockwise: bool,
+    ) -> Vec<Coord> {
+        let [alpha, beta, helical] = self.plane.axes();
+        // radius vector from the centre to the current position
+        let (r_a, r_b) = (-offset_a, -offset_b);
+        let center_a = alpha.of(current) - r_a;
+        let center_b = beta.of(current) - r_b;
+        let rt_a = alpha.of(target) - center_a;
+        let rt_b = beta.of(target) - center_b;
+        let mut angular_travel = (r_a * rt_b - r_b * rt_a).atan2(r_a * rt_a + r_b * rt_b);
+        if angular_travel < 0.0 {
+            angular_travel += TAU;
+        }
+        if clockwise {
+            angular_travel -= TAU;
+        }
+        if angular_travel == 0.0
+            && alpha.of(current) == alpha.of(target)
+            && beta.of(current) == beta.of(target)
+        {
+            angular_travel = TAU;
+        }
+
+        let linear_travel = helical.of(target) - helical.of(current);
+        let flat_mm = offset_a.hypot(offset_b) * angular_travel;
+        let mm_of_travel = if linear_travel != 0.0 {
+            flat_mm.hypot(linear_travel)
+        } else {
+            flat_mm.abs()
+        };
+        let segments = (mm_of_travel / self.resolution.0).floor().max(1.0);
+        let theta_per_segment = angular_travel / segments;
+        let linear_per_segment = linear_travel / segments;
+        let mut coords: Vec<_> = (1..segments as usize)
+            .map(|i| {
+                let i = i as f64;
+                let (sin, cos) = (i * theta_per_segment).sin_cos();
+                let r_a = -offset_a * cos + offset_b * sin;
+                let r_b = -offset_a * sin - offset_b * cos;
+                alpha.coord(center_a + r_a)
+                    + beta.coord(center_b + r_b)
+                    + helical.coord(helical.of(current) + i * linear_per_segment)
+            })
+            .collect();
+        coords.push(target);
+        coords
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fake_printer::{printer, Recorder};
+    use super::*;
+
+    fn start() -> MoveParams {
+        MoveParams {
+            x: Some(60.0),
+            y: Some(50.0),
+            z: Some(10.0),
+            ..MoveParams::default()
+        }
+    }
+
+    /// Angle of `pos` round the centre, in the plane of `axes`
+    fn angle(pos: Coord, [alpha, beta, _]: [Axis; 3], centre: (f64, f64)) -> f64 {
+        (beta.of(pos) - centre.1).atan2(alpha.of(pos) - centre.0)
+    }
+
+    #[test]
+    fn test_arcs() {
+        let mut printer = printer();
+        let recorder = Recorder::default();
+        let mut ctx = MoveContext {
+            kin: &printer.kin,
+            extruder: &printer.extruder,
+            can_extrude: true,
+            planner: &mut printer.planner,
+            transform: &recorder,
+        };
+        let mut gcode = GCodeMove::new();
+        gcode.linear_move(&mut ctx, &start()).unwrap();
+        let arcs = GCodeArcs::new(Millimeters(1.0)).unwrap();
+
+        // a quarter turn anticlockwise, 15.7mm round, extruding along the way
+        recorder.moves.borrow_mut().clear();
+        let params = ArcParams {
+            x: Some(50.0),
+            y: Some(60.0),
+            e: Some(1.5),
+            i: Some(-10.0),
+            feedrate: Some(1200.0),
+            ..ArcParams::default()
+        };
+        arcs.arc_move(&mut gcode, &mut ctx, &params, false).unwrap();
+        let moves = recorder.moves.take();
+        assert_eq!(moves.len(), 15);
+        let mut prev = 0.0;
+        for pos in &moves {
+            assert!(((*pos - Coord::new(50.0, 50.0, 10.0)).norm() - 10.0).abs() < 1e-9);
+            let angle = angle(*pos, ArcPlane::XY.axes(), (50.0, 50.0));
+            assert!(angle > prev);
+            prev = angle;
+        }
+        assert_eq!(moves.last(), Some(&Coord::new(50.0, 60.0, 10.0)));
+        assert!((ctx.planner.e_position() - Millimeters(1.5)).abs() < Millimeters(1e-9));
+        assert_eq!(gcode.speed().0, 20.0);
+
+        // on round the long way, with relative extrusion split up the same
+        gcode.set_absolute_extrude(false);
+        let params = ArcParams {
+            x: Some(60.0),
+            y: Some(50.0),
+            e: Some(4.7),
+            j: Some(-10.0),
+            ..ArcParams::default()
+        };
+        arcs.arc_move(&mut gcode, &mut ctx, &params, false).unwrap();
+        let moves = recorder.moves.take();
+        assert_eq!(moves.len(), 47);
+        let mut prev = 0.0;
+        for pos in &moves[..46] {
+            let angle = angle(*pos, ArcPlane::XY.axes(), (50.0, 50.0)).rem_euclid(TAU);
+            assert!(angle > prev, "{angle} after {prev}");
+            prev = angle;
+        }
+        assert!((ctx.planner.e_position() - Millimeters(6.2)).abs() < Millimeters(1e-9));
+
+        // and clockwise down to the bottom is the short way
+        let params = ArcParams {
+            x: Some(50.0),
+            y: Some(40.0),
+            i: Some(-10.0),
+            ..ArcParams::default()
+        };
+        arcs.arc_move(&mut gcode, &mut ctx, &params, true).unwrap();
+        assert_eq!(recorder.moves.take().len(), 15);
+    }
+
+    #[test]
+    fn test_helix_and_full_circle() {
+        let mut printer = printer();
+        let recorder = Recorder::default();
+        let mut ctx = MoveContext {
+            kin: &printer.kin,
+            extruder: &printer.extruder,
+            can_extrude: true,
+            planner: &mut printer.planner,
+            transform: &recorder,
+        };
+        let mut gcode = GCodeMove::new();
+        gcode.linear_move(&mut ctx, &start()).unwrap();
+        let mut arcs = GCodeArcs::new(Millimeters(2.0)).unwrap();
+
+        // once round in the XZ plane, centred 10mm up, while moving 4mm along Y
+        arcs.set_plane(ArcPlane::XZ);
+        recorder.moves.borrow_mut().clear();
+        let params = ArcParams {
+            y: Some(54.0),
+            k: Some(10.0),
+            ..ArcParams::default()
+        };
+        arcs.arc_move(&mut gcode, &mut ctx, &params, false).unwrap();
+        let moves = recorder.moves.take();
+        // 62.83mm round and 4mm along, in 2mm pieces
+        assert_eq!(moves.len(), 31);
+        for (i, pos) in moves.iter().enumerate() {
+            let radius = (pos.x - 60.0).hypot(pos.z - 20.0);
+            assert!((radius - 10.0).abs() < 1e-9);
+            assert!((pos.y - 50.0 - 4.0 * (i + 1) as f64 / 31.0).abs() < 1e-9);
+        }
+        assert_eq!(ctx.planner.position(), Coord::new(60.0, 54.0, 10.0));
+
+        // I does nothing in the YZ plane
+        arcs.set_plane(ArcPlane::YZ);
+        let params = ArcParams {
+            x: Some(70.0),
+            i: Some(10.0),
+            ..ArcParams::default()
+        };
+        assert_eq!(
+            arcs.arc_move(&mut gcode, &mut ctx, &params, true),
+            Err(GCodeError::MissingArcOffsets)
+        );
+        gcode.set_absolute_coordinates(false);
+        let params = ArcParams {
+            k: Some(10.0),
+            ..ArcParams::default()
+        };
+        assert_eq!(
+            arcs.arc_move(&mut gcode, &mut ctx, &params, true),
+            Err(GCodeError::RelativeArc)
+        );
+        assert!(matches!(
+            GCodeArcs::new(Millimeters(0.0)),
+            Err(GCodeError::InvalidParameter("resolution"))
+        ));
+    }
+}
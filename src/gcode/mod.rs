@@ -0,0 +1,404 @@
+//! The G-code side of moving: absolute or relative coordinates, `G92` offsets and the
+//! current feedrate, turned into toolhead moves. Klipper's `gcode_move.py`, with
+//! `firmware_retraction.py` and `gcode_arcs.py` building on it
+
+pub mod arcs;
+pub mod retraction;
+
+use crate::kinematics::extruder::Extruder;
+use crate::kinematics::{Kinematics, KinematicsError};
+use crate::planner::{MoveChecks, MoveTransform, Planner};
+use crate::trapq::Coord;
+use crate::units::{Millimeters, MillimetersPerSecond};
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum GCodeError {
+    #[error("Invalid speed F{0}")]
+    InvalidSpeed(f64),
+    #[error("{0} out of range")]
+    InvalidParameter(&'static str),
+    #[error("G2/G3 does not support relative move mode")]
+    RelativeArc,
+    #[error("G2/G3 requires IJ, IK or JK parameters")]
+    MissingArcOffsets,
+    #[error(transparent)]
+    Kinematics(#[from] KinematicsError),
+}
+
+/// Where G-code moves go: through `transform`, then onto the planner if `kin` and
+/// `extruder` allow
+pub struct MoveContext<'a, K> {
+    pub kin: &'a K,
+    pub extruder: &'a Extruder,
+    /// The extruder heater's `can_extrude`
+    pub can_extrude: bool,
+    pub planner: &'a mut Planner,
+    pub transform: &'a dyn MoveTransform,
+}
+
+impl<K: Kinematics> MoveContext<'_, K> {
+    fn move_to(
+        &mut self,
+        pos: Coord,
+        e: Millimeters,
+        speed: MillimetersPerSecond,
+    ) -> Result<(), GCodeError> {
+        let checks = MoveChecks {
+            kin: self.kin,
+            extruder: self.extruder,
+            can_extrude: self.can_extrude,
+        };
+        self.planner
+            .transformed_move(&checks, self.transform, pos, e, speed)?;
+        Ok(())
+    }
+}
+
+/// `G0`/`G1` and `G92` parameters, as given
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MoveParams {
+    pub x: Option<f64>,
+    pub y: Option<f64>,
+    pub z: Option<f64>,
+    pub e: Option<f64>,
+    /// In mm/min
+    pub feedrate: Option<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GCodeMove {
+    absolute_coord: bool,
+    absolute_extrude: bool,
+    /// Where the last move went, before any transform
+    last_position: Coord,
+    last_e: Millimeters,
+    /// What `G92` made zero
+    base_position: Coord,
+    base_e: Millimeters,
+    speed: MillimetersPerSecond,
+}
+
+impl Default for GCodeMove {
+    fn default() -> Self {
+        Self {
+            absolute_coord: true,
+            absolute_extrude: true,
+            last_position: Coord::default(),
+            last_e: Millimeters::ZERO,
+            base_position: Coord::default(),
+            base_e: Millimeters::ZERO,
+            speed: MillimetersPerSecond(25.0),
+        }
+    }
+}
+
+impl GCodeMove {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn absolute_coordinates(&self) -> bool {
+        self.absolute_coord
+    }
+
+    /// `G90`, or `G91` when not `absolute`
+    pub fn set_absolute_coordinates(&mut self, absolute: bool) {
+        self.absolute_coord = absolute;
+    }
+
+    pub fn absolute_extrude(&self) -> bool {
+        self.absolute_extrude
+    }
+
+    /// `M82`, or `M83` when not `absolute`
+    pub fn set_absolute_extrude(&mut self, absolute: bool) {
+        self.absolute_extrude = absolute;
+    }
+
+    /// Position in G-code coordinates, `G92` offsets and all
+    pub fn position(&self) -> Coord {
+        self.last_position - self.base_position
+    }
+
+    pub fn e_position(&self) -> Millimeters {
+        self.last_e - self.base_e
+    }
+
+    /// Speed of moves that don't give a feedrate
+    pub fn speed(&self) -> MillimetersPerSecond {
+        self.speed
+    }
+
+    /// Pick up wherever the toolhead has got to, e.g. after homing or a change of transform
+    pub fn reset_last_position<K>(&mut self, ctx: &MoveContext<K>) {
+        self.last_position = ctx.transform.requested_position(ctx.planner.position());
+        self.last_e = ctx.planner.e_position();
+    }
+
+    /// `G0`/`G1`
+    pub fn linear_move<K: Kinematics>(
+        &mut self,
+        ctx: &mut MoveContext<K>,
+        params: &MoveParams,
+    ) -> Result<(), GCodeError> {
+        let mut pos = self.last_position;
+        let base = self.base_position;
+        for (pos, base, value) in [
+            (&mut pos.x, base.x, params.x),
+            (&mut pos.y, base.y, params.y),
+            (&mut pos.z, base.z, params.z),
+        ] {
+            if let Some(value) = value {
+                *pos = if self.absolute_coord {
+                    value + base
+                } else {
+                    *pos + value
+                };
+            }
+        }
+        let e = match params.e.map(Millimeters) {
+            Some(e) if self.absolute_coord && self.absolute_extrude => e + self.base_e,
+            Some(e) => self.last_e + e,
+            None => self.last_e,
+        };
+        let speed = match params.feedrate {
+            Some(feedrate) if feedrate <= 0.0 => return Err(GCodeError::InvalidSpeed(feedrate)),
+            Some(feedrate) => MillimetersPerSecond(feedrate / 60.0),
+            None => self.speed,
+        };
+        ctx.move_to(pos, e, speed)?;
+        self.last_position = pos;
+        self.last_e = e;
+        self.speed = speed;
+        Ok(())
+    }
+
+    /// Move the extruder alone by `distance`, leaving the feedrate, modes and G-code E
+    /// position as they were. What klipper does with `G91`, `G1 E` and
+    /// `RESTORE_GCODE_STATE`, which shifts the `G92` base along with the move
+    pub fn extrude_by<K: Kinematics>(
+        &mut self,
+        ctx: &mut MoveContext<K>,
+        distance: Millimeters,
+        speed: MillimetersPerSecond,
+    ) -> Result<(), GCodeError> {
+        let e = self.last_e + distance;
+        ctx.move_to(self.last_position, e, speed)?;
+        self.last_e = e;
+        self.base_e += distance;
+        Ok(())
+    }
+
+    /// `G92`: make the current position read as given, or zero everywhere if nothing is
+    pub fn set_position(&mut self, params: &MoveParams) {
+        let MoveParams { x, y, z, e, .. } = *params;
+        if [x, y, z, e].iter().all(Option::is_none) {
+            self.base_position = self.last_position;
+            self.base_e = self.last_e;
+            return;
+        }
+        let last = self.last_position;
+        let base = &mut self.base_position;
+        for (base, last, value) in [
+            (&mut base.x, last.x, x),
+            (&mut base.y, last.y, y),
+            (&mut base.z, last.z, z),
+        ] {
+            if let Some(value) = value {
+                *base = last - value;
+            }
+        }
+        if let Some(e) = e {
+            self.base_e = self.last_e - Millimeters(e);
+        }
+    }
+}
+
+/// A printer to send G-code moves to, and a transform that keeps track of them
+#[cfg(test)]
+mod fake_printer {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::kinematics::Axis;
+    use crate::planner::{MoveSegment, Untransformed};
+    use crate::testutils::FakePrinter;
+
+    /// Passes moves straight through, noting where each one ends
+    #[derive(Default)]
+    pub(super) struct Recorder {
+        pub moves: RefCell<Vec<Coord>>,
+    }
+
+    impl MoveTransform for Recorder {
+        fn split_move(&self, from: Coord, to: Coord) -> Vec<MoveSegment> {
+            self.moves.borrow_mut().push(to);
+            Untransformed.split_move(from, to)
+        }
+
+        fn requested_position(&self, pos: Coord) -> Coord {
+            pos
+        }
+    }
+
+    /// Splits moves in two, overshooting by 300mm in X on the second half
+    pub(super) struct Overshoot;
+
+    impl MoveTransform for Overshoot {
+        fn split_move(&self, from: Coord, to: Coord) -> Vec<MoveSegment> {
+            let halfway = MoveSegment {
+                end_pos: from + (to - from) * 0.5,
+                fraction: 0.5,
+            };
+            let end = MoveSegment {
+                end_pos: to + Coord::new(300.0, 0.0, 0.0),
+                fraction: 1.0,
+            };
+            vec![halfway, end]
+        }
+
+        fn requested_position(&self, pos: Coord) -> Coord {
+            pos - Coord::new(300.0, 0.0, 0.0)
+        }
+    }
+
+    /// The shared fake printer, homed with the toolhead at the origin
+    pub(super) fn printer() -> FakePrinter {
+        let mut printer = FakePrinter::new(1);
+        let origin = Coord::default();
+        printer.ctx().set_position(origin, Axis::XYZ).unwrap();
+        printer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fake_printer::{printer, Overshoot, Recorder};
+    use super::*;
+    use crate::planner::Untransformed;
+
+    fn g1(x: Option<f64>, y: Option<f64>, e: Option<f64>, feedrate: Option<f64>) -> MoveParams {
+        MoveParams {
+            x,
+            y,
+            e,
+            feedrate,
+            ..MoveParams::default()
+        }
+    }
+
+    #[test]
+    fn test_linear_move() {
+        let mut printer = printer();
+        let mut ctx = MoveContext {
+            kin: &printer.kin,
+            extruder: &printer.extruder,
+            can_extrude: true,
+            planner: &mut printer.planner,
+            transform: &Untransformed,
+        };
+        let mut gcode = GCodeMove::new();
+        gcode
+            .linear_move(
+                &mut ctx,
+                &g1(Some(10.0), Some(20.0), Some(1.0), Some(6000.0)),
+            )
+            .unwrap();
+        assert_eq!(ctx.planner.position(), Coord::new(10.0, 20.0, 0.0));
+        assert_eq!(ctx.planner.e_position(), Millimeters(1.0));
+        assert_eq!(gcode.speed(), MillimetersPerSecond(100.0));
+
+        // relative moves, with extrusion following unless it's been told not to
+        gcode.set_absolute_coordinates(false);
+        gcode
+            .linear_move(&mut ctx, &g1(Some(5.0), None, Some(0.5), None))
+            .unwrap();
+        assert_eq!(ctx.planner.position(), Coord::new(15.0, 20.0, 0.0));
+        assert_eq!(ctx.planner.e_position(), Millimeters(1.5));
+        gcode.set_absolute_coordinates(true);
+        gcode.set_absolute_extrude(false);
+        gcode
+            .linear_move(&mut ctx, &g1(Some(30.0), None, Some(0.5), None))
+            .unwrap();
+        assert_eq!(ctx.planner.position(), Coord::new(30.0, 20.0, 0.0));
+        assert_eq!(ctx.planner.e_position(), Millimeters(2.0));
+
+        // G92 shifts where G-code coordinates start
+        gcode.set_absolute_extrude(true);
+        gcode.set_position(&g1(Some(0.0), None, Some(0.0), None));
+        assert_eq!(gcode.position(), Coord::new(0.0, 20.0, 0.0));
+        assert_eq!(gcode.e_position(), Millimeters::ZERO);
+        gcode
+            .linear_move(&mut ctx, &g1(Some(10.0), None, Some(1.0), None))
+            .unwrap();
+        assert_eq!(ctx.planner.position(), Coord::new(40.0, 20.0, 0.0));
+        assert_eq!(ctx.planner.e_position(), Millimeters(3.0));
+        gcode.set_position(&MoveParams::default());
+        assert_eq!(gcode.position(), Coord::default());
+
+        // nothing changes when a move can't be made
+        let before = gcode.clone();
+        assert_eq!(
+            gcode.linear_move(&mut ctx, &g1(None, None, None, Some(0.0))),
+            Err(GCodeError::InvalidSpeed(0.0))
+        );
+        assert!(matches!(
+            gcode.linear_move(&mut ctx, &g1(Some(500.0), None, None, None)),
+            Err(GCodeError::Kinematics(KinematicsError::OutOfRange(_)))
+        ));
+        ctx.can_extrude = false;
+        assert_eq!(
+            gcode.linear_move(&mut ctx, &g1(Some(20.0), None, Some(2.0), None)),
+            Err(GCodeError::Kinematics(KinematicsError::ColdExtrude))
+        );
+        assert_eq!(gcode, before);
+        assert_eq!(ctx.planner.e_position(), Millimeters(3.0));
+
+        // not even part of one that gets split up
+        let mut ctx = MoveContext {
+            can_extrude: true,
+            transform: &Overshoot,
+            ..ctx
+        };
+        assert!(matches!(
+            gcode.linear_move(&mut ctx, &g1(Some(60.0), None, None, None)),
+            Err(GCodeError::Kinematics(KinematicsError::OutOfRange(_)))
+        ));
+        assert_eq!(gcode, before);
+        assert_eq!(ctx.planner.position(), Coord::new(40.0, 20.0, 0.0));
+        ctx.planner.flush();
+        let end = ctx.planner.trapq().position_at(ctx.planner.print_time());
+        assert_eq!(end, Some(Coord::new(40.0, 20.0, 0.0)));
+    }
+
+    #[test]
+    fn test_through_transform() {
+        let mut printer = printer();
+        let recorder = Recorder::default();
+        let mut ctx = MoveContext {
+            kin: &printer.kin,
+            extruder: &printer.extruder,
+            can_extrude: true,
+            planner: &mut printer.planner,
+            transform: &recorder,
+        };
+        let mut gcode = GCodeMove::new();
+        gcode
+            .linear_move(&mut ctx, &g1(Some(10.0), Some(20.0), None, None))
+            .unwrap();
+        gcode
+            .extrude_by(&mut ctx, Millimeters(-1.0), MillimetersPerSecond(30.0))
+            .unwrap();
+        assert_eq!(
+            *recorder.moves.borrow(),
+            [Coord::new(10.0, 20.0, 0.0), Coord::new(10.0, 20.0, 0.0)]
+        );
+        assert_eq!(ctx.planner.e_position(), Millimeters(-1.0));
+        assert_eq!(gcode.speed(), MillimetersPerSecond(25.0));
+
+        // picking up where the toolhead really is
+        ctx.planner.set_position(Coord::new(1.0, 2.0, 3.0));
+        gcode.reset_last_position(&ctx);
+        assert_eq!(gcode.position(), Coord::new(1.0, 2.0, 3.0));
+    }
+}
@@ -0,0 +1,299 @@
+//! `G10`/`G11` firmware retraction: the slicer says when, the config says how far and how
+//! fast. Klipper's `firmware_retraction.py`
+
+use super::{GCodeError, GCodeMove, MoveContext};
+use crate::kinematics::Kinematics;
+use crate::units::{Millimeters, MillimetersPerSecond};
+
+/// A `[firmware_retraction]` section
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetractionConfig {
+    pub retract_length: Millimeters,
+    pub retract_speed: MillimetersPerSecond,
+    /// Filament pushed back on top of what was pulled out
+    pub unretract_extra_length: Millimeters,
+    pub unretract_speed: MillimetersPerSecond,
+}
+
+impl Default for RetractionConfig {
+    fn default() -> Self {
+        Self {
+            retract_length: Millimeters(0.0),
+            retract_speed: MillimetersPerSecond(20.0),
+            unretract_extra_length: Millimeters(0.0),
+            unretract_speed: MillimetersPerSecond(10.0),
+        }
+    }
+}
+
+impl RetractionConfig {
+    fn verify(&self) -> Result<(), GCodeError> {
+        if self.retract_length < Millimeters(0.0) {
+            return Err(GCodeError::InvalidParameter("retract_length"));
+        }
+        if self.retract_speed < MillimetersPerSecond(1.0) {
+            return Err(GCodeError::InvalidParameter("retract_speed"));
+        }
+        if self.unretract_extra_length < Millimeters(0.0) {
+            return Err(GCodeError::InvalidParameter("unretract_extra_length"));
+        }
+        if self.unretract_speed < MillimetersPerSecond(1.0) {
+            return Err(GCodeError::InvalidParameter("unretract_speed"));
+        }
+        Ok(())
+    }
+}
+
+/// `SET_RETRACTION` parameters, each replacing the config's when given
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SetRetractionParams {
+    pub retract_length: Option<Millimeters>,
+    pub retract_speed: Option<MillimetersPerSecond>,
+    pub unretract_extra_length: Option<Millimeters>,
+    pub unretract_speed: Option<MillimetersPerSecond>,
+}
+
+pub struct FirmwareRetraction {
+    config: RetractionConfig,
+    /// How much filament is pulled back, if any
+    retracted: Option<Millimeters>,
+}
+
+impl FirmwareRetraction {
+    pub fn new(config: RetractionConfig) -> Result<Self, GCodeError> {
+        config.verify()?;
+        Ok(Self {
+            config,
+            retracted: None,
+        })
+    }
+
+    /// `GET_RETRACTION`
+    pub fn config(&self) -> &RetractionConfig {
+        &self.config
+    }
+
+    pub fn is_retracted(&self) -> bool {
+        self.retracted.is_some()
+    }
+
+    /// `SET_RETRACTION`. Nothing changes unless every given value is in range
+    pub fn set_retraction(&mut self, params: &SetRetractionParams) -> Result<(), GCodeError> {
+        let config = RetractionConfig {
+            retract_length: params.retract_length.unwrap_or(self.config.retract_length),
+            retract_speed: params.retract_speed.unwrap_or(self.config.retract_speed),
+            unretract_extra_length: params
+                .unretract_extra_length
+                .unwrap_or(self.config.unretract_extra_length),
+            unretract_speed: params
+                .unretract_speed
+                .unwrap_or(self.config.unretract_speed),
+        };
+        config.verify()?;
+        self.config = config;
+        Ok(())
+    }
+
+    /// `G10`: pull the filament back, unless it already is
+    pub fn retract<K: Kinematics>(
+        &mut self,
+        gcode: &mut GCodeMove,
+        ctx: &mut MoveContext<K>,
+    ) -> Result<(), GCodeError> {
+        if self.retracted.is_some() {
+            return Ok(());
+        }
+        let length = self.config.retract_length;
+        gcode.extrude_by(ctx, -length, self.config.retract_speed)?;
+        self.retracted = Some(length);
+        Ok(())
+    }
+
+    /// `G11`: push back what `G10` pulled out, plus the extra, if it's been retracted. A
+    /// `SET_RETRACTION` in between doesn't change how much that is
+    pub fn unretract<K: Kinematics>(
+        &mut self,
+        gcode: &mut GCodeMove,
+        ctx: &mut MoveContext<K>,
+    ) -> Result<(), GCodeError> {
+        let Some(length) = self.retracted else {
+            return Ok(());
+        };
+        let length = length + self.config.unretract_extra_length;
+        gcode.extrude_by(ctx, length, self.config.unretract_speed)?;
+        self.retracted = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fake_printer::printer;
+    use super::super::MoveParams;
+    use super::*;
+    use crate::kinematics::KinematicsError;
+    use crate::planner::Untransformed;
+    use crate::trapq::Coord;
+    use crate::units::Seconds;
+
+    fn config() -> RetractionConfig {
+        RetractionConfig {
+            retract_length: Millimeters(0.8),
+            retract_speed: MillimetersPerSecond(40.0),
+            unretract_extra_length: Millimeters(0.05),
+            unretract_speed: MillimetersPerSecond(20.0),
+        }
+    }
+
+    #[test]
+    fn test_retract_and_unretract() {
+        let mut relative = printer();
+        let mut ctx = MoveContext {
+            kin: &relative.kin,
+            extruder: &relative.extruder,
+            can_extrude: true,
+            planner: &mut relative.planner,
+            transform: &Untransformed,
+        };
+        let mut gcode = GCodeMove::new();
+        gcode.set_absolute_extrude(false);
+        let to = MoveParams {
+            x: Some(20.0),
+            e: Some(1.0),
+            feedrate: Some(3000.0),
+            ..MoveParams::default()
+        };
+        gcode.linear_move(&mut ctx, &to).unwrap();
+        let mut retraction = FirmwareRetraction::new(config()).unwrap();
+
+        // only the first G10 pulls back, as fast as the extruder can get it to the retract
+        // speed over so short a distance
+        let accel = ctx.extruder.config().max_extrude_only_accel;
+        let fastest = Seconds(2.0 * (0.8 / accel.0).sqrt());
+        ctx.planner.flush();
+        let start = ctx.planner.print_time();
+        retraction.retract(&mut gcode, &mut ctx).unwrap();
+        retraction.retract(&mut gcode, &mut ctx).unwrap();
+        assert!(retraction.is_retracted());
+        assert!((ctx.planner.e_position() - Millimeters(0.2)).abs() < Millimeters(1e-9));
+        ctx.planner.flush();
+        let took = ctx.planner.print_time() - start;
+        assert!(took > Seconds(0.8 / 40.0));
+        assert!((took - fastest).abs() < Seconds(1e-9), "{took}");
+        assert_eq!(ctx.planner.position(), Coord::new(20.0, 0.0, 0.0));
+
+        // G11 puts it all back, and then some, even if the length's changed since
+        let params = SetRetractionParams {
+            retract_length: Some(Millimeters(2.0)),
+            ..SetRetractionParams::default()
+        };
+        retraction.set_retraction(&params).unwrap();
+        retraction.unretract(&mut gcode, &mut ctx).unwrap();
+        retraction.unretract(&mut gcode, &mut ctx).unwrap();
+        assert!(!retraction.is_retracted());
+        assert!((ctx.planner.e_position() - Millimeters(1.05)).abs() < Millimeters(1e-9));
+
+        // and G-code carries on as it was
+        assert_eq!(gcode.speed(), MillimetersPerSecond(50.0));
+        assert!(!gcode.absolute_extrude());
+        let to = MoveParams {
+            x: Some(30.0),
+            e: Some(0.5),
+            ..MoveParams::default()
+        };
+        gcode.linear_move(&mut ctx, &to).unwrap();
+        assert!((ctx.planner.e_position() - Millimeters(1.55)).abs() < Millimeters(1e-9));
+
+        // with absolute extrusion the G-code E position stays put, so the next move
+        // extrudes as much as it would have anyway
+        let mut absolute = printer();
+        let mut ctx = MoveContext {
+            kin: &absolute.kin,
+            extruder: &absolute.extruder,
+            can_extrude: true,
+            planner: &mut absolute.planner,
+            transform: &Untransformed,
+        };
+        let mut gcode = GCodeMove::new();
+        let to = MoveParams {
+            x: Some(20.0),
+            e: Some(4.0),
+            feedrate: Some(3000.0),
+            ..MoveParams::default()
+        };
+        gcode.linear_move(&mut ctx, &to).unwrap();
+        let mut retraction = FirmwareRetraction::new(config()).unwrap();
+        retraction.retract(&mut gcode, &mut ctx).unwrap();
+        assert!((gcode.e_position() - Millimeters(4.0)).abs() < Millimeters(1e-9));
+        retraction.unretract(&mut gcode, &mut ctx).unwrap();
+        assert!((gcode.e_position() - Millimeters(4.0)).abs() < Millimeters(1e-9));
+        let to = MoveParams {
+            e: Some(5.0),
+            ..MoveParams::default()
+        };
+        gcode.linear_move(&mut ctx, &to).unwrap();
+        assert!((ctx.planner.e_position() - Millimeters(5.05)).abs() < Millimeters(1e-9));
+    }
+
+    #[test]
+    fn test_retract_too_far() {
+        let mut printer = printer();
+        let mut ctx = MoveContext {
+            kin: &printer.kin,
+            extruder: &printer.extruder,
+            can_extrude: true,
+            planner: &mut printer.planner,
+            transform: &Untransformed,
+        };
+        let mut gcode = GCodeMove::new();
+        let config = RetractionConfig {
+            retract_length: Millimeters(60.0),
+            ..config()
+        };
+        let mut retraction = FirmwareRetraction::new(config).unwrap();
+        assert!(matches!(
+            retraction.retract(&mut gcode, &mut ctx),
+            Err(GCodeError::Kinematics(
+                KinematicsError::ExtrudeOnlyTooLong { .. }
+            ))
+        ));
+        assert!(!retraction.is_retracted());
+        assert_eq!(gcode, GCodeMove::new());
+        assert_eq!(ctx.planner.e_position(), Millimeters::ZERO);
+    }
+
+    #[test]
+    fn test_set_retraction() {
+        let mut retraction = FirmwareRetraction::new(RetractionConfig::default()).unwrap();
+        let params = SetRetractionParams {
+            retract_length: Some(Millimeters(1.5)),
+            unretract_speed: Some(MillimetersPerSecond(0.5)),
+            ..SetRetractionParams::default()
+        };
+        assert_eq!(
+            retraction.set_retraction(&params),
+            Err(GCodeError::InvalidParameter("unretract_speed"))
+        );
+        assert_eq!(retraction.config(), &RetractionConfig::default());
+
+        let params = SetRetractionParams {
+            unretract_speed: Some(MillimetersPerSecond(15.0)),
+            ..params
+        };
+        retraction.set_retraction(&params).unwrap();
+        assert_eq!(retraction.config().retract_length, Millimeters(1.5));
+        assert_eq!(
+            retraction.config().retract_speed,
+            MillimetersPerSecond(20.0)
+        );
+
+        let config = RetractionConfig {
+            retract_length: Millimeters(-1.0),
+            ..RetractionConfig::default()
+        };
+        assert!(matches!(
+            FirmwareRetraction::new(config),
+            Err(GCodeError::InvalidParameter("retract_length"))
+        ));
+    }
+}
@@ -0,0 +1,301 @@
+//! `PID_CALIBRATE`: bang-bang the heater around the target and
+//! derive PID gains from the oscillation it settles into
+
+use std::cell::RefCell;
+use std::f64::consts::PI;
+use std::rc::Rc;
+
+use super::control::{ControlConfig, HeaterControl, PID_PARAM_BASE};
+use super::{Heater, HeaterError};
+use crate::units::Seconds;
+
+/// The heater swings between the target and this far below it
+const TUNE_PID_DELTA: f64 = 5.0;
+/// Peaks recorded before the oscillation is trusted
+const MIN_PEAKS: usize = 12;
+
+/// PID gains in klipper's units
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PidGains {
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+}
+
+impl From<PidGains> for ControlConfig {
+    fn from(PidGains { kp, ki, kd }: PidGains) -> Self {
+        ControlConfig::Pid { kp, ki, kd }
+    }
+}
+
+/// Relay control that records every peak of the oscillation it causes
+pub struct ControlAutoTune {
+    max_power: f64,
+    calibrate_temp: f64,
+    heating: bool,
+    /// Most extreme temperature since the last crossing, and when
+    peak: (f64, Seconds),
+    peaks: Vec<(f64, Seconds)>,
+}
+
+impl ControlAutoTune {
+    pub fn new(max_power: f64, calibrate_temp: f64) -> Self {
+        Self {
+            max_power,
+            calibrate_temp,
+            heating: false,
+            peak: (0.0, Seconds::ZERO),
+            peaks: Vec::new(),
+        }
+    }
+
+    fn check_peaks(&mut self) {
+        self.peaks.push(self.peak);
+        let reset = if self.heating {
+            f64::INFINITY
+        } else {
+            f64::NEG_INFINITY
+        };
+        self.peak = (reset, Seconds::ZERO);
+    }
+
+    /// Astrom-Hagglund for the ultimate gain and period, then Ziegler-Nichols for the gains
+    fn calc_pid(&self, pos: usize) -> PidGains {
+        let temp_diff = self.peaks[pos].0 - self.peaks[pos - 1].0;
+        let time_diff = self.peaks[pos].1 - self.peaks[pos - 2].1;
+        let amplitude = 0.5 * temp_diff.abs();
+        let ku = 4.0 * self.max_power / (PI * amplitude);
+        let tu = time_diff.0;
+
+        let ti = 0.5 * tu;
+        let td = 0.125 * tu;
+        let kp = 0.6 * ku * PID_PARAM_BASE;
+        PidGains {
+            kp,
+            ki: kp / ti,
+            kd: kp * td,
+        }
+    }
+
+    /// Gains from the cycle with the median period, shrugging off any odd cycles
+    pub fn calc_final_pid(&self) -> Option<PidGains> {
+        let mut cycle_times: Vec<_> = (4..self.peaks.len())
+            .map(|pos| (self.peaks[pos].1 - self.peaks[pos - 2].1, pos))
+            .collect();
+        if cycle_times.is_empty() {
+            return None;
+        }
+        cycle_times.sort_by(|a, b| a.0 .0.total_cmp(&b.0 .0));
+        let (_, midpoint_pos) = cycle_times[cycle_times.len() / 2];
+        Some(self.calc_pid(midpoint_pos))
+    }
+}
+
+impl HeaterControl for ControlAutoTune {
+    fn temperature_update(&mut self, read_time: Seconds, temp: f64, _target_temp: f64) -> f64 {
+        // the relay flips when the temperature crosses whichever target is current
+        let target_temp = if self.heating {
+            self.calibrate_temp
+        } else {
+            self.calibrate_temp - TUNE_PID_DELTA
+        };
+        if self.heating && temp >= target_temp {
+            self.heating = false;
+            self.check_peaks();
+        } else if !self.heating && temp <= target_temp {
+            self.heating = true;
+            self.check_peaks();
+        }
+
+        if self.heating {
+            if temp < self.peak.0 {
+                self.peak = (temp, read_time);
+            }
+            self.max_power
+        } else {
+            if temp > self.peak.0 {
+                self.peak = (temp, read_time);
+            }
+            0.0
+        }
+    }
+
+    fn check_busy(&self, _smoothed_temp: f64, _target_temp: f64) -> bool {
+        self.heating || self.peaks.len() < MIN_PEAKS
+    }
+}
+
+/// A calibration in progress. The heater keeps getting updated as usual
+/// until [`PidCalibrate::is_done`], then [`PidCalibrate::finish`] puts things back
+pub struct PidCalibrate {
+    tune: Rc<RefCell<ControlAutoTune>>,
+    old_control: Box<dyn HeaterControl>,
+}
+
+impl PidCalibrate {
+    pub fn start(heater: &mut Heater, target: f64) -> Result<Self, HeaterError> {
+        heater.set_temp(target)?;
+        let tune = Rc::new(RefCell::new(ControlAutoTune::new(
+            heater.max_power(),
+            target,
+        )));
+        let old_control = heater.set_control(Box::new(tune.clone()));
+        Ok(Self { tune, old_control })
+    }
+
+    pub fn is_done(&self) -> bool {
+        !self.tune.borrow().check_busy(0.0, 0.0)
+    }
+
+    /// Restore the heater's own control, turn it off, and work out the gains
+    pub fn finish(self, heater: &mut Heater) -> Result<PidGains, HeaterError> {
+        heater.set_control(self.old_control);
+        heater.set_temp(0.0)?;
+        let tune = self.tune.borrow();
+        tune.calc_final_pid()
+            .ok_or(HeaterError::CalibrationIncomplete)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::super::plant::ThermalPlant;
+    use super::super::HeaterConfig;
+    use super::*;
+    use crate::mcu::pin::PinRef;
+    use crate::mcu::{Command, Mcu, Oid, Response};
+    use crate::sensors::{preset, AdcConversion, SensorConfig};
+    use crate::units::Hertz;
+
+    const SENSOR: &str = "EPCOS 100K B57560G104F";
+    const ADC_MAX: f64 = 4095.0;
+    const REPORT_TIME: f64 = 0.3;
+
+    /// A heater wired to a simulated hotend through a simulated MCU
+    struct Sim {
+        mcu: Mcu,
+        heater: Heater,
+        plant: ThermalPlant,
+        sensor: Box<dyn AdcConversion>,
+        time: Seconds,
+        duty: f64,
+        /// `(time, duty)` the MCU has been told to switch to
+        pending: VecDeque<(Seconds, f64)>,
+    }
+
+    impl Sim {
+        fn new(control: ControlConfig) -> Self {
+            let mut mcu = Mcu::new("mcu", Hertz(1_000_000.0));
+            mcu.set_constant("ADC_MAX", ADC_MAX);
+            let pin = |name: &str| PinRef {
+                mcu: "mcu".into(),
+                pin: name.into(),
+            };
+            let config = HeaterConfig::new(0.0, 300.0, control);
+            let sensor = preset(SENSOR, &SensorConfig::default()).unwrap();
+            let heater = Heater::new(
+                &mut mcu,
+                "extruder",
+                config,
+                sensor,
+                pin("PA0"),
+                pin("PA1"),
+                false,
+            )
+            .unwrap();
+            Self {
+                mcu,
+                heater,
+                plant: ThermalPlant::hotend(),
+                sensor: preset(SENSOR, &SensorConfig::default()).unwrap(),
+                time: Seconds::ZERO,
+                duty: 0.0,
+                pending: VecDeque::new(),
+            }
+        }
+
+        /// Advance one ADC report period
+        fn tick(&mut self) {
+            while let Some((at, duty)) = self.pending.front().copied() {
+                if at > self.time {
+                    break;
+                }
+                self.duty = duty;
+                self.pending.pop_front();
+            }
+            self.plant.step(Seconds(REPORT_TIME), self.duty);
+            self.time += Seconds(REPORT_TIME);
+
+            let adc = self.sensor.calc_adc(self.plant.sensor_temperature());
+            let next_clock = self
+                .mcu
+                .print_time_to_clock(self.time + Seconds(REPORT_TIME));
+            self.mcu
+                .handle_response(&Response::AnalogInState {
+                    oid: Oid(0),
+                    next_clock: next_clock.0 as u32,
+                    value: (adc * ADC_MAX * 8.0).round() as u16,
+                })
+                .unwrap();
+            self.heater.update(&mut self.mcu).unwrap();
+            self.heater.verify(self.time).unwrap();
+
+            for cmd in self.mcu.drain_queued() {
+                if let Command::QueueDigitalOut {
+                    clock, on_ticks, ..
+                } = cmd
+                {
+                    let cycle = self.heater.pwm.cycle_ticks();
+                    let at = self.mcu.clock_to_print_time(clock);
+                    self.pending
+                        .push_back((at, on_ticks.0 as f64 / cycle.0 as f64));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_calibrate_then_hold() {
+        let mut sim = Sim::new(ControlConfig::Watermark { max_delta: 2.0 });
+        let calibrate = PidCalibrate::start(&mut sim.heater, 200.0).unwrap();
+        let mut ticks = 0;
+        while !calibrate.is_done() {
+            sim.tick();
+            ticks += 1;
+            assert!(ticks < 10_000, "calibration never finished");
+        }
+        let gains = calibrate.finish(&mut sim.heater).unwrap();
+        assert_eq!(sim.heater.get_temp().1, 0.0);
+        // ballpark of what real 40W hotends come out with
+        assert!((10.0..60.0).contains(&gains.kp), "{:?}", gains);
+        assert!((0.5..10.0).contains(&gains.ki), "{:?}", gains);
+        assert!((30.0..400.0).contains(&gains.kd), "{:?}", gains);
+
+        // let it cool off, then hold temperature with the new gains
+        let mut sim = Sim::new(gains.into());
+        sim.heater.set_temp(200.0).unwrap();
+        let mut max_temp = f64::MIN;
+        for _ in 0..(300.0 / REPORT_TIME) as usize {
+            sim.tick();
+            max_temp = max_temp.max(sim.plant.temperature());
+        }
+        let (temp, _) = sim.heater.get_temp();
+        assert!((temp - 200.0).abs() < 1.0, "settled at {}", temp);
+        assert!(max_temp < 215.0, "overshot to {}", max_temp);
+        assert!(!sim.heater.check_busy());
+    }
+
+    #[test]
+    fn test_too_few_peaks() {
+        let mut sim = Sim::new(ControlConfig::Watermark { max_delta: 2.0 });
+        let calibrate = PidCalibrate::start(&mut sim.heater, 200.0).unwrap();
+        sim.tick();
+        assert!(!calibrate.is_done());
+        assert!(matches!(
+            calibrate.finish(&mut sim.heater),
+            Err(HeaterError::CalibrationIncomplete)
+        ));
+    }
+}
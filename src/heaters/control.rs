@@ -0,0 +1,162 @@
+//! Control algorithms that turn temperatures into heater power
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::units::Seconds;
+
+/// Klipper's PID gains are scaled for a 0-255 PWM range
+pub const PID_PARAM_BASE: f64 = 255.0;
+/// Temperature the PID assumes before it has seen a reading
+const AMBIENT_TEMP: f64 = 25.0;
+/// Within this many degrees of target (and barely moving) counts as settled
+const PID_SETTLE_DELTA: f64 = 1.0;
+const PID_SETTLE_SLOPE: f64 = 0.1;
+
+/// Decides how much power a heater should get
+pub trait HeaterControl {
+    /// A new reading came in, return the power (`0.0..=max_power`) to apply
+    fn temperature_update(&mut self, read_time: Seconds, temp: f64, target_temp: f64) -> f64;
+
+    /// Whether the heater is still working its way to the target
+    fn check_busy(&self, smoothed_temp: f64, target_temp: f64) -> bool;
+}
+
+/// Lets whoever swapped a control in keep an eye on it
+impl<T: HeaterControl> HeaterControl for Rc<RefCell<T>> {
+    fn temperature_update(&mut self, read_time: Seconds, temp: f64, target_temp: f64) -> f64 {
+        self.borrow_mut()
+            .temperature_update(read_time, temp, target_temp)
+    }
+
+    fn check_busy(&self, smoothed_temp: f64, target_temp: f64) -> bool {
+        self.borrow().check_busy(smoothed_temp, target_temp)
+    }
+}
+
+/// Which control algorithm to use, and its settings
+#[derive(Clone, Debug, PartialEq)]
+pub enum ControlConfig {
+    /// Bang-bang: full power until `max_delta` above target, off until `max_delta` below
+    Watermark { max_delta: f64 },
+    /// Gains in klipper's units, i.e. as printed by `PID_CALIBRATE`
+    Pid { kp: f64, ki: f64, kd: f64 },
+}
+
+impl ControlConfig {
+    pub fn build(&self, max_power: f64, smooth_time: Seconds) -> Box<dyn HeaterControl> {
+        match *self {
+            ControlConfig::Watermark { max_delta } => Box::new(BangBang::new(max_delta, max_power)),
+            ControlConfig::Pid { kp, ki, kd } => {
+                Box::new(Pid::new(kp, ki, kd, max_power, smooth_time))
+            }
+        }
+    }
+}
+
+pub struct BangBang {
+    max_delta: f64,
+    max_power: f64,
+    heating: bool,
+}
+
+impl BangBang {
+    pub fn new(max_delta: f64, max_power: f64) -> Self {
+        Self {
+            max_delta,
+            max_power,
+            heating: false,
+        }
+    }
+}
+
+impl HeaterControl for BangBang {
+    fn temperature_update(&mut self, _read_time: Seconds, temp: f64, target_temp: f64) -> f64 {
+        if self.heating && temp >= target_temp + self.max_delta {
+            self.heating = false;
+        } else if !self.heating && temp <= target_temp - self.max_delta {
+            self.heating = true;
+        }
+        if self.heating {
+            self.max_power
+        } else {
+            0.0
+        }
+    }
+
+    fn check_busy(&self, smoothed_temp: f64, target_temp: f64) -> bool {
+        smoothed_temp < target_temp - self.max_delta
+    }
+}
+
+pub struct Pid {
+    kp: f64,
+    ki: f64,
+    kd: f64,
+    max_power: f64,
+    /// Derivative is averaged over at least this long, so sensor noise doesn't dominate it
+    min_deriv_time: Seconds,
+    /// Integral is clamped here so it can't wind up past full power
+    temp_integ_max: f64,
+    prev_temp: f64,
+    prev_temp_time: Seconds,
+    prev_temp_deriv: f64,
+    prev_temp_integ: f64,
+}
+
+impl Pid {
+    /// Gains are in klipper's units, i.e. as printed by `PID_CALIBRATE`
+    pub fn new(kp: f64, ki: f64, kd: f64, max_power: f64, smooth_time: Seconds) -> Self {
+        let (kp, ki, kd) = (
+            kp / PID_PARAM_BASE,
+            ki / PID_PARAM_BASE,
+            kd / PID_PARAM_BASE,
+        );
+        Self {
+            kp,
+            ki,
+            kd,
+            max_power,
+            min_deriv_time: smooth_time,
+            temp_integ_max: if ki != 0.0 { max_power / ki } else { 0.0 },
+            prev_temp: AMBIENT_TEMP,
+            prev_temp_time: Seconds::ZERO,
+            prev_temp_deriv: 0.0,
+            prev_temp_integ: 0.0,
+        }
+    }
+}
+
+impl HeaterControl for Pid {
+    fn temperature_update(&mut self, read_time: Seconds, temp: f64, target_temp: f64) -> f64 {
+        let time_diff = read_time - self.prev_temp_time;
+        let temp_diff = temp - self.prev_temp;
+        let temp_deriv = if time_diff >= self.min_deriv_time {
+            temp_diff / time_diff.0
+        } else {
+            (self.prev_temp_deriv * (self.min_deriv_time - time_diff).0 + temp_diff)
+                / self.min_deriv_time.0
+        };
+
+        let temp_err = target_temp - temp;
+        let temp_integ =
+            (self.prev_temp_integ + temp_err * time_diff.0).clamp(0.0, self.temp_integ_max);
+
+        let co = self.kp * temp_err + self.ki * temp_integ - self.kd * temp_deriv;
+        let bounded_co = co.clamp(0.0, self.max_power);
+
+        self.prev_temp = temp;
+        self.prev_temp_time = read_time;
+        self.prev_temp_deriv = temp_deriv;
+        // only integrate while the output isn't saturated, that's the anti-windup
+        if co == bounded_co {
+            self.prev_temp_integ = temp_integ;
+        }
+        bounded_co
+    }
+
+    fn check_busy(&self, smoothed_temp: f64, target_temp: f64) -> bool {
+        (target_temp - smoothed_temp).abs() > PID_SETTLE_DELTA
+            || self.prev_temp_deriv.abs() > PID_SETTLE_SLOPE
+    }
+}
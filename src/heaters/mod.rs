@@ -0,0 +1,381 @@
+//! Heaters: a temperature sensor, a PWM output, and something deciding between the two
+
+use crossbeam::channel::Receiver;
+
+use crate::mcu::adc::{Adc, AdcReading};
+use crate::mcu::output::{OutputConfig, PwmMode, PwmOut};
+use crate::mcu::pin::PinRef;
+use crate::mcu::{self, Mcu};
+use crate::sensors::AdcConversion;
+use crate::units::Seconds;
+use crate::{Printer, Ready, Shutdown};
+
+pub mod calibrate;
+pub mod control;
+pub mod plant;
+pub mod verify;
+
+use control::{ControlConfig, HeaterControl};
+use verify::{NotHeating, VerifyConfig, VerifyHeater};
+
+/// The MCU turns the heater off if it hears nothing for this long
+const MAX_HEAT_TIME: Seconds = Seconds(5.0);
+
+#[derive(thiserror::Error, Debug)]
+pub enum HeaterError {
+    #[error("Requested temperature ({target:.1}) out of range ({min:.1}:{max:.1})")]
+    TargetOutOfRange { target: f64, min: f64, max: f64 },
+    #[error("Heater {0} not heating at expected rate")]
+    NotHeating(String),
+    #[error("Not enough oscillations to calculate PID gains")]
+    CalibrationIncomplete,
+    #[error(transparent)]
+    Mcu(#[from] mcu::Error),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeaterConfig {
+    pub min_temp: f64,
+    pub max_temp: f64,
+    /// Extruding below this is refused
+    pub min_extrude_temp: f64,
+    /// Cap on the duty cycle, `0.0..=1.0`
+    pub max_power: f64,
+    /// Time constant for smoothing readings
+    pub smooth_time: Seconds,
+    pub pwm_cycle_time: Seconds,
+    pub control: ControlConfig,
+    /// Thermal runaway checks. Turning these off is a fantastic way to start a fire
+    pub verify: Option<VerifyConfig>,
+}
+
+impl HeaterConfig {
+    /// Klipper's defaults for everything that has one
+    pub fn new(min_temp: f64, max_temp: f64, control: ControlConfig) -> Self {
+        Self {
+            min_temp,
+            max_temp,
+            min_extrude_temp: 170.0,
+            max_power: 1.0,
+            smooth_time: Seconds(1.0),
+            pwm_cycle_time: Seconds(0.100),
+            control,
+            verify: Some(VerifyConfig::extruder()),
+        }
+    }
+}
+
+pub struct Heater {
+    name: String,
+    config: HeaterConfig,
+    sensor: Box<dyn AdcConversion>,
+    _adc: Adc,
+    readings: Receiver<AdcReading>,
+    pwm: PwmOut,
+    control: Box<dyn HeaterControl>,
+    verify: Option<VerifyHeater>,
+    /// How far ahead of a reading the resulting PWM change gets scheduled
+    pwm_delay: Seconds,
+    last_temp: f64,
+    last_temp_time: Seconds,
+    smoothed_temp: f64,
+    target_temp: f64,
+    next_pwm_time: Seconds,
+    last_pwm_value: f64,
+}
+
+impl Heater {
+    pub fn new(
+        mcu: &mut Mcu,
+        name: &str,
+        config: HeaterConfig,
+        sensor: Box<dyn AdcConversion>,
+        sensor_pin: PinRef,
+        heater_pin: PinRef,
+        invert: bool,
+    ) -> Result<Self, HeaterError> {
+        let adc_config = sensor.adc_config(config.min_temp, config.max_temp);
+        let pwm_delay = adc_config.report_time;
+        let (adc, readings) = Adc::with_channel(mcu, sensor_pin, adc_config)?;
+        let pwm = PwmOut::new(
+            mcu,
+            heater_pin,
+            invert,
+            config.pwm_cycle_time,
+            PwmMode::Software,
+            OutputConfig {
+                max_duration: MAX_HEAT_TIME,
+                ..Default::default()
+            },
+        )?;
+        let control = config.control.build(config.max_power, config.smooth_time);
+        let verify = config.verify.clone().map(VerifyHeater::new);
+        Ok(Self {
+            name: name.into(),
+            config,
+            sensor,
+            _adc: adc,
+            readings,
+            pwm,
+            control,
+            verify,
+            pwm_delay,
+            last_temp: 0.0,
+            last_temp_time: Seconds::ZERO,
+            smoothed_temp: 0.0,
+            target_temp: 0.0,
+            next_pwm_time: Seconds::ZERO,
+            last_pwm_value: 0.0,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Run the control loop on every reading the MCU has sent since last time
+    pub fn update(&mut self, mcu: &mut Mcu) -> Result<(), HeaterError> {
+        while let Ok(reading) = self.readings.try_recv() {
+            let temp = self.sensor.calc_temp(reading.value);
+            self.temperature_update(mcu, reading.time, temp)?;
+        }
+        Ok(())
+    }
+
+    fn temperature_update(
+        &mut self,
+        mcu: &mut Mcu,
+        read_time: Seconds,
+        temp: f64,
+    ) -> Result<(), HeaterError> {
+        let time_diff = read_time - self.last_temp_time;
+        self.last_temp = temp;
+        self.last_temp_time = read_time;
+        let power = self
+            .control
+            .temperature_update(read_time, temp, self.target_temp);
+        self.set_pwm(mcu, read_time, power)?;
+
+        let adj_time = (time_diff / self.config.smooth_time).min(1.0);
+        self.smoothed_temp += (temp - self.smoothed_temp) * adj_time;
+        Ok(())
+    }
+
+    fn set_pwm(
+        &mut self,
+        mcu: &mut Mcu,
+        read_time: Seconds,
+        value: f64,
+    ) -> Result<(), HeaterError> {
+        let value = if self.target_temp <= 0.0 { 0.0 } else { value };
+        // skip insignificant changes, as long as the MCU's max duration isn't coming up
+        let must_refresh = read_time >= self.next_pwm_time && self.last_pwm_value != 0.0;
+        if !must_refresh && (value - self.last_pwm_value).abs() < 0.05 {
+            return Ok(());
+        }
+        let pwm_time = read_time + self.pwm_delay;
+        self.next_pwm_time = pwm_time + MAX_HEAT_TIME * 0.75;
+        self.last_pwm_value = value;
+        self.pwm.set_pwm(mcu, pwm_time, value)?;
+        Ok(())
+    }
+
+    pub fn set_temp(&mut self, degrees: f64) -> Result<(), HeaterError> {
+        let (min, max) = (self.config.min_temp, self.config.max_temp);
+        if degrees != 0.0 && !(min..=max).contains(&degrees) {
+            return Err(HeaterError::TargetOutOfRange {
+                target: degrees,
+                min,
+                max,
+            });
+        }
+        self.target_temp = degrees;
+        Ok(())
+    }
+
+    /// Smoothed temperature and target
+    pub fn get_temp(&self) -> (f64, f64) {
+        (self.smoothed_temp, self.target_temp)
+    }
+
+    /// Most recent raw reading and when it was taken
+    pub fn last_temp(&self) -> (Seconds, f64) {
+        (self.last_temp_time, self.last_temp)
+    }
+
+    /// Duty cycle most recently sent to the MCU
+    pub fn last_pwm_value(&self) -> f64 {
+        self.last_pwm_value
+    }
+
+    pub fn max_power(&self) -> f64 {
+        self.config.max_power
+    }
+
+    pub fn can_extrude(&self) -> bool {
+        self.smoothed_temp >= self.config.min_extrude_temp
+    }
+
+    /// Whether the heater is still on its way to the target
+    pub fn check_busy(&self) -> bool {
+        self.control
+            .check_busy(self.smoothed_temp, self.target_temp)
+    }
+
+    /// Swap in a different control algorithm, handing back the old one
+    pub fn set_control(&mut self, control: Box<dyn HeaterControl>) -> Box<dyn HeaterControl> {
+        std::mem::replace(&mut self.control, control)
+    }
+
+    /// Thermal runaway check, meant to run about once a second
+    pub fn verify(&mut self, eventtime: Seconds) -> Result<(), HeaterError> {
+        let (temp, target) = self.get_temp();
+        match &mut self.verify {
+            Some(verify) => verify
+                .check(eventtime, temp, target)
+                .map_err(|NotHeating| HeaterError::NotHeating(self.name.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Printer<Ready> {
+    /// Run every heater's runaway check, shutting the printer down on the first failure
+    pub fn verify_heaters<'a>(
+        self,
+        heaters: impl IntoIterator<Item = &'a mut Heater>,
+        eventtime: Seconds,
+    ) -> Result<Self, Printer<Shutdown>> {
+        for heater in heaters {
+            if let Err(e) = heater.verify(eventtime) {
+                return Err(self.shutdown(e));
+            }
+        }
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcu::{Command, Response};
+    use crate::sensors::{preset, SensorConfig};
+    use crate::units::{Hertz, Ticks};
+
+    const ADC_MAX: f64 = 4095.0;
+    const SAMPLE_COUNT: f64 = 8.0;
+
+    fn pin(name: &str) -> PinRef {
+        PinRef {
+            mcu: "mcu".into(),
+            pin: name.into(),
+        }
+    }
+
+    fn setup(control: ControlConfig) -> (Mcu, Heater) {
+        let mut mcu = Mcu::new("mcu", Hertz(1_000_000.0));
+        mcu.set_constant("ADC_MAX", ADC_MAX);
+        let sensor = preset("EPCOS 100K B57560G104F", &SensorConfig::default()).unwrap();
+        let config = HeaterConfig::new(0.0, 250.0, control);
+        let heater = Heater::new(
+            &mut mcu,
+            "extruder",
+            config,
+            sensor,
+            pin("PA0"),
+            pin("PA1"),
+            false,
+        )
+        .unwrap();
+        (mcu, heater)
+    }
+
+    /// Pretend the MCU read `temp` and reported it at `time`
+    fn report(mcu: &mut Mcu, time: f64, temp: f64) {
+        let sensor = preset("EPCOS 100K B57560G104F", &SensorConfig::default()).unwrap();
+        let value = (sensor.calc_adc(temp) * ADC_MAX * SAMPLE_COUNT).round() as u16;
+        let next_clock = mcu.print_time_to_clock(Seconds(time + 0.3));
+        mcu.handle_response(&Response::AnalogInState {
+            oid: mcu::Oid(0),
+            next_clock: next_clock.0 as u32,
+            value,
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_bang_bang() {
+        let (mut mcu, mut heater) = setup(ControlConfig::Watermark { max_delta: 2.0 });
+        heater.set_temp(200.0).unwrap();
+        report(&mut mcu, 1.0, 150.0);
+        heater.update(&mut mcu).unwrap();
+        assert_eq!(heater.last_pwm_value(), 1.0);
+        // on until max_delta over target
+        report(&mut mcu, 2.0, 201.0);
+        heater.update(&mut mcu).unwrap();
+        assert_eq!(heater.last_pwm_value(), 1.0);
+        report(&mut mcu, 3.0, 202.5);
+        heater.update(&mut mcu).unwrap();
+        assert_eq!(heater.last_pwm_value(), 0.0);
+
+        let on_ticks: Vec<_> = mcu
+            .drain_queued()
+            .into_iter()
+            .filter_map(|c| match c {
+                Command::QueueDigitalOut { on_ticks, .. } => Some(on_ticks),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(on_ticks, vec![Ticks(100_000), Ticks(0)]);
+    }
+
+    #[test]
+    fn test_pid_windup() {
+        let (mut mcu, mut heater) = setup(ControlConfig::Pid {
+            kp: 22.2,
+            ki: 1.08,
+            kd: 114.0,
+        });
+        heater.set_temp(200.0).unwrap();
+        // way below target for ages, the output is pinned at max
+        for s in 1..100 {
+            report(&mut mcu, s as f64, 25.0);
+            heater.update(&mut mcu).unwrap();
+        }
+        assert_eq!(heater.last_pwm_value(), 1.0);
+        // at target, a wound up integral would keep it cooking
+        report(&mut mcu, 100.0, 200.0);
+        heater.update(&mut mcu).unwrap();
+        assert!(heater.last_pwm_value() < 0.5);
+    }
+
+    #[test]
+    fn test_runaway_shuts_down() {
+        let (mut mcu, mut heater) = setup(ControlConfig::Watermark { max_delta: 2.0 });
+        assert!(matches!(
+            heater.set_temp(300.0),
+            Err(HeaterError::TargetOutOfRange { .. })
+        ));
+        heater.set_temp(200.0).unwrap();
+
+        let mut printer = Printer::new().ready();
+        let mut shutdown = None;
+        for s in 1..100 {
+            // heater never gets anywhere
+            report(&mut mcu, s as f64, 30.0);
+            heater.update(&mut mcu).unwrap();
+            match printer.verify_heaters([&mut heater], Seconds(s as f64)) {
+                Ok(p) => printer = p,
+                Err(p) => {
+                    shutdown = Some(p);
+                    break;
+                }
+            }
+        }
+        let shutdown = shutdown.expect("heater never faulted");
+        assert_eq!(
+            shutdown.state().reason(),
+            "Heater extruder not heating at expected rate"
+        );
+    }
+}
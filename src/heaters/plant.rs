@@ -0,0 +1,75 @@
+//! A lump of metal with a heater and a thermistor stuck to it, for exercising
+//! control loops without setting anything on fire
+
+use crate::units::Seconds;
+
+/// Longest stretch simulated in one go, keeps the sensor lag honest
+const MAX_STEP: Seconds = Seconds(0.01);
+
+/// First order thermal model: heat goes in from the heater, leaks out to ambient,
+/// and the sensor trails behind the block it's measuring
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThermalPlant {
+    /// Joules to raise the block by one degree
+    pub heat_capacity: f64,
+    /// Watts lost per degree above ambient
+    pub loss: f64,
+    /// Watts at 100% duty cycle
+    pub heater_power: f64,
+    /// Time constant of the sensor catching up with the block
+    pub sensor_lag: Seconds,
+    pub ambient: f64,
+    temp: f64,
+    sensor_temp: f64,
+}
+
+impl ThermalPlant {
+    pub fn new(
+        heat_capacity: f64,
+        loss: f64,
+        heater_power: f64,
+        sensor_lag: Seconds,
+        ambient: f64,
+    ) -> Self {
+        Self {
+            heat_capacity,
+            loss,
+            heater_power,
+            sensor_lag,
+            ambient,
+            temp: ambient,
+            sensor_temp: ambient,
+        }
+    }
+
+    /// Roughly a 40W hotend: ~2°C/s at full power, tops out well past 400°C
+    pub fn hotend() -> Self {
+        Self::new(18.0, 0.09, 40.0, Seconds(2.0), 25.0)
+    }
+
+    /// Let `duration` pass with the heater at `duty` (`0.0..=1.0`)
+    pub fn step(&mut self, duration: Seconds, duty: f64) {
+        let power = self.heater_power * duty.clamp(0.0, 1.0);
+        // temperature the block would settle at with this much power going in
+        let equilibrium = self.ambient + power / self.loss;
+        let mut left = duration;
+        while left > Seconds::ZERO {
+            let dt = left.min(MAX_STEP);
+            left -= dt;
+            let decay = (-dt.0 * self.loss / self.heat_capacity).exp();
+            self.temp = equilibrium + (self.temp - equilibrium) * decay;
+            let lag = (-(dt / self.sensor_lag)).exp();
+            self.sensor_temp = self.temp + (self.sensor_temp - self.temp) * lag;
+        }
+    }
+
+    /// Actual temperature of the block
+    pub fn temperature(&self) -> f64 {
+        self.temp
+    }
+
+    /// What the sensor currently reads
+    pub fn sensor_temperature(&self) -> f64 {
+        self.sensor_temp
+    }
+}
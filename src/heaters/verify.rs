@@ -0,0 +1,159 @@
+//! Thermal runaway checks, klipper's `verify_heater`
+
+use crate::units::Seconds;
+
+/// How far a heater may lag behind before it's considered broken
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifyConfig {
+    /// Degrees below target that still count as "at temperature"
+    pub hysteresis: f64,
+    /// Accumulated degree-checks below target before giving up
+    pub max_error: f64,
+    /// Degrees the heater must gain every `check_gain_time` while heating up
+    pub heating_gain: f64,
+    pub check_gain_time: Seconds,
+}
+
+impl VerifyConfig {
+    /// Klipper's defaults for an extruder
+    pub fn extruder() -> Self {
+        Self {
+            hysteresis: 5.0,
+            max_error: 120.0,
+            heating_gain: 2.0,
+            check_gain_time: Seconds(20.0),
+        }
+    }
+
+    /// Klipper's defaults for a bed, which is allowed to be a lot slower
+    pub fn bed() -> Self {
+        Self {
+            check_gain_time: Seconds(60.0),
+            ..Self::extruder()
+        }
+    }
+}
+
+/// Why a heater got declared broken
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NotHeating;
+
+/// Watches a heater's temperature against its target, checked about once a second
+pub struct VerifyHeater {
+    config: VerifyConfig,
+    approaching_target: bool,
+    starting_approach: bool,
+    last_target: f64,
+    goal_temp: f64,
+    goal_time: Seconds,
+    error: f64,
+}
+
+impl VerifyHeater {
+    pub fn new(config: VerifyConfig) -> Self {
+        Self {
+            config,
+            approaching_target: false,
+            starting_approach: false,
+            last_target: 0.0,
+            goal_temp: 0.0,
+            goal_time: Seconds(f64::INFINITY),
+            error: 0.0,
+        }
+    }
+
+    pub fn check(&mut self, eventtime: Seconds, temp: f64, target: f64) -> Result<(), NotHeating> {
+        let VerifyConfig {
+            hysteresis,
+            max_error,
+            heating_gain,
+            check_gain_time,
+        } = self.config;
+
+        if temp >= target - hysteresis || target <= 0.0 {
+            // near target (or off), nothing to see here
+            self.approaching_target = false;
+            self.starting_approach = false;
+            if temp <= target + hysteresis {
+                self.error = 0.0;
+            }
+            self.last_target = target;
+            return Ok(());
+        }
+
+        self.error += (target - hysteresis) - temp;
+        if !self.approaching_target {
+            if target != self.last_target {
+                // new target, give it time to get going
+                self.approaching_target = true;
+                self.starting_approach = true;
+                self.goal_temp = temp + heating_gain;
+                self.goal_time = eventtime + check_gain_time;
+            } else if self.error >= max_error {
+                // was at temperature, and can't hold it any more
+                return Err(NotHeating);
+            }
+        } else if temp >= self.goal_temp {
+            // making progress, move the goalposts
+            self.starting_approach = false;
+            self.error = 0.0;
+            self.goal_temp = temp + heating_gain;
+            self.goal_time = eventtime + check_gain_time;
+        } else if eventtime >= self.goal_time {
+            // stalled out; from here on the error accumulates
+            self.approaching_target = false;
+        } else if self.starting_approach {
+            self.goal_temp = self.goal_temp.min(temp + heating_gain);
+        }
+        self.last_target = target;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runaway() {
+        let mut verify = VerifyHeater::new(VerifyConfig::extruder());
+        let mut t = Seconds::ZERO;
+        let mut tick = |verify: &mut VerifyHeater, temp| {
+            t += Seconds(1.0);
+            verify.check(t, temp, 200.0)
+        };
+
+        // heating nicely towards the target
+        let mut temp = 25.0;
+        while temp < 196.0 {
+            tick(&mut verify, temp).unwrap();
+            temp += 1.0;
+        }
+        // held at temperature for a while
+        for _ in 0..100 {
+            tick(&mut verify, 199.0).unwrap();
+        }
+        // heater cartridge falls out
+        let mut checks = 0;
+        while tick(&mut verify, 150.0).is_ok() {
+            checks += 1;
+        }
+        // 45 degrees short per check against a 120 degree budget
+        assert_eq!(checks, 2);
+    }
+
+    #[test]
+    fn test_stalled_approach() {
+        let mut verify = VerifyHeater::new(VerifyConfig::extruder());
+        // never gains the 2 degrees it needs to
+        let mut failed_at = None;
+        for s in 1..100 {
+            if verify.check(Seconds(s as f64), 30.0, 200.0).is_err() {
+                failed_at = Some(s);
+                break;
+            }
+        }
+        // 20s grace period, then 165 degrees of error per check
+        assert_eq!(failed_at, Some(22));
+    }
+}
@@ -0,0 +1,579 @@
+//! Homing: drive rails into their endstops, then work out where everything ended up.
+//! A port of klipper's `homing.py`
+
+use enumflags2::BitFlags;
+
+use crate::kinematics::{Axis, HomeRails, Kinematics, Stepper};
+use crate::mcu::endstop::Endstop;
+use crate::mcu::{self, Mcu, McuLink};
+use crate::planner::Planner;
+use crate::stepcompress::StepCompressError;
+use crate::trapq::Coord;
+use crate::units::{Millimeters, MillimetersPerSecond, Seconds};
+
+/// Time between arming the endstops and starting to move
+const HOMING_START_DELAY: Seconds = Seconds(0.001);
+const ENDSTOP_SAMPLE_TIME: Seconds = Seconds(0.000_015);
+const ENDSTOP_SAMPLE_COUNT: u8 = 4;
+/// How far ahead steps get sent during a homing move. Every trsync is kept alive once a
+/// segment, so this has to stay well under [`mcu::endstop::TRSYNC_TIMEOUT`]
+const DRIP_SEGMENT_TIME: Seconds = Seconds(0.010);
+
+#[derive(thiserror::Error, Debug)]
+pub enum HomingError {
+    #[error("No trigger on {0} after full movement")]
+    NoTrigger(String),
+    #[error("Endstop {0} still triggered after retract")]
+    StillTriggered(String),
+    #[error("No endstop for rail {0}")]
+    NoEndstop(usize),
+    #[error(transparent)]
+    Mcu(#[from] mcu::Error),
+    #[error(transparent)]
+    StepCompress(#[from] StepCompressError),
+}
+
+/// An endstop, and the steppers it stops
+pub struct HomingEndstop {
+    name: String,
+    endstop: Endstop,
+    /// Indices into [`Kinematics::steppers`], of only those with an MCU to stop
+    steppers: Vec<usize>,
+}
+
+impl HomingEndstop {
+    pub fn new<K: Kinematics, L: McuLink>(
+        link: &mut L,
+        kin: &K,
+        name: &str,
+        mut endstop: Endstop,
+        steppers: Vec<usize>,
+    ) -> Result<Self, HomingError> {
+        let all = kin.steppers();
+        let mut wired = Vec::new();
+        for index in steppers {
+            let stepper = all[index];
+            if let (Some(mcu), Some(oid)) = (stepper.mcu(), stepper.oid()) {
+                endstop.add_stepper(link.mcu(mcu)?, oid);
+                wired.push(index);
+            }
+        }
+        Ok(Self {
+            name: name.into(),
+            endstop,
+            steppers: wired,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Where a stepper got to during a homing move, in MCU steps
+#[derive(Clone, Copy, Debug)]
+struct StepperTravel {
+    /// Index of the endstop stopping it, in the homing move's list
+    endstop: usize,
+    stepper: usize,
+    start: i64,
+    trig: i64,
+    halt: i64,
+}
+
+/// How a homing move went
+#[derive(Debug)]
+pub struct HomingMove {
+    /// Toolhead position when the endstops triggered
+    pub trigger_pos: Coord,
+    travel: Vec<StepperTravel>,
+}
+
+impl HomingMove {
+    /// First endstop, by index, that triggered before any of its steppers took a step
+    pub fn no_movement(&self) -> Option<usize> {
+        self.travel
+            .iter()
+            .find(|t| t.trig == t.start)
+            .map(|t| t.endstop)
+    }
+}
+
+/// The MCU `stepper` sends its steps to
+fn mcu_of<'l, L: McuLink>(link: &'l mut L, stepper: &Stepper) -> Result<&'l mut Mcu, mcu::Error> {
+    link.mcu(stepper.mcu().unwrap_or_default())
+}
+
+/// `pos` along `axes`, `current` along the rest
+fn fill_coord(axes: BitFlags<Axis>, pos: Coord, current: Coord) -> Coord {
+    let pick = |axis: Axis| {
+        if axes.contains(axis) {
+            axis.of(pos)
+        } else {
+            axis.of(current)
+        }
+    };
+    Coord::new(pick(Axis::X), pick(Axis::Y), pick(Axis::Z))
+}
+
+/// Everything a homing move pushes around: the kinematics, the planner feeding them, and
+/// the MCUs
+pub struct HomingContext<'a, K, L> {
+    pub kin: &'a mut K,
+    pub planner: &'a mut Planner,
+    pub link: &'a mut L,
+}
+
+impl<K: Kinematics<Position = Coord>, L: McuLink> HomingContext<'_, K, L> {
+    /// Generate every stepper's steps up to `flush_time` and send them off
+    pub fn flush_steps(&mut self, flush_time: Seconds) -> Result<(), HomingError> {
+        let trapq = self.planner.trapq();
+        for stepper in self.kin.steppers_mut() {
+            let cmds = stepper.flush_steps(trapq, flush_time)?;
+            if let Some(name) = stepper.mcu() {
+                let mcu = self.link.mcu(name)?;
+                for cmd in cmds {
+                    mcu.send(cmd);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Plain move to `pos`, stepped and sent off straight away
+    pub fn move_to(&mut self, pos: Coord, speed: MillimetersPerSecond) -> Result<(), HomingError> {
+        self.planner.move_to(pos, speed);
+        self.planner.flush();
+        self.flush_steps(self.planner.print_time())
+    }
+
+    /// Declare the toolhead to be at `pos`, once everything queued has been stepped.
+    /// Any `homing_axes` count as homed from now on
+    pub fn set_position(
+        &mut self,
+        pos: Coord,
+        homing_axes: BitFlags<Axis>,
+    ) -> Result<(), HomingError> {
+        self.planner.flush();
+        self.flush_steps(self.planner.print_time())?;
+        self.planner.set_position(pos);
+        self.kin.set_position(pos, homing_axes);
+        Ok(())
+    }
+
+    /// Toolhead position with each stepper at `start`, plus its `offsets` in steps
+    fn position_with_offsets(&mut self, start: &[Millimeters], offsets: &[(usize, i64)]) -> Coord {
+        let mut steppers = self.kin.steppers_mut();
+        for (stepper, &pos) in steppers.iter_mut().zip(start) {
+            stepper.set_commanded_position(pos);
+        }
+        for &(index, steps) in offsets {
+            let stepper = &mut steppers[index];
+            let pos = stepper.position() + stepper.step_distance() * steps as f64;
+            stepper.set_commanded_position(pos);
+        }
+        self.kin.calculate_position()
+    }
+
+    /// Time between checks of the endstop: about one step's worth of the move
+    fn endstop_rate(
+        &self,
+        endstop: &HomingEndstop,
+        start: Coord,
+        end: Coord,
+        speed: MillimetersPerSecond,
+    ) -> Seconds {
+        let move_t = Millimeters((end - start).norm()) / speed;
+        let steppers = self.kin.steppers();
+        let max_steps = endstop
+            .steppers
+            .iter()
+            .map(|&index| {
+                let stepper = steppers[index];
+                let d =
+                    stepper.calc_position_from_coord(end) - stepper.calc_position_from_coord(start);
+                d.abs() / stepper.step_distance()
+            })
+            .fold(0.0, f64::max);
+        if max_steps <= 0.0 {
+            Seconds(0.001)
+        } else {
+            move_t / max_steps
+        }
+    }
+
+    /// Move towards `movepos` until every endstop triggers. Afterwards the toolhead is
+    /// wherever the steppers actually stopped. When homing that's found by taking
+    /// `movepos` as the trigger position, when probing by working out the trigger
+    /// position from the steps taken
+    pub fn homing_move(
+        &mut self,
+        endstops: &mut [&mut HomingEndstop],
+        movepos: Coord,
+        speed: MillimetersPerSecond,
+        probe_pos: bool,
+    ) -> Result<HomingMove, HomingError> {
+        let start_pos = self.planner.position();
+        let kin_spos: Vec<_> = self.kin.steppers().iter().map(|s| s.position()).collect();
+        let print_time = self.planner.print_time();
+        // everything before this move has to have been stepped for the start to count
+        self.link.run_until(print_time)?;
+        for endstop in endstops.iter() {
+            for &index in &endstop.steppers {
+                let stepper = self.kin.steppers()[index];
+                stepper.query_mcu_position(mcu_of(self.link, stepper)?);
+            }
+        }
+        self.link.run_until(print_time)?;
+        let mut travel = Vec::new();
+        for (i, endstop) in endstops.iter().enumerate() {
+            for &index in &endstop.steppers {
+                let start = self.kin.steppers()[index]
+                    .mcu_position()
+                    .ok_or(mcu::Error::HomingTimeout)?;
+                travel.push(StepperTravel {
+                    endstop: i,
+                    stepper: index,
+                    start,
+                    trig: start,
+                    halt: start,
+                });
+            }
+        }
+
+        for endstop in endstops.iter_mut() {
+            let rest_time = self.endstop_rate(endstop, start_pos, movepos, speed);
+            endstop.endstop.home_start(
+                self.link,
+                print_time,
+                ENDSTOP_SAMPLE_TIME,
+                ENDSTOP_SAMPLE_COUNT,
+                rest_time,
+                true,
+            )?;
+        }
+        self.planner.dwell(HOMING_START_DELAY);
+        self.planner.move_to(movepos, speed);
+        self.planner.flush();
+        let move_end = self.planner.print_time();
+        let mut flushed = print_time;
+        while flushed < move_end {
+            flushed = (flushed + DRIP_SEGMENT_TIME).min(move_end);
+            self.flush_steps(flushed)?;
+            self.link.run_until(flushed)?;
+            let mut all_triggered = true;
+            for endstop in endstops.iter() {
+                all_triggered &= endstop.endstop.poll(self.link, flushed)?;
+            }
+            if all_triggered {
+                break;
+            }
+        }
+
+        let mut error = None;
+        let mut trigger_times = Vec::new();
+        for endstop in endstops.iter_mut() {
+            let trigger_time = endstop.endstop.home_wait(self.link, move_end)?;
+            if trigger_time.is_none() && error.is_none() {
+                error = Some(HomingError::NoTrigger(endstop.name.clone()));
+            }
+            trigger_times.push(trigger_time.unwrap_or(move_end));
+        }
+        // the rest of the move, which the stopped steppers throw away
+        self.flush_steps(move_end)?;
+        let mut steppers = self.kin.steppers_mut();
+        for t in &travel {
+            let stepper = &mut steppers[t.stepper];
+            let mcu = mcu_of(self.link, stepper)?;
+            stepper.note_homing_end(mcu)?;
+            stepper.query_mcu_position(mcu);
+        }
+        self.link.run_until(move_end)?;
+        let steppers = self.kin.steppers();
+        for t in &mut travel {
+            let stepper = steppers[t.stepper];
+            t.halt = stepper.mcu_position().ok_or(mcu::Error::HomingTimeout)?;
+            // a trigger reported in whole ticks can land just before the start, where the
+            // trapq still has the toolhead wherever it was before being told otherwise
+            let trigger_time = trigger_times[t.endstop].max(print_time);
+            let trig_coord = self
+                .planner
+                .trapq()
+                .position_at(trigger_time)
+                .unwrap_or(movepos);
+            let moved = stepper.calc_position_from_coord(trig_coord) - kin_spos[t.stepper];
+            t.trig = t.start + (moved / stepper.step_distance()).round() as i64;
+        }
+
+        let trigger_pos = if probe_pos {
+            let trig: Vec<_> = travel
+                .iter()
+                .map(|t| (t.stepper, t.trig - t.start))
+                .collect();
+            let halt: Vec<_> = travel
+                .iter()
+                .map(|t| (t.stepper, t.halt - t.start))
+                .collect();
+            let trigpos = self.position_with_offsets(&kin_spos, &trig);
+            let haltpos = if trig == halt {
+                trigpos
+            } else {
+                self.position_with_offsets(&kin_spos, &halt)
+            };
+            self.set_position(haltpos, BitFlags::empty())?;
+            trigpos
+        } else {
+            let over: Vec<_> = travel
+                .iter()
+                .map(|t| (t.stepper, t.halt - t.trig))
+                .collect();
+            let mut haltpos = movepos;
+            if over.iter().any(|&(_, steps)| steps != 0) {
+                self.set_position(movepos, BitFlags::empty())?;
+                let halt_kin_spos: Vec<_> =
+                    self.kin.steppers().iter().map(|s| s.position()).collect();
+                haltpos = self.position_with_offsets(&halt_kin_spos, &over);
+            }
+            self.set_position(haltpos, BitFlags::empty())?;
+            movepos
+        };
+        match error {
+            Some(error) => Err(error),
+            None => Ok(HomingMove {
+                trigger_pos,
+                travel,
+            }),
+        }
+    }
+
+    /// Home the rails of `endstops` together: approach fast, back off, approach again slowly
+    pub fn home_rails(
+        &mut self,
+        endstops: &mut [&mut HomingEndstop],
+        plan: &HomeRails,
+    ) -> Result<(), HomingError> {
+        let current = self.planner.position();
+        let start = fill_coord(plan.axes, plan.force_pos, current);
+        let home = fill_coord(plan.axes, plan.home_pos, current);
+        self.set_position(start, plan.axes)?;
+        let speeds = &plan.speeds;
+        self.homing_move(endstops, home, speeds.speed, false)?;
+        if speeds.retract_dist <= Millimeters::ZERO {
+            return Ok(());
+        }
+        let axes_d = home - start;
+        let retract_r = (speeds.retract_dist.0 / axes_d.norm()).min(1.0);
+        let retract = home - axes_d * retract_r;
+        self.move_to(retract, speeds.retract_speed)?;
+        self.set_position(retract - axes_d * retract_r, BitFlags::empty())?;
+        let hmove = self.homing_move(endstops, home, speeds.second_speed, false)?;
+        match hmove.no_movement() {
+            Some(i) => Err(HomingError::StillTriggered(endstops[i].name.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Every rail's endstop, for `G28`
+#[derive(Default)]
+pub struct Homing {
+    /// Rail index, and its endstop
+    rails: Vec<(usize, HomingEndstop)>,
+}
+
+impl Homing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Home `rail` against `endstop`, which stops every stepper that moves the rail
+    pub fn add_endstop<K: Kinematics, L: McuLink>(
+        &mut self,
+        link: &mut L,
+        kin: &K,
+        rail: usize,
+        name: &str,
+        endstop: Endstop,
+    ) -> Result<(), HomingError> {
+        let steppers = kin.endstop_steppers(rail);
+        let endstop = HomingEndstop::new(link, kin, name, endstop, steppers)?;
+        self.rails.push((rail, endstop));
+        Ok(())
+    }
+
+    /// Home `axes`, following the kinematics' plan
+    pub fn home_axes<K: Kinematics<Position = Coord>, L: McuLink>(
+        &mut self,
+        ctx: &mut HomingContext<K, L>,
+        axes: BitFlags<Axis>,
+    ) -> Result<(), HomingError> {
+        for plan in ctx.kin.homing_plan(axes) {
+            if plan.rails.is_empty() {
+                let home = fill_coord(plan.axes, plan.home_pos, ctx.planner.position());
+                ctx.set_position(home, plan.axes)?;
+                continue;
+            }
+            if let Some(&rail) = plan
+                .rails
+                .iter()
+                .find(|rail| !self.rails.iter().any(|(r, _)| r == *rail))
+            {
+                return Err(HomingError::NoEndstop(rail));
+            }
+            let mut endstops: Vec<_> = self
+                .rails
+                .iter_mut()
+                .filter(|(rail, _)| plan.rails.contains(rail))
+                .map(|(_, endstop)| endstop)
+                .collect();
+            ctx.home_rails(&mut endstops, &plan)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::kinematics::cartesian::{Cartesian, CartesianConfig};
+    use crate::kinematics::{HomingSpeeds, RailConfig};
+    use crate::mcu::fake::FakeLink;
+    use crate::mcu::pin::PinRef;
+    use crate::planner::PlannerConfig;
+    use crate::stepcompress::StepCompressor;
+    use crate::units::{Hertz, MillimetersPerSecondSquared};
+
+    const FREQ: Hertz = Hertz(1_000_000.0);
+    const STEP: f64 = 0.0125;
+
+    struct Printer {
+        kin: Cartesian,
+        planner: Planner,
+        link: FakeLink,
+        homing: Homing,
+        /// X steps taken, as far as the MCU is concerned
+        x_steps: Rc<Cell<i64>>,
+    }
+
+    impl Printer {
+        fn home_x(&mut self) -> Result<(), HomingError> {
+            let mut ctx = HomingContext {
+                kin: &mut self.kin,
+                planner: &mut self.planner,
+                link: &mut self.link,
+            };
+            self.homing.home_axes(&mut ctx, Axis::X.into())
+        }
+    }
+
+    /// Cartesian printer with X on `x_mcu` and its endstop on `endstop_mcu`. The carriage
+    /// starts `start_x` from the switch and `pressed` says, given where it is, whether
+    /// the switch is pressed
+    fn printer(
+        x_mcu: &str,
+        endstop_mcu: &str,
+        start_x: f64,
+        pressed: impl Fn(f64) -> bool + 'static,
+    ) -> Printer {
+        let rail = |max: f64| {
+            let mut rail = RailConfig::new(
+                Millimeters(STEP),
+                Millimeters(0.0),
+                Millimeters(max),
+                Millimeters(0.0),
+            )
+            .unwrap();
+            rail.homing = HomingSpeeds::new(MillimetersPerSecond(50.0));
+            rail
+        };
+        let config = CartesianConfig {
+            x: rail(200.0),
+            y: rail(200.0),
+            z: rail(150.0),
+            max_z_velocity: MillimetersPerSecond(10.0),
+            max_z_accel: MillimetersPerSecondSquared(100.0),
+        };
+        let mut kin = Cartesian::new(config, FREQ);
+        let planner = Planner::new(PlannerConfig::new(
+            MillimetersPerSecond(300.0),
+            MillimetersPerSecondSquared(3000.0),
+        ));
+        let mut names = vec![x_mcu, endstop_mcu];
+        names.dedup();
+        let mut link = FakeLink::new(&names, FREQ);
+
+        let mcu = link.mcu(x_mcu).unwrap();
+        let oid = mcu.create_oid();
+        let sc = StepCompressor::new(oid, FREQ, Seconds(0.000_025), false);
+        kin.steppers_mut()[0].set_stepcompress(mcu, sc);
+        let x_steps = link.fake(x_mcu).stepper_position(oid);
+
+        let pin = PinRef {
+            mcu: endstop_mcu.into(),
+            pin: "PA1".into(),
+        };
+        let endstop = Endstop::new(link.mcu(endstop_mcu).unwrap(), pin, false, false);
+        let steps = x_steps.clone();
+        link.fake(endstop_mcu)
+            .set_pin("PA1", move || pressed(start_x + steps.get() as f64 * STEP));
+        let mut homing = Homing::new();
+        homing
+            .add_endstop(&mut link, &kin, 0, "x", endstop)
+            .unwrap();
+        Printer {
+            kin,
+            planner,
+            link,
+            homing,
+            x_steps,
+        }
+    }
+
+    fn assert_homed(printer: &Printer, start_x: f64) {
+        let actual = start_x + printer.x_steps.get() as f64 * STEP;
+        let host = printer.planner.position().x;
+        assert!(
+            (host - actual).abs() <= STEP,
+            "host thinks {host}, carriage is at {actual}"
+        );
+        assert!((printer.kin.calculate_position().x - host).abs() < 1e-9);
+        assert!(printer.kin.homed_axes().contains(Axis::X));
+    }
+
+    #[test]
+    fn test_home_x() {
+        let mut printer = printer("mcu", "mcu", 37.3, |x| x <= 0.0);
+        printer.home_x().unwrap();
+        assert_homed(&printer, 37.3);
+        // and having stopped on the switch, a little past where it triggered
+        let actual = 37.3 + printer.x_steps.get() as f64 * STEP;
+        assert!(actual <= 0.0 && actual > -0.5, "stopped at {actual}");
+
+        // homing again from where it ended up works just as well
+        printer.home_x().unwrap();
+        assert_homed(&printer, 37.3);
+    }
+
+    #[test]
+    fn test_home_across_mcus() {
+        // the host has to relay the trigger, so X overshoots further, but that's
+        // accounted for
+        let mut printer = printer("aux", "mcu", 62.0, |x| x <= 0.0);
+        printer.home_x().unwrap();
+        assert_homed(&printer, 62.0);
+    }
+
+    #[test]
+    fn test_homing_failures() {
+        let mut broken = printer("mcu", "mcu", 10.0, |_| false);
+        let err = broken.home_x().unwrap_err();
+        assert!(matches!(err, HomingError::NoTrigger(ref name) if name == "x"));
+
+        let mut stuck = printer("mcu", "mcu", 10.0, |_| true);
+        let err = stuck.home_x().unwrap_err();
+        assert!(matches!(err, HomingError::StillTriggered(ref name) if name == "x"));
+    }
+}
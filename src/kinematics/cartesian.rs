@@ -0,0 +1,286 @@
+//! Cartesian kinematics, one stepper per axis, and maybe a few more on Z
+
+use enumflags2::BitFlags;
+
+use super::{Axis, AxisLimits, HomeRails, Kinematics, KinematicsError, RailConfig, Stepper};
+use crate::planner::Move;
+use crate::trapq::Coord;
+use crate::units::{Hertz, Millimeters, MillimetersPerSecond, MillimetersPerSecondSquared};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CartesianConfig {
+    pub x: RailConfig,
+    pub y: RailConfig,
+    pub z: RailConfig,
+    pub max_z_velocity: MillimetersPerSecond,
+    pub max_z_accel: MillimetersPerSecondSquared,
+}
+
+pub struct Cartesian {
+    /// X, Y, Z, then any extra Z steppers
+    steppers: Vec<Stepper>,
+    rails: [RailConfig; 3],
+    limits: AxisLimits,
+    clock_freq: Hertz,
+}
+
+impl Cartesian {
+    pub fn new(config: CartesianConfig, clock_freq: Hertz) -> Self {
+        let rails = [config.x, config.y, config.z];
+        let steppers = AxisLimits::AXES
+            .map(|axis| {
+                Stepper::new(
+                    rails[axis.index()].step_distance,
+                    clock_freq,
+                    axis.into(),
+                    Box::new(move |m, t| Millimeters(axis.of(m.coord(t)))),
+                )
+            })
+            .into();
+        Self {
+            steppers,
+            rails,
+            limits: AxisLimits::new(config.max_z_velocity, config.max_z_accel),
+            clock_freq,
+        }
+    }
+
+    /// Another stepper driving Z alongside the first, like `[stepper_z1]`. Returns its
+    /// index into [`Kinematics::steppers`]
+    pub fn add_z_stepper(&mut self, step_distance: Millimeters) -> usize {
+        push_z_stepper(&mut self.steppers, step_distance, self.clock_freq)
+    }
+
+    pub fn rail(&self, axis: Axis) -> &RailConfig {
+        &self.rails[axis.index()]
+    }
+}
+
+impl Kinematics for Cartesian {
+    type Position = Coord;
+
+    fn steppers(&self) -> Vec<&Stepper> {
+        self.steppers.iter().collect()
+    }
+
+    fn steppers_mut(&mut self) -> Vec<&mut Stepper> {
+        self.steppers.iter_mut().collect()
+    }
+
+    fn calculate_position(&self) -> Coord {
+        let [x, y, z, ..] = &self.steppers[..] else {
+            unreachable!("always at least three steppers");
+        };
+        Coord::new(x.position().0, y.position().0, z.position().0)
+    }
+
+    fn set_position(&mut self, pos: Coord, homing_axes: BitFlags<Axis>) {
+        for stepper in &mut self.steppers {
+            stepper.set_position(pos);
+        }
+        for axis in homing_axes & Axis::XYZ {
+            self.limits.set_homed(axis, &self.rails[axis.index()]);
+        }
+    }
+
+    fn check_move(&self, mv: &mut Move) -> Result<(), KinematicsError> {
+        self.limits.check_move(mv)
+    }
+
+    fn homed_axes(&self) -> BitFlags<Axis> {
+        self.limits.homed_axes()
+    }
+
+    fn clear_homing_state(&mut self, axes: BitFlags<Axis>) {
+        self.limits.clear(axes);
+    }
+
+    /// Each axis on its own, X then Y then Z
+    fn homing_plan(&self, axes: BitFlags<Axis>) -> Vec<HomeRails> {
+        (axes & Axis::XYZ)
+            .iter()
+            .map(|axis| self.rails[axis.index()].home_rails(axis, axis.index()))
+            .collect()
+    }
+
+    /// The Z endstop stops every Z stepper
+    fn endstop_steppers(&self, rail: usize) -> Vec<usize> {
+        match rail {
+            2 => [2].into_iter().chain(3..self.steppers.len()).collect(),
+            _ => vec![rail],
+        }
+    }
+}
+
+/// A stepper that follows Z and nothing else
+pub(super) fn z_stepper(step_distance: Millimeters, clock_freq: Hertz) -> Stepper {
+    Stepper::new(
+        step_distance,
+        clock_freq,
+        Axis::Z.into(),
+        Box::new(|m, t| Millimeters(m.coord(t).z)),
+    )
+}
+
+/// Add a [`z_stepper`] on the end of `steppers`, returning its index
+pub(super) fn push_z_stepper(
+    steppers: &mut Vec<Stepper>,
+    step_distance: Millimeters,
+    clock_freq: Hertz,
+) -> usize {
+    steppers.push(z_stepper(step_distance, clock_freq));
+    steppers.len() - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::planner::{Planner, PlannerConfig};
+    use crate::units::Seconds;
+
+    fn rail(max: f64, endstop: f64) -> RailConfig {
+        RailConfig::new(
+            Millimeters(0.0125),
+            Millimeters(0.0),
+            Millimeters(max),
+            Millimeters(endstop),
+        )
+        .unwrap()
+    }
+
+    fn printer() -> (Cartesian, Planner) {
+        let config = CartesianConfig {
+            x: rail(200.0, 0.0),
+            y: rail(200.0, 200.0),
+            z: rail(150.0, 0.0),
+            max_z_velocity: MillimetersPerSecond(10.0),
+            max_z_accel: MillimetersPerSecondSquared(100.0),
+        };
+        let planner = Planner::new(PlannerConfig::new(
+            MillimetersPerSecond(300.0),
+            MillimetersPerSecondSquared(3000.0),
+        ));
+        (Cartesian::new(config, Hertz(1_000_000.0)), planner)
+    }
+
+    #[test]
+    fn test_rail_config() {
+        let y = rail(200.0, 200.0);
+        assert!(y.homing_positive_dir);
+        // pretend it's 1.5x the axis length away from the endstop
+        assert_eq!(y.homing_start(), Millimeters(-100.0));
+        let x = rail(200.0, 0.0);
+        assert!(!x.homing_positive_dir);
+        assert_eq!(x.homing_start(), Millimeters(300.0));
+
+        let config = |endstop| {
+            RailConfig::new(
+                Millimeters(0.01),
+                Millimeters(0.0),
+                Millimeters(200.0),
+                Millimeters(endstop),
+            )
+        };
+        assert_eq!(config(100.0), Err(KinematicsError::AmbiguousHomingDir));
+        assert_eq!(config(201.0), Err(KinematicsError::EndstopOutOfRange));
+    }
+
+    #[test]
+    fn test_limits() {
+        let (mut kin, mut planner) = printer();
+        let speed = MillimetersPerSecond(100.0);
+        assert!(matches!(
+            planner.checked_move(&kin, Coord::new(10.0, 0.0, 0.0), speed),
+            Err(KinematicsError::MustHomeFirst(_))
+        ));
+        assert!(kin.homed_axes().is_empty());
+
+        kin.set_position(Coord::default(), Axis::X | Axis::Y);
+        planner.set_position(Coord::default());
+        assert_eq!(kin.homed_axes(), Axis::X | Axis::Y);
+        planner
+            .checked_move(&kin, Coord::new(10.0, 20.0, 0.0), speed)
+            .unwrap();
+        assert_eq!(
+            planner.checked_move(&kin, Coord::new(10.0, 201.0, 0.0), speed),
+            Err(KinematicsError::OutOfRange(Coord::new(10.0, 201.0, 0.0)))
+        );
+        // Z still isn't homed
+        assert!(matches!(
+            planner.checked_move(&kin, Coord::new(10.0, 20.0, 5.0), speed),
+            Err(KinematicsError::MustHomeFirst(_))
+        ));
+
+        kin.motors_off();
+        assert!(kin.homed_axes().is_empty());
+    }
+
+    #[test]
+    fn test_z_moves_are_slowed() {
+        let (mut kin, planner) = printer();
+        kin.set_position(Coord::default(), Axis::XYZ);
+        // 3-4-5 triangle through X and Z, so Z covers 4/5ths of the distance
+        let mut mv = planner.make_move(Coord::new(3.0, 0.0, 4.0), MillimetersPerSecond(100.0));
+        kin.check_move(&mut mv).unwrap();
+        assert!((mv.min_move_t - Seconds(5.0 / 12.5)).abs() < Seconds(1e-9));
+        assert!((mv.accel - MillimetersPerSecondSquared(125.0)).abs().0 < 1e-9);
+
+        let mut mv = planner.make_move(Coord::new(30.0, 40.0, 0.0), MillimetersPerSecond(100.0));
+        kin.check_move(&mut mv).unwrap();
+        assert_eq!(mv.accel, MillimetersPerSecondSquared(3000.0));
+    }
+
+    #[test]
+    fn test_steps_and_position() {
+        let (mut kin, mut planner) = printer();
+        kin.set_position(Coord::default(), Axis::XYZ);
+        let target = Coord::new(10.0, 5.0, 1.0);
+        planner
+            .checked_move(&kin, target, MillimetersPerSecond(100.0))
+            .unwrap();
+        planner.flush();
+        let end = planner.print_time();
+
+        assert_eq!(kin.active_axes(), BitFlags::empty());
+        assert_eq!(kin.will_be_active(planner.trapq(), end), Axis::XYZ);
+        let steps = kin.generate_steps(planner.trapq(), end);
+        let counts: Vec<_> = steps.iter().map(Vec::len).collect();
+        assert_eq!(counts, [800, 400, 80]);
+        assert_eq!(kin.active_axes(), Axis::XYZ);
+        assert!((kin.calculate_position() - target).norm() < 1e-9);
+
+        kin.motors_off();
+        assert_eq!(kin.active_axes(), BitFlags::empty());
+    }
+
+    #[test]
+    fn test_extra_z_steppers() {
+        let (mut kin, mut planner) = printer();
+        assert_eq!(kin.add_z_stepper(Millimeters(0.0025)), 3);
+        assert_eq!(kin.add_z_stepper(Millimeters(0.0025)), 4);
+        assert_eq!(kin.endstop_steppers(2), [2, 3, 4]);
+        assert_eq!(kin.endstop_steppers(1), [1]);
+        kin.set_position(Coord::default(), Axis::XYZ);
+
+        // one held back while the rest move
+        kin.steppers_mut()[4].set_following(false);
+        planner.move_to(Coord::new(0.0, 0.0, 1.0), MillimetersPerSecond(10.0));
+        planner.flush();
+        let first = planner.print_time();
+        let steps = kin.generate_steps(planner.trapq(), first);
+        let counts: Vec<_> = steps.iter().map(Vec::len).collect();
+        assert_eq!(counts, [0, 0, 80, 400, 0]);
+
+        // it's still where it was, until told otherwise
+        assert_eq!(kin.steppers()[4].position(), Millimeters(0.0));
+        kin.steppers_mut()[4].set_following(true);
+        kin.set_position(Coord::new(0.0, 0.0, 1.0), BitFlags::empty());
+        planner.set_position(Coord::new(0.0, 0.0, 1.0));
+        planner.move_to(Coord::new(0.0, 0.0, 2.0), MillimetersPerSecond(10.0));
+        planner.flush();
+        let steps = kin.generate_steps(planner.trapq(), planner.print_time());
+        let counts: Vec<_> = steps.iter().map(Vec::len).collect();
+        assert_eq!(counts, [0, 0, 80, 400, 400]);
+        assert!((kin.calculate_position() - Coord::new(0.0, 0.0, 2.0)).norm() < 1e-9);
+    }
+}
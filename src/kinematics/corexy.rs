@@ -0,0 +1,223 @@
+//! CoreXY kinematics: two motors share the X and Y belts, `a = x + y` and `b = x - y`
+
+use enumflags2::BitFlags;
+
+use super::cartesian::{push_z_stepper, z_stepper, CartesianConfig};
+use super::{Axis, AxisLimits, HomeRails, Kinematics, KinematicsError, RailConfig, Stepper};
+use crate::planner::Move;
+use crate::trapq::Coord;
+use crate::units::{Hertz, Millimeters};
+
+/// Same `[stepper_x]`, `[stepper_y]` and `[stepper_z]` sections as a cartesian printer
+pub type CoreXYConfig = CartesianConfig;
+
+pub struct CoreXY {
+    /// A, B, Z, then any extra Z steppers
+    steppers: Vec<Stepper>,
+    rails: [RailConfig; 3],
+    limits: AxisLimits,
+    clock_freq: Hertz,
+}
+
+impl CoreXY {
+    pub fn new(config: CoreXYConfig, clock_freq: Hertz) -> Self {
+        let xy = Axis::X | Axis::Y;
+        let steppers = vec![
+            Stepper::new(
+                config.x.step_distance,
+                clock_freq,
+                xy,
+                Box::new(|m, t| {
+                    let c = m.coord(t);
+                    Millimeters(c.x + c.y)
+                }),
+            ),
+            Stepper::new(
+                config.y.step_distance,
+                clock_freq,
+                xy,
+                Box::new(|m, t| {
+                    let c = m.coord(t);
+                    Millimeters(c.x - c.y)
+                }),
+            ),
+            z_stepper(config.z.step_distance, clock_freq),
+        ];
+        Self {
+            steppers,
+            rails: [config.x, config.y, config.z],
+            limits: AxisLimits::new(config.max_z_velocity, config.max_z_accel),
+            clock_freq,
+        }
+    }
+
+    /// Same as [`Cartesian::add_z_stepper`](super::cartesian::Cartesian::add_z_stepper)
+    pub fn add_z_stepper(&mut self, step_distance: Millimeters) -> usize {
+        push_z_stepper(&mut self.steppers, step_distance, self.clock_freq)
+    }
+
+    pub fn rail(&self, axis: Axis) -> &RailConfig {
+        &self.rails[axis.index()]
+    }
+}
+
+impl Kinematics for CoreXY {
+    type Position = Coord;
+
+    fn steppers(&self) -> Vec<&Stepper> {
+        self.steppers.iter().collect()
+    }
+
+    fn steppers_mut(&mut self) -> Vec<&mut Stepper> {
+        self.steppers.iter_mut().collect()
+    }
+
+    fn calculate_position(&self) -> Coord {
+        let [a, b, z, ..] = &self.steppers[..] else {
+            unreachable!("always at least three steppers");
+        };
+        let (a, b) = (a.position().0, b.position().0);
+        Coord::new(0.5 * (a + b), 0.5 * (a - b), z.position().0)
+    }
+
+    fn set_position(&mut self, pos: Coord, homing_axes: BitFlags<Axis>) {
+        for stepper in &mut self.steppers {
+            stepper.set_position(pos);
+        }
+        for axis in homing_axes & Axis::XYZ {
+            self.limits.set_homed(axis, &self.rails[axis.index()]);
+        }
+    }
+
+    fn check_move(&self, mv: &mut Move) -> Result<(), KinematicsError> {
+        self.limits.check_move(mv)
+    }
+
+    fn homed_axes(&self) -> BitFlags<Axis> {
+        self.limits.homed_axes()
+    }
+
+    fn clear_homing_state(&mut self, axes: BitFlags<Axis>) {
+        self.limits.clear(axes);
+    }
+
+    /// Each axis on its own, X then Y then Z
+    fn homing_plan(&self, axes: BitFlags<Axis>) -> Vec<HomeRails> {
+        (axes & Axis::XYZ)
+            .iter()
+            .map(|axis| self.rails[axis.index()].home_rails(axis, axis.index()))
+            .collect()
+    }
+
+    /// Both belts move X and Y, so both motors have to stop for either endstop. The Z
+    /// endstop stops every Z stepper
+    fn endstop_steppers(&self, rail: usize) -> Vec<usize> {
+        match rail {
+            0 | 1 => vec![0, 1],
+            _ => [2].into_iter().chain(3..self.steppers.len()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::itersolve::Step;
+    use super::*;
+    use crate::planner::{Planner, PlannerConfig};
+    use crate::units::{MillimetersPerSecond, MillimetersPerSecondSquared};
+
+    const FREQ: Hertz = Hertz(1_000_000.0);
+    const STEP: f64 = 0.0125;
+
+    fn corexy() -> CoreXY {
+        let rail = |endstop| {
+            RailConfig::new(
+                Millimeters(STEP),
+                Millimeters(0.0),
+                Millimeters(200.0),
+                Millimeters(endstop),
+            )
+            .unwrap()
+        };
+        let config = CoreXYConfig {
+            x: rail(200.0),
+            y: rail(200.0),
+            z: rail(0.0),
+            max_z_velocity: MillimetersPerSecond(10.0),
+            max_z_accel: MillimetersPerSecondSquared(100.0),
+        };
+        CoreXY::new(config, FREQ)
+    }
+
+    /// Homed at the origin, then a few moves to (15.2, 2.7), and the steps for them
+    fn moved() -> (CoreXY, Planner, Vec<Vec<Step>>) {
+        let mut kin = corexy();
+        kin.set_position(Coord::default(), Axis::XYZ);
+        let mut planner = Planner::new(PlannerConfig::new(
+            MillimetersPerSecond(200.0),
+            MillimetersPerSecondSquared(2000.0),
+        ));
+        // pure X moves both motors, pure Y moves them in opposite directions,
+        // and diagonals only move one
+        for (x, y) in [(10.0, 0.0), (10.0, 10.0), (20.0, 20.0), (15.2, 2.7)] {
+            planner
+                .checked_move(&kin, Coord::new(x, y, 0.0), MillimetersPerSecond(150.0))
+                .unwrap();
+        }
+        planner.flush();
+        let steps = kin.generate_steps(planner.trapq(), planner.print_time());
+        (kin, planner, steps)
+    }
+
+    #[test]
+    fn test_steps_and_position() {
+        let (kin, _, steps) = moved();
+        // 15.2 + 2.7 and 15.2 - 2.7, with Z left alone
+        let net = |steps: &[Step]| {
+            steps
+                .iter()
+                .map(|s| if s.dir { 1 } else { -1 })
+                .sum::<i64>()
+        };
+        assert_eq!(net(&steps[0]), 1432);
+        assert_eq!(net(&steps[1]), 1000);
+        assert!(steps[2].is_empty());
+
+        // and back again from the motor positions
+        let pos = kin.calculate_position();
+        assert!((pos - Coord::new(15.2, 2.7, 0.0)).norm() < STEP, "{pos:?}");
+    }
+
+    /// Needs klipper's C, built with the `klipper-steps` feature
+    #[cfg(feature = "klipper-steps")]
+    #[test]
+    fn test_against_klipper() {
+        use std::os::raw::c_char;
+
+        use super::super::{klipper, reference};
+        use crate::ffi::generated::corexy_stepper_alloc;
+
+        let (_, planner, steps) = moved();
+        let end = planner.print_time();
+        let klipper_steps = |axis: u8| {
+            let sk = unsafe { corexy_stepper_alloc(axis as c_char) };
+            klipper::steps(sk, planner.trapq(), STEP, Coord::default(), end, FREQ)
+        };
+        reference::assert_matches(&steps[0], &klipper_steps(b'+'));
+        reference::assert_matches(&steps[1], &klipper_steps(b'-'));
+    }
+
+    #[test]
+    fn test_set_position() {
+        let mut kin = corexy();
+        kin.set_position(Coord::new(30.0, 10.0, 5.0), Axis::X | Axis::Y);
+        let [a, b, z] = &kin.steppers[..] else {
+            unreachable!()
+        };
+        assert_eq!(a.position(), Millimeters(40.0));
+        assert_eq!(b.position(), Millimeters(20.0));
+        assert_eq!(z.position(), Millimeters(5.0));
+        assert_eq!(kin.calculate_position(), Coord::new(30.0, 10.0, 5.0));
+        assert_eq!(kin.homed_axes(), Axis::X | Axis::Y);
+    }
+}
@@ -0,0 +1,207 @@
+//! CoreXZ kinematics: two motors share the X and Z belts, `a = x + z` and `b = x - z`
+
+use enumflags2::BitFlags;
+
+use super::cartesian::CartesianConfig;
+use super::{Axis, AxisLimits, HomeRails, Kinematics, KinematicsError, RailConfig, Stepper};
+use crate::planner::Move;
+use crate::trapq::Coord;
+use crate::units::{Hertz, Millimeters};
+
+/// Same `[stepper_x]`, `[stepper_y]` and `[stepper_z]` sections as a cartesian printer
+pub type CoreXZConfig = CartesianConfig;
+
+pub struct CoreXZ {
+    /// A, Y, B
+    steppers: [Stepper; 3],
+    rails: [RailConfig; 3],
+    limits: AxisLimits,
+}
+
+impl CoreXZ {
+    pub fn new(config: CoreXZConfig, clock_freq: Hertz) -> Self {
+        let xz = Axis::X | Axis::Z;
+        let steppers = [
+            Stepper::new(
+                config.x.step_distance,
+                clock_freq,
+                xz,
+                Box::new(|m, t| {
+                    let c = m.coord(t);
+                    Millimeters(c.x + c.z)
+                }),
+            ),
+            Stepper::new(
+                config.y.step_distance,
+                clock_freq,
+                Axis::Y.into(),
+                Box::new(|m, t| Millimeters(m.coord(t).y)),
+            ),
+            Stepper::new(
+                config.z.step_distance,
+                clock_freq,
+                xz,
+                Box::new(|m, t| {
+                    let c = m.coord(t);
+                    Millimeters(c.x - c.z)
+                }),
+            ),
+        ];
+        Self {
+            steppers,
+            rails: [config.x, config.y, config.z],
+            limits: AxisLimits::new(config.max_z_velocity, config.max_z_accel),
+        }
+    }
+
+    pub fn rail(&self, axis: Axis) -> &RailConfig {
+        &self.rails[axis.index()]
+    }
+}
+
+impl Kinematics for CoreXZ {
+    type Position = Coord;
+
+    fn steppers(&self) -> Vec<&Stepper> {
+        self.steppers.iter().collect()
+    }
+
+    fn steppers_mut(&mut self) -> Vec<&mut Stepper> {
+        self.steppers.iter_mut().collect()
+    }
+
+    fn calculate_position(&self) -> Coord {
+        let [a, y, b] = &self.steppers;
+        let (a, b) = (a.position().0, b.position().0);
+        Coord::new(0.5 * (a + b), y.position().0, 0.5 * (a - b))
+    }
+
+    fn set_position(&mut self, pos: Coord, homing_axes: BitFlags<Axis>) {
+        for stepper in &mut self.steppers {
+            stepper.set_position(pos);
+        }
+        for axis in homing_axes & Axis::XYZ {
+            self.limits.set_homed(axis, &self.rails[axis.index()]);
+        }
+    }
+
+    fn check_move(&self, mv: &mut Move) -> Result<(), KinematicsError> {
+        self.limits.check_move(mv)
+    }
+
+    fn homed_axes(&self) -> BitFlags<Axis> {
+        self.limits.homed_axes()
+    }
+
+    fn clear_homing_state(&mut self, axes: BitFlags<Axis>) {
+        self.limits.clear(axes);
+    }
+
+    /// Each axis on its own, X then Y then Z
+    fn homing_plan(&self, axes: BitFlags<Axis>) -> Vec<HomeRails> {
+        (axes & Axis::XYZ)
+            .iter()
+            .map(|axis| self.rails[axis.index()].home_rails(axis, axis.index()))
+            .collect()
+    }
+
+    /// Both belts move X and Z, so both motors have to stop for either endstop
+    fn endstop_steppers(&self, rail: usize) -> Vec<usize> {
+        match rail {
+            0 | 2 => vec![0, 2],
+            _ => vec![rail],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::itersolve::Step;
+    use super::*;
+    use crate::planner::{Planner, PlannerConfig};
+    use crate::units::{MillimetersPerSecond, MillimetersPerSecondSquared};
+
+    const FREQ: Hertz = Hertz(1_000_000.0);
+    const STEP: f64 = 0.01;
+
+    /// Homed at the origin, then a few moves to (4.3, 5.0, 3.1), and the steps for them
+    fn moved() -> (CoreXZ, Planner, Vec<Vec<Step>>) {
+        let rail = |endstop| {
+            RailConfig::new(
+                Millimeters(STEP),
+                Millimeters(0.0),
+                Millimeters(100.0),
+                Millimeters(endstop),
+            )
+            .unwrap()
+        };
+        let config = CoreXZConfig {
+            x: rail(0.0),
+            y: rail(0.0),
+            z: rail(0.0),
+            max_z_velocity: MillimetersPerSecond(20.0),
+            max_z_accel: MillimetersPerSecondSquared(500.0),
+        };
+        let mut kin = CoreXZ::new(config, FREQ);
+        kin.set_position(Coord::default(), Axis::XYZ);
+        let mut planner = Planner::new(PlannerConfig::new(
+            MillimetersPerSecond(200.0),
+            MillimetersPerSecondSquared(2000.0),
+        ));
+        for target in [
+            Coord::new(10.0, 0.0, 0.0),
+            Coord::new(10.0, 5.0, 2.0),
+            Coord::new(4.3, 5.0, 3.1),
+        ] {
+            planner
+                .checked_move(&kin, target, MillimetersPerSecond(100.0))
+                .unwrap();
+        }
+        planner.flush();
+        let steps = kin.generate_steps(planner.trapq(), planner.print_time());
+        (kin, planner, steps)
+    }
+
+    #[test]
+    fn test_steps_and_position() {
+        let (kin, _, steps) = moved();
+        // 4.3 + 3.1, 5.0 and 4.3 - 3.1
+        let net = |steps: &[Step]| {
+            steps
+                .iter()
+                .map(|s| if s.dir { 1 } else { -1 })
+                .sum::<i64>()
+        };
+        assert_eq!(net(&steps[0]), 740);
+        assert_eq!(net(&steps[1]), 500);
+        assert_eq!(net(&steps[2]), 120);
+
+        let pos = kin.calculate_position();
+        assert!((pos - Coord::new(4.3, 5.0, 3.1)).norm() < STEP, "{pos:?}");
+    }
+
+    /// Needs klipper's C, built with the `klipper-steps` feature
+    #[cfg(feature = "klipper-steps")]
+    #[test]
+    fn test_against_klipper() {
+        use std::os::raw::c_char;
+
+        use super::super::{klipper, reference};
+        use crate::ffi::generated::{cartesian_stepper_alloc, corexz_stepper_alloc};
+
+        let (_, planner, steps) = moved();
+        let end = planner.print_time();
+        let klipper_steps =
+            |sk| klipper::steps(sk, planner.trapq(), STEP, Coord::default(), end, FREQ);
+        let (a, y, b) = unsafe {
+            (
+                corexz_stepper_alloc(b'+' as c_char),
+                cartesian_stepper_alloc(b'y' as c_char),
+                corexz_stepper_alloc(b'-' as c_char),
+            )
+        };
+        reference::assert_matches(&steps[0], &klipper_steps(a));
+        reference::assert_matches(&steps[1], &klipper_steps(y));
+        reference::assert_matches(&steps[2], &klipper_steps(b));
+    }
+}
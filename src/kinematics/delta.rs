@@ -0,0 +1,553 @@
+//! Linear delta kinematics, klipper's `delta.py`
+//!
+//! Three carriages ride up vertical towers, each pulling the effector with a fixed length
+//! arm. A carriage's height is the effector's height plus however far up the tower the
+//! arm reaches, `sqrt(arm² - dx² - dy²)`. Going back the other way means finding where
+//! three spheres meet
+
+use enumflags2::BitFlags;
+
+use super::{Axis, HomeRails, HomingSpeeds, Kinematics, KinematicsError, Stepper};
+use crate::mathutil;
+use crate::planner::{Move, PlannerConfig};
+use crate::trapq::Coord;
+use crate::units::{Hertz, Millimeters, MillimetersPerSecond, MillimetersPerSecondSquared};
+
+/// Tower travel per unit of XY travel above which moves get slowed down
+const SLOW_RATIO: f64 = 3.0;
+/// Where the towers sit, counter-clockwise from the front left, in degrees
+pub const DEFAULT_ANGLES: [f64; 3] = [210.0, 330.0, 90.0];
+
+/// One tower, what klipper has in a `[stepper_a]` section
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeltaTower {
+    /// Angle around the centre of the bed, in degrees
+    pub angle: f64,
+    /// Added to `delta_radius` for just this tower
+    pub radius_offset: Millimeters,
+    pub arm_length: Millimeters,
+    /// Height of the effector when this carriage triggers its endstop
+    pub position_endstop: Millimeters,
+    /// Amount the carriage moves per step
+    pub step_distance: Millimeters,
+    /// Only tower A's are used, all three home together
+    pub homing: HomingSpeeds,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeltaConfig {
+    /// Horizontal distance from the centre to each tower's arm joint, less the effector's
+    pub radius: Millimeters,
+    /// How far from the centre the toolhead may go
+    pub print_radius: Millimeters,
+    pub minimum_z_position: Millimeters,
+    /// A, B, C
+    pub towers: [DeltaTower; 3],
+    pub max_velocity: MillimetersPerSecond,
+    pub max_accel: MillimetersPerSecondSquared,
+    pub max_z_velocity: MillimetersPerSecond,
+    pub max_z_accel: MillimetersPerSecondSquared,
+}
+
+impl DeltaConfig {
+    /// Three identical towers at the usual angles, printing out to `radius`
+    pub fn new(
+        radius: Millimeters,
+        arm_length: Millimeters,
+        position_endstop: Millimeters,
+        step_distance: Millimeters,
+        planner: &PlannerConfig,
+    ) -> Self {
+        Self {
+            radius,
+            print_radius: radius,
+            minimum_z_position: Millimeters(0.0),
+            towers: DEFAULT_ANGLES.map(|angle| DeltaTower {
+                angle,
+                radius_offset: Millimeters(0.0),
+                arm_length,
+                position_endstop,
+                step_distance,
+                homing: HomingSpeeds::default(),
+            }),
+            max_velocity: planner.max_velocity,
+            max_accel: planner.max_accel,
+            max_z_velocity: planner.max_velocity,
+            max_z_accel: planner.max_accel,
+        }
+    }
+
+    /// XY of each tower's arm joint, with z left at zero
+    pub fn tower_positions(&self) -> [Coord; 3] {
+        self.towers.clone().map(|t| {
+            let (sin, cos) = t.angle.to_radians().sin_cos();
+            let radius = (self.radius + t.radius_offset).0;
+            Coord::new(cos * radius, sin * radius, 0.0)
+        })
+    }
+
+    /// Carriage heights with the effector at `pos`
+    pub fn carriage_positions(&self, pos: Coord) -> [Millimeters; 3] {
+        let towers = self.tower_positions();
+        std::array::from_fn(|i| {
+            let (dx, dy) = (towers[i].x - pos.x, towers[i].y - pos.y);
+            Millimeters((self.towers[i].arm_length.0.powi(2) - dx * dx - dy * dy).sqrt() + pos.z)
+        })
+    }
+
+    /// Effector position with the carriages at the given heights, `None` if the arms
+    /// can't reach each other
+    pub fn effector_position(&self, carriages: [Millimeters; 3]) -> Option<Coord> {
+        let (centres, arm2) = self.arm_spheres(carriages);
+        // the effector hangs below the carriages
+        mathutil::trilateration(centres, arm2).map(|(below, _)| below)
+    }
+
+    /// Same again, except arms that can't reach each other get as close as they can
+    pub fn closest_effector_position(&self, carriages: [Millimeters; 3]) -> Coord {
+        let (centres, arm2) = self.arm_spheres(carriages);
+        mathutil::closest_trilateration(centres, arm2).0
+    }
+
+    /// Where the effector can be from each carriage: centres and squared radii of spheres
+    fn arm_spheres(&self, carriages: [Millimeters; 3]) -> ([Coord; 3], [f64; 3]) {
+        let towers = self.tower_positions();
+        let centres = std::array::from_fn(|i| Coord::new(towers[i].x, towers[i].y, carriages[i].0));
+        (centres, self.towers.clone().map(|t| t.arm_length.0.powi(2)))
+    }
+
+    /// Carriage heights when each one triggers its endstop
+    fn abs_endstops(&self) -> [Millimeters; 3] {
+        self.towers.clone().map(|t| {
+            let radius = self.radius + t.radius_offset;
+            t.position_endstop + Millimeters((t.arm_length.0.powi(2) - radius.0.powi(2)).sqrt())
+        })
+    }
+
+    /// Steps each carriage is below its endstop with the effector at `pos`. Unlike a
+    /// position these don't change when the geometry is recalibrated
+    pub fn stable_position(&self, pos: Coord) -> [f64; 3] {
+        let (endstops, carriages) = (self.abs_endstops(), self.carriage_positions(pos));
+        std::array::from_fn(|i| (endstops[i] - carriages[i]) / self.towers[i].step_distance)
+    }
+
+    pub fn position_from_stable(&self, stable: [f64; 3]) -> Option<Coord> {
+        let endstops = self.abs_endstops();
+        self.effector_position(std::array::from_fn(|i| {
+            endstops[i] - self.towers[i].step_distance * stable[i]
+        }))
+    }
+}
+
+pub struct Delta {
+    /// A, B, C
+    steppers: [Stepper; 3],
+    config: DeltaConfig,
+    homed: bool,
+    /// Effector position with every carriage at its endstop
+    home_position: Coord,
+    min_z: Millimeters,
+    max_z: Millimeters,
+    /// Above this the arms start to limit how far out the effector can reach
+    limit_z: Millimeters,
+    min_arm_length: Millimeters,
+    max_xy2: f64,
+    /// Out past these, moves are cut to a half and a quarter of full speed
+    slow_xy2: f64,
+    very_slow_xy2: f64,
+}
+
+impl Delta {
+    pub fn new(config: DeltaConfig, clock_freq: Hertz) -> Result<Self, KinematicsError> {
+        if config
+            .towers
+            .iter()
+            .any(|t| t.arm_length <= config.radius + t.radius_offset)
+        {
+            return Err(KinematicsError::ArmTooShort);
+        }
+        let towers = config.tower_positions();
+        let steppers = std::array::from_fn(|i| {
+            let (tower, arm2) = (towers[i], config.towers[i].arm_length.0.powi(2));
+            Stepper::new(
+                config.towers[i].step_distance,
+                clock_freq,
+                Axis::XYZ,
+                Box::new(move |m, t| {
+                    let c = m.coord(t);
+                    let (dx, dy) = (tower.x - c.x, tower.y - c.y);
+                    Millimeters((arm2 - dx * dx - dy * dy).sqrt() + c.z)
+                }),
+            )
+        });
+
+        let abs_endstops = config.abs_endstops();
+        let home_position = config
+            .effector_position(abs_endstops)
+            .ok_or(KinematicsError::ArmTooShort)?;
+        let fold_min = |it: &mut dyn Iterator<Item = Millimeters>| {
+            it.fold(Millimeters(f64::MAX), Millimeters::min)
+        };
+        let max_z = fold_min(&mut config.towers.iter().map(|t| t.position_endstop));
+        let limit_z = fold_min(
+            &mut abs_endstops
+                .iter()
+                .zip(&config.towers)
+                .map(|(&e, t)| e - t.arm_length),
+        );
+        let min_arm_length = fold_min(&mut config.towers.iter().map(|t| t.arm_length));
+        let half_min_step = fold_min(&mut config.towers.iter().map(|t| t.step_distance)).0 * 0.5;
+
+        // how far out an XY move can make a carriage travel `ratio` times as far
+        let ratio_to_xy = |ratio: f64| {
+            ratio
+                * ((min_arm_length.0.powi(2) / (ratio * ratio + 1.0)) - half_min_step.powi(2))
+                    .sqrt()
+                + half_min_step
+                - config.radius.0
+        };
+        let max_xy = config
+            .print_radius
+            .0
+            .min((min_arm_length - config.radius).0)
+            .min(ratio_to_xy(4.0 * SLOW_RATIO));
+
+        Ok(Self {
+            steppers,
+            home_position,
+            min_z: config.minimum_z_position,
+            max_z,
+            limit_z,
+            min_arm_length,
+            max_xy2: max_xy * max_xy,
+            slow_xy2: ratio_to_xy(SLOW_RATIO).powi(2),
+            very_slow_xy2: ratio_to_xy(2.0 * SLOW_RATIO).powi(2),
+            config,
+            homed: false,
+        })
+    }
+
+    pub fn config(&self) -> &DeltaConfig {
+        &self.config
+    }
+
+    /// Effector position with every carriage at its endstop
+    pub fn home_position(&self) -> Coord {
+        self.home_position
+    }
+
+    /// Where to pretend the effector starts homing from, far enough below the endstops
+    /// that the carriages are guaranteed to reach them
+    pub fn homing_start(&self) -> Coord {
+        let max_arm = self
+            .config
+            .towers
+            .iter()
+            .fold(0.0_f64, |max, t| max.max(t.arm_length.0));
+        Coord::new(
+            self.home_position.x,
+            self.home_position.y,
+            -1.5 * (max_arm * max_arm - self.max_xy2).sqrt(),
+        )
+    }
+}
+
+impl Kinematics for Delta {
+    type Position = Coord;
+
+    fn steppers(&self) -> Vec<&Stepper> {
+        self.steppers.iter().collect()
+    }
+
+    fn steppers_mut(&mut self) -> Vec<&mut Stepper> {
+        self.steppers.iter_mut().collect()
+    }
+
+    fn calculate_position(&self) -> Coord {
+        let [a, b, c] = &self.steppers;
+        // homing stops each carriage wherever its own endstop triggers, which can leave
+        // them where the arms don't quite meet
+        self.config
+            .closest_effector_position([a.position(), b.position(), c.position()])
+    }
+
+    fn set_position(&mut self, pos: Coord, homing_axes: BitFlags<Axis>) {
+        for stepper in &mut self.steppers {
+            stepper.set_position(pos);
+        }
+        // every tower is involved in every axis, so they're all homed together
+        if homing_axes.contains(Axis::XYZ) {
+            self.homed = true;
+        }
+    }
+
+    fn check_move(&self, mv: &mut Move) -> Result<(), KinematicsError> {
+        let end = mv.end_pos;
+        if !self.homed {
+            return Err(KinematicsError::MustHomeFirst(end));
+        }
+        let (end_xy2, end_z) = (end.x * end.x + end.y * end.y, Millimeters(end.z));
+        let mut limit_xy2 = self.max_xy2;
+        if end_z > self.limit_z {
+            // the arms are getting close to vertical, so can't reach as far out
+            let above_z_limit = end_z - self.limit_z;
+            let allowed_radius = self.config.radius.0
+                - (self.min_arm_length.0.powi(2) - (self.min_arm_length - above_z_limit).0.powi(2))
+                    .sqrt();
+            limit_xy2 = limit_xy2.min(allowed_radius * allowed_radius);
+        }
+        if end_xy2 > limit_xy2 || end_z > self.max_z || end_z < self.min_z {
+            // the only way up there is straight up from the centre while homing
+            let home = self.home_position;
+            if (end.x, end.y) != (home.x, home.y) || end_z < self.min_z || end.z > home.z {
+                return Err(KinematicsError::OutOfRange(end));
+            }
+        }
+        if mv.axes_r.z != 0.0 {
+            let z_ratio = 1.0 / mv.axes_r.z.abs();
+            mv.limit_speed(
+                self.config.max_z_velocity * z_ratio,
+                self.config.max_z_accel * z_ratio,
+            );
+        }
+        // near the towers a little XY makes for a lot of carriage travel
+        let start = mv.start_pos;
+        let extreme_xy2 = end_xy2.max(start.x * start.x + start.y * start.y);
+        if extreme_xy2 > self.slow_xy2 {
+            let r = if extreme_xy2 > self.very_slow_xy2 {
+                0.25
+            } else {
+                0.5
+            };
+            mv.limit_speed(self.config.max_velocity * r, self.config.max_accel * r);
+        }
+        Ok(())
+    }
+
+    fn homed_axes(&self) -> BitFlags<Axis> {
+        if self.homed {
+            Axis::XYZ
+        } else {
+            BitFlags::empty()
+        }
+    }
+
+    /// Can't unhome one axis on its own, any of them unhomes the lot
+    fn clear_homing_state(&mut self, axes: BitFlags<Axis>) {
+        if !axes.is_empty() {
+            self.homed = false;
+        }
+    }
+
+    /// All three towers at once, whichever axes were asked for
+    fn homing_plan(&self, axes: BitFlags<Axis>) -> Vec<HomeRails> {
+        if (axes & Axis::XYZ).is_empty() {
+            return Vec::new();
+        }
+        vec![HomeRails {
+            axes: Axis::XYZ,
+            rails: vec![0, 1, 2],
+            force_pos: self.homing_start(),
+            home_pos: self.home_position,
+            speeds: self.config.towers[0].homing.clone(),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::itersolve::Step;
+    use super::super::reference;
+    use super::*;
+    use crate::planner::Planner;
+
+    const FREQ: Hertz = Hertz(1_000_000.0);
+    const STEP: f64 = 0.01;
+
+    fn printer() -> (Delta, Planner) {
+        let planner_config = PlannerConfig::new(
+            MillimetersPerSecond(300.0),
+            MillimetersPerSecondSquared(3000.0),
+        );
+        let mut config = DeltaConfig::new(
+            Millimeters(140.0),
+            Millimeters(250.0),
+            Millimeters(300.0),
+            Millimeters(STEP),
+            &planner_config,
+        );
+        config.print_radius = Millimeters(108.0);
+        config.max_z_velocity = MillimetersPerSecond(100.0);
+        let delta = Delta::new(config, FREQ).unwrap();
+        (delta, Planner::new(planner_config))
+    }
+
+    #[test]
+    fn test_geometry() {
+        let (delta, _) = printer();
+        let home = delta.home_position();
+        assert!(
+            (home - Coord::new(0.0, 0.0, 300.0)).norm() < 1e-9,
+            "{home:?}"
+        );
+        assert!(delta.homing_start().z < -300.0);
+        for pos in [
+            Coord::new(0.0, 0.0, 0.0),
+            Coord::new(50.0, -20.0, 10.0),
+            Coord::new(-90.0, 40.0, 150.0),
+        ] {
+            let carriages = delta.config().carriage_positions(pos);
+            let found = delta.config().effector_position(carriages).unwrap();
+            assert!(
+                (found - pos).norm() < 1e-9,
+                "{pos:?} came back as {found:?}"
+            );
+            let stable = delta.config().stable_position(pos);
+            let found = delta.config().position_from_stable(stable).unwrap();
+            assert!((found - pos).norm() < 1e-9);
+        }
+
+        let mut config = delta.config().clone();
+        config.towers[1].arm_length = Millimeters(140.0);
+        assert_eq!(
+            Delta::new(config, FREQ).err(),
+            Some(KinematicsError::ArmTooShort)
+        );
+    }
+
+    #[test]
+    fn test_arms_that_dont_meet() {
+        let (mut delta, _) = printer();
+        delta.set_position(Coord::default(), Axis::XYZ);
+        // one carriage stopped further above the others than its arm reaches
+        let a = delta.steppers[0].position();
+        delta.steppers[0].set_commanded_position(a + Millimeters(500.0));
+        let [a, b, c] = &delta.steppers;
+        let carriages = [a.position(), b.position(), c.position()];
+        assert_eq!(delta.config().effector_position(carriages), None);
+        let pos = delta.calculate_position();
+        assert!(
+            [pos.x, pos.y, pos.z].iter().all(|v| v.is_finite()),
+            "{pos:?}"
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let (mut delta, planner) = printer();
+        let speed = MillimetersPerSecond(100.0);
+        let mut mv = planner.make_move(Coord::new(10.0, 0.0, 0.0), speed);
+        assert!(matches!(
+            delta.check_move(&mut mv),
+            Err(KinematicsError::MustHomeFirst(_))
+        ));
+        delta.set_position(Coord::default(), Axis::XYZ);
+        assert_eq!(delta.homed_axes(), Axis::XYZ);
+        delta.check_move(&mut mv).unwrap();
+
+        for end in [
+            Coord::new(0.0, 109.0, 0.0),
+            Coord::new(0.0, 0.0, -1.0),
+            Coord::new(0.0, 0.0, 301.0),
+            // high up the arms can't reach as far out
+            Coord::new(80.0, 0.0, 280.0),
+        ] {
+            let mut mv = planner.make_move(end, speed);
+            assert_eq!(
+                delta.check_move(&mut mv),
+                Err(KinematicsError::OutOfRange(end))
+            );
+        }
+        let mut mv = planner.make_move(Coord::new(80.0, 0.0, 100.0), speed);
+        delta.check_move(&mut mv).unwrap();
+        let mut mv = planner.make_move(Coord::new(0.0, 0.0, 300.0), speed);
+        delta.check_move(&mut mv).unwrap();
+
+        delta.clear_homing_state(Axis::Z.into());
+        assert!(delta.homed_axes().is_empty());
+    }
+
+    #[test]
+    fn test_slow_zone() {
+        let (mut delta, planner) = printer();
+        delta.set_position(Coord::default(), Axis::XYZ);
+        let speed = MillimetersPerSecond(300.0);
+        let accel_to = |end| {
+            let mut mv = planner.make_move(end, speed);
+            delta.check_move(&mut mv).unwrap();
+            mv.accel
+        };
+        assert_eq!(
+            accel_to(Coord::new(90.0, 0.0, 0.0)),
+            MillimetersPerSecondSquared(3000.0)
+        );
+        assert_eq!(
+            accel_to(Coord::new(0.0, 100.0, 0.0)),
+            MillimetersPerSecondSquared(1500.0)
+        );
+        assert_eq!(
+            accel_to(Coord::new(-107.0, 0.0, 0.0)),
+            MillimetersPerSecondSquared(750.0)
+        );
+    }
+
+    /// Homed at the origin, then a few moves to (-15, 10, 2), and the steps for them
+    fn moved() -> (Delta, Planner, Vec<Vec<Step>>) {
+        let (mut delta, mut planner) = printer();
+        delta.set_position(Coord::default(), Axis::XYZ);
+        for target in [
+            Coord::new(20.0, 0.0, 0.0),
+            Coord::new(20.0, 30.0, 2.0),
+            Coord::new(-15.0, 10.0, 2.0),
+        ] {
+            planner
+                .checked_move(&delta, target, MillimetersPerSecond(150.0))
+                .unwrap();
+        }
+        planner.flush();
+        let steps = delta.generate_steps(planner.trapq(), planner.print_time());
+        (delta, planner, steps)
+    }
+
+    #[test]
+    fn test_against_reference() {
+        let (delta, planner, steps) = moved();
+
+        // steps are counted from wherever the carriages started, not from zero
+        let config = delta.config().clone();
+        let start = config.carriage_positions(Coord::default());
+        for (i, got) in steps.iter().enumerate() {
+            let expected = reference::sampled_steps(
+                planner.trapq(),
+                |c| (config.carriage_positions(c)[i] - start[i]).0,
+                STEP,
+                FREQ,
+            );
+            reference::assert_matches(got, &expected);
+        }
+        let pos = delta.calculate_position();
+        assert!(
+            (pos - Coord::new(-15.0, 10.0, 2.0)).norm() < 2.0 * STEP,
+            "{pos:?}"
+        );
+    }
+
+    /// Needs klipper's C, built with the `klipper-steps` feature
+    #[cfg(feature = "klipper-steps")]
+    #[test]
+    fn test_against_klipper() {
+        use super::super::klipper;
+        use crate::ffi::generated::delta_stepper_alloc;
+
+        let (delta, planner, steps) = moved();
+        let end = planner.print_time();
+        let config = delta.config();
+        let towers = config.tower_positions();
+        for (i, got) in steps.iter().enumerate() {
+            let arm2 = config.towers[i].arm_length.0.powi(2);
+            let sk = unsafe { delta_stepper_alloc(arm2, towers[i].x, towers[i].y) };
+            let expected = klipper::steps(sk, planner.trapq(), STEP, Coord::default(), end, FREQ);
+            reference::assert_matches(got, &expected);
+        }
+    }
+}
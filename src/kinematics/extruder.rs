@@ -0,0 +1,500 @@
+//! Extruder kinematics, klipper's `extruder.py` and `kin_extruder.c`
+//!
+//! The extruder stepper follows a trapq of its own, which the planner fills with the
+//! filament's share of each toolhead move. Pressure advance pushes filament ahead of
+//! where it would otherwise be, in proportion to how fast it's going, and averages that
+//! over a short window so the stepper never has to jump
+
+use std::f64::consts::PI;
+
+use super::itersolve::{CalcPosition, MoveCursor, Step};
+use super::{Axis, KinematicsError, Stepper};
+use crate::planner::{Move, PlannerConfig};
+use crate::trapq::{self, Coord, TrapQ};
+use crate::units::{
+    Hertz, Millimeters, MillimetersPerSecond, MillimetersPerSecondSquared, Seconds,
+};
+
+/// Longest `pressure_advance_smooth_time` allowed
+const MAX_SMOOTH_TIME: Seconds = Seconds(0.200);
+
+/// What klipper has in an `[extruder]` section, less the heater
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtruderConfig {
+    /// Filament pushed per step
+    pub step_distance: Millimeters,
+    pub nozzle_diameter: Millimeters,
+    pub filament_diameter: Millimeters,
+    /// Widest line that can be printed, in mm²
+    pub max_extrude_cross_section: f64,
+    pub max_extrude_only_distance: Millimeters,
+    pub max_extrude_only_velocity: MillimetersPerSecond,
+    pub max_extrude_only_accel: MillimetersPerSecondSquared,
+    /// Extra filament pushed per mm/s of extrusion speed
+    pub pressure_advance: Seconds,
+    pub pressure_advance_smooth_time: Seconds,
+}
+
+impl ExtruderConfig {
+    /// Klipper's defaults for everything else, extrude-only moves going as fast as the
+    /// widest allowed line would at the toolhead's limits
+    pub fn new(
+        step_distance: Millimeters,
+        nozzle_diameter: Millimeters,
+        filament_diameter: Millimeters,
+        planner: &PlannerConfig,
+    ) -> Self {
+        let max_extrude_cross_section = 4.0 * nozzle_diameter.0.powi(2);
+        let max_extrude_ratio = max_extrude_cross_section / filament_area(filament_diameter);
+        Self {
+            step_distance,
+            nozzle_diameter,
+            filament_diameter,
+            max_extrude_cross_section,
+            max_extrude_only_distance: Millimeters(50.0),
+            max_extrude_only_velocity: planner.max_velocity * max_extrude_ratio,
+            max_extrude_only_accel: planner.max_accel * max_extrude_ratio,
+            pressure_advance: Seconds::ZERO,
+            pressure_advance_smooth_time: Seconds(0.040),
+        }
+    }
+
+    /// Filament per mm of toolhead travel that makes the widest allowed line
+    fn max_extrude_ratio(&self) -> f64 {
+        self.max_extrude_cross_section / filament_area(self.filament_diameter)
+    }
+}
+
+fn filament_area(diameter: Millimeters) -> f64 {
+    PI * (diameter.0 * 0.5).powi(2)
+}
+
+pub struct Extruder {
+    config: ExtruderConfig,
+    stepper: Stepper,
+}
+
+impl Extruder {
+    pub fn new(config: ExtruderConfig, clock_freq: Hertz) -> Result<Self, KinematicsError> {
+        let stepper = Stepper::new(
+            config.step_distance,
+            clock_freq,
+            Axis::E.into(),
+            Box::new(|m, t| Millimeters(m.coord(t).x)),
+        );
+        let mut extruder = Self { config, stepper };
+        extruder.set_pressure_advance(
+            extruder.config.pressure_advance,
+            extruder.config.pressure_advance_smooth_time,
+        )?;
+        Ok(extruder)
+    }
+
+    pub fn config(&self) -> &ExtruderConfig {
+        &self.config
+    }
+
+    pub fn stepper(&self) -> &Stepper {
+        &self.stepper
+    }
+
+    pub fn stepper_mut(&mut self) -> &mut Stepper {
+        &mut self.stepper
+    }
+
+    /// `SET_PRESSURE_ADVANCE`. Steps already generated keep the old settings
+    pub fn set_pressure_advance(
+        &mut self,
+        pressure_advance: Seconds,
+        smooth_time: Seconds,
+    ) -> Result<(), KinematicsError> {
+        if pressure_advance < Seconds::ZERO
+            || smooth_time < Seconds::ZERO
+            || smooth_time > MAX_SMOOTH_TIME
+        {
+            return Err(KinematicsError::InvalidPressureAdvance {
+                pressure_advance,
+                smooth_time,
+            });
+        }
+        self.config.pressure_advance = pressure_advance;
+        self.config.pressure_advance_smooth_time = smooth_time;
+        // no advance means nothing to smooth either
+        let half_smooth_time = if pressure_advance == Seconds::ZERO {
+            Seconds::ZERO
+        } else {
+            smooth_time * 0.5
+        };
+        self.stepper.calc_position = calc_position(pressure_advance, half_smooth_time);
+        // the averaging window reaches this far either side of every move
+        self.stepper.set_leading_steps(half_smooth_time);
+        self.stepper.set_trailing_steps(half_smooth_time);
+        Ok(())
+    }
+
+    /// Declare the filament to be at `e`
+    pub fn set_position(&mut self, e: Millimeters) {
+        self.stepper.set_position(Coord::new(e.0, 0.0, 0.0));
+    }
+
+    /// Filament position the stepper has been commanded to, pressure advance and all
+    pub fn position(&self) -> Millimeters {
+        self.stepper.position()
+    }
+
+    /// Reject moves the extruder can't make, and slow down extrude-only moves to what
+    /// the extruder can manage. `can_extrude` is whether the hotend is up to temperature
+    pub fn check_move(&self, mv: &mut Move, can_extrude: bool) -> Result<(), KinematicsError> {
+        if mv.end_e == mv.start_e {
+            return Ok(());
+        }
+        if !can_extrude {
+            return Err(KinematicsError::ColdExtrude);
+        }
+        let e_d = mv.end_e - mv.start_e;
+        let max_extrude_ratio = self.config.max_extrude_ratio();
+        if (mv.axes_r.x == 0.0 && mv.axes_r.y == 0.0) || mv.extrude_r < 0.0 {
+            // extrude-only or retraction, limited by the extruder itself
+            if e_d.abs() > self.config.max_extrude_only_distance {
+                return Err(KinematicsError::ExtrudeOnlyTooLong {
+                    distance: e_d,
+                    max: self.config.max_extrude_only_distance,
+                });
+            }
+            let inv_extrude_r = 1.0 / mv.extrude_r.abs();
+            mv.limit_speed(
+                self.config.max_extrude_only_velocity * inv_extrude_r,
+                self.config.max_extrude_only_accel * inv_extrude_r,
+            );
+        } else if mv.extrude_r > max_extrude_ratio {
+            // a tiny blob is fine, e.g. priming after a retraction
+            if e_d <= self.config.nozzle_diameter * max_extrude_ratio {
+                return Ok(());
+            }
+            let area = filament_area(self.config.filament_diameter);
+            return Err(KinematicsError::Overextrude {
+                area: mv.extrude_r * area,
+                max: self.config.max_extrude_cross_section,
+            });
+        }
+        Ok(())
+    }
+
+    /// Steps up to `flush_time`, from the planner's extruder trapq
+    pub fn generate_steps(&mut self, trapq: &TrapQ, flush_time: Seconds) -> Vec<Step> {
+        self.stepper.generate_steps(trapq, flush_time)
+    }
+}
+
+/// Filament position along an extruder trapq, averaging the advanced position over
+/// `half_smooth_time` either side with a triangular weighting
+fn calc_position(pressure_advance: Seconds, half_smooth_time: Seconds) -> CalcPosition {
+    if half_smooth_time == Seconds::ZERO {
+        return Box::new(|m, t| Millimeters(m.coord(t).x));
+    }
+    let (pa, hst) = (pressure_advance.0, half_smooth_time.0);
+    Box::new(move |m, t| Millimeters(pa_range_integrate(m, t.0, pa, hst) / (hst * hst)))
+}
+
+/// `∫ (s - offset)·(base + v·s + ha·s²) ds` from `start` to `end`
+fn weighted_integral(base: f64, v: f64, ha: f64, start: f64, end: f64, offset: f64) -> f64 {
+    let antiderivative = |s: f64| {
+        let weighted = s * s * (0.5 * base + s * (v / 3.0 + s * 0.25 * ha));
+        let plain = s * (base + s * (0.5 * v + s * ha / 3.0));
+        weighted - offset * plain
+    };
+    antiderivative(end) - antiderivative(start)
+}
+
+/// The weighted integral over the part of one move between `start` and `end`, with the
+/// advance added on if the move allows it
+fn pa_move_integrate(m: &trapq::Move, pa: f64, start: f64, end: f64, offset: f64) -> f64 {
+    let (start, end) = (start.max(0.0), end.min(m.move_t.0));
+    let (mut base, mut v, ha) = (m.start_pos.x, m.start_v.0, m.half_accel.0);
+    if m.axes_r.y != 0.0 {
+        base += pa * v;
+        v += pa * 2.0 * ha;
+    }
+    weighted_integral(base, v, ha, start, end, offset)
+}
+
+/// Triangle-weighted integral of the advanced position from `move_time - hst` to
+/// `move_time + hst`, spilling over into neighbouring moves. Past either end of the
+/// trapq the filament is taken to be sitting still
+fn pa_range_integrate(cursor: MoveCursor<'_>, move_time: f64, pa: f64, hst: f64) -> f64 {
+    let m = cursor.get();
+    let (mut start, mut end) = (move_time - hst, move_time + hst);
+    let mut res = pa_move_integrate(m, pa, start, move_time, start);
+    res -= pa_move_integrate(m, pa, move_time, end, end);
+
+    let mut prev = cursor;
+    while start < 0.0 {
+        match prev.prev() {
+            Some(p) => {
+                prev = p;
+                start += p.get().move_t.0;
+                res += pa_move_integrate(p.get(), pa, start, p.get().move_t.0, start);
+            }
+            None => {
+                let first = prev.get().start_pos.x;
+                res += weighted_integral(first, 0.0, 0.0, start, 0.0, start);
+                break;
+            }
+        }
+    }
+    let mut next = cursor;
+    while end > next.get().move_t.0 {
+        end -= next.get().move_t.0;
+        match next.next() {
+            Some(n) => {
+                next = n;
+                res -= pa_move_integrate(n.get(), pa, 0.0, end, end);
+            }
+            None => {
+                let last = next.coord(next.get().move_t).x;
+                res -= weighted_integral(last, 0.0, 0.0, 0.0, end, end);
+                break;
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::reference;
+    use super::*;
+    use crate::planner::{MoveChecks, Planner};
+    use crate::testutils::FakePrinter;
+
+    const FREQ: Hertz = Hertz(1_000_000.0);
+    const STEP: f64 = 0.002;
+
+    fn printer() -> (Extruder, Planner) {
+        let planner_config = PlannerConfig::new(
+            MillimetersPerSecond(300.0),
+            MillimetersPerSecondSquared(3000.0),
+        );
+        let config = ExtruderConfig::new(
+            Millimeters(STEP),
+            Millimeters(0.4),
+            Millimeters(1.75),
+            &planner_config,
+        );
+        (
+            Extruder::new(config, FREQ).unwrap(),
+            Planner::new(planner_config),
+        )
+    }
+
+    #[test]
+    fn test_defaults() {
+        let (extruder, _) = printer();
+        let config = extruder.config();
+        assert!((config.max_extrude_cross_section - 0.64).abs() < 1e-12);
+        // 0.64mm² out of 2.405mm² of filament
+        let ratio = 0.64 / filament_area(Millimeters(1.75));
+        assert!((config.max_extrude_only_velocity.0 - 300.0 * ratio).abs() < 1e-9);
+
+        let (mut extruder, _) = printer();
+        assert!(extruder
+            .set_pressure_advance(Seconds(0.05), Seconds(0.3))
+            .is_err());
+        assert!(extruder
+            .set_pressure_advance(Seconds(-0.01), Seconds(0.04))
+            .is_err());
+        extruder
+            .set_pressure_advance(Seconds(0.05), Seconds(0.04))
+            .unwrap();
+        assert_eq!(extruder.config().pressure_advance, Seconds(0.05));
+    }
+
+    #[test]
+    fn test_check_move() {
+        let (extruder, planner) = printer();
+        let speed = MillimetersPerSecond(100.0);
+        let printing =
+            |e| planner.make_extrude_move(Coord::new(50.0, 0.0, 0.0), Millimeters(e), speed);
+
+        let mut mv = printing(2.0);
+        assert_eq!(
+            extruder.check_move(&mut mv, false),
+            Err(KinematicsError::ColdExtrude)
+        );
+        extruder.check_move(&mut mv, true).unwrap();
+        assert_eq!(mv.accel, MillimetersPerSecondSquared(3000.0));
+        // travel doesn't care how cold it is
+        let mut mv = printing(0.0);
+        extruder.check_move(&mut mv, false).unwrap();
+
+        // 10mm of filament over 50mm is about a 0.48mm² line
+        let mut mv = printing(10.0);
+        extruder.check_move(&mut mv, true).unwrap();
+        let mut mv = printing(15.0);
+        assert!(matches!(
+            extruder.check_move(&mut mv, true),
+            Err(KinematicsError::Overextrude { .. })
+        ));
+        // but a tiny blob is fine, however thick
+        let mut mv = planner.make_extrude_move(Coord::new(0.1, 0.0, 0.0), Millimeters(0.05), speed);
+        extruder.check_move(&mut mv, true).unwrap();
+
+        let mut mv = planner.make_extrude_move(Coord::default(), Millimeters(51.0), speed);
+        assert!(!mv.is_kinematic);
+        assert_eq!(
+            extruder.check_move(&mut mv, true),
+            Err(KinematicsError::ExtrudeOnlyTooLong {
+                distance: Millimeters(51.0),
+                max: Millimeters(50.0),
+            })
+        );
+        let mut mv = planner.make_extrude_move(Coord::default(), Millimeters(-5.0), speed);
+        extruder.check_move(&mut mv, true).unwrap();
+        assert_eq!(mv.accel, extruder.config().max_extrude_only_accel);
+        assert!(
+            (mv.min_move_t - Millimeters(5.0) / extruder.config().max_extrude_only_velocity).abs()
+                < Seconds(1e-12)
+        );
+    }
+
+    #[test]
+    fn test_checked_by_the_planner() {
+        let mut printer = FakePrinter::new(1);
+        let origin = Coord::default();
+        printer.ctx().set_position(origin, Axis::XYZ).unwrap();
+        let mut checks = MoveChecks {
+            kin: &printer.kin,
+            extruder: &printer.extruder,
+            can_extrude: false,
+        };
+        let planner = &mut printer.planner;
+        let speed = MillimetersPerSecond(100.0);
+        let to = Coord::new(50.0, 0.0, 0.0);
+        assert_eq!(
+            planner.checked_extrude_move(&checks, to, Millimeters(2.0), speed),
+            Err(KinematicsError::ColdExtrude)
+        );
+        assert_eq!(planner.position(), origin);
+        assert_eq!(planner.e_position(), Millimeters::ZERO);
+
+        checks.can_extrude = true;
+        planner
+            .checked_extrude_move(&checks, to, Millimeters(2.0), speed)
+            .unwrap();
+        assert_eq!(planner.e_position(), Millimeters(2.0));
+        assert!(matches!(
+            planner.checked_extrude_move(&checks, to, Millimeters(60.0), speed),
+            Err(KinematicsError::ExtrudeOnlyTooLong { .. })
+        ));
+        // the kinematics still get their say
+        assert!(matches!(
+            planner.checked_extrude_move(
+                &checks,
+                Coord::new(500.0, 0.0, 0.0),
+                Millimeters(2.0),
+                speed
+            ),
+            Err(KinematicsError::OutOfRange(_))
+        ));
+        assert_eq!(planner.position(), to);
+    }
+
+    #[test]
+    fn test_steps_without_pressure_advance() {
+        let (mut extruder, mut planner) = printer();
+        let speed = MillimetersPerSecond(100.0);
+        planner.set_e_position(Millimeters::ZERO);
+        let mut mv = planner.make_extrude_move(Coord::new(40.0, 0.0, 0.0), Millimeters(2.0), speed);
+        extruder.check_move(&mut mv, true).unwrap();
+        planner.add_move(mv);
+        let mut mv = planner.make_extrude_move(Coord::new(40.0, 0.0, 0.0), Millimeters(1.0), speed);
+        extruder.check_move(&mut mv, true).unwrap();
+        planner.add_move(mv);
+        planner.flush();
+
+        let trapq = planner.extruder_trapq();
+        let steps = extruder.generate_steps(trapq, planner.print_time());
+        let expected = reference::sampled_steps(trapq, |c| c.x, STEP, FREQ);
+        reference::assert_matches(&steps, &expected);
+        assert_eq!(steps.iter().filter(|s| !s.dir).count(), 500);
+        assert!((extruder.position() - Millimeters(1.0)).abs() < Millimeters(STEP));
+    }
+
+    #[test]
+    fn test_pressure_advance() {
+        let (mut extruder, mut planner) = printer();
+        extruder
+            .set_pressure_advance(Seconds(0.05), Seconds(0.04))
+            .unwrap();
+        // 100mm at 100mm/s, extruding 5mm so cruising at 5mm/s of filament
+        let mv = planner.make_extrude_move(
+            Coord::new(100.0, 0.0, 0.0),
+            Millimeters(5.0),
+            MillimetersPerSecond(100.0),
+        );
+        planner.add_move(mv);
+        planner.flush();
+        let moves: Vec<_> = planner.extruder_trapq().moves().copied().collect();
+        let cruise = moves.iter().position(|m| m.half_accel.0 == 0.0).unwrap();
+        let cursor = MoveCursor::new(&moves, cruise);
+        let calc = calc_position(Seconds(0.05), Seconds(0.02));
+
+        // while cruising it's simply ahead by the advance
+        let t = Seconds(0.3);
+        let plain = cursor.coord(t).x;
+        let advanced = calc(cursor, t).0;
+        assert!(
+            (advanced - (plain + 0.05 * 5.0)).abs() < 1e-9,
+            "{advanced} vs {plain}"
+        );
+
+        let steps = extruder.generate_steps(
+            planner.extruder_trapq(),
+            planner.print_time() + Seconds(0.1),
+        );
+        let forward = steps.iter().filter(|s| s.dir).count();
+        let backward = steps.len() - forward;
+        // pushes out more than it needs then pulls the extra back in at the end,
+        // finishing off after the move itself has
+        assert!(backward > 0);
+        assert_eq!(forward - backward, 2500);
+        assert!((extruder.position() - Millimeters(5.0)).abs() < Millimeters(STEP));
+    }
+
+    /// Needs klipper's C, built with the `klipper-steps` feature
+    #[cfg(feature = "klipper-steps")]
+    #[test]
+    fn test_against_klipper() {
+        use super::super::klipper;
+        use crate::ffi::generated::{extruder_set_pressure_advance, extruder_stepper_alloc};
+
+        // printing, a retraction, then printing again
+        for (pressure_advance, smooth_time) in [(0.0, 0.0), (0.05, 0.04)] {
+            let (mut extruder, mut planner) = printer();
+            extruder
+                .set_pressure_advance(Seconds(pressure_advance), Seconds(smooth_time))
+                .unwrap();
+            let speed = MillimetersPerSecond(100.0);
+            for (x, e) in [(40.0, 2.0), (40.0, 1.2), (90.0, 3.5)] {
+                let mut mv =
+                    planner.make_extrude_move(Coord::new(x, 0.0, 0.0), Millimeters(e), speed);
+                extruder.check_move(&mut mv, true).unwrap();
+                planner.add_move(mv);
+            }
+            planner.flush();
+            // far enough past the end for any smoothing to finish
+            let end = planner.print_time() + Seconds(0.1);
+            let trapq = planner.extruder_trapq();
+            let steps = extruder.generate_steps(trapq, end);
+
+            let sk = unsafe {
+                let sk = extruder_stepper_alloc();
+                // from the start of time, before any of the moves
+                extruder_set_pressure_advance(sk, 0.0, pressure_advance, smooth_time);
+                sk
+            };
+            let expected = klipper::steps(sk, trapq, STEP, Coord::default(), end, FREQ);
+            reference::assert_matches(&steps, &expected);
+        }
+    }
+}
@@ -0,0 +1,450 @@
+//! Iterative step time solver, a port of klipper's `itersolve.c`
+//!
+//! A step is due whenever the stepper's position crosses the halfway point to the next
+//! full step. Position is some kinematics-specific function of time along the trapq, so
+//! each crossing is found numerically: secant method, falling back to bisection once the
+//! step is bracketed
+
+use super::Stepper;
+use crate::trapq::{Coord, Move, TrapQ};
+use crate::units::{Millimeters, Seconds, Ticks};
+
+/// How far past the previous step to look for the next one, when there's nothing better
+const SEEK_TIME_RESET: Seconds = Seconds(0.000_100);
+/// Close enough to the target position to call it a step
+const POSITION_TOLERANCE: Millimeters = Millimeters(0.000_000_001);
+/// Close enough in time to call it a step
+const TIME_TOLERANCE: Seconds = Seconds(0.000_000_001);
+
+/// Moves in order, in however many pieces they're stored: the trapq's two halves and the
+/// tail sentinel
+#[derive(Clone, Copy)]
+struct Moves<'a>([&'a [Move]; 3]);
+
+impl<'a> Moves<'a> {
+    fn get(&self, mut index: usize) -> Option<&'a Move> {
+        for part in self.0 {
+            if index < part.len() {
+                return Some(&part[index]);
+            }
+            index -= part.len();
+        }
+        None
+    }
+}
+
+/// A move on the trapq, with a way to peek at its neighbours
+#[derive(Clone, Copy)]
+pub struct MoveCursor<'a> {
+    moves: Moves<'a>,
+    index: usize,
+}
+
+impl<'a> MoveCursor<'a> {
+    pub fn new(moves: &'a [Move], index: usize) -> Self {
+        Self {
+            moves: Moves([moves, &[], &[]]),
+            index,
+        }
+    }
+
+    pub fn get(&self) -> &'a Move {
+        self.moves.get(self.index).unwrap()
+    }
+
+    pub fn prev(&self) -> Option<Self> {
+        let index = self.index.checked_sub(1)?;
+        Some(Self { index, ..*self })
+    }
+
+    pub fn next(&self) -> Option<Self> {
+        let index = self.index + 1;
+        self.moves.get(index).map(|_| Self { index, ..*self })
+    }
+
+    /// Toolhead position `move_time` into the move
+    pub fn coord(&self, move_time: Seconds) -> Coord {
+        self.get().get_coord(move_time)
+    }
+}
+
+/// Stepper position `move_time` into a move, klipper's `calc_position_cb`
+pub type CalcPosition = Box<dyn Fn(MoveCursor<'_>, Seconds) -> Millimeters>;
+
+/// A single step, ready for step compression
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Step {
+    pub clock: Ticks,
+    /// `true` steps in the positive direction
+    pub dir: bool,
+}
+
+/// `distance` in the direction of `dir`
+fn toward(dir: bool, distance: Millimeters) -> Millimeters {
+    if dir {
+        distance
+    } else {
+        -distance
+    }
+}
+
+#[derive(Clone, Copy)]
+struct TimePos {
+    time: Seconds,
+    position: Millimeters,
+}
+
+impl Stepper {
+    /// Whether the move goes along any axis this stepper cares about
+    fn check_active(&self, m: &Move) -> bool {
+        self.active.iter().any(|axis| axis.of(m.axes_r) != 0.0)
+    }
+
+    /// Where the stepper is with the toolhead at `pos`
+    pub fn calc_position_from_coord(&self, pos: Coord) -> Millimeters {
+        let still = [Move::null(Seconds::ZERO, Seconds::ZERO, pos)];
+        (self.calc_position)(MoveCursor::new(&still, 0), Seconds::ZERO)
+    }
+
+    /// Set the commanded position to wherever the stepper is with the toolhead at `pos`
+    pub fn set_position(&mut self, pos: Coord) {
+        self.position = self.calc_position_from_coord(pos);
+    }
+
+    /// Set the commanded position directly, e.g. to where homing found the stepper stopped
+    pub fn set_commanded_position(&mut self, position: Millimeters) {
+        self.position = position;
+    }
+
+    /// Find every step between `abs_start` and `abs_end` during one move
+    fn gen_steps_range(
+        &mut self,
+        cursor: MoveCursor<'_>,
+        abs_start: Seconds,
+        abs_end: Seconds,
+        steps: &mut Vec<Step>,
+    ) {
+        let m = cursor.get();
+        let half_step = self.step_distance * 0.5;
+        let start = (abs_start - m.print_time).max(Seconds::ZERO);
+        let end = (abs_end - m.print_time).min(m.move_t);
+        let mut old_guess = TimePos {
+            time: start,
+            position: self.position,
+        };
+        let mut guess = old_guess;
+        let mut sdir = self.step_dir;
+        let (mut is_dir_change, mut have_bracket, mut check_oscillate) = (false, false, false);
+        let mut target = self.position + toward(sdir, half_step);
+        let mut last_time = start;
+        let mut low_time = start;
+        let mut high_time = (start + SEEK_TIME_RESET).min(end);
+        loop {
+            // secant method guess from the last two positions
+            let guess_dist = (guess.position - target).0;
+            let og_dist = (old_guess.position - target).0;
+            let mut next_time = Seconds(
+                (old_guess.time.0 * guess_dist - guess.time.0 * og_dist) / (guess_dist - og_dist),
+            );
+            // written this way round so NaN fails too
+            if !(next_time > low_time && next_time < high_time) {
+                if have_bracket {
+                    // bad guess, bisect instead
+                    next_time = (low_time + high_time) * 0.5;
+                    check_oscillate = false;
+                } else if guess.time >= end {
+                    // no more steps in this range
+                    break;
+                } else {
+                    // maybe a bad guess, search exponentially
+                    next_time = high_time;
+                    high_time = (high_time * 2.0 - last_time).min(end);
+                }
+            }
+
+            old_guess = guess;
+            guess = TimePos {
+                time: next_time,
+                position: self.shaped_position(cursor, next_time),
+            };
+            let guess_dist = guess.position - target;
+            if guess_dist.abs() > POSITION_TOLERANCE {
+                let rel_dist = toward(sdir, guess_dist);
+                if rel_dist > Millimeters::ZERO {
+                    // past the target, so there's definitely a step in here
+                    if have_bracket && old_guess.time <= low_time {
+                        if check_oscillate {
+                            // force a bisection to stop the secant method flip-flopping
+                            old_guess = guess;
+                        }
+                        check_oscillate = true;
+                    }
+                    high_time = guess.time;
+                    have_bracket = true;
+                } else if rel_dist < -(half_step * 2.0 + POSITION_TOLERANCE * 10.0) {
+                    // went back a whole step, the stepper changed direction
+                    sdir = !sdir;
+                    target += toward(sdir, self.step_distance);
+                    low_time = last_time;
+                    high_time = guess.time;
+                    is_dir_change = true;
+                    have_bracket = true;
+                    check_oscillate = false;
+                } else {
+                    low_time = guess.time;
+                }
+                if !have_bracket || high_time - low_time > TIME_TOLERANCE {
+                    continue;
+                }
+            }
+
+            // found the step
+            steps.push(Step {
+                clock: (m.print_time + guess.time).to_ticks(self.clock_freq),
+                dir: sdir,
+            });
+            target += toward(sdir, self.step_distance);
+            let mut seek_time_delta = ((guess.time - last_time) * 1.5).max(TIME_TOLERANCE);
+            if is_dir_change {
+                seek_time_delta = seek_time_delta.min(SEEK_TIME_RESET);
+            }
+            last_time = guess.time;
+            low_time = guess.time;
+            high_time = (guess.time + seek_time_delta).min(end);
+            is_dir_change = false;
+            have_bracket = false;
+            check_oscillate = false;
+        }
+        self.step_dir = sdir;
+        self.position = target - toward(sdir, half_step);
+    }
+
+    /// When this stepper next has something to do, if it's before `flush_time`
+    pub fn next_activity(&self, trapq: &TrapQ, flush_time: Seconds) -> Option<Seconds> {
+        if !self.following {
+            return None;
+        }
+        trapq
+            .moves()
+            .skip_while(|m| self.last_flushed >= m.end_time())
+            .take_while(|m| m.print_time < flush_time)
+            .find(|m| self.check_active(m))
+            .map(|m| m.print_time)
+    }
+
+    /// Steps for every move on the trapq from the last flush up to `flush_time`,
+    /// including any leading/trailing steps around moves that don't involve this stepper
+    pub fn generate_steps(&mut self, trapq: &TrapQ, flush_time: Seconds) -> Vec<Step> {
+        let mut steps = Vec::new();
+        let last_flush_time = std::mem::replace(&mut self.last_flushed, flush_time);
+        if !self.following {
+            return steps;
+        }
+        let (front, back) = trapq.move_slices();
+        let Some(last) = back.last().or(front.last()) else {
+            return steps;
+        };
+        // klipper's tail sentinel: sit still after the last move, so there's somewhere
+        // for trailing steps to go
+        let tail = Move::null(
+            last.end_time(),
+            Seconds(f64::MAX),
+            last.get_coord(last.move_t),
+        );
+        let moves = Moves([front, back, std::slice::from_ref(&tail)]);
+        let cursor = |index| MoveCursor { moves, index };
+        // there's always the sentinel, even when everything else has been flushed
+        let mut i = trapq
+            .moves()
+            .position(|m| last_flush_time < m.end_time())
+            .unwrap_or(front.len() + back.len());
+
+        let (leading_steps, trailing_steps) = (self.leading_time(), self.trailing_time());
+        let mut force_steps_time = self.last_moved + trailing_steps;
+        let mut skip_count = 0;
+        while let Some(m) = moves.get(i) {
+            let (move_start, move_end) = (m.print_time, m.end_time());
+            if self.check_active(m) {
+                self.enabled = true;
+                if skip_count > 0 && leading_steps > Seconds::ZERO {
+                    // catch up on the moves leading up to activity
+                    let abs_start = (move_start - leading_steps)
+                        .max(last_flush_time)
+                        .max(force_steps_time);
+                    let mut pm = i - 1;
+                    loop {
+                        skip_count -= 1;
+                        if skip_count == 0 || cursor(pm).get().print_time <= abs_start {
+                            break;
+                        }
+                        pm -= 1;
+                    }
+                    for j in pm..i {
+                        self.gen_steps_range(cursor(j), abs_start, flush_time, &mut steps);
+                    }
+                }
+                self.gen_steps_range(cursor(i), last_flush_time, flush_time, &mut steps);
+                if move_end >= flush_time {
+                    self.last_moved = flush_time;
+                    return steps;
+                }
+                skip_count = 0;
+                self.last_moved = move_end;
+                force_steps_time = self.last_moved + trailing_steps;
+            } else {
+                if move_start < force_steps_time {
+                    // still winding down from activity
+                    let abs_end = force_steps_time.min(flush_time);
+                    self.gen_steps_range(cursor(i), last_flush_time, abs_end, &mut steps);
+                    skip_count = 1;
+                } else {
+                    skip_count += 1;
+                }
+                if flush_time + leading_steps <= move_end {
+                    return steps;
+                }
+            }
+            i += 1;
+        }
+        steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kinematics::Axis;
+    use crate::planner::{Planner, PlannerConfig};
+    use crate::units::{Hertz, MillimetersPerSecond, MillimetersPerSecondSquared};
+    use enumflags2::BitFlags;
+
+    const FREQ: Hertz = Hertz(1_000_000.0);
+
+    fn x_stepper() -> Stepper {
+        Stepper::new(
+            Millimeters(0.01),
+            FREQ,
+            Axis::X.into(),
+            Box::new(|m, t| Millimeters(m.coord(t).x)),
+        )
+    }
+
+    fn planner() -> Planner {
+        Planner::new(PlannerConfig::new(
+            MillimetersPerSecond(100.0),
+            MillimetersPerSecondSquared(1000.0),
+        ))
+    }
+
+    #[test]
+    fn test_cursor_across_pieces() {
+        let at = |t| Move::null(Seconds(t), Seconds(1.0), Coord::default());
+        let (front, back, tail) = ([at(0.0)], [at(1.0), at(2.0)], [at(3.0)]);
+        let first = MoveCursor {
+            moves: Moves([&front, &back, &tail]),
+            index: 0,
+        };
+        let forward: Vec<_> = std::iter::successors(Some(first), MoveCursor::next).collect();
+        let times: Vec<_> = forward.iter().map(|c| c.get().print_time.0).collect();
+        assert_eq!(times, [0.0, 1.0, 2.0, 3.0]);
+        let last = *forward.last().unwrap();
+        let back: Vec<_> = std::iter::successors(Some(last), MoveCursor::prev).collect();
+        assert_eq!(back.len(), 4);
+        assert_eq!(back[3].get().print_time, Seconds(0.0));
+    }
+
+    #[test]
+    fn test_steps_land_on_half_steps() {
+        let mut p = planner();
+        p.move_to(Coord::new(10.0, 0.0, 0.0), MillimetersPerSecond(100.0));
+        p.flush();
+        let mut stepper = x_stepper();
+        let steps = stepper.generate_steps(p.trapq(), p.print_time());
+
+        assert_eq!(steps.len(), 1000);
+        assert!(steps.iter().all(|s| s.dir));
+        assert!(steps.windows(2).all(|w| w[0].clock <= w[1].clock));
+        for (k, step) in steps.iter().enumerate() {
+            let x = p
+                .trapq()
+                .position_at(step.clock.to_seconds(FREQ))
+                .unwrap()
+                .x;
+            // a clock tick is 0.1um at full speed
+            assert!(
+                (x - (k as f64 + 0.5) * 0.01).abs() < 2e-4,
+                "step {k} at {x}"
+            );
+        }
+        assert!((stepper.position() - Millimeters(10.0)).abs() < Millimeters(1e-9));
+    }
+
+    #[test]
+    fn test_direction_change_and_split_flushes() {
+        let mut p = planner();
+        p.move_to(Coord::new(1.0, 0.0, 0.0), MillimetersPerSecond(50.0));
+        p.move_to(Coord::new(0.5, 0.0, 0.0), MillimetersPerSecond(50.0));
+        p.flush();
+        let end = p.print_time();
+
+        let mut whole = x_stepper();
+        let all = whole.generate_steps(p.trapq(), end);
+        assert_eq!(all.iter().filter(|s| s.dir).count(), 100);
+        assert_eq!(all.iter().filter(|s| !s.dir).count(), 50);
+        assert!((whole.position() - Millimeters(0.5)).abs() < Millimeters(1e-9));
+
+        let mut pieces = x_stepper();
+        let mut split = Vec::new();
+        for n in 1..=10 {
+            split.extend(pieces.generate_steps(p.trapq(), end * (n as f64 / 10.0)));
+        }
+        assert_eq!(split, all);
+    }
+
+    #[test]
+    fn test_leading_and_trailing_steps() {
+        // a stepper that moves with Y, but only counts X moves as activity
+        let stepper = || {
+            Stepper::new(
+                Millimeters(0.01),
+                FREQ,
+                BitFlags::from(Axis::X),
+                Box::new(|m, t| {
+                    let c = m.coord(t);
+                    Millimeters(c.x + c.y)
+                }),
+            )
+        };
+        let mut p = planner();
+        p.move_to(Coord::new(1.0, 0.0, 0.0), MillimetersPerSecond(50.0));
+        p.flush();
+        let x_done = p.print_time();
+        p.move_to(Coord::new(1.0, 1.0, 0.0), MillimetersPerSecond(50.0));
+        p.flush();
+        let y_done = p.print_time();
+        p.move_to(Coord::new(2.0, 1.0, 0.0), MillimetersPerSecond(50.0));
+        p.flush();
+        let end = p.print_time();
+        let during_y = |steps: &[Step]| {
+            steps
+                .iter()
+                .filter(|s| s.clock > x_done.to_ticks(FREQ) && s.clock < y_done.to_ticks(FREQ))
+                .count()
+        };
+
+        let mut plain = stepper();
+        let steps = plain.generate_steps(p.trapq(), end);
+        assert_eq!(during_y(&steps), 0);
+
+        let mut leading = stepper();
+        leading.set_leading_steps(Seconds(1.0));
+        let steps = leading.generate_steps(p.trapq(), end);
+        assert_eq!(during_y(&steps), 100);
+
+        let mut trailing = stepper();
+        trailing.set_trailing_steps(Seconds(1.0));
+        let steps = trailing.generate_steps(p.trapq(), end);
+        assert_eq!(during_y(&steps), 100);
+        assert_eq!(steps.len(), 300);
+    }
+}
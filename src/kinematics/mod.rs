@@ -0,0 +1,663 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::mcu::{Command, Mcu, Oid, Response};
+use crate::planner::Move;
+use crate::stepcompress::{StepCompressError, StepCompressor};
+use crate::trapq::{Coord, TrapQ};
+use crate::units::{
+    Hertz, Millimeters, MillimetersPerSecond, MillimetersPerSecondSquared, Seconds, Ticks,
+};
+use enumflags2::{make_bitflags, BitFlags};
+
+pub mod cartesian;
+pub mod corexy;
+pub mod corexz;
+pub mod delta;
+pub mod extruder;
+pub mod itersolve;
+pub mod shaper;
+pub mod winch;
+
+use itersolve::{CalcPosition, Step};
+use shaper::ShaperPulses;
+
+/// Known travelling axes
+#[enumflags2::bitflags]
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Axis {
+    X = 0b0001,
+    Y = 0b0010,
+    Z = 0b0100,
+    /// The extruder, which has a trapq of its own with E in x
+    E = 0b1000,
+}
+
+impl Axis {
+    /// Everything the toolhead moves along
+    pub const XYZ: BitFlags<Axis> = make_bitflags!(Axis::{X | Y | Z});
+
+    /// Index into an `[x, y, z, e]` array
+    pub fn index(self) -> usize {
+        match self {
+            Axis::X => 0,
+            Axis::Y => 1,
+            Axis::Z => 2,
+            Axis::E => 3,
+        }
+    }
+
+    /// A coordinate `value` along this axis, and nowhere along the others
+    pub fn coord(self, value: f64) -> Coord {
+        match self {
+            Axis::X | Axis::E => Coord::new(value, 0.0, 0.0),
+            Axis::Y => Coord::new(0.0, value, 0.0),
+            Axis::Z => Coord::new(0.0, 0.0, value),
+        }
+    }
+
+    /// This axis' component of a coordinate, on whichever trapq the axis lives
+    pub fn of(self, coord: Coord) -> f64 {
+        match self {
+            Axis::X | Axis::E => coord.x,
+            Axis::Y => coord.y,
+            Axis::Z => coord.z,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum KinematicsError {
+    #[error("Move out of range: {:.3} {:.3} {:.3}", .0.x, .0.y, .0.z)]
+    OutOfRange(Coord),
+    #[error("Must home axis first: {:.3} {:.3} {:.3}", .0.x, .0.y, .0.z)]
+    MustHomeFirst(Coord),
+    #[error("position_endstop must be between position_min and position_max")]
+    EndstopOutOfRange,
+    #[error("Unable to infer homing_positive_dir")]
+    AmbiguousHomingDir,
+    #[error("Winch needs between 3 and 26 anchors, not {0}")]
+    AnchorCount(usize),
+    #[error("Winch anchors must span all three axes")]
+    AnchorsDontSpan,
+    #[error("Delta arm_length must be longer than delta_radius")]
+    ArmTooShort,
+    #[error("Extrude below minimum temp")]
+    ColdExtrude,
+    #[error("Extrude only move too long ({:.3}mm vs {:.3}mm)", .distance.0, .max.0)]
+    ExtrudeOnlyTooLong {
+        distance: Millimeters,
+        max: Millimeters,
+    },
+    #[error("Move exceeds maximum extrusion ({area:.3}mm^2 vs {max:.3}mm^2)")]
+    Overextrude { area: f64, max: f64 },
+    #[error("Invalid pressure advance {pressure_advance} or smooth time {smooth_time}")]
+    InvalidPressureAdvance {
+        pressure_advance: Seconds,
+        smooth_time: Seconds,
+    },
+}
+
+/// How fast and how far a rail homes
+#[derive(Clone, Debug, PartialEq)]
+pub struct HomingSpeeds {
+    pub speed: MillimetersPerSecond,
+    /// How far to back off after first hitting the endstop. Zero skips the second approach
+    pub retract_dist: Millimeters,
+    pub retract_speed: MillimetersPerSecond,
+    /// Speed of the second, more accurate, approach
+    pub second_speed: MillimetersPerSecond,
+}
+
+impl HomingSpeeds {
+    /// Klipper's defaults, given `homing_speed`
+    pub fn new(speed: MillimetersPerSecond) -> Self {
+        Self {
+            speed,
+            retract_dist: Millimeters(5.0),
+            retract_speed: speed,
+            second_speed: speed * 0.5,
+        }
+    }
+}
+
+impl Default for HomingSpeeds {
+    fn default() -> Self {
+        Self::new(MillimetersPerSecond(5.0))
+    }
+}
+
+/// One go at homing: `axes` are homed together by moving from `force_pos` to `home_pos`
+/// until the endstops of `rails` trigger. Only the `axes` parts of the positions matter
+#[derive(Clone, Debug, PartialEq)]
+pub struct HomeRails {
+    pub axes: BitFlags<Axis>,
+    /// Index into [`Kinematics::steppers`] of each rail's own stepper. No rails means
+    /// there's nothing to home against and the toolhead is declared to be at `home_pos`
+    pub rails: Vec<usize>,
+    /// Where the toolhead pretends to start, far enough away to be sure of reaching the
+    /// endstops from wherever it really is
+    pub force_pos: Coord,
+    /// Where the toolhead is when the endstops trigger
+    pub home_pos: Coord,
+    pub speeds: HomingSpeeds,
+}
+
+/// Travel and homing settings for one axis, what klipper has in a `[stepper_x]` section
+#[derive(Clone, Debug, PartialEq)]
+pub struct RailConfig {
+    /// Amount the stepper moves per step
+    pub step_distance: Millimeters,
+    pub position_min: Millimeters,
+    pub position_max: Millimeters,
+    /// Where the toolhead is when the endstop triggers
+    pub position_endstop: Millimeters,
+    pub homing: HomingSpeeds,
+    /// Whether homing moves towards `position_max`
+    pub homing_positive_dir: bool,
+}
+
+impl RailConfig {
+    /// Defaults for everything else, with the homing direction worked out from which
+    /// end of the axis the endstop is nearest
+    pub fn new(
+        step_distance: Millimeters,
+        position_min: Millimeters,
+        position_max: Millimeters,
+        position_endstop: Millimeters,
+    ) -> Result<Self, KinematicsError> {
+        if position_endstop < position_min || position_endstop > position_max {
+            return Err(KinematicsError::EndstopOutOfRange);
+        }
+        let quarter = (position_max - position_min) * 0.25;
+        let homing_positive_dir = if position_endstop <= position_min + quarter {
+            false
+        } else if position_endstop >= position_max - quarter {
+            true
+        } else {
+            return Err(KinematicsError::AmbiguousHomingDir);
+        };
+        Ok(Self {
+            step_distance,
+            position_min,
+            position_max,
+            position_endstop,
+            homing: HomingSpeeds::default(),
+            homing_positive_dir,
+        })
+    }
+
+    /// Where to pretend the toolhead starts a homing move from, so it's guaranteed to
+    /// travel far enough to hit the endstop wherever it really is
+    pub fn homing_start(&self) -> Millimeters {
+        if self.homing_positive_dir {
+            self.position_endstop - (self.position_endstop - self.position_min) * 1.5
+        } else {
+            self.position_endstop + (self.position_max - self.position_endstop) * 1.5
+        }
+    }
+
+    /// Home this rail by itself, along `axis`. `rail` is the index of its stepper
+    pub fn home_rails(&self, axis: Axis, rail: usize) -> HomeRails {
+        HomeRails {
+            axes: axis.into(),
+            rails: vec![rail],
+            force_pos: axis.coord(self.homing_start().0),
+            home_pos: axis.coord(self.position_endstop.0),
+            speeds: self.homing.clone(),
+        }
+    }
+}
+
+pub struct Stepper {
+    /// Amount the stepper moves per step
+    step_distance: Millimeters,
+    /// Position this stepper has been commanded to
+    position: Millimeters,
+    /// Direction of the last step, `true` is positive
+    step_dir: bool,
+    /// Clock that step times are reported in
+    clock_freq: Hertz,
+    /// Print time steps have been generated up to
+    last_flushed: Seconds,
+    /// Print time of last stepper activity
+    last_moved: Seconds,
+    /// Axes whose movement moves this stepper
+    active: BitFlags<Axis>,
+    /// Steps are generated this long before the stepper goes active
+    leading_steps: Seconds,
+    /// Steps are generated this long after the stepper goes inactive
+    trailing_steps: Seconds,
+    /// Where the stepper is along a move
+    calc_position: CalcPosition,
+    /// Input shaping along X and Y, if any
+    shaper_x: ShaperPulses,
+    shaper_y: ShaperPulses,
+    /// Where steps go to become MCU commands
+    stepcompress: Option<StepCompressor>,
+    /// Name of the MCU the steps go to
+    mcu: Option<String>,
+    /// Step count the MCU last reported, in its own direction
+    mcu_position: Rc<Cell<Option<i32>>>,
+    /// Has moved since the motor was last turned off
+    enabled: bool,
+    /// Stepping along with the toolhead, rather than held still
+    following: bool,
+}
+
+impl Stepper {
+    pub fn new(
+        step_distance: Millimeters,
+        clock_freq: Hertz,
+        active: BitFlags<Axis>,
+        calc_position: CalcPosition,
+    ) -> Self {
+        Self {
+            step_distance,
+            position: Millimeters::ZERO,
+            step_dir: false,
+            clock_freq,
+            last_flushed: Seconds::ZERO,
+            last_moved: Seconds::ZERO,
+            active,
+            leading_steps: Seconds::ZERO,
+            trailing_steps: Seconds::ZERO,
+            calc_position,
+            shaper_x: ShaperPulses::default(),
+            shaper_y: ShaperPulses::default(),
+            stepcompress: None,
+            mcu: None,
+            mcu_position: Rc::new(Cell::new(None)),
+            enabled: false,
+            following: true,
+        }
+    }
+
+    pub fn step_distance(&self) -> Millimeters {
+        self.step_distance
+    }
+
+    /// Position the stepper has been commanded to
+    pub fn position(&self) -> Millimeters {
+        self.position
+    }
+
+    pub fn active_axes(&self) -> BitFlags<Axis> {
+        self.active
+    }
+
+    /// Whether the motor has moved since it was last turned off
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Turn the motor off
+    pub fn disable(&mut self) {
+        self.enabled = false;
+    }
+
+    /// Follow the toolhead, or hold still while others on the same axis move without it,
+    /// klipper's `set_trapq`
+    pub fn set_following(&mut self, following: bool) {
+        self.following = following;
+    }
+
+    /// Generate steps this long before the stepper starts moving
+    pub fn set_leading_steps(&mut self, lead: Seconds) {
+        self.leading_steps = lead;
+    }
+
+    /// Generate steps this long after the stepper stops moving
+    pub fn set_trailing_steps(&mut self, trail: Seconds) {
+        self.trailing_steps = trail;
+    }
+
+    /// Send steps to `mcu`, compressed by `stepcompress`
+    pub fn set_stepcompress(&mut self, mcu: &mut Mcu, stepcompress: StepCompressor) {
+        let reported = self.mcu_position.clone();
+        mcu.register_response(
+            stepcompress.oid(),
+            Box::new(move |response| {
+                if let Response::StepperPosition { pos, .. } = response {
+                    reported.set(Some(*pos));
+                }
+                Ok(())
+            }),
+        );
+        self.mcu = Some(mcu.name().into());
+        self.stepcompress = Some(stepcompress);
+    }
+
+    /// Name of the MCU the steps go to, if they go anywhere
+    pub fn mcu(&self) -> Option<&str> {
+        self.mcu.as_deref()
+    }
+
+    pub fn oid(&self) -> Option<Oid> {
+        self.stepcompress.as_ref().map(StepCompressor::oid)
+    }
+
+    /// Ask the MCU how far it's stepped, see [`Stepper::mcu_position`] for the answer
+    pub fn query_mcu_position(&self, mcu: &mut Mcu) {
+        if let Some(oid) = self.oid() {
+            self.mcu_position.set(None);
+            mcu.send(Command::StepperGetPosition { oid });
+        }
+    }
+
+    /// Steps the MCU says it's taken, counting positive steps as positive
+    pub fn mcu_position(&self) -> Option<i64> {
+        let sc = self.stepcompress.as_ref()?;
+        let pos = i64::from(self.mcu_position.get()?);
+        Some(if sc.invert_dir() { -pos } else { pos })
+    }
+
+    /// Pick up again after the MCU stopped this stepper partway through its queue
+    pub fn note_homing_end(&mut self, mcu: &mut Mcu) -> Result<(), StepCompressError> {
+        let Some(sc) = self.stepcompress.as_mut() else {
+            return Ok(());
+        };
+        sc.reset(Ticks(0))?;
+        // anything still queued up, the MCU has already thrown away
+        sc.drain_commands();
+        mcu.send(Command::ResetStepClock {
+            oid: sc.oid(),
+            clock: Ticks(0),
+        });
+        Ok(())
+    }
+
+    /// Generate and compress steps up to `flush_time`, returning the `queue_step`s to send.
+    /// Without a step compressor there's nothing to send
+    pub fn flush_steps(
+        &mut self,
+        trapq: &TrapQ,
+        flush_time: Seconds,
+    ) -> Result<Vec<Command>, StepCompressError> {
+        let steps = self.generate_steps(trapq, flush_time);
+        let Some(sc) = self.stepcompress.as_mut() else {
+            return Ok(Vec::new());
+        };
+        for step in steps {
+            sc.append(step)?;
+        }
+        sc.flush(flush_time.to_ticks(self.clock_freq))?;
+        Ok(sc.drain_commands())
+    }
+}
+
+/// Homed travel limits of X, Y and Z, for kinematics that move the toolhead around a box
+#[derive(Clone, Debug, Default)]
+struct AxisLimits {
+    /// `None` until the axis has been homed
+    limits: [Option<(Millimeters, Millimeters)>; 3],
+    max_z_velocity: MillimetersPerSecond,
+    max_z_accel: MillimetersPerSecondSquared,
+}
+
+impl AxisLimits {
+    const AXES: [Axis; 3] = [Axis::X, Axis::Y, Axis::Z];
+
+    fn new(max_z_velocity: MillimetersPerSecond, max_z_accel: MillimetersPerSecondSquared) -> Self {
+        Self {
+            limits: [None; 3],
+            max_z_velocity,
+            max_z_accel,
+        }
+    }
+
+    fn contains(&self, axis: Axis, pos: Coord) -> bool {
+        let pos = Millimeters(axis.of(pos));
+        matches!(self.limits[axis.index()], Some((min, max)) if pos >= min && pos <= max)
+    }
+
+    fn set_homed(&mut self, axis: Axis, rail: &RailConfig) {
+        self.limits[axis.index()] = Some((rail.position_min, rail.position_max));
+    }
+
+    fn homed_axes(&self) -> BitFlags<Axis> {
+        Self::AXES
+            .into_iter()
+            .filter(|axis| self.limits[axis.index()].is_some())
+            .collect()
+    }
+
+    fn clear(&mut self, axes: BitFlags<Axis>) {
+        for axis in axes & Axis::XYZ {
+            self.limits[axis.index()] = None;
+        }
+    }
+
+    /// Every axis the move travels along has to end up within its limits
+    fn check_endstops(&self, mv: &Move) -> Result<(), KinematicsError> {
+        let axes_d = mv.axes_d();
+        for axis in Self::AXES {
+            if axis.of(axes_d) == 0.0 || self.contains(axis, mv.end_pos) {
+                continue;
+            }
+            return Err(match self.limits[axis.index()] {
+                None => KinematicsError::MustHomeFirst(mv.end_pos),
+                Some(_) => KinematicsError::OutOfRange(mv.end_pos),
+            });
+        }
+        Ok(())
+    }
+
+    fn check_move(&self, mv: &mut Move) -> Result<(), KinematicsError> {
+        if !self.contains(Axis::X, mv.end_pos) || !self.contains(Axis::Y, mv.end_pos) {
+            self.check_endstops(mv)?;
+        }
+        if mv.axes_r.z == 0.0 {
+            // plain XY move, nothing to slow down for
+            return Ok(());
+        }
+        self.check_endstops(mv)?;
+        // Z is usually much slower, keep its share of the move within its own limits
+        let z_ratio = 1.0 / mv.axes_r.z.abs();
+        mv.limit_speed(self.max_z_velocity * z_ratio, self.max_z_accel * z_ratio);
+        Ok(())
+    }
+}
+
+pub trait Kinematics {
+    type Position;
+
+    fn steppers(&self) -> Vec<&Stepper>;
+    fn steppers_mut(&mut self) -> Vec<&mut Stepper>;
+    /// Toolhead position the steppers have been commanded to
+    fn calculate_position(&self) -> Self::Position;
+    /// Declare the toolhead to be at `pos`. Any `homing_axes` count as homed from now on
+    fn set_position(&mut self, pos: Coord, homing_axes: BitFlags<Axis>);
+    /// Reject moves the printer can't make, and slow down any it can only make slowly
+    fn check_move(&self, mv: &mut Move) -> Result<(), KinematicsError>;
+    fn homed_axes(&self) -> BitFlags<Axis>;
+    /// Forget homing, e.g. once the motors are turned off
+    fn clear_homing_state(&mut self, axes: BitFlags<Axis>);
+    /// How to home `axes`, one [`HomeRails`] after another
+    fn homing_plan(&self, axes: BitFlags<Axis>) -> Vec<HomeRails>;
+
+    /// Steppers the endstop on `rail` has to stop, by index into [`Kinematics::steppers`]
+    fn endstop_steppers(&self, rail: usize) -> Vec<usize> {
+        vec![rail]
+    }
+
+    /// Steps up to `flush_time` for each stepper, in MCU clock ticks
+    fn generate_steps(&mut self, trapq: &TrapQ, flush_time: Seconds) -> Vec<Vec<Step>> {
+        self.steppers_mut()
+            .into_iter()
+            .map(|s| s.generate_steps(trapq, flush_time))
+            .collect()
+    }
+
+    /// Axes that are going to move before `flush_time`
+    fn will_be_active(&self, trapq: &TrapQ, flush_time: Seconds) -> BitFlags<Axis> {
+        self.steppers()
+            .into_iter()
+            .filter(|s| s.next_activity(trapq, flush_time).is_some())
+            .map(Stepper::active_axes)
+            .collect()
+    }
+
+    /// Axes with motors that have moved since last turned off
+    fn active_axes(&self) -> BitFlags<Axis> {
+        self.steppers()
+            .into_iter()
+            .filter(|s| s.is_enabled())
+            .map(Stepper::active_axes)
+            .collect()
+    }
+
+    /// Turn every motor off, forgetting where they were homed
+    fn motors_off(&mut self) {
+        for stepper in self.steppers_mut() {
+            stepper.disable();
+        }
+        self.clear_homing_state(Axis::XYZ);
+    }
+}
+
+/// Klipper's own step generation, `itersolve.c` and friends, to check ours against. Only
+/// built with the `klipper-steps` feature
+#[cfg(all(test, feature = "klipper-steps"))]
+mod klipper {
+    use super::itersolve::Step;
+    use crate::ffi::generated::{
+        itersolve_generate_steps, itersolve_set_position, itersolve_set_stepcompress,
+        itersolve_set_trapq, stepcapture_alloc, stepcapture_count, stepcapture_free,
+        stepcapture_free_kinematics, stepcapture_steps, stepper_kinematics, trapq_alloc,
+        trapq_append, trapq_free,
+    };
+    use crate::trapq::{Coord, TrapQ};
+    use crate::units::{Hertz, Seconds};
+
+    /// Steps klipper generates up to `flush_time` for the stepper kinematics `sk`, once
+    /// set at `start` and following the moves in `trapq`. Takes ownership of `sk`
+    pub(super) fn steps(
+        sk: *mut stepper_kinematics,
+        trapq: &TrapQ,
+        step_distance: f64,
+        start: Coord,
+        flush_time: Seconds,
+        freq: Hertz,
+    ) -> Vec<Step> {
+        assert!(!sk.is_null());
+        unsafe {
+            let tq = trapq_alloc();
+            // each piece goes in as a trapezoid with only the one phase, klipper fills
+            // in any gaps between them
+            for m in trapq.moves().filter(|m| !m.is_null()) {
+                let (t, ha) = (m.move_t.0, m.half_accel.0);
+                let (accel_t, cruise_t, decel_t) = if ha > 0.0 {
+                    (t, 0.0, 0.0)
+                } else if ha < 0.0 {
+                    (0.0, 0.0, t)
+                } else {
+                    (0.0, t, 0.0)
+                };
+                let (p, r, v) = (m.start_pos, m.axes_r, m.start_v.0);
+                trapq_append(
+                    tq,
+                    m.print_time.0,
+                    accel_t,
+                    cruise_t,
+                    decel_t,
+                    p.x,
+                    p.y,
+                    p.z,
+                    r.x,
+                    r.y,
+                    r.z,
+                    v,
+                    v,
+                    2.0 * ha.abs(),
+                );
+            }
+            let sc = stepcapture_alloc();
+            itersolve_set_stepcompress(sk, sc, step_distance);
+            itersolve_set_trapq(sk, tq);
+            itersolve_set_position(sk, start.x, start.y, start.z);
+            assert_eq!(itersolve_generate_steps(sk, flush_time.0), 0);
+
+            let count = stepcapture_count(sc) as usize;
+            let captured = match count {
+                0 => &[][..],
+                _ => std::slice::from_raw_parts(stepcapture_steps(sc), count),
+            };
+            let steps = captured
+                .iter()
+                .map(|s| Step {
+                    clock: Seconds(s.time).to_ticks(freq),
+                    dir: s.dir != 0,
+                })
+                .collect();
+            stepcapture_free_kinematics(sk);
+            stepcapture_free(sc);
+            trapq_free(tq);
+            steps
+        }
+    }
+}
+
+/// Step times worked out independently, for checking the iterative solver against
+#[cfg(test)]
+mod reference {
+    use super::itersolve::Step;
+    use crate::trapq::{Coord, TrapQ};
+    use crate::units::{Hertz, Seconds};
+
+    /// Same again for any motor, by walking each move in small time slices and bisecting
+    /// whichever slices cross a half step
+    pub(super) fn sampled_steps(
+        trapq: &TrapQ,
+        motor: impl Fn(Coord) -> f64,
+        step_distance: f64,
+        freq: Hertz,
+    ) -> Vec<Step> {
+        const SLICE: f64 = 10e-6;
+        let mut steps = Vec::new();
+        for m in trapq.moves().filter(|m| !m.is_null()) {
+            let at = |t: f64| motor(m.get_coord(Seconds(t)));
+            let cell = |p: f64| (p / step_distance - 0.5).floor() as i64;
+            let slices = (m.move_t.0 / SLICE).ceil() as usize;
+            let mut t0 = 0.0;
+            for i in 1..=slices {
+                let t1 = (i as f64 * SLICE).min(m.move_t.0);
+                let (c0, c1) = (cell(at(t0)), cell(at(t1)));
+                let dir = c1 > c0;
+                let boundaries: Vec<_> = if dir {
+                    (c0 + 1..=c1).collect()
+                } else {
+                    (c1 + 1..=c0).rev().collect()
+                };
+                for n in boundaries {
+                    let target = (n as f64 + 0.5) * step_distance;
+                    let (mut lo, mut hi) = (t0, t1);
+                    for _ in 0..60 {
+                        let mid = 0.5 * (lo + hi);
+                        if (at(mid) >= target) == dir {
+                            hi = mid;
+                        } else {
+                            lo = mid;
+                        }
+                    }
+                    steps.push(Step {
+                        clock: (m.print_time + Seconds(hi)).to_ticks(freq),
+                        dir,
+                    });
+                }
+                t0 = t1;
+            }
+        }
+        steps
+    }
+
+    /// Same steps, give or take a tick of rounding
+    pub(super) fn assert_matches(got: &[Step], expected: &[Step]) {
+        assert_eq!(got.len(), expected.len());
+        for (g, e) in got.iter().zip(expected) {
+            assert_eq!(g.dir, e.dir);
+            assert!(g.clock.0.abs_diff(e.clock.0) <= 1, "{g:?} vs {e:?}");
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! Input shaping during step generation, klipper's `kin_shaper.c`
+//!
+//! Rather than following the toolhead, a shaped stepper follows a weighted average of
+//! where the toolhead was and will be at a few points in time around now. Pick the
+//! weights and times right and the ringing each one sets off cancels out
+
+use super::itersolve::MoveCursor;
+use super::{Axis, Stepper};
+use crate::trapq::Move;
+use crate::units::{Millimeters, Seconds};
+
+/// One impulse of a shaper
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pulse {
+    /// Share of the position taken from this impulse, they add up to one
+    pub a: f64,
+    /// Offset from the time being evaluated
+    pub t: Seconds,
+}
+
+/// A shaper's impulses, centred so the shaped motion doesn't lag on average. No pulses
+/// means no shaping
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ShaperPulses {
+    pulses: Vec<Pulse>,
+}
+
+impl ShaperPulses {
+    /// Impulses of amplitude `a[i]` at time `t[i]`, as a shaper defines them
+    pub fn new(a: &[f64], t: &[Seconds]) -> Self {
+        let sum_a: f64 = a.iter().sum();
+        let sum_a_t: f64 = a.iter().zip(t).map(|(a, t)| a * t.0).sum();
+        let t_shift = Seconds(sum_a_t / sum_a);
+        // reversed, so later impulses look further back in time
+        let pulses = a
+            .iter()
+            .zip(t)
+            .rev()
+            .map(|(&a, &t)| Pulse {
+                a: a / sum_a,
+                t: t_shift - t,
+            })
+            .collect();
+        Self { pulses }
+    }
+
+    pub fn pulses(&self) -> &[Pulse] {
+        &self.pulses
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pulses.is_empty()
+    }
+
+    /// How far ahead the shaped position looks
+    fn lookahead(&self) -> Seconds {
+        self.pulses
+            .iter()
+            .fold(Seconds::ZERO, |max, p| max.max(p.t))
+    }
+
+    /// How far behind the shaped position looks
+    fn lookbehind(&self) -> Seconds {
+        self.pulses
+            .iter()
+            .fold(Seconds::ZERO, |max, p| max.max(-p.t))
+    }
+
+    /// Shaped position along `axis`, `move_time` into the move under `cursor`
+    pub fn position(&self, cursor: MoveCursor<'_>, axis: Axis, move_time: Seconds) -> f64 {
+        self.pulses
+            .iter()
+            .map(|p| p.a * position_across_moves(cursor, axis, move_time + p.t))
+            .sum()
+    }
+}
+
+/// Position along `axis` at `move_time` relative to the move under `cursor`, which might
+/// be in a different move entirely. Off either end of the trapq the toolhead sits still
+fn position_across_moves(cursor: MoveCursor<'_>, axis: Axis, move_time: Seconds) -> f64 {
+    let (mut cursor, mut move_time) = (cursor, move_time);
+    while move_time < Seconds::ZERO {
+        let Some(prev) = cursor.prev() else {
+            return axis.of(cursor.get().start_pos);
+        };
+        cursor = prev;
+        move_time += cursor.get().move_t;
+    }
+    while move_time > cursor.get().move_t {
+        let Some(next) = cursor.next() else {
+            return axis.of(cursor.coord(cursor.get().move_t));
+        };
+        move_time -= cursor.get().move_t;
+        cursor = next;
+    }
+    axis.of(cursor.coord(move_time))
+}
+
+impl Stepper {
+    /// Shape this stepper's motion along `axis`, which has to be X or Y. Steppers that
+    /// don't move with `axis` are left alone, as are steps that have already been made
+    pub fn set_input_shaper(&mut self, axis: Axis, pulses: ShaperPulses) {
+        if !self.active.contains(axis) {
+            return;
+        }
+        match axis {
+            Axis::X => self.shaper_x = pulses,
+            Axis::Y => self.shaper_y = pulses,
+            Axis::Z | Axis::E => {}
+        }
+    }
+
+    /// Stepper position `move_time` into a move, after input shaping
+    pub(super) fn shaped_position(
+        &self,
+        cursor: MoveCursor<'_>,
+        move_time: Seconds,
+    ) -> Millimeters {
+        if self.shaper_x.is_empty() && self.shaper_y.is_empty() {
+            return (self.calc_position)(cursor, move_time);
+        }
+        let mut pos = cursor.coord(move_time);
+        if !self.shaper_x.is_empty() {
+            pos.x = self.shaper_x.position(cursor, Axis::X, move_time);
+        }
+        if !self.shaper_y.is_empty() {
+            pos.y = self.shaper_y.position(cursor, Axis::Y, move_time);
+        }
+        let still = [Move::null(Seconds::ZERO, Seconds::ZERO, pos)];
+        (self.calc_position)(MoveCursor::new(&still, 0), Seconds::ZERO)
+    }
+
+    /// How long before a move steps need generating, shaping looks ahead at it
+    pub(super) fn leading_time(&self) -> Seconds {
+        self.leading_steps
+            .max(self.shaper_x.lookahead())
+            .max(self.shaper_y.lookahead())
+    }
+
+    /// How long after a move steps need generating, shaping still remembers it
+    pub(super) fn trailing_time(&self) -> Seconds {
+        self.trailing_steps
+            .max(self.shaper_x.lookbehind())
+            .max(self.shaper_y.lookbehind())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::planner::{Planner, PlannerConfig};
+    use crate::trapq::Coord;
+    use crate::units::{Hertz, MillimetersPerSecond, MillimetersPerSecondSquared};
+    use enumflags2::BitFlags;
+
+    #[test]
+    fn test_pulses_are_centred() {
+        let pulses = ShaperPulses::new(&[1.0, 1.0], &[Seconds(0.0), Seconds(0.01)]);
+        assert_eq!(
+            pulses.pulses(),
+            [
+                Pulse {
+                    a: 0.5,
+                    t: Seconds(-0.005)
+                },
+                Pulse {
+                    a: 0.5,
+                    t: Seconds(0.005)
+                },
+            ]
+        );
+        assert_eq!(pulses.lookahead(), Seconds(0.005));
+        assert_eq!(pulses.lookbehind(), Seconds(0.005));
+    }
+
+    #[test]
+    fn test_shaped_steps() {
+        let mut p = Planner::new(PlannerConfig::new(
+            MillimetersPerSecond(100.0),
+            MillimetersPerSecondSquared(1000.0),
+        ));
+        // a pause first, so there's time to start moving early
+        p.dwell(Seconds(0.1));
+        p.move_to(Coord::new(10.0, 0.0, 0.0), MillimetersPerSecond(100.0));
+        p.flush();
+        let (trapq, end) = (p.trapq(), p.print_time());
+        let new_stepper = || {
+            Stepper::new(
+                Millimeters(0.01),
+                Hertz(1_000_000.0),
+                BitFlags::from(Axis::X),
+                Box::new(|m, t| Millimeters(m.coord(t).x)),
+            )
+        };
+        let pulses = ShaperPulses::new(&[1.0, 1.0], &[Seconds(0.0), Seconds(0.02)]);
+
+        let mut plain = new_stepper();
+        let plain_steps = plain.generate_steps(trapq, end + Seconds(0.1));
+        let mut shaped = new_stepper();
+        shaped.set_input_shaper(Axis::X, pulses.clone());
+        // Y doesn't move this stepper, so can't shape it
+        shaped.set_input_shaper(Axis::Y, pulses);
+        assert!(shaped.shaper_y.is_empty());
+        let shaped_steps = shaped.generate_steps(trapq, end + Seconds(0.1));
+
+        // same distance, but starting early and finishing late
+        assert_eq!(plain_steps.len(), 1000);
+        assert_eq!(shaped_steps.len(), 1000);
+        assert!(shaped_steps[0].clock < plain_steps[0].clock);
+        assert!(shaped_steps[999].clock > plain_steps[999].clock);
+        assert!((shaped.position() - Millimeters(10.0)).abs() < Millimeters(1e-9));
+    }
+}
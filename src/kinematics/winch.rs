@@ -0,0 +1,341 @@
+//! Cable winch kinematics, klipper's `winch.py`
+//!
+//! Each stepper reels in a cable running from its anchor to the toolhead, so its position
+//! is just the distance between the two. Going back the other way has no neat closed form
+//! once there are more than three cables, so it's solved numerically
+
+use enumflags2::BitFlags;
+
+use super::{Axis, HomeRails, HomingSpeeds, Kinematics, KinematicsError, Stepper};
+use crate::mathutil;
+use crate::planner::Move;
+use crate::trapq::Coord;
+use crate::units::{Hertz, Millimeters};
+
+const MIN_ANCHORS: usize = 3;
+/// One per letter, `stepper_a` to `stepper_z`
+const MAX_ANCHORS: usize = 26;
+const FK_ITERATIONS: usize = 50;
+/// Stop refining the position once a correction is this small
+const FK_TOLERANCE: f64 = 1e-9;
+
+/// Where a cable is anchored, and how much cable a step reels in
+#[derive(Clone, Debug, PartialEq)]
+pub struct Anchor {
+    pub position: Coord,
+    pub step_distance: Millimeters,
+}
+
+pub struct Winch {
+    steppers: Vec<Stepper>,
+    anchors: Vec<Coord>,
+    /// Corners of the box around the anchors, the toolhead can't leave it
+    axes_min: Coord,
+    axes_max: Coord,
+}
+
+impl Winch {
+    pub fn new(anchors: Vec<Anchor>, clock_freq: Hertz) -> Result<Self, KinematicsError> {
+        if !(MIN_ANCHORS..=MAX_ANCHORS).contains(&anchors.len()) {
+            return Err(KinematicsError::AnchorCount(anchors.len()));
+        }
+        let positions: Vec<_> = anchors.iter().map(|a| a.position).collect();
+        let (axes_min, axes_max) = positions.iter().fold(
+            (
+                Coord::new(f64::MAX, f64::MAX, f64::MAX),
+                Coord::new(f64::MIN, f64::MIN, f64::MIN),
+            ),
+            |(min, max), a| {
+                (
+                    Coord::new(min.x.min(a.x), min.y.min(a.y), min.z.min(a.z)),
+                    Coord::new(max.x.max(a.x), max.y.max(a.y), max.z.max(a.z)),
+                )
+            },
+        );
+        let extent = axes_max - axes_min;
+        // the first three get used to find a starting point, so can't be in a line either
+        let spread = (positions[1] - positions[0]).cross(positions[2] - positions[0]);
+        if extent.x <= 0.0 || extent.y <= 0.0 || extent.z <= 0.0 || spread.norm() < 1e-9 {
+            return Err(KinematicsError::AnchorsDontSpan);
+        }
+
+        let steppers = anchors
+            .into_iter()
+            .map(|anchor| {
+                let position = anchor.position;
+                Stepper::new(
+                    anchor.step_distance,
+                    clock_freq,
+                    Axis::XYZ,
+                    Box::new(move |m, t| Millimeters((m.coord(t) - position).norm())),
+                )
+            })
+            .collect();
+        Ok(Self {
+            steppers,
+            anchors: positions,
+            axes_min,
+            axes_max,
+        })
+    }
+
+    pub fn anchors(&self) -> &[Coord] {
+        &self.anchors
+    }
+
+    /// Length of each cable with the toolhead at `pos`
+    pub fn cable_lengths(&self, pos: Coord) -> Vec<Millimeters> {
+        self.anchors
+            .iter()
+            .map(|&a| Millimeters((pos - a).norm()))
+            .collect()
+    }
+
+    /// Toolhead position for the given cable lengths. If they don't quite agree (say,
+    /// they've been rounded to whole steps) it's the position that fits them best
+    pub fn toolhead_position(&self, lengths: &[Millimeters]) -> Coord {
+        let residual = |p: Coord| -> f64 {
+            self.anchors
+                .iter()
+                .zip(lengths)
+                .map(|(&a, l)| ((p - a).norm() - l.0).powi(2))
+                .sum()
+        };
+        // start from whichever side of the first three anchors fits the rest better
+        let first = [self.anchors[0], self.anchors[1], self.anchors[2]];
+        let radius2 = [lengths[0].0, lengths[1].0, lengths[2].0].map(|l| l * l);
+        let (below, above) = mathutil::closest_trilateration(first, radius2);
+        let mut pos = if residual(above) < residual(below) {
+            above
+        } else {
+            below
+        };
+
+        // then Gauss-Newton over every cable
+        for _ in 0..FK_ITERATIONS {
+            let mut jtj = [[0.0; 3]; 3];
+            let mut jtr = [0.0; 3];
+            for (&a, l) in self.anchors.iter().zip(lengths) {
+                let d = pos - a;
+                let dist = d.norm();
+                if dist == 0.0 {
+                    continue;
+                }
+                let j = [d.x / dist, d.y / dist, d.z / dist];
+                let r = dist - l.0;
+                for row in 0..3 {
+                    jtr[row] += j[row] * r;
+                    for col in 0..3 {
+                        jtj[row][col] += j[row] * j[col];
+                    }
+                }
+            }
+            let Some(step) = solve3(jtj, jtr) else {
+                break;
+            };
+            pos = pos - step;
+            if step.norm() < FK_TOLERANCE {
+                break;
+            }
+        }
+        pos
+    }
+}
+
+/// Solve a 3x3 linear system with Cramer's rule, `None` if it's singular
+fn solve3(m: [[f64; 3]; 3], b: [f64; 3]) -> Option<Coord> {
+    let det = |m: [[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let d = det(m);
+    if d.abs() < 1e-12 {
+        return None;
+    }
+    let column = |c: usize| {
+        let mut mc = m;
+        for row in 0..3 {
+            mc[row][c] = b[row];
+        }
+        det(mc) / d
+    };
+    Some(Coord::new(column(0), column(1), column(2)))
+}
+
+impl Kinematics for Winch {
+    type Position = Coord;
+
+    fn steppers(&self) -> Vec<&Stepper> {
+        self.steppers.iter().collect()
+    }
+
+    fn steppers_mut(&mut self) -> Vec<&mut Stepper> {
+        self.steppers.iter_mut().collect()
+    }
+
+    fn calculate_position(&self) -> Coord {
+        let lengths: Vec<_> = self.steppers.iter().map(Stepper::position).collect();
+        self.toolhead_position(&lengths)
+    }
+
+    fn set_position(&mut self, pos: Coord, _homing_axes: BitFlags<Axis>) {
+        for stepper in &mut self.steppers {
+            stepper.set_position(pos);
+        }
+    }
+
+    fn check_move(&self, mv: &mut Move) -> Result<(), KinematicsError> {
+        let (end, min, max) = (mv.end_pos, self.axes_min, self.axes_max);
+        let outside = end.x < min.x
+            || end.x > max.x
+            || end.y < min.y
+            || end.y > max.y
+            || end.z < min.z
+            || end.z > max.z;
+        if outside {
+            return Err(KinematicsError::OutOfRange(end));
+        }
+        Ok(())
+    }
+
+    /// There's nothing to home against, cable lengths are set by hand
+    fn homed_axes(&self) -> BitFlags<Axis> {
+        Axis::XYZ
+    }
+
+    fn clear_homing_state(&mut self, _axes: BitFlags<Axis>) {}
+
+    /// Nothing to home against, homing just declares the toolhead to be at the origin
+    fn homing_plan(&self, axes: BitFlags<Axis>) -> Vec<HomeRails> {
+        if (axes & Axis::XYZ).is_empty() {
+            return Vec::new();
+        }
+        vec![HomeRails {
+            axes: Axis::XYZ,
+            rails: Vec::new(),
+            force_pos: Coord::default(),
+            home_pos: Coord::default(),
+            speeds: HomingSpeeds::default(),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::planner::{Planner, PlannerConfig};
+    use crate::units::{MillimetersPerSecond, MillimetersPerSecondSquared};
+
+    const FREQ: Hertz = Hertz(1_000_000.0);
+
+    fn anchor(x: f64, y: f64, z: f64) -> Anchor {
+        Anchor {
+            position: Coord::new(x, y, z),
+            step_distance: Millimeters(0.01),
+        }
+    }
+
+    /// klipper's example-winch.cfg
+    fn example() -> Vec<Anchor> {
+        vec![
+            anchor(0.0, -2000.0, -100.0),
+            anchor(1000.0, 1000.0, -100.0),
+            anchor(-1000.0, 1000.0, -100.0),
+            anchor(0.0, 0.0, 3000.0),
+        ]
+    }
+
+    #[test]
+    fn test_validation() {
+        let mut anchors = example();
+        anchors.truncate(2);
+        assert_eq!(
+            Winch::new(anchors, FREQ).err(),
+            Some(KinematicsError::AnchorCount(2))
+        );
+        let anchors = (0..27).map(|i| anchor(i as f64, 0.0, 0.0)).collect();
+        assert_eq!(
+            Winch::new(anchors, FREQ).err(),
+            Some(KinematicsError::AnchorCount(27))
+        );
+        // all at the same height leaves no room to move in Z
+        let mut anchors = example();
+        anchors.pop();
+        assert_eq!(
+            Winch::new(anchors, FREQ).err(),
+            Some(KinematicsError::AnchorsDontSpan)
+        );
+        let line = vec![
+            anchor(0.0, 0.0, 0.0),
+            anchor(1.0, 1.0, 1.0),
+            anchor(2.0, 2.0, 2.0),
+            anchor(0.0, 5.0, 0.0),
+        ];
+        assert_eq!(
+            Winch::new(line, FREQ).err(),
+            Some(KinematicsError::AnchorsDontSpan)
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let winch = Winch::new(example(), FREQ).unwrap();
+        for pos in [
+            Coord::new(0.0, 0.0, 0.0),
+            Coord::new(100.0, -200.0, 50.0),
+            Coord::new(-300.0, 400.0, 1500.0),
+            Coord::new(10.0, 10.0, -90.0),
+        ] {
+            let lengths = winch.cable_lengths(pos);
+            let found = winch.toolhead_position(&lengths);
+            assert!(
+                (found - pos).norm() < 1e-6,
+                "{pos:?} came back as {found:?}"
+            );
+        }
+
+        // six cables that disagree slightly still land close
+        let mut anchors = example();
+        anchors.push(anchor(2000.0, 0.0, 500.0));
+        anchors.push(anchor(-2000.0, 0.0, 500.0));
+        let winch = Winch::new(anchors, FREQ).unwrap();
+        let pos = Coord::new(120.0, -80.0, 300.0);
+        let lengths: Vec<_> = winch
+            .cable_lengths(pos)
+            .into_iter()
+            .enumerate()
+            .map(|(i, l)| l + Millimeters(if i % 2 == 0 { 0.004 } else { -0.004 }))
+            .collect();
+        assert!((winch.toolhead_position(&lengths) - pos).norm() < 0.02);
+    }
+
+    #[test]
+    fn test_moves() {
+        let mut winch = Winch::new(example(), FREQ).unwrap();
+        winch.set_position(Coord::default(), BitFlags::empty());
+        assert_eq!(winch.homed_axes(), Axis::XYZ);
+        let mut planner = Planner::new(PlannerConfig::new(
+            MillimetersPerSecond(100.0),
+            MillimetersPerSecondSquared(1000.0),
+        ));
+        let target = Coord::new(30.0, -20.0, 10.0);
+        planner
+            .checked_move(&winch, target, MillimetersPerSecond(50.0))
+            .unwrap();
+        planner.flush();
+        let steps = winch.generate_steps(planner.trapq(), planner.print_time());
+        assert!(steps.iter().all(|s| !s.is_empty()));
+        // stepper positions are whole steps away from exact, which is close enough
+        assert!((winch.calculate_position() - target).norm() < 0.05);
+
+        assert!(matches!(
+            planner.checked_move(
+                &winch,
+                Coord::new(0.0, 0.0, 3001.0),
+                MillimetersPerSecond(50.0)
+            ),
+            Err(KinematicsError::OutOfRange(_))
+        ));
+    }
+}
@@ -0,0 +1,348 @@
+//! Leveling whatever hangs off several Z steppers, bed or gantry: probe it, work out how
+//! far off each stepper is, then move them on their own until they agree. Klipper's
+//! `z_tilt.py` and `quad_gantry_level.py`
+
+pub mod quad_gantry_level;
+pub mod z_tilt;
+
+use enumflags2::BitFlags;
+
+use crate::homing::{HomingContext, HomingError};
+use crate::kinematics::Kinematics;
+use crate::mcu::McuLink;
+use crate::probe::{z_steppers, ProbeError};
+use crate::trapq::Coord;
+use crate::units::{Millimeters, MillimetersPerSecond};
+
+#[derive(thiserror::Error, Debug)]
+pub enum LevelingError {
+    #[error("z_positions needs exactly {expected} items, got {found}")]
+    StepperCount { expected: usize, found: usize },
+    #[error("Need at least {0} probe points")]
+    TooFewPoints(usize),
+    #[error("{0} out of range")]
+    InvalidParameter(&'static str),
+    #[error(
+        "Retries aborting: Probed points range is increasing. Possibly Z motor numbering is wrong"
+    )]
+    RangeIncreasing,
+    #[error("Too many retries")]
+    TooManyRetries,
+    #[error(
+        "Aborting quad_gantry_level required adjustment {adjust:.6} is greater than \
+         max_adjust {max_adjust:.6}"
+    )]
+    MaxAdjust { adjust: f64, max_adjust: f64 },
+    #[error(transparent)]
+    Probe(#[from] ProbeError),
+    #[error(transparent)]
+    Homing(#[from] HomingError),
+}
+
+/// `Z_TILT_ADJUST` and `QUAD_GANTRY_LEVEL` parameters, each overriding the config when given
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LevelingParams {
+    pub retries: Option<usize>,
+    pub retry_tolerance: Option<Millimeters>,
+}
+
+/// Keeps probing and adjusting until the probed heights agree well enough, klipper's
+/// `RetryHelper`
+struct RetryHelper {
+    max_retries: usize,
+    retry_tolerance: Millimeters,
+    current_retry: usize,
+    previous: Option<f64>,
+    /// Rounds the range has grown, less rounds it's shrunk
+    increasing: usize,
+}
+
+impl RetryHelper {
+    fn new(
+        retries: usize,
+        retry_tolerance: Millimeters,
+        params: &LevelingParams,
+    ) -> Result<Self, LevelingError> {
+        let max_retries = params.retries.unwrap_or(retries);
+        let retry_tolerance = params.retry_tolerance.unwrap_or(retry_tolerance);
+        if max_retries > 30 {
+            return Err(LevelingError::InvalidParameter("retries"));
+        }
+        if !(0.0..=1.0).contains(&retry_tolerance.0) {
+            return Err(LevelingError::InvalidParameter("retry_tolerance"));
+        }
+        Ok(Self {
+            max_retries,
+            retry_tolerance,
+            current_retry: 0,
+            previous: None,
+            increasing: 0,
+        })
+    }
+
+    /// Note the range, returning whether it's grown twice more than it's shrunk
+    fn check_increase(&mut self, range: f64) -> bool {
+        match self.previous {
+            Some(previous) if range > previous + 0.000_000_1 => self.increasing += 1,
+            _ => self.increasing = self.increasing.saturating_sub(1),
+        }
+        self.previous = Some(range);
+        self.increasing > 1
+    }
+
+    /// Whether to probe again, given the heights just probed
+    fn check_retry(&mut self, z_positions: &[f64]) -> Result<bool, LevelingError> {
+        if self.max_retries == 0 {
+            return Ok(false);
+        }
+        let max = z_positions
+            .iter()
+            .copied()
+            .fold(f64::NEG_INFINITY, f64::max);
+        let min = z_positions.iter().copied().fold(f64::INFINITY, f64::min);
+        let range = ((max - min) * 1e6).round() / 1e6;
+        if self.check_increase(range) {
+            return Err(LevelingError::RangeIncreasing);
+        }
+        if range <= self.retry_tolerance.0 {
+            return Ok(false);
+        }
+        self.current_retry += 1;
+        if self.current_retry > self.max_retries {
+            return Err(LevelingError::TooManyRetries);
+        }
+        Ok(true)
+    }
+}
+
+/// Move each Z stepper on its own by its share of `adjustments`, one per
+/// [`z_steppers`], at up to `speed`, klipper's `ZAdjustHelper`. Only the differences get
+/// moved, the stepper with the smallest adjustment stays put, and Z gets corrected by
+/// that smallest adjustment instead
+pub fn adjust_steppers<K: Kinematics<Position = Coord>, L: McuLink>(
+    ctx: &mut HomingContext<K, L>,
+    adjustments: &[f64],
+    speed: MillimetersPerSecond,
+) -> Result<(), LevelingError> {
+    let steppers = z_steppers(ctx.kin);
+    if adjustments.len() != steppers.len() {
+        return Err(LevelingError::StepperCount {
+            expected: steppers.len(),
+            found: adjustments.len(),
+        });
+    }
+    ctx.planner.flush();
+    ctx.flush_steps(ctx.planner.print_time())?;
+    for &stepper in &steppers {
+        ctx.kin.steppers_mut()[stepper].set_following(false);
+    }
+    // lowest first, each joining in once the ones below it have caught up
+    let mut offsets: Vec<_> = adjustments.iter().map(|&a| -a).zip(steppers).collect();
+    offsets.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut pos = ctx.planner.position();
+    let moved = adjust_in_turn(ctx, &offsets, pos, speed);
+    for &(_, stepper) in &offsets {
+        ctx.kin.steppers_mut()[stepper].set_following(true);
+    }
+    moved?;
+    pos.z = ctx.planner.position().z + offsets[0].0;
+    ctx.set_position(pos, BitFlags::empty())?;
+    Ok(())
+}
+
+fn adjust_in_turn<K: Kinematics<Position = Coord>, L: McuLink>(
+    ctx: &mut HomingContext<K, L>,
+    offsets: &[(f64, usize)],
+    mut pos: Coord,
+    speed: MillimetersPerSecond,
+) -> Result<(), HomingError> {
+    let z_low = pos.z - offsets[0].0;
+    for pair in offsets.windows(2) {
+        let [(_, stepper), (next_offset, _)] = *pair else {
+            unreachable!();
+        };
+        ctx.kin.steppers_mut()[stepper].set_following(true);
+        pos.z = z_low + next_offset;
+        ctx.move_to(pos, speed)?;
+        ctx.set_position(pos, BitFlags::empty())?;
+    }
+    Ok(())
+}
+
+/// A cartesian printer with a Z stepper at each corner of something that doesn't start
+/// out level, for the solvers to sort out
+#[cfg(test)]
+mod fake_gantry {
+    use std::rc::Rc;
+
+    use crate::homing::{HomingContext, HomingEndstop};
+    use crate::kinematics::cartesian::Cartesian;
+    use crate::kinematics::Axis;
+    use crate::mcu::endstop::Endstop;
+    use crate::mcu::fake::FakeLink;
+    use crate::mcu::pin::PinRef;
+    use crate::mcu::McuLink;
+    use crate::probe::{z_steppers, Probe, ProbeConfig, SwitchProbe};
+    use crate::testutils::FakePrinter;
+    use crate::trapq::Coord;
+    use crate::units::Millimeters;
+
+    pub(super) const Z_STEP: f64 = FakePrinter::Z_STEP;
+    /// Where the toolhead gets declared to be
+    const START: Coord = Coord::new(100.0, 100.0, 10.0);
+
+    /// Gap between nozzle and bed at a spot, given how high each Z stepper has it
+    pub(super) type Gap = fn(&[f64], f64, f64) -> f64;
+
+    pub(super) struct Gantry {
+        pub printer: FakePrinter,
+        /// Where each Z stepper really has things, as far as the MCU has got
+        read_heights: Rc<dyn Fn() -> Vec<f64>>,
+    }
+
+    impl Gantry {
+        pub fn ctx(&mut self) -> HomingContext<'_, Cartesian, FakeLink> {
+            self.printer.ctx()
+        }
+
+        /// Where each Z stepper has things once everything queued has been stepped
+        pub fn heights(&mut self) -> Vec<f64> {
+            let print_time = self.printer.planner.print_time();
+            self.printer.link.run_until(print_time).unwrap();
+            (self.read_heights)()
+        }
+
+        /// Furthest apart the Z steppers are
+        pub fn spread(&mut self) -> f64 {
+            let heights = self.heights();
+            let max = heights.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            let min = heights.iter().copied().fold(f64::INFINITY, f64::min);
+            max - min
+        }
+    }
+
+    /// A Z stepper for each of `errors`, each that far off to start with, and a probe
+    /// 1mm above its trigger point that trips once `gap` closes to that
+    pub(super) fn setup(errors: &[f64], gap: Gap, offsets: (f64, f64)) -> (Gantry, Probe) {
+        let mut printer = FakePrinter::new(errors.len());
+        let position = printer.travel();
+        let errors = errors.to_vec();
+        let heights: Rc<dyn Fn() -> Vec<f64>> = Rc::new({
+            let position = position.clone();
+            move || {
+                let z = errors.iter().enumerate();
+                z.map(|(i, error)| START.z + error + position(i + 2))
+                    .collect()
+            }
+        });
+
+        let link = &mut printer.link;
+        let pin = PinRef {
+            mcu: "mcu".into(),
+            pin: "PB7".into(),
+        };
+        let endstop = Endstop::new(link.mcu("mcu").unwrap(), pin, false, true);
+        let z = heights.clone();
+        link.fake("mcu").set_pin("PB7", move || {
+            let (x, y) = (START.x + position(0), START.y + position(1));
+            gap(&z(), x + offsets.0, y + offsets.1) <= 1.0
+        });
+        let steppers = z_steppers(&printer.kin);
+        let endstop =
+            HomingEndstop::new(&mut printer.link, &printer.kin, "probe", endstop, steppers)
+                .unwrap();
+        let config = ProbeConfig::new(
+            Millimeters(offsets.0),
+            Millimeters(offsets.1),
+            Millimeters(1.0),
+        );
+        let probe = Probe::new(config, endstop, Box::new(SwitchProbe));
+        let mut gantry = Gantry {
+            printer,
+            read_heights: heights,
+        };
+        gantry.ctx().set_position(START, Axis::XYZ).unwrap();
+        (gantry, probe)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fake_gantry::{setup, Z_STEP};
+    use super::*;
+
+    #[test]
+    fn test_adjust_steppers() {
+        let flat = |_: &[f64], _, _| 5.0;
+        let (mut gantry, _) = setup(&[0.0, 0.0, 0.0], flat, (0.0, 0.0));
+        let before = gantry.heights();
+        let speed = MillimetersPerSecond(5.0);
+        adjust_steppers(&mut gantry.ctx(), &[0.3, -0.2, 0.05], speed).unwrap();
+        // each one moves up by how far it's above the lowest, and the toolhead then says
+        // how far the lowest was below where it was thought to be
+        let after = gantry.heights();
+        for ((after, before), expected) in after.iter().zip(before).zip([0.5, 0.0, 0.25]) {
+            assert!(
+                (after - before - expected).abs() <= Z_STEP,
+                "{after} from {before}"
+            );
+        }
+        assert!((gantry.printer.planner.position().z - 10.2).abs() < 1e-9);
+        assert!((gantry.printer.kin.calculate_position().z - 10.2).abs() < 1e-9);
+
+        // and they all move together again afterwards
+        let pos = Coord::new(100.0, 100.0, 11.2);
+        gantry.ctx().move_to(pos, speed).unwrap();
+        for (moved, after) in gantry.heights().iter().zip(after) {
+            assert!(
+                (moved - after - 1.0).abs() <= Z_STEP,
+                "{moved} from {after}"
+            );
+        }
+
+        let err = adjust_steppers(&mut gantry.ctx(), &[0.1], speed);
+        assert!(matches!(
+            err,
+            Err(LevelingError::StepperCount {
+                expected: 3,
+                found: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn test_retries() {
+        let params = LevelingParams::default();
+        let mut retry = RetryHelper::new(0, Millimeters(0.01), &params).unwrap();
+        assert!(!retry.check_retry(&[0.0, 1.0]).unwrap());
+
+        let mut retry = RetryHelper::new(2, Millimeters(0.01), &params).unwrap();
+        assert!(retry.check_retry(&[0.0, 0.5]).unwrap());
+        assert!(retry.check_retry(&[0.0, 0.1]).unwrap());
+        assert!(!retry.check_retry(&[0.0, 0.005]).unwrap());
+
+        let mut retry = RetryHelper::new(2, Millimeters(0.01), &params).unwrap();
+        assert!(retry.check_retry(&[0.0, 0.1]).unwrap());
+        assert!(retry.check_retry(&[0.0, 0.05]).unwrap());
+        assert!(matches!(
+            retry.check_retry(&[0.0, 0.03]),
+            Err(LevelingError::TooManyRetries)
+        ));
+        let mut retry = RetryHelper::new(5, Millimeters(0.01), &params).unwrap();
+        assert!(retry.check_retry(&[0.0, 0.1]).unwrap());
+        assert!(retry.check_retry(&[0.0, 0.2]).unwrap());
+        assert!(matches!(
+            retry.check_retry(&[0.0, 0.3]),
+            Err(LevelingError::RangeIncreasing)
+        ));
+
+        let params = LevelingParams {
+            retries: Some(31),
+            ..LevelingParams::default()
+        };
+        assert!(matches!(
+            RetryHelper::new(0, Millimeters(0.01), &params),
+            Err(LevelingError::InvalidParameter("retries"))
+        ));
+    }
+}
@@ -0,0 +1,245 @@
+//! A gantry hung off a Z stepper at each corner: probe near each corner, run lines
+//! through the heights out to the corners, and move each stepper to meet the average
+
+use super::{adjust_steppers, LevelingError, LevelingParams, RetryHelper};
+use crate::homing::HomingContext;
+use crate::kinematics::Kinematics;
+use crate::mcu::McuLink;
+use crate::probe::{z_steppers, Probe, ProbePoints};
+use crate::trapq::Coord;
+use crate::units::{Millimeters, MillimetersPerSecond};
+
+/// A `[quad_gantry_level]` section
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuadGantryLevelConfig {
+    /// Nozzle positions to probe at, nearest Z, Z1, Z2 and Z3 in that order. The first and
+    /// last share a Y, as do the middle two
+    pub points: [(f64, f64); 4],
+    /// Opposite corners of the gantry where Z and Z2 hold it, Z1 and Z3 being the other two
+    pub gantry_corners: [(f64, f64); 2],
+    pub speed: MillimetersPerSecond,
+    pub horizontal_move_z: Millimeters,
+    /// Most any stepper may be moved in one go
+    pub max_adjust: Millimeters,
+    /// Times to probe and adjust again while the probed heights are further apart than
+    /// `retry_tolerance`
+    pub retries: usize,
+    pub retry_tolerance: Millimeters,
+}
+
+impl QuadGantryLevelConfig {
+    /// Klipper's defaults for everything but the positions
+    pub fn new(points: [(f64, f64); 4], gantry_corners: [(f64, f64); 2]) -> Self {
+        Self {
+            points,
+            gantry_corners,
+            speed: MillimetersPerSecond(50.0),
+            horizontal_move_z: Millimeters(5.0),
+            max_adjust: Millimeters(4.0),
+            retries: 0,
+            retry_tolerance: Millimeters(0.0),
+        }
+    }
+}
+
+/// Slope and intercept of the line through two points
+fn linefit((x1, y1): (f64, f64), (x2, y2): (f64, f64)) -> (f64, f64) {
+    if y1 == y2 {
+        return (0.0, y1);
+    }
+    let m = (y2 - y1) / (x2 - x1);
+    (m, y1 - m * x1)
+}
+
+fn plot((m, b): (f64, f64), x: f64) -> f64 {
+    m * x + b
+}
+
+/// How far each corner needs to move to meet the average, from toolhead `positions` where
+/// a probe at `offsets` triggered, probing down from `horizontal_move_z`
+fn gantry_adjustments(
+    positions: &[Coord],
+    offsets: Coord,
+    horizontal_move_z: Millimeters,
+    [(x0, y0), (x1, y1)]: [(f64, f64); 2],
+) -> [f64; 4] {
+    let x = |i: usize| positions[i].x + offsets.x;
+    let y = |i: usize| positions[i].y + offsets.y;
+    let z = |i: usize| horizontal_move_z.0 - positions[i].z;
+    // along X through the front pair of points and the back pair, then along Y through
+    // those out at either side of the gantry
+    let front = linefit((x(0), z(0)), (x(3), z(3)));
+    let back = linefit((x(1), z(1)), (x(2), z(2)));
+    let left = linefit((y(0), plot(front, x0)), (y(1), plot(back, x0)));
+    let right = linefit((y(0), plot(front, x1)), (y(1), plot(back, x1)));
+    let heights = [
+        plot(left, y0),
+        plot(left, y1),
+        plot(right, y1),
+        plot(right, y0),
+    ];
+    let average = heights.iter().sum::<f64>() / 4.0;
+    heights.map(|z| average - z)
+}
+
+pub struct QuadGantryLevel {
+    config: QuadGantryLevelConfig,
+}
+
+impl QuadGantryLevel {
+    pub fn new(config: QuadGantryLevelConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn config(&self) -> &QuadGantryLevelConfig {
+        &self.config
+    }
+
+    /// `QUAD_GANTRY_LEVEL`: probe, level, and go again for as long as it takes and the
+    /// retries allow. Returns the last adjustments made, Z through Z3
+    pub fn level<K: Kinematics<Position = Coord>, L: McuLink>(
+        &self,
+        ctx: &mut HomingContext<K, L>,
+        probe: &mut Probe,
+        params: &LevelingParams,
+    ) -> Result<[f64; 4], LevelingError> {
+        let found = z_steppers(ctx.kin).len();
+        if found != 4 {
+            return Err(LevelingError::StepperCount { expected: 4, found });
+        }
+        let mut retry = RetryHelper::new(self.config.retries, self.config.retry_tolerance, params)?;
+        probe.begin(ctx)?;
+        let adjustments = self.level_probed(ctx, probe, &mut retry);
+        probe.end(ctx)?;
+        adjustments
+    }
+
+    fn level_probed<K: Kinematics<Position = Coord>, L: McuLink>(
+        &self,
+        ctx: &mut HomingContext<K, L>,
+        probe: &mut Probe,
+        retry: &mut RetryHelper,
+    ) -> Result<[f64; 4], LevelingError> {
+        let probe_points = ProbePoints {
+            points: self.config.points.to_vec(),
+            horizontal_move_z: self.config.horizontal_move_z,
+            speed: self.config.speed,
+            use_offsets: false,
+        };
+        loop {
+            let positions = probe_points.run(ctx, probe)?;
+            let adjustments = gantry_adjustments(
+                &positions,
+                probe.offsets(),
+                self.config.horizontal_move_z,
+                self.config.gantry_corners,
+            );
+            let adjust = adjustments
+                .iter()
+                .copied()
+                .fold(f64::NEG_INFINITY, f64::max);
+            if adjust > self.config.max_adjust.0 {
+                return Err(LevelingError::MaxAdjust {
+                    adjust,
+                    max_adjust: self.config.max_adjust.0,
+                });
+            }
+            adjust_steppers(ctx, &adjustments, probe.config().lift_speed)?;
+            let z: Vec<_> = positions.iter().map(|pos| pos.z).collect();
+            if !retry.check_retry(&z)? {
+                return Ok(adjustments);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fake_gantry::setup;
+    use super::*;
+
+    const CORNERS: [(f64, f64); 2] = [(-40.0, -10.0), (240.0, 210.0)];
+
+    /// Gantry sitting on the four corners, Z at the front left going round clockwise
+    fn tilted_gantry(heights: &[f64], x: f64, y: f64) -> f64 {
+        let [(x0, y0), (x1, y1)] = CORNERS;
+        let u = (x - x0) / (x1 - x0);
+        let v = (y - y0) / (y1 - y0);
+        heights[0] * (1.0 - u) * (1.0 - v)
+            + heights[1] * (1.0 - u) * v
+            + heights[2] * u * v
+            + heights[3] * u * (1.0 - v)
+    }
+
+    fn config() -> QuadGantryLevelConfig {
+        let points = [(30.0, 20.0), (30.0, 170.0), (170.0, 170.0), (170.0, 20.0)];
+        let mut config = QuadGantryLevelConfig::new(points, CORNERS);
+        config.retries = 3;
+        config.retry_tolerance = Millimeters(0.0075);
+        config
+    }
+
+    #[test]
+    fn test_gantry_adjustments() {
+        // the corners' heights come straight back out of the probed ones
+        let corners = [0.2, -0.1, 0.3, 0.0];
+        let offsets = Coord::new(0.0, 10.0, 1.0);
+        let positions: Vec<_> = config()
+            .points
+            .into_iter()
+            .map(|(x, y)| {
+                let gap = tilted_gantry(&corners, x + offsets.x, y + offsets.y);
+                Coord::new(x, y, offsets.z - gap)
+            })
+            .collect();
+        let adjustments = gantry_adjustments(&positions, offsets, Millimeters(5.0), CORNERS);
+        for (adjustment, corner) in adjustments.iter().zip(corners) {
+            assert!((adjustment - (0.1 - corner)).abs() < 1e-9, "{adjustment}");
+        }
+    }
+
+    #[test]
+    fn test_quad_gantry_level() {
+        let errors = [0.4, -0.3, 0.2, -0.1];
+        let (mut gantry, mut probe) = setup(&errors, tilted_gantry, (0.0, 10.0));
+        let qgl = QuadGantryLevel::new(config());
+        let params = LevelingParams::default();
+        qgl.level(&mut gantry.ctx(), &mut probe, &params).unwrap();
+        assert!(gantry.spread() < 0.0075 * 2.0, "{}", gantry.spread());
+
+        // already level, so nothing much left to do
+        let params = LevelingParams {
+            retries: Some(0),
+            ..LevelingParams::default()
+        };
+        let adjustments = qgl.level(&mut gantry.ctx(), &mut probe, &params).unwrap();
+        assert!(
+            adjustments.iter().all(|a| a.abs() < 0.01),
+            "{adjustments:?}"
+        );
+    }
+
+    #[test]
+    fn test_quad_gantry_level_errors() {
+        let errors = [2.0, -2.0, 0.0, 0.0];
+        let (mut gantry, mut probe) = setup(&errors, tilted_gantry, (0.0, 10.0));
+        let mut config = config();
+        config.max_adjust = Millimeters(1.0);
+        let qgl = QuadGantryLevel::new(config);
+        let err = qgl.level(&mut gantry.ctx(), &mut probe, &LevelingParams::default());
+        assert!(matches!(err, Err(LevelingError::MaxAdjust { .. })));
+        // nothing got moved
+        let heights = gantry.heights();
+        assert!((heights[0] - heights[1] - 4.0).abs() < 1e-9);
+
+        let (mut gantry, mut probe) = setup(&[0.0; 3], tilted_gantry, (0.0, 10.0));
+        let err = qgl.level(&mut gantry.ctx(), &mut probe, &LevelingParams::default());
+        assert!(matches!(
+            err,
+            Err(LevelingError::StepperCount {
+                expected: 4,
+                found: 3
+            })
+        ));
+    }
+}
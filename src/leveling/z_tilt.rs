@@ -0,0 +1,204 @@
+//! A bed held up by independent Z steppers at a few pivots: fit a plane to the probed
+//! heights and move each stepper by the plane's height over its pivot
+
+use super::{adjust_steppers, LevelingError, LevelingParams, RetryHelper};
+use crate::homing::HomingContext;
+use crate::kinematics::Kinematics;
+use crate::mathutil::coordinate_descent;
+use crate::mcu::McuLink;
+use crate::probe::{z_steppers, Probe, ProbePoints};
+use crate::trapq::Coord;
+use crate::units::{Millimeters, MillimetersPerSecond};
+
+/// A `[z_tilt]` section
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZTiltConfig {
+    /// Where each Z stepper holds the bed, in stepper order
+    pub z_positions: Vec<(f64, f64)>,
+    /// Nozzle positions to probe at
+    pub points: Vec<(f64, f64)>,
+    pub speed: MillimetersPerSecond,
+    pub horizontal_move_z: Millimeters,
+    /// Times to probe and adjust again while the probed heights are further apart than
+    /// `retry_tolerance`
+    pub retries: usize,
+    pub retry_tolerance: Millimeters,
+}
+
+impl ZTiltConfig {
+    /// Klipper's defaults for everything but the positions
+    pub fn new(z_positions: Vec<(f64, f64)>, points: Vec<(f64, f64)>) -> Self {
+        Self {
+            z_positions,
+            points,
+            speed: MillimetersPerSecond(50.0),
+            horizontal_move_z: Millimeters(5.0),
+            retries: 0,
+            retry_tolerance: Millimeters(0.0),
+        }
+    }
+}
+
+/// Bed height over each of `z_positions`, from toolhead `positions` where a probe at
+/// `offsets` triggered
+fn tilt_adjustments(positions: &[Coord], offsets: Coord, z_positions: &[(f64, f64)]) -> Vec<f64> {
+    // least squares plane z = x * x_adjust + y * y_adjust + z_adjust
+    let mut params = [0.0, 0.0, offsets.z];
+    coordinate_descent(&mut params, |p| {
+        positions
+            .iter()
+            .map(|pos| (pos.z - pos.x * p[0] - pos.y * p[1] - p[2]).powi(2))
+            .sum()
+    });
+    let [x_adjust, y_adjust, z_adjust] = params;
+    // moved over from toolhead positions to where the probe was
+    let z_adjust = z_adjust - offsets.z - x_adjust * offsets.x - y_adjust * offsets.y;
+    z_positions
+        .iter()
+        .map(|&(x, y)| x * x_adjust + y * y_adjust + z_adjust)
+        .collect()
+}
+
+pub struct ZTilt {
+    config: ZTiltConfig,
+}
+
+impl ZTilt {
+    pub fn new(config: ZTiltConfig) -> Result<Self, LevelingError> {
+        if config.points.len() < 2 {
+            return Err(LevelingError::TooFewPoints(2));
+        }
+        Ok(Self { config })
+    }
+
+    pub fn config(&self) -> &ZTiltConfig {
+        &self.config
+    }
+
+    /// `Z_TILT_ADJUST`: probe, level, and go again for as long as it takes and the retries
+    /// allow. Returns the last adjustments made, one per Z stepper
+    pub fn adjust<K: Kinematics<Position = Coord>, L: McuLink>(
+        &self,
+        ctx: &mut HomingContext<K, L>,
+        probe: &mut Probe,
+        params: &LevelingParams,
+    ) -> Result<Vec<f64>, LevelingError> {
+        let expected = z_steppers(ctx.kin).len();
+        if self.config.z_positions.len() != expected {
+            return Err(LevelingError::StepperCount {
+                expected,
+                found: self.config.z_positions.len(),
+            });
+        }
+        let mut retry = RetryHelper::new(self.config.retries, self.config.retry_tolerance, params)?;
+        probe.begin(ctx)?;
+        let adjustments = self.level(ctx, probe, &mut retry);
+        probe.end(ctx)?;
+        adjustments
+    }
+
+    fn level<K: Kinematics<Position = Coord>, L: McuLink>(
+        &self,
+        ctx: &mut HomingContext<K, L>,
+        probe: &mut Probe,
+        retry: &mut RetryHelper,
+    ) -> Result<Vec<f64>, LevelingError> {
+        let probe_points = ProbePoints {
+            points: self.config.points.clone(),
+            horizontal_move_z: self.config.horizontal_move_z,
+            speed: self.config.speed,
+            use_offsets: false,
+        };
+        loop {
+            let positions = probe_points.run(ctx, probe)?;
+            let adjustments =
+                tilt_adjustments(&positions, probe.offsets(), &self.config.z_positions);
+            adjust_steppers(ctx, &adjustments, probe.config().lift_speed)?;
+            let z: Vec<_> = positions.iter().map(|pos| pos.z).collect();
+            if !retry.check_retry(&z)? {
+                return Ok(adjustments);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::fake_gantry::{setup, Z_STEP};
+    use super::*;
+
+    const PIVOTS: [(f64, f64); 3] = [(-20.0, 10.0), (100.0, 230.0), (220.0, 10.0)];
+
+    /// Flat bed resting on the three pivots
+    fn tilted_bed(heights: &[f64], x: f64, y: f64) -> f64 {
+        let [(x1, y1), (x2, y2), (x3, y3)] = PIVOTS;
+        let det = (y2 - y3) * (x1 - x3) + (x3 - x2) * (y1 - y3);
+        let l1 = ((y2 - y3) * (x - x3) + (x3 - x2) * (y - y3)) / det;
+        let l2 = ((y3 - y1) * (x - x3) + (x1 - x3) * (y - y3)) / det;
+        l1 * heights[0] + l2 * heights[1] + (1.0 - l1 - l2) * heights[2]
+    }
+
+    fn config() -> ZTiltConfig {
+        let points = vec![(30.0, 20.0), (100.0, 170.0), (170.0, 20.0)];
+        let mut config = ZTiltConfig::new(PIVOTS.to_vec(), points);
+        config.retries = 3;
+        config.retry_tolerance = Millimeters(0.01);
+        config
+    }
+
+    #[test]
+    fn test_tilt_adjustments() {
+        // probe 10mm behind the nozzle, triggering 1mm above a bed sloping up to the back
+        let bed = |x: f64, y: f64| 0.002 * x + 0.004 * y - 0.1;
+        let positions: Vec<_> = [(30.0, 20.0), (100.0, 170.0), (170.0, 20.0), (50.0, 90.0)]
+            .into_iter()
+            .map(|(x, y)| Coord::new(x, y, bed(x, y + 10.0) + 1.0))
+            .collect();
+        let adjustments = tilt_adjustments(&positions, Coord::new(0.0, 10.0, 1.0), &PIVOTS);
+        for (adjustment, (x, y)) in adjustments.iter().zip(PIVOTS) {
+            assert!(
+                (adjustment - bed(x, y)).abs() < 1e-4,
+                "{adjustment} at {x},{y}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_z_tilt_adjust() {
+        let (mut gantry, mut probe) = setup(&[0.3, -0.2, 0.1], tilted_bed, (0.0, 10.0));
+        let z_tilt = ZTilt::new(config()).unwrap();
+        let params = LevelingParams::default();
+        let adjustments = z_tilt
+            .adjust(&mut gantry.ctx(), &mut probe, &params)
+            .unwrap();
+        assert_eq!(adjustments.len(), 3);
+
+        // level, and with Z saying how far the nozzle really is above the bed
+        let z = gantry.printer.planner.position().z;
+        for height in gantry.heights() {
+            assert!((height - z).abs() < 4.0 * Z_STEP, "{height} with Z at {z}");
+        }
+        assert!(gantry.spread() < 0.01);
+    }
+
+    #[test]
+    fn test_z_tilt_errors() {
+        let (mut gantry, mut probe) = setup(&[0.0, 0.0], tilted_bed, (0.0, 10.0));
+        let z_tilt = ZTilt::new(config()).unwrap();
+        let err = z_tilt.adjust(&mut gantry.ctx(), &mut probe, &LevelingParams::default());
+        assert!(matches!(
+            err,
+            Err(LevelingError::StepperCount {
+                expected: 2,
+                found: 3
+            })
+        ));
+
+        let mut config = config();
+        config.points.truncate(1);
+        assert!(matches!(
+            ZTilt::new(config),
+            Err(LevelingError::TooFewPoints(2))
+        ));
+    }
+}
@@ -1,32 +1,110 @@
-use std::fmt::Display;
-use std::marker::PhantomData;
+use std::fmt::{self, Display};
 
+mod bed_mesh;
 mod cli;
 mod data;
+mod delta_calibrate;
 mod ffi;
+mod gcode;
+mod heaters;
+mod homing;
 mod kinematics;
+mod leveling;
+mod mathutil;
 mod mcu;
 mod msgblock;
+mod planner;
+mod probe;
+mod sensors;
+mod shaper;
+mod skew_correction;
+mod stepcompress;
 #[cfg(test)]
 mod testutils;
+mod trapq;
 pub mod proto;
+pub mod units;
 //mod serialqueue;
 
-// TODO! make the unit system based on a feature?
-//pub type Meter = dimensioned::si::Meter<f32>;
-//pub type MeterPerSecond = dimensioned::si::MeterPerSecond<f32>;
-#[repr(transparent)]
-#[derive(derive_more::From)]
-pub struct Millimeters(f32);
-#[repr(transparent)]
-#[derive(derive_more::From)]
-pub struct MillimetersPerSecond(f32);
+pub use units::{Millimeters, MillimetersPerSecond};
 
 pub trait PrinterState: Display {}
 
 pub struct Printer<P: PrinterState> {
-    state: PhantomData<P>,
+    state: P,
 }
 
 pub struct Startup;
+pub struct Ready;
+/// Something went wrong and everything got turned off
+pub struct Shutdown {
+    reason: String,
+}
 pub struct Halted;
+
+impl Display for Startup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Printer is not ready")
+    }
+}
+
+impl Display for Ready {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Printer is ready")
+    }
+}
+
+impl Display for Shutdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Printer is shutdown: {}", self.reason)
+    }
+}
+
+impl Display for Halted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Printer is halted")
+    }
+}
+
+impl PrinterState for Startup {}
+impl PrinterState for Ready {}
+impl PrinterState for Shutdown {}
+impl PrinterState for Halted {}
+
+impl Default for Printer<Startup> {
+    fn default() -> Self {
+        Self { state: Startup }
+    }
+}
+
+impl Printer<Startup> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Everything is configured and connected
+    pub fn ready(self) -> Printer<Ready> {
+        Printer { state: Ready }
+    }
+}
+
+impl<P: PrinterState> Printer<P> {
+    pub fn state(&self) -> &P {
+        &self.state
+    }
+
+    /// Stop everything. There's no coming back from this without a restart
+    pub fn shutdown(self, reason: impl Display) -> Printer<Shutdown> {
+        Printer {
+            state: Shutdown {
+                reason: reason.to_string(),
+            },
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
@@ -0,0 +1,110 @@
+//! Bits of maths shared between kinematics and calibration, klipper's `mathutil.py`
+
+use crate::trapq::Coord;
+
+/// Smallest total step size worth carrying on for
+const DESCENT_THRESHOLD: f64 = 0.00001;
+const DESCENT_MAX_ROUNDS: usize = 10_000;
+
+/// Minimise `error_func` by nudging one parameter at a time, growing the nudge while it
+/// helps and shrinking it when it doesn't. Returns the best error found
+pub fn coordinate_descent(params: &mut [f64], error_func: impl Fn(&[f64]) -> f64) -> f64 {
+    let mut dp = vec![1.0; params.len()];
+    let mut best_err = error_func(params);
+    let mut rounds = 0;
+    while dp.iter().sum::<f64>() > DESCENT_THRESHOLD && rounds < DESCENT_MAX_ROUNDS {
+        rounds += 1;
+        for i in 0..params.len() {
+            let orig = params[i];
+            let improved = [orig + dp[i], orig - dp[i]].into_iter().any(|value| {
+                params[i] = value;
+                let err = error_func(params);
+                if err < best_err {
+                    best_err = err;
+                    true
+                } else {
+                    false
+                }
+            });
+            if improved {
+                dp[i] *= 1.1;
+            } else {
+                params[i] = orig;
+                dp[i] *= 0.9;
+            }
+        }
+    }
+    best_err
+}
+
+/// Where three spheres meet. There are two answers mirrored through the plane of the
+/// centres, klipper's comes first: on the side away from `(c2 - c1) x (c3 - c1)`.
+/// `None` if the spheres don't all meet
+pub fn trilateration(centres: [Coord; 3], radius2: [f64; 3]) -> Option<(Coord, Coord)> {
+    let (in_plane, ez, z2) = trilateration_parts(centres, radius2);
+    if z2.is_nan() || z2 < 0.0 {
+        return None;
+    }
+    let z = z2.sqrt();
+    Some((in_plane - ez * z, in_plane + ez * z))
+}
+
+/// Same again, except spheres that don't all meet get the closest point in the plane of
+/// the centres, for when there has to be an answer
+pub fn closest_trilateration(centres: [Coord; 3], radius2: [f64; 3]) -> (Coord, Coord) {
+    let (in_plane, ez, z2) = trilateration_parts(centres, radius2);
+    let z = z2.max(0.0).sqrt();
+    (in_plane - ez * z, in_plane + ez * z)
+}
+
+/// Where the answers are in the plane of the centres, the normal to it, and the square
+/// of how far the answers are from it either side
+fn trilateration_parts(centres: [Coord; 3], radius2: [f64; 3]) -> (Coord, Coord, f64) {
+    let [c1, c2, c3] = centres;
+    let s21 = c2 - c1;
+    let s31 = c3 - c1;
+    let d = s21.norm();
+    let ex = s21 * (1.0 / d);
+    let i = ex.dot(s31);
+    let vect_ey = s31 - ex * i;
+    let ey = vect_ey * (1.0 / vect_ey.norm());
+    let ez = ex.cross(ey);
+    let j = ey.dot(s31);
+    let x = (radius2[0] - radius2[1] + d * d) / (2.0 * d);
+    let y = (radius2[0] - radius2[2] - x * x + (x - i).powi(2) + j * j) / (2.0 * j);
+    (c1 + ex * x + ey * y, ez, radius2[0] - x * x - y * y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coordinate_descent() {
+        let mut params = [0.0, 0.0];
+        let err = coordinate_descent(&mut params, |p| (p[0] - 3.0).powi(2) + (p[1] + 1.5).powi(2));
+        assert!(err < 1e-8);
+        assert!((params[0] - 3.0).abs() < 1e-4 && (params[1] + 1.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_trilateration() {
+        let centres = [
+            Coord::new(0.0, 0.0, 0.0),
+            Coord::new(10.0, 0.0, 0.0),
+            Coord::new(0.0, 10.0, 0.0),
+        ];
+        let point = Coord::new(2.0, 3.0, -4.0);
+        let radius2 = centres.map(|c| (point - c).dot(point - c));
+        let (first, mirror) = trilateration(centres, radius2).unwrap();
+        assert!((first - point).norm() < 1e-9);
+        assert!((mirror - Coord::new(2.0, 3.0, 4.0)).norm() < 1e-9);
+        assert_eq!(closest_trilateration(centres, radius2), (first, mirror));
+
+        // too short to meet, so only the closest point will do
+        assert_eq!(trilateration(centres, [1.0, 1.0, 1.0]), None);
+        let (below, above) = closest_trilateration(centres, [1.0, 1.0, 1.0]);
+        assert_eq!(below, above);
+        assert!((below - Coord::new(5.0, 5.0, 0.0)).norm() < 1e-9);
+    }
+}
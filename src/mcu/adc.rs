@@ -0,0 +1,222 @@
+//! Analog inputs, mirroring klipper's `MCU_adc`
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crossbeam::channel::{unbounded, Receiver};
+
+use super::pin::PinRef;
+use super::{clock32_to_clock64, Command, Error, Mcu, Oid, Response};
+use crate::units::{Seconds, Ticks};
+
+/// How the MCU should sample and report an analog pin
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdcConfig {
+    /// Time spent on each individual sample
+    pub sample_time: Seconds,
+    /// Samples summed into each report
+    pub sample_count: u8,
+    /// Time between reports
+    pub report_time: Seconds,
+    /// Lowest acceptable reading, normalised to `0.0..=1.0`
+    pub min_value: f64,
+    /// Highest acceptable reading, normalised to `0.0..=1.0`
+    pub max_value: f64,
+    /// Consecutive out of range reports before the MCU shuts down. Zero disables the check
+    pub range_check_count: u8,
+}
+
+impl Default for AdcConfig {
+    /// Klipper's defaults for temperature sensors
+    fn default() -> Self {
+        Self {
+            sample_time: Seconds(0.001),
+            sample_count: 8,
+            report_time: Seconds(0.300),
+            min_value: 0.0,
+            max_value: 1.0,
+            range_check_count: 4,
+        }
+    }
+}
+
+/// One report from the MCU
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdcReading {
+    /// Print time the last sample of the report was taken
+    pub time: Seconds,
+    /// Reading normalised to `0.0..=1.0` of the ADC's full scale
+    pub value: f64,
+}
+
+/// An analog pin the MCU reports on periodically
+pub struct Adc {
+    oid: Oid,
+    pin: PinRef,
+    last: Rc<Cell<Option<AdcReading>>>,
+}
+
+impl Adc {
+    /// Configure the pin on `mcu` and hand every reading to `callback`
+    pub fn new(
+        mcu: &mut Mcu,
+        pin: PinRef,
+        config: AdcConfig,
+        mut callback: impl FnMut(AdcReading) + 'static,
+    ) -> Result<Self, Error> {
+        let oid = mcu.create_oid();
+        let max_adc = f64::from(config.sample_count) * mcu.constant("ADC_MAX")?;
+        let sample_ticks = mcu.seconds_to_clock(config.sample_time);
+        let report_ticks = mcu.seconds_to_clock(config.report_time);
+        let to_wire = |v: f64| (v * max_adc).clamp(0.0, f64::from(u16::MAX));
+
+        mcu.add_config_cmd(Command::ConfigAnalogIn {
+            oid,
+            pin: pin.clone(),
+        });
+        mcu.add_config_cmd(Command::QueryAnalogIn {
+            oid,
+            clock: mcu.query_slot(oid),
+            sample_ticks,
+            sample_count: config.sample_count,
+            rest_ticks: report_ticks,
+            min_value: to_wire(config.min_value).floor() as u16,
+            max_value: to_wire(config.max_value).ceil() as u16,
+            range_check_count: config.range_check_count,
+        });
+
+        let last = Rc::new(Cell::new(None));
+        let freq = mcu.clock_freq();
+        let mut last_clock = Ticks(0);
+        let mut out_of_range = 0;
+        mcu.register_response(
+            oid,
+            Box::new({
+                let last = last.clone();
+                move |response| {
+                    let (next_clock, value) = match response {
+                        Response::AnalogInState {
+                            next_clock, value, ..
+                        } => (*next_clock, *value),
+                        _ => return Ok(()),
+                    };
+                    // the report was taken one period before the next one is due
+                    last_clock = clock32_to_clock64(last_clock, next_clock);
+                    let read_clock = Ticks(last_clock.0.saturating_sub(report_ticks.0));
+                    let reading = AdcReading {
+                        time: read_clock.to_seconds(freq),
+                        value: f64::from(value) / max_adc,
+                    };
+
+                    // the MCU enforces this too, but don't trust it with a heater
+                    if (config.min_value..=config.max_value).contains(&reading.value) {
+                        out_of_range = 0;
+                    } else if config.range_check_count > 0 {
+                        out_of_range += 1;
+                        if out_of_range >= config.range_check_count {
+                            return Err(Error::AdcOutOfRange);
+                        }
+                    }
+
+                    last.set(Some(reading));
+                    callback(reading);
+                    Ok(())
+                }
+            }),
+        );
+        Ok(Self { oid, pin, last })
+    }
+
+    /// Same as [`Adc::new`], but readings come out of a channel instead of a callback
+    pub fn with_channel(
+        mcu: &mut Mcu,
+        pin: PinRef,
+        config: AdcConfig,
+    ) -> Result<(Self, Receiver<AdcReading>), Error> {
+        let (tx, rx) = unbounded();
+        let adc = Self::new(mcu, pin, config, move |reading| {
+            // nobody listening is their problem, not ours
+            let _ = tx.send(reading);
+        })?;
+        Ok((adc, rx))
+    }
+
+    pub fn oid(&self) -> Oid {
+        self.oid
+    }
+
+    pub fn pin(&self) -> &PinRef {
+        &self.pin
+    }
+
+    /// Most recent reading, if the MCU has reported yet
+    pub fn last_reading(&self) -> Option<AdcReading> {
+        self.last.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::Hertz;
+
+    #[test]
+    fn test_adc_reports() {
+        let mut mcu = Mcu::new("mcu", Hertz(1_000_000.0));
+        mcu.set_constant("ADC_MAX", 4095.0);
+        let pin = PinRef {
+            mcu: "mcu".into(),
+            pin: "PA0".into(),
+        };
+        let config = AdcConfig {
+            min_value: 0.1,
+            max_value: 0.9,
+            range_check_count: 2,
+            ..Default::default()
+        };
+        let (adc, readings) = Adc::with_channel(&mut mcu, pin, config).unwrap();
+        match &mcu.config_cmds()[1] {
+            Command::QueryAnalogIn {
+                min_value,
+                max_value,
+                rest_ticks,
+                ..
+            } => {
+                assert_eq!(*min_value, 3276);
+                assert_eq!(*max_value, 29484);
+                assert_eq!(*rest_ticks, Ticks(300_000));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let report = |next_clock, value| Response::AnalogInState {
+            oid: adc.oid(),
+            next_clock,
+            value,
+        };
+        mcu.handle_response(&report(1_300_000, 16380)).unwrap();
+        let reading = readings.try_recv().unwrap();
+        assert_eq!(reading.time, Seconds(1.0));
+        assert_eq!(reading.value, 0.5);
+        assert_eq!(adc.last_reading(), Some(reading));
+
+        // a single excursion is tolerated, two in a row is not
+        mcu.handle_response(&report(1_600_000, 100)).unwrap();
+        mcu.handle_response(&report(1_900_000, 16380)).unwrap();
+        mcu.handle_response(&report(2_200_000, 100)).unwrap();
+        assert!(matches!(
+            mcu.handle_response(&report(2_500_000, 100)),
+            Err(Error::AdcOutOfRange)
+        ));
+    }
+
+    #[test]
+    fn test_clock_wrap() {
+        let recent = Ticks((1 << 32) - 10);
+        assert_eq!(clock32_to_clock64(recent, 5), Ticks((1 << 32) + 5));
+        assert_eq!(
+            clock32_to_clock64(Ticks(1 << 32), u32::MAX),
+            Ticks((1 << 32) - 1)
+        );
+    }
+}
@@ -0,0 +1,338 @@
+//! Endstops, and the trigger syncs that stop steppers the moment one trips, mirroring
+//! klipper's `MCU_endstop`, `MCU_trsync` and `TriggerDispatch`
+//!
+//! Every MCU with a stepper to stop gets a trsync. The endstop trips the one on its own
+//! MCU, and the host passes the news on to the rest
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use super::pin::PinRef;
+use super::{clock32_to_clock64, Command, Error, Mcu, McuLink, Oid, Response};
+use crate::units::{Seconds, Ticks};
+
+/// How long an MCU carries on without hearing from the host before it gives up homing
+pub const TRSYNC_TIMEOUT: Seconds = Seconds(0.025);
+/// With only one MCU involved, the host isn't relaying anything in a hurry
+pub const TRSYNC_SINGLE_MCU_TIMEOUT: Seconds = Seconds(0.250);
+
+/// Why a trsync triggered
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriggerReason {
+    EndstopHit = 1,
+    HostRequest = 2,
+    PastEndTime = 3,
+    CommsTimeout = 4,
+}
+
+impl TriggerReason {
+    pub fn from_wire(reason: u8) -> Option<Self> {
+        match reason {
+            1 => Some(Self::EndstopHit),
+            2 => Some(Self::HostRequest),
+            3 => Some(Self::PastEndTime),
+            4 => Some(Self::CommsTimeout),
+            _ => None,
+        }
+    }
+}
+
+/// One MCU's part in a homing move
+pub struct Trsync {
+    mcu: String,
+    oid: Oid,
+    /// Steppers on this MCU to stop when it triggers
+    steppers: Vec<Oid>,
+    /// Why it triggered, once it has. Unknown reasons count as a timeout
+    triggered: Rc<Cell<Option<TriggerReason>>>,
+}
+
+impl Trsync {
+    pub fn new(mcu: &mut Mcu) -> Self {
+        let oid = mcu.create_oid();
+        mcu.add_config_cmd(Command::ConfigTrsync { oid });
+        let triggered = Rc::new(Cell::new(None));
+        mcu.register_response(
+            oid,
+            Box::new({
+                let triggered = triggered.clone();
+                move |response| {
+                    if let Response::TrsyncState {
+                        can_trigger: 0,
+                        trigger_reason,
+                        ..
+                    } = *response
+                    {
+                        let reason = TriggerReason::from_wire(trigger_reason)
+                            .unwrap_or(TriggerReason::CommsTimeout);
+                        triggered.set(Some(reason));
+                    }
+                    Ok(())
+                }
+            }),
+        );
+        Self {
+            mcu: mcu.name().into(),
+            oid,
+            steppers: Vec::new(),
+            triggered,
+        }
+    }
+
+    pub fn oid(&self) -> Oid {
+        self.oid
+    }
+
+    pub fn triggered(&self) -> Option<TriggerReason> {
+        self.triggered.get()
+    }
+
+    /// Arm at `print_time`, giving up unless told otherwise within `expire_timeout`
+    fn start(&self, mcu: &mut Mcu, print_time: Seconds, expire_timeout: Seconds) {
+        self.triggered.set(None);
+        let clock = mcu.print_time_to_clock(print_time);
+        mcu.send(Command::TrsyncStart {
+            oid: self.oid,
+            report_clock: clock,
+            report_ticks: mcu.seconds_to_clock(expire_timeout * 0.3),
+            expire_reason: TriggerReason::CommsTimeout as u8,
+        });
+        for &stepper in &self.steppers {
+            mcu.send(Command::StepperStopOnTrigger {
+                oid: stepper,
+                trsync_oid: self.oid,
+            });
+        }
+        let expire_ticks = mcu.seconds_to_clock(expire_timeout);
+        mcu.send(Command::TrsyncSetTimeout {
+            oid: self.oid,
+            clock: Ticks(clock.0 + expire_ticks.0),
+        });
+    }
+
+    fn trigger(&self, mcu: &mut Mcu, reason: TriggerReason) {
+        mcu.send(Command::TrsyncTrigger {
+            oid: self.oid,
+            reason: reason as u8,
+        });
+    }
+}
+
+/// Every trsync one endstop needs, its own MCU's first
+pub struct TriggerDispatch {
+    trsyncs: Vec<Trsync>,
+    expire_timeout: Seconds,
+}
+
+impl TriggerDispatch {
+    fn new(mcu: &mut Mcu) -> Self {
+        Self {
+            trsyncs: vec![Trsync::new(mcu)],
+            expire_timeout: TRSYNC_SINGLE_MCU_TIMEOUT,
+        }
+    }
+
+    /// Stop `stepper` on `mcu` when triggered
+    fn add_stepper(&mut self, mcu: &mut Mcu, stepper: Oid) {
+        let index = match self.trsyncs.iter().position(|t| t.mcu == mcu.name()) {
+            Some(index) => index,
+            None => {
+                self.trsyncs.push(Trsync::new(mcu));
+                self.trsyncs.len() - 1
+            }
+        };
+        self.trsyncs[index].steppers.push(stepper);
+    }
+
+    fn start<L: McuLink>(&mut self, link: &mut L, print_time: Seconds) -> Result<(), Error> {
+        self.expire_timeout = if self.trsyncs.len() == 1 {
+            TRSYNC_SINGLE_MCU_TIMEOUT
+        } else {
+            TRSYNC_TIMEOUT
+        };
+        for trsync in &self.trsyncs {
+            trsync.start(link.mcu(&trsync.mcu)?, print_time, self.expire_timeout);
+        }
+        Ok(())
+    }
+
+    /// Catch up once the MCUs have run to `print_time`: pass a trigger on to every other
+    /// MCU, or if nothing's triggered, stop them timing out. Returns whether triggered
+    fn poll<L: McuLink>(&self, link: &mut L, print_time: Seconds) -> Result<bool, Error> {
+        let reason = self.trsyncs.iter().find_map(Trsync::triggered);
+        for trsync in &self.trsyncs {
+            let mcu = link.mcu(&trsync.mcu)?;
+            match reason {
+                Some(_) if trsync.triggered().is_some() => {}
+                Some(reason) => trsync.trigger(mcu, reason),
+                None => mcu.send(Command::TrsyncSetTimeout {
+                    oid: trsync.oid,
+                    clock: mcu.print_time_to_clock(print_time + self.expire_timeout),
+                }),
+            }
+        }
+        Ok(reason.is_some())
+    }
+
+    /// Disarm every trsync, returning why the endstop's own triggered
+    fn stop<L: McuLink>(&self, link: &mut L, print_time: Seconds) -> Result<TriggerReason, Error> {
+        for trsync in &self.trsyncs {
+            trsync.trigger(link.mcu(&trsync.mcu)?, TriggerReason::HostRequest);
+        }
+        link.run_until(print_time)?;
+        let reasons: Vec<_> = self.trsyncs.iter().map(Trsync::triggered).collect();
+        if reasons
+            .iter()
+            .any(|r| matches!(r, None | Some(TriggerReason::CommsTimeout)))
+        {
+            return Ok(TriggerReason::CommsTimeout);
+        }
+        Ok(reasons[0].unwrap_or(TriggerReason::CommsTimeout))
+    }
+}
+
+/// Last `endstop_state` report
+#[derive(Clone, Copy, Debug)]
+struct EndstopState {
+    next_clock: u32,
+    pin_value: bool,
+}
+
+/// A switch that stops steppers when it trips
+pub struct Endstop {
+    mcu: String,
+    oid: Oid,
+    invert: bool,
+    dispatch: TriggerDispatch,
+    /// Time between checks of the pin, during the current homing move
+    rest_ticks: Ticks,
+    state: Rc<Cell<Option<EndstopState>>>,
+}
+
+impl Endstop {
+    pub fn new(mcu: &mut Mcu, pin: PinRef, invert: bool, pull_up: bool) -> Self {
+        let oid = mcu.create_oid();
+        mcu.add_config_cmd(Command::ConfigEndstop {
+            oid,
+            pin,
+            pull_up: u8::from(pull_up),
+        });
+        let dispatch = TriggerDispatch::new(mcu);
+        let state = Rc::new(Cell::new(None));
+        mcu.register_response(
+            oid,
+            Box::new({
+                let state = state.clone();
+                move |response| {
+                    if let Response::EndstopState {
+                        next_clock,
+                        pin_value,
+                        ..
+                    } = *response
+                    {
+                        state.set(Some(EndstopState {
+                            next_clock,
+                            pin_value: pin_value != 0,
+                        }));
+                    }
+                    Ok(())
+                }
+            }),
+        );
+        Self {
+            mcu: mcu.name().into(),
+            oid,
+            invert,
+            dispatch,
+            rest_ticks: Ticks(0),
+            state,
+        }
+    }
+
+    pub fn mcu(&self) -> &str {
+        &self.mcu
+    }
+
+    /// Stop `stepper`, on `mcu`, when the endstop trips
+    pub fn add_stepper(&mut self, mcu: &mut Mcu, stepper: Oid) {
+        self.dispatch.add_stepper(mcu, stepper);
+    }
+
+    /// From `print_time`, check the pin every `rest_time` until it reads `triggered`
+    /// `sample_count` times in a row, `sample_time` apart
+    pub fn home_start<L: McuLink>(
+        &mut self,
+        link: &mut L,
+        print_time: Seconds,
+        sample_time: Seconds,
+        sample_count: u8,
+        rest_time: Seconds,
+        triggered: bool,
+    ) -> Result<(), Error> {
+        self.dispatch.start(link, print_time)?;
+        let mcu = link.mcu(&self.mcu)?;
+        let clock = mcu.print_time_to_clock(print_time);
+        self.rest_ticks = Ticks(mcu.print_time_to_clock(print_time + rest_time).0 - clock.0);
+        mcu.send(Command::EndstopHome {
+            oid: self.oid,
+            clock,
+            sample_ticks: mcu.seconds_to_clock(sample_time),
+            sample_count,
+            rest_ticks: self.rest_ticks,
+            pin_value: u8::from(triggered ^ self.invert),
+            trsync_oid: self.dispatch.trsyncs[0].oid(),
+            trigger_reason: TriggerReason::EndstopHit as u8,
+        });
+        Ok(())
+    }
+
+    /// Keep homing going once the MCUs have run to `print_time`. Returns whether it's all
+    /// over, for one reason or another
+    pub fn poll<L: McuLink>(&self, link: &mut L, print_time: Seconds) -> Result<bool, Error> {
+        self.dispatch.poll(link, print_time)
+    }
+
+    /// Stop homing with the MCUs at `print_time`. Returns when the endstop tripped, if it did
+    pub fn home_wait<L: McuLink>(
+        &mut self,
+        link: &mut L,
+        print_time: Seconds,
+    ) -> Result<Option<Seconds>, Error> {
+        link.mcu(&self.mcu)?.send(Command::EndstopHome {
+            oid: self.oid,
+            clock: Ticks(0),
+            sample_ticks: Ticks(0),
+            sample_count: 0,
+            rest_ticks: Ticks(0),
+            pin_value: 0,
+            trsync_oid: Oid(0),
+            trigger_reason: 0,
+        });
+        match self.dispatch.stop(link, print_time)? {
+            TriggerReason::CommsTimeout => return Err(Error::HomingTimeout),
+            TriggerReason::EndstopHit => {}
+            TriggerReason::HostRequest | TriggerReason::PastEndTime => return Ok(None),
+        }
+        // the pin first read as triggered one rest before the next check was due
+        self.state.set(None);
+        link.mcu(&self.mcu)?
+            .send(Command::EndstopQueryState { oid: self.oid });
+        link.run_until(print_time)?;
+        let state = self.state.get().ok_or(Error::HomingTimeout)?;
+        let mcu = link.mcu(&self.mcu)?;
+        let next_clock = clock32_to_clock64(mcu.print_time_to_clock(print_time), state.next_clock);
+        Ok(Some(mcu.clock_to_print_time(Ticks(
+            next_clock.0 - self.rest_ticks.0,
+        ))))
+    }
+
+    /// Whether the endstop reads as triggered, with the MCUs at `print_time`
+    pub fn query<L: McuLink>(&self, link: &mut L, print_time: Seconds) -> Result<bool, Error> {
+        self.state.set(None);
+        link.mcu(&self.mcu)?
+            .send(Command::EndstopQueryState { oid: self.oid });
+        link.run_until(print_time)?;
+        let state = self.state.get().ok_or(Error::HomingTimeout)?;
+        Ok(state.pin_value ^ self.invert)
+    }
+}
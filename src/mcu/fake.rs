@@ -0,0 +1,357 @@
+//! Pretend MCUs for tests: they step, sample endstops and run trsyncs much like the
+//! firmware does, on a clock that only moves when the host says so
+
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+use super::{Command, Error, Mcu, McuLink, Oid, Response};
+use crate::units::{Hertz, Seconds};
+
+/// Reads an input pin
+pub type PinReader = Box<dyn Fn() -> bool>;
+
+#[derive(Default)]
+struct FakeStepper {
+    /// Steps taken, counting steps with the dir pin high as positive
+    position: Rc<Cell<i64>>,
+    next_dir: bool,
+    last_clock: u64,
+    /// Clock and direction of every step still to come
+    queue: VecDeque<(u64, bool)>,
+    /// Stopped by a trsync, so ignoring steps until the step clock gets reset
+    need_reset: bool,
+}
+
+struct EndstopHoming {
+    /// Next time the pin gets read
+    wake: u64,
+    sample_ticks: u64,
+    sample_count: u8,
+    rest_ticks: u64,
+    pin_value: bool,
+    trsync: Oid,
+    trigger_reason: u8,
+    /// Matching reads still needed, while oversampling
+    left: Option<u8>,
+}
+
+struct FakeEndstop {
+    pin: String,
+    homing: Option<EndstopHoming>,
+    /// When the pin's due a read after the first matching one
+    nextwake: u64,
+}
+
+#[derive(Default)]
+struct FakeTrsync {
+    can_trigger: bool,
+    trigger_reason: u8,
+    expire_reason: u8,
+    expire_clock: Option<u64>,
+    steppers: Vec<Oid>,
+}
+
+/// What's next in the firmware's timer queue. Steps go first when things coincide
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Event {
+    Step(Oid),
+    Endstop(Oid),
+    Expire(Oid),
+}
+
+#[derive(Default)]
+pub struct FakeMcu {
+    now: u64,
+    pins: HashMap<String, PinReader>,
+    steppers: HashMap<Oid, FakeStepper>,
+    endstops: HashMap<Oid, FakeEndstop>,
+    trsyncs: HashMap<Oid, FakeTrsync>,
+    /// Config commands already seen
+    configured: usize,
+    outbox: Vec<Response>,
+}
+
+impl FakeMcu {
+    pub fn set_pin(&mut self, pin: &str, read: impl Fn() -> bool + 'static) {
+        self.pins.insert(pin.into(), Box::new(read));
+    }
+
+    /// Live step count of the stepper, e.g. to drive a pin with
+    pub fn stepper_position(&mut self, oid: Oid) -> Rc<Cell<i64>> {
+        self.steppers.entry(oid).or_default().position.clone()
+    }
+
+    fn read_pin(&self, pin: &str) -> bool {
+        self.pins.get(pin).is_some_and(|read| read())
+    }
+
+    fn handle(&mut self, cmd: Command) {
+        match cmd {
+            Command::QueueStep {
+                oid,
+                interval,
+                count,
+                add,
+            } => {
+                let stepper = self.steppers.entry(oid).or_default();
+                if stepper.need_reset {
+                    return;
+                }
+                let mut interval = i64::from(interval);
+                for _ in 0..count {
+                    stepper.last_clock = (stepper.last_clock as i64 + interval) as u64;
+                    stepper
+                        .queue
+                        .push_back((stepper.last_clock, stepper.next_dir));
+                    interval += i64::from(add);
+                }
+            }
+            Command::SetNextStepDir { oid, dir } => {
+                self.steppers.entry(oid).or_default().next_dir = dir != 0;
+            }
+            Command::ResetStepClock { oid, clock } => {
+                let stepper = self.steppers.entry(oid).or_default();
+                stepper.last_clock = clock.0;
+                stepper.need_reset = false;
+            }
+            Command::StepperGetPosition { oid } => {
+                let pos = self.steppers.entry(oid).or_default().position.get();
+                self.outbox.push(Response::StepperPosition {
+                    oid,
+                    pos: pos as i32,
+                });
+            }
+            Command::StepperStopOnTrigger { oid, trsync_oid } => {
+                self.trsyncs
+                    .entry(trsync_oid)
+                    .or_default()
+                    .steppers
+                    .push(oid);
+            }
+            Command::ConfigEndstop { oid, pin, .. } => {
+                self.endstops.insert(
+                    oid,
+                    FakeEndstop {
+                        pin: pin.pin,
+                        homing: None,
+                        nextwake: 0,
+                    },
+                );
+            }
+            Command::EndstopHome {
+                oid,
+                clock,
+                sample_ticks,
+                sample_count,
+                rest_ticks,
+                pin_value,
+                trsync_oid,
+                trigger_reason,
+            } => {
+                let Some(endstop) = self.endstops.get_mut(&oid) else {
+                    return;
+                };
+                endstop.homing = (sample_count > 0).then_some(EndstopHoming {
+                    wake: clock.0,
+                    sample_ticks: sample_ticks.0,
+                    sample_count,
+                    rest_ticks: rest_ticks.0,
+                    pin_value: pin_value != 0,
+                    trsync: trsync_oid,
+                    trigger_reason,
+                    left: None,
+                });
+            }
+            Command::EndstopQueryState { oid } => {
+                if let Some(endstop) = self.endstops.get(&oid) {
+                    let pin_value = self.read_pin(&endstop.pin);
+                    self.outbox.push(Response::EndstopState {
+                        oid,
+                        homing: u8::from(endstop.homing.is_some()),
+                        next_clock: endstop.nextwake as u32,
+                        pin_value: u8::from(pin_value),
+                    });
+                }
+            }
+            Command::ConfigTrsync { oid } => {
+                self.trsyncs.insert(oid, FakeTrsync::default());
+            }
+            Command::TrsyncStart {
+                oid, expire_reason, ..
+            } => {
+                let trsync = self.trsyncs.entry(oid).or_default();
+                trsync.can_trigger = true;
+                trsync.trigger_reason = 0;
+                trsync.expire_reason = expire_reason;
+                trsync.expire_clock = None;
+                trsync.steppers.clear();
+            }
+            Command::TrsyncSetTimeout { oid, clock } => {
+                let trsync = self.trsyncs.entry(oid).or_default();
+                if trsync.can_trigger {
+                    trsync.expire_clock = Some(clock.0);
+                }
+            }
+            Command::TrsyncTrigger { oid, reason } => self.do_trigger(oid, reason),
+            _ => {}
+        }
+    }
+
+    /// Trigger, unless it already has, and report either way
+    fn do_trigger(&mut self, oid: Oid, reason: u8) {
+        let trsync = self.trsyncs.entry(oid).or_default();
+        if trsync.can_trigger {
+            trsync.can_trigger = false;
+            trsync.trigger_reason = reason;
+            trsync.expire_clock = None;
+            for oid in std::mem::take(&mut trsync.steppers) {
+                let stepper = self.steppers.entry(oid).or_default();
+                stepper.queue.clear();
+                stepper.need_reset = true;
+            }
+        }
+        self.report_trsync(oid);
+    }
+
+    fn report_trsync(&mut self, oid: Oid) {
+        let trsync = &self.trsyncs[&oid];
+        self.outbox.push(Response::TrsyncState {
+            oid,
+            can_trigger: u8::from(trsync.can_trigger),
+            trigger_reason: trsync.trigger_reason,
+            clock: self.now as u32,
+        });
+    }
+
+    fn next_event(&self) -> Option<(u64, Event)> {
+        let steps = self
+            .steppers
+            .iter()
+            .filter_map(|(&oid, s)| Some((s.queue.front()?.0, Event::Step(oid))));
+        let endstops = self
+            .endstops
+            .iter()
+            .filter_map(|(&oid, e)| Some((e.homing.as_ref()?.wake, Event::Endstop(oid))));
+        let expiries = self
+            .trsyncs
+            .iter()
+            .filter_map(|(&oid, t)| Some((t.expire_clock?, Event::Expire(oid))));
+        steps.chain(endstops).chain(expiries).min()
+    }
+
+    fn fire(&mut self, clock: u64, event: Event) {
+        self.now = clock;
+        match event {
+            Event::Step(oid) => {
+                let stepper = self.steppers.get_mut(&oid).unwrap();
+                let (_, dir) = stepper.queue.pop_front().unwrap();
+                stepper
+                    .position
+                    .set(stepper.position.get() + if dir { 1 } else { -1 });
+            }
+            Event::Endstop(oid) => {
+                let value = self.read_pin(&self.endstops[&oid].pin);
+                let endstop = self.endstops.get_mut(&oid).unwrap();
+                let homing = endstop.homing.as_mut().unwrap();
+                if value != homing.pin_value {
+                    homing.wake = match homing.left {
+                        Some(_) => endstop.nextwake,
+                        None => homing.wake + homing.rest_ticks,
+                    };
+                    homing.left = None;
+                    return;
+                }
+                if homing.left.is_none() {
+                    endstop.nextwake = homing.wake + homing.rest_ticks;
+                }
+                let left = homing.left.unwrap_or(homing.sample_count) - 1;
+                if left > 0 {
+                    homing.left = Some(left);
+                    homing.wake += homing.sample_ticks;
+                    return;
+                }
+                let (trsync, reason) = (homing.trsync, homing.trigger_reason);
+                endstop.homing = None;
+                self.do_trigger(trsync, reason);
+            }
+            Event::Expire(oid) => {
+                let reason = self.trsyncs[&oid].expire_reason;
+                self.do_trigger(oid, reason);
+            }
+        }
+    }
+}
+
+/// A host's worth of [`FakeMcu`]s, all on one clock frequency
+pub struct FakeLink {
+    mcus: Vec<(Mcu, FakeMcu)>,
+}
+
+impl FakeLink {
+    pub fn new(names: &[&str], clock_freq: Hertz) -> Self {
+        let mcus = names
+            .iter()
+            .map(|name| (Mcu::new(name, clock_freq), FakeMcu::default()))
+            .collect();
+        Self { mcus }
+    }
+
+    pub fn fake(&mut self, name: &str) -> &mut FakeMcu {
+        let (_, fake) = self
+            .mcus
+            .iter_mut()
+            .find(|(mcu, _)| mcu.name() == name)
+            .expect("no such mcu");
+        fake
+    }
+
+    fn deliver(mcu: &mut Mcu, fake: &mut FakeMcu) -> Result<(), Error> {
+        let config = mcu.config_cmds()[fake.configured..].to_vec();
+        fake.configured += config.len();
+        for cmd in config.into_iter().chain(mcu.drain_queued()) {
+            fake.handle(cmd);
+        }
+        for response in std::mem::take(&mut fake.outbox) {
+            mcu.handle_response(&response)?;
+        }
+        Ok(())
+    }
+}
+
+impl McuLink for FakeLink {
+    fn mcu(&mut self, name: &str) -> Result<&mut Mcu, Error> {
+        self.mcus
+            .iter_mut()
+            .map(|(mcu, _)| mcu)
+            .find(|mcu| mcu.name() == name)
+            .ok_or_else(|| Error::UnknownMcu(name.into()))
+    }
+
+    fn run_until(&mut self, print_time: Seconds) -> Result<(), Error> {
+        for (mcu, fake) in &mut self.mcus {
+            Self::deliver(mcu, fake)?;
+        }
+        loop {
+            let next = self
+                .mcus
+                .iter()
+                .enumerate()
+                .filter_map(|(i, (mcu, fake))| {
+                    let (clock, event) = fake.next_event()?;
+                    (clock <= mcu.print_time_to_clock(print_time).0).then_some((clock, event, i))
+                })
+                .min();
+            let Some((clock, event, i)) = next else {
+                break;
+            };
+            let (mcu, fake) = &mut self.mcus[i];
+            fake.fire(clock, event);
+            Self::deliver(mcu, fake)?;
+        }
+        for (mcu, fake) in &mut self.mcus {
+            fake.now = fake.now.max(mcu.print_time_to_clock(print_time).0);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+
+use crate::units::{Hertz, Seconds, Ticks};
+use crate::Millimeters;
+use dimensioned::ucum::Radian;
+
+pub mod adc;
+pub mod endstop;
+#[cfg(test)]
+pub mod fake;
+pub mod output;
+pub mod pin;
+
+use pin::{McuPin, PinRef};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Timer too close")]
+    TimerTooClose,
+    #[error("Missed scheduling of next {0}")]
+    MissedSchedule(&'static str),
+    #[error("ADC out of range")]
+    AdcOutOfRange,
+    #[error("Attempted to schedule event in the past")]
+    TimeParadox, // couldnt think of a good name
+    #[error("MCU did not report constant {0}")]
+    MissingConstant(&'static str),
+    #[error("Pin {0} is static and can't be scheduled")]
+    StaticPin(PinRef),
+    #[error("Static pin {0} can't have a shutdown value different from its start value")]
+    StaticShutdownValue(PinRef),
+    #[error("Pin {0} has a max duration, so its start value must equal its shutdown value")]
+    MaxDurationStartValue(PinRef),
+    #[error("Max duration on pin {0} is too large")]
+    MaxDurationTooLong(PinRef),
+    #[error("Unknown mcu {0}")]
+    UnknownMcu(String),
+    #[error("Communication timeout during homing")]
+    HomingTimeout,
+}
+
+/// Object id, the handle the MCU uses for everything we configure on it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Oid(pub u8);
+
+/// Called with every response addressed to an oid
+pub type ResponseHandler = Box<dyn FnMut(&Response) -> Result<(), Error>>;
+
+/// Host side view of a single micro-controller
+pub struct Mcu {
+    name: String,
+    clock_freq: Hertz,
+    /// Constants out of the data dictionary (`PWM_MAX`, `ADC_MAX`, ...)
+    constants: HashMap<String, f64>,
+    next_oid: u8,
+    /// Sent once, in order, when the MCU gets configured
+    config_cmds: Vec<Command>,
+    /// Everything sent after configuration, waiting for the serial queue
+    queued: Vec<Command>,
+    handlers: HashMap<Oid, ResponseHandler>,
+}
+
+impl Mcu {
+    pub fn new(name: &str, clock_freq: Hertz) -> Self {
+        Self {
+            name: name.into(),
+            clock_freq,
+            constants: HashMap::new(),
+            next_oid: 0,
+            config_cmds: Vec::new(),
+            queued: Vec::new(),
+            handlers: HashMap::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn clock_freq(&self) -> Hertz {
+        self.clock_freq
+    }
+
+    /// Record a constant the MCU reported in its data dictionary
+    pub fn set_constant(&mut self, name: &str, value: f64) {
+        self.constants.insert(name.into(), value);
+    }
+
+    pub fn constant(&self, name: &'static str) -> Result<f64, Error> {
+        self.constants
+            .get(name)
+            .copied()
+            .ok_or(Error::MissingConstant(name))
+    }
+
+    /// Hand out the next free object id
+    pub fn create_oid(&mut self) -> Oid {
+        let oid = Oid(self.next_oid);
+        self.next_oid += 1;
+        oid
+    }
+
+    pub fn add_config_cmd(&mut self, cmd: Command) {
+        self.config_cmds.push(cmd);
+    }
+
+    pub fn config_cmds(&self) -> &[Command] {
+        &self.config_cmds
+    }
+
+    pub fn send(&mut self, cmd: Command) {
+        self.queued.push(cmd);
+    }
+
+    /// Take everything sent since the last drain, oldest first
+    pub fn drain_queued(&mut self) -> Vec<Command> {
+        std::mem::take(&mut self.queued)
+    }
+
+    /// Route every response carrying `oid` to `handler`
+    pub fn register_response(&mut self, oid: Oid, handler: ResponseHandler) {
+        self.handlers.insert(oid, handler);
+    }
+
+    /// Pass a response from the MCU to whoever owns its oid
+    pub fn handle_response(&mut self, response: &Response) -> Result<(), Error> {
+        match response.oid().and_then(|oid| self.handlers.get_mut(&oid)) {
+            Some(handler) => handler(response),
+            // klipper just logs these, so do we (eventually)
+            None => Ok(()),
+        }
+    }
+
+    /// Clock at which a periodic query for `oid` should start,
+    /// staggered so every object doesn't report in the same instant
+    pub fn query_slot(&self, oid: Oid) -> Ticks {
+        self.print_time_to_clock(Seconds(1.5 + 0.01 * f64::from(oid.0)))
+    }
+
+    /// MCU clock at the given print time.
+    /// Clock sync isn't a thing yet, so print time 0 is clock 0
+    pub fn print_time_to_clock(&self, print_time: Seconds) -> Ticks {
+        print_time.to_ticks(self.clock_freq)
+    }
+
+    pub fn clock_to_print_time(&self, clock: Ticks) -> Seconds {
+        clock.to_seconds(self.clock_freq)
+    }
+
+    /// Length of a duration in MCU clock ticks
+    pub fn seconds_to_clock(&self, duration: Seconds) -> Ticks {
+        duration.to_ticks(self.clock_freq)
+    }
+}
+
+/// Whatever's on the other end of the MCUs' serial ports
+pub trait McuLink {
+    fn mcu(&mut self, name: &str) -> Result<&mut Mcu, Error>;
+    /// Deliver everything queued on every MCU, and pass back everything they say, until
+    /// they've all reached `print_time`
+    fn run_until(&mut self, print_time: Seconds) -> Result<(), Error>;
+}
+
+/// Units a [`Stepper`] measures its travel in
+pub trait StepperUnits {
+    /// What positions and `rotation_distance` are expressed in
+    type Distance: Copy;
+
+    /// Interpret a bare number from the config file in these units
+    fn from_config(value: f64) -> Self::Distance;
+    /// Strip the units off, leaving the number the step math runs on
+    fn to_raw(distance: Self::Distance) -> f64;
+    /// Put the units back on a number coming out of the step math
+    fn from_raw(raw: f64) -> Self::Distance;
+}
+
+/// Plain old linear axis, measured in millimetres
+pub struct Linear;
+
+/// Rotary axis, measured in radians.
+/// Like klipper's `units_in_radians`, config values are given in degrees
+pub struct Rotary;
+
+impl StepperUnits for Linear {
+    type Distance = Millimeters;
+
+    fn from_config(value: f64) -> Self::Distance {
+        Self::from_raw(value)
+    }
+
+    fn to_raw(distance: Self::Distance) -> f64 {
+        distance.0
+    }
+
+    fn from_raw(raw: f64) -> Self::Distance {
+        Millimeters(raw)
+    }
+}
+
+impl StepperUnits for Rotary {
+    type Distance = Radian<f64>;
+
+    fn from_config(value: f64) -> Self::Distance {
+        Self::from_raw(value.to_radians())
+    }
+
+    fn to_raw(distance: Self::Distance) -> f64 {
+        distance.value_unsafe
+    }
+
+    fn from_raw(raw: f64) -> Self::Distance {
+        Radian::new(raw)
+    }
+}
+
+pub struct Stepper<U: StepperUnits = Linear> {
+    name: String,
+    /// Distance travelled per full rotation of the motor
+    rotation_distance: U::Distance,
+    steps_per_rotation: f64,
+    step_pulse_duration: f64,
+    mcu: Mcu,
+    oid: Oid,
+    step: McuPin,
+    dir: McuPin,
+}
+
+impl<U: StepperUnits> Stepper<U> {
+    /// Distance covered by a single step
+    fn step_dist(&self) -> U::Distance {
+        U::from_raw(U::to_raw(self.rotation_distance) / self.steps_per_rotation)
+    }
+
+    /// Number of (possibly fractional) steps needed to cover `distance`
+    fn steps_for(&self, distance: U::Distance) -> f64 {
+        U::to_raw(distance) * self.steps_per_rotation / U::to_raw(self.rotation_distance)
+    }
+
+    /// Distance covered by `steps` steps
+    fn distance_for(&self, steps: f64) -> U::Distance {
+        U::from_raw(steps * U::to_raw(self.step_dist()))
+    }
+}
+
+// <1 byte length><1 byte sequence><n-byte content><2 byte crc><1 byte sync>
+
+#[repr(C)]
+struct RawMessage {
+    len: u8,
+    seq: u8,
+    data: Vec<u8>,
+    crc: u16,
+    sync: u8,
+}
+
+#[repr(u8)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Identify,
+    SetDigitalOut {
+        pin: PinRef,
+        value: u8,
+    },
+    SetPwmOut {
+        pin: PinRef,
+        cycle_ticks: Ticks,
+        value: u16,
+    },
+    ConfigDigitalOut {
+        oid: Oid,
+        pin: PinRef,
+        value: u8,
+        default_value: u8,
+        max_duration: Ticks,
+    },
+    SetDigitalOutPwmCycle {
+        oid: Oid,
+        cycle_ticks: Ticks,
+    },
+    QueueDigitalOut {
+        oid: Oid,
+        clock: Ticks,
+        on_ticks: Ticks,
+    },
+    ConfigPwmOut {
+        oid: Oid,
+        pin: PinRef,
+        cycle_ticks: Ticks,
+        value: u16,
+        default_value: u16,
+        max_duration: Ticks,
+    },
+    QueuePwmOut {
+        oid: Oid,
+        clock: Ticks,
+        value: u16,
+    },
+    ConfigAnalogIn {
+        oid: Oid,
+        pin: PinRef,
+    },
+    QueryAnalogIn {
+        oid: Oid,
+        clock: Ticks,
+        sample_ticks: Ticks,
+        sample_count: u8,
+        rest_ticks: Ticks,
+        min_value: u16,
+        max_value: u16,
+        range_check_count: u8,
+    },
+    /// Steps `count` times, `interval` ticks apart, with the interval growing by `add` each step
+    QueueStep {
+        oid: Oid,
+        interval: u32,
+        count: u16,
+        add: i16,
+    },
+    SetNextStepDir {
+        oid: Oid,
+        dir: u8,
+    },
+    /// The next `queue_step` interval counts from `clock`
+    ResetStepClock {
+        oid: Oid,
+        clock: Ticks,
+    },
+    StepperGetPosition {
+        oid: Oid,
+    },
+    /// Stop the stepper when the trsync triggers
+    StepperStopOnTrigger {
+        oid: Oid,
+        trsync_oid: Oid,
+    },
+    ConfigEndstop {
+        oid: Oid,
+        pin: PinRef,
+        pull_up: u8,
+    },
+    /// Check the pin every `rest_ticks` from `clock`. Once it reads `pin_value`
+    /// `sample_count` times, `sample_ticks` apart, trigger the trsync. Zero `sample_count` stops
+    EndstopHome {
+        oid: Oid,
+        clock: Ticks,
+        sample_ticks: Ticks,
+        sample_count: u8,
+        rest_ticks: Ticks,
+        pin_value: u8,
+        trsync_oid: Oid,
+        trigger_reason: u8,
+    },
+    EndstopQueryState {
+        oid: Oid,
+    },
+    ConfigTrsync {
+        oid: Oid,
+    },
+    /// Arm the trsync, reporting on it every `report_ticks` from `report_clock`
+    TrsyncStart {
+        oid: Oid,
+        report_clock: Ticks,
+        report_ticks: Ticks,
+        expire_reason: u8,
+    },
+    /// Trigger with the `expire_reason` if not heard from by `clock`
+    TrsyncSetTimeout {
+        oid: Oid,
+        clock: Ticks,
+    },
+    /// Trigger now, unless it already has. Always answered with a `trsync_state`
+    TrsyncTrigger {
+        oid: Oid,
+        reason: u8,
+    },
+}
+
+#[repr(u8)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    Identify = 1,
+    AnalogInState {
+        oid: Oid,
+        /// Low 32 bits of the clock the next report will be taken at
+        next_clock: u32,
+        value: u16,
+    },
+    StepperPosition {
+        oid: Oid,
+        pos: i32,
+    },
+    EndstopState {
+        oid: Oid,
+        homing: u8,
+        /// Low 32 bits of the clock the pin's due to be checked next
+        next_clock: u32,
+        pin_value: u8,
+    },
+    TrsyncState {
+        oid: Oid,
+        can_trigger: u8,
+        trigger_reason: u8,
+        clock: u32,
+    },
+}
+
+impl Response {
+    /// Object the response is about, if any
+    pub fn oid(&self) -> Option<Oid> {
+        match self {
+            Response::Identify => None,
+            Response::AnalogInState { oid, .. }
+            | Response::StepperPosition { oid, .. }
+            | Response::EndstopState { oid, .. }
+            | Response::TrsyncState { oid, .. } => Some(*oid),
+        }
+    }
+}
+
+/// Widen a 32 bit clock from the MCU into a full clock, using a recent full clock as reference
+pub fn clock32_to_clock64(recent: Ticks, clock32: u32) -> Ticks {
+    let diff = clock32.wrapping_sub(recent.0 as u32) as i32;
+    Ticks(recent.0.wrapping_add(diff as i64 as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+
+    fn stepper<U: StepperUnits>(rotation_distance: f64) -> Stepper<U> {
+        let pin = |desc: &str| desc.parse::<McuPin>().unwrap();
+        Stepper {
+            name: "stepper_a".into(),
+            rotation_distance: U::from_config(rotation_distance),
+            steps_per_rotation: 200.0 * 16.0,
+            step_pulse_duration: 0.000_002,
+            mcu: Mcu::new("mcu", Hertz(16_000_000.0)),
+            oid: Oid(0),
+            step: pin("PA0"),
+            dir: pin("PA1"),
+        }
+    }
+
+    #[test]
+    fn test_linear_step_dist() {
+        let s = stepper::<Linear>(40.0);
+        assert_eq!(s.step_dist(), Millimeters(40.0 / 3200.0));
+        assert_eq!(s.steps_for(Millimeters(10.0)), 800.0);
+    }
+
+    #[test]
+    fn test_rotary_in_degrees() {
+        // one full turn of the motor is one full turn of the table
+        let s = stepper::<Rotary>(360.0);
+        let quarter_turn = Rotary::from_config(90.0);
+        assert!((s.step_dist().value_unsafe - 2.0 * PI / 3200.0).abs() < 1e-12);
+        assert!((s.steps_for(quarter_turn) - 800.0).abs() < 1e-9);
+        assert!((s.distance_for(800.0).value_unsafe - PI / 2.0).abs() < 1e-12);
+    }
+}
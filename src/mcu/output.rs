@@ -0,0 +1,362 @@
+//! Digital and PWM output pins, mirroring klipper's `MCU_digital_out` and `MCU_pwm`
+
+use super::pin::PinRef;
+use super::{Command, Error, Mcu, Oid};
+use crate::units::{Seconds, Ticks};
+
+/// Largest `max_duration` the MCU will accept, in ticks
+const MAX_DURATION_TICKS: u64 = 1 << 31;
+
+/// Settings shared by every kind of output pin. Values are in `0.0..=1.0`, before inversion
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputConfig {
+    /// Value the pin holds from configuration until the first update
+    pub start_value: f64,
+    /// Value the MCU falls back to when it shuts down
+    pub shutdown_value: f64,
+    /// Longest the MCU will hold a value other than `shutdown_value` without a fresh update.
+    /// Zero disables the check
+    pub max_duration: Seconds,
+    /// Set once at configuration and never touched again
+    pub is_static: bool,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            start_value: 0.0,
+            shutdown_value: 0.0,
+            max_duration: Seconds(2.0),
+            is_static: false,
+        }
+    }
+}
+
+/// How the MCU makes a PWM signal
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PwmMode {
+    /// Timer peripheral does the work, duty cycle is scaled to the MCU's `PWM_MAX`
+    Hardware,
+    /// MCU toggles the pin from its timer queue
+    Software,
+}
+
+/// Bookkeeping every scheduled output needs
+struct Schedule {
+    oid: Option<Oid>,
+    pin: PinRef,
+    invert: bool,
+    last_clock: Ticks,
+}
+
+impl Schedule {
+    fn new(mcu: &mut Mcu, pin: PinRef, invert: bool, config: &OutputConfig) -> Result<Self, Error> {
+        if config.is_static {
+            if config.start_value != config.shutdown_value {
+                return Err(Error::StaticShutdownValue(pin));
+            }
+            return Ok(Self {
+                oid: None,
+                pin,
+                invert,
+                last_clock: Ticks(0),
+            });
+        }
+        if config.max_duration > Seconds::ZERO && config.start_value != config.shutdown_value {
+            return Err(Error::MaxDurationStartValue(pin));
+        }
+        if mcu.seconds_to_clock(config.max_duration).0 >= MAX_DURATION_TICKS {
+            return Err(Error::MaxDurationTooLong(pin));
+        }
+        Ok(Self {
+            oid: Some(mcu.create_oid()),
+            pin,
+            invert,
+            last_clock: Ticks(0),
+        })
+    }
+
+    /// Check the update at `print_time` isn't going backwards, and claim its clock
+    fn advance(&mut self, mcu: &Mcu, print_time: Seconds) -> Result<(Oid, Ticks), Error> {
+        let oid = self.oid.ok_or_else(|| Error::StaticPin(self.pin.clone()))?;
+        let clock = mcu.print_time_to_clock(print_time);
+        if clock < self.last_clock {
+            return Err(Error::TimeParadox);
+        }
+        self.last_clock = clock;
+        Ok((oid, clock))
+    }
+
+    /// Flip the value if the pin is active low
+    fn polarity(&self, value: f64) -> f64 {
+        let value = value.clamp(0.0, 1.0);
+        if self.invert {
+            1.0 - value
+        } else {
+            value
+        }
+    }
+}
+
+/// A pin that is either on or off
+pub struct DigitalOut {
+    schedule: Schedule,
+}
+
+impl DigitalOut {
+    pub fn new(
+        mcu: &mut Mcu,
+        pin: PinRef,
+        invert: bool,
+        config: OutputConfig,
+    ) -> Result<Self, Error> {
+        let schedule = Schedule::new(mcu, pin, invert, &config)?;
+        let value = schedule.polarity(config.start_value).round() as u8;
+        let default_value = schedule.polarity(config.shutdown_value).round() as u8;
+        let pin = schedule.pin.clone();
+        match schedule.oid {
+            None => mcu.add_config_cmd(Command::SetDigitalOut { pin, value }),
+            Some(oid) => {
+                let max_duration = mcu.seconds_to_clock(config.max_duration);
+                mcu.add_config_cmd(Command::ConfigDigitalOut {
+                    oid,
+                    pin,
+                    value,
+                    default_value,
+                    max_duration,
+                })
+            }
+        }
+        Ok(Self { schedule })
+    }
+
+    /// Switch the pin at `print_time`
+    pub fn set_digital(
+        &mut self,
+        mcu: &mut Mcu,
+        print_time: Seconds,
+        on: bool,
+    ) -> Result<(), Error> {
+        let (oid, clock) = self.schedule.advance(mcu, print_time)?;
+        let on_ticks = Ticks((on ^ self.schedule.invert) as u64);
+        mcu.send(Command::QueueDigitalOut {
+            oid,
+            clock,
+            on_ticks,
+        });
+        Ok(())
+    }
+}
+
+/// A pin driven with a duty cycle
+pub struct PwmOut {
+    schedule: Schedule,
+    mode: PwmMode,
+    cycle_ticks: Ticks,
+    /// What a duty cycle of 1.0 maps to on the wire
+    pwm_max: f64,
+}
+
+impl PwmOut {
+    pub fn new(
+        mcu: &mut Mcu,
+        pin: PinRef,
+        invert: bool,
+        cycle_time: Seconds,
+        mode: PwmMode,
+        config: OutputConfig,
+    ) -> Result<Self, Error> {
+        let schedule = Schedule::new(mcu, pin, invert, &config)?;
+        let cycle_ticks = mcu.seconds_to_clock(cycle_time);
+        let pwm_max = match mode {
+            PwmMode::Hardware => mcu.constant("PWM_MAX")?,
+            PwmMode::Software => cycle_ticks.0 as f64,
+        };
+        let this = Self {
+            schedule,
+            mode,
+            cycle_ticks,
+            pwm_max,
+        };
+
+        let max_duration = mcu.seconds_to_clock(config.max_duration);
+        let pin = this.schedule.pin.clone();
+        match (this.schedule.oid, mode) {
+            (None, PwmMode::Hardware) => mcu.add_config_cmd(Command::SetPwmOut {
+                pin,
+                cycle_ticks,
+                value: this.scale(config.start_value) as u16,
+            }),
+            // a software pwm pin that never changes is just a digital pin
+            (None, PwmMode::Software) => mcu.add_config_cmd(Command::SetDigitalOut {
+                pin,
+                value: this.schedule.polarity(config.start_value).round() as u8,
+            }),
+            (Some(oid), PwmMode::Hardware) => mcu.add_config_cmd(Command::ConfigPwmOut {
+                oid,
+                pin,
+                cycle_ticks,
+                value: this.scale(config.start_value) as u16,
+                default_value: this.scale(config.shutdown_value) as u16,
+                max_duration,
+            }),
+            (Some(oid), PwmMode::Software) => {
+                mcu.add_config_cmd(Command::ConfigDigitalOut {
+                    oid,
+                    pin,
+                    value: this.schedule.polarity(config.start_value).round() as u8,
+                    default_value: this.schedule.polarity(config.shutdown_value).round() as u8,
+                    max_duration,
+                });
+                mcu.add_config_cmd(Command::SetDigitalOutPwmCycle { oid, cycle_ticks });
+            }
+        }
+        Ok(this)
+    }
+
+    /// Turn a duty cycle into what goes on the wire:
+    /// `PWM_MAX` steps for hardware, on-ticks of the cycle for software
+    fn scale(&self, value: f64) -> f64 {
+        (self.schedule.polarity(value) * self.pwm_max).round()
+    }
+
+    /// Change the duty cycle at `print_time`
+    pub fn set_pwm(&mut self, mcu: &mut Mcu, print_time: Seconds, value: f64) -> Result<(), Error> {
+        let (oid, clock) = self.schedule.advance(mcu, print_time)?;
+        let scaled = self.scale(value);
+        mcu.send(match self.mode {
+            PwmMode::Hardware => Command::QueuePwmOut {
+                oid,
+                clock,
+                value: scaled as u16,
+            },
+            PwmMode::Software => Command::QueueDigitalOut {
+                oid,
+                clock,
+                on_ticks: Ticks(scaled as u64),
+            },
+        });
+        Ok(())
+    }
+
+    pub fn cycle_ticks(&self) -> Ticks {
+        self.cycle_ticks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::Hertz;
+
+    fn mcu() -> Mcu {
+        let mut mcu = Mcu::new("mcu", Hertz(1_000_000.0));
+        mcu.set_constant("PWM_MAX", 255.0);
+        mcu
+    }
+
+    fn pin(name: &str) -> PinRef {
+        PinRef {
+            mcu: "mcu".into(),
+            pin: name.into(),
+        }
+    }
+
+    #[test]
+    fn test_hardware_pwm() {
+        let mut mcu = mcu();
+        let mut fan = PwmOut::new(
+            &mut mcu,
+            pin("PA8"),
+            false,
+            Seconds(0.01),
+            PwmMode::Hardware,
+            OutputConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            mcu.config_cmds(),
+            &[Command::ConfigPwmOut {
+                oid: Oid(0),
+                pin: pin("PA8"),
+                cycle_ticks: Ticks(10_000),
+                value: 0,
+                default_value: 0,
+                max_duration: Ticks(2_000_000),
+            }]
+        );
+        fan.set_pwm(&mut mcu, Seconds(1.0), 0.5).unwrap();
+        assert_eq!(
+            mcu.drain_queued(),
+            vec![Command::QueuePwmOut {
+                oid: Oid(0),
+                clock: Ticks(1_000_000),
+                value: 128,
+            }]
+        );
+        assert!(matches!(
+            fan.set_pwm(&mut mcu, Seconds(0.5), 1.0),
+            Err(Error::TimeParadox)
+        ));
+    }
+
+    #[test]
+    fn test_inverted_software_pwm() {
+        let mut mcu = mcu();
+        let config = OutputConfig {
+            max_duration: Seconds::ZERO,
+            ..Default::default()
+        };
+        let mut heater = PwmOut::new(
+            &mut mcu,
+            pin("PB0"),
+            true,
+            Seconds(0.1),
+            PwmMode::Software,
+            config,
+        )
+        .unwrap();
+        assert_eq!(mcu.config_cmds().len(), 2);
+        heater.set_pwm(&mut mcu, Seconds(0.0), 0.25).unwrap();
+        assert_eq!(
+            mcu.drain_queued(),
+            vec![Command::QueueDigitalOut {
+                oid: Oid(0),
+                clock: Ticks(0),
+                on_ticks: Ticks(75_000),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_static_and_safety() {
+        let mut mcu = mcu();
+        let config = OutputConfig {
+            start_value: 1.0,
+            shutdown_value: 1.0,
+            is_static: true,
+            ..Default::default()
+        };
+        let mut led = DigitalOut::new(&mut mcu, pin("PC13"), false, config).unwrap();
+        assert_eq!(
+            mcu.config_cmds(),
+            &[Command::SetDigitalOut {
+                pin: pin("PC13"),
+                value: 1
+            }]
+        );
+        assert!(matches!(
+            led.set_digital(&mut mcu, Seconds(1.0), false),
+            Err(Error::StaticPin(_))
+        ));
+
+        let config = OutputConfig {
+            start_value: 1.0,
+            ..Default::default()
+        };
+        assert!(matches!(
+            DigitalOut::new(&mut mcu, pin("PC14"), false, config),
+            Err(Error::MaxDurationStartValue(_))
+        ));
+    }
+}
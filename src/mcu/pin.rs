@@ -0,0 +1,270 @@
+//! Klipper-style pin descriptions, e.g. `^!toolboard:PB3`
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// MCU a pin belongs to when the description doesn't name one
+pub const DEFAULT_MCU: &str = "mcu";
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum PinError {
+    #[error("Invalid pin description `{0}`")]
+    InvalidDescription(String),
+    #[error("Unknown pin chip name `{0}`")]
+    UnknownMcu(String),
+    #[error("Invalid pin alias `{0}`")]
+    InvalidAlias(String),
+    #[error("Pin {pin} is reserved for {owner} - can't claim it for {section}")]
+    AlreadyClaimed {
+        pin: PinRef,
+        owner: String,
+        section: String,
+    },
+    #[error("Shared pin {0} must have the same polarity and pull in every section")]
+    SharedMismatch(PinRef),
+}
+
+/// Internal resistor to enable on an input pin
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Pull {
+    #[default]
+    Floating,
+    /// `^` prefix
+    Up,
+    /// `~` prefix
+    Down,
+}
+
+/// A physical pin, after any aliases have been resolved
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PinRef {
+    pub mcu: String,
+    pub pin: String,
+}
+
+impl Display for PinRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.mcu, self.pin)
+    }
+}
+
+/// A pin as written in the config: `[^|~][!][mcu_name:]pin`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct McuPin {
+    /// Name of the MCU the pin lives on
+    pub mcu: String,
+    /// Pin name (or alias) on that MCU
+    pub pin: String,
+    pub invert: bool,
+    pub pull: Pull,
+}
+
+impl FromStr for McuPin {
+    type Err = PinError;
+
+    fn from_str(desc: &str) -> Result<Self, Self::Err> {
+        let invalid = || PinError::InvalidDescription(desc.to_string());
+
+        // klipper insists on pull first, then invert
+        let mut rest = desc.trim();
+        let pull = match rest.chars().next() {
+            Some('^') => Pull::Up,
+            Some('~') => Pull::Down,
+            _ => Pull::Floating,
+        };
+        if pull != Pull::Floating {
+            rest = rest[1..].trim_start();
+        }
+        let invert = rest.starts_with('!');
+        if invert {
+            rest = rest[1..].trim_start();
+        }
+
+        let (mcu, pin) = match rest.split_once(':') {
+            Some((mcu, pin)) => (mcu.trim(), pin.trim()),
+            None => (DEFAULT_MCU, rest),
+        };
+        let is_name = |s: &str| {
+            !s.is_empty() && !s.contains(|c: char| "^~!:".contains(c) || c.is_whitespace())
+        };
+        if !is_name(mcu) || !is_name(pin) {
+            return Err(invalid());
+        }
+
+        Ok(McuPin {
+            mcu: mcu.into(),
+            pin: pin.into(),
+            invert,
+            pull,
+        })
+    }
+}
+
+impl Display for McuPin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.pull {
+            Pull::Floating => {}
+            Pull::Up => write!(f, "^")?,
+            Pull::Down => write!(f, "~")?,
+        }
+        if self.invert {
+            write!(f, "!")?;
+        }
+        write!(f, "{}:{}", self.mcu, self.pin)
+    }
+}
+
+/// Who currently holds a physical pin
+struct Claim {
+    section: String,
+    /// Pins can only be shared between users that agree on a share type
+    share_type: Option<String>,
+    invert: bool,
+    pull: Pull,
+}
+
+/// Knows every MCU's pin aliases and which config section owns which pin
+#[derive(Default)]
+pub struct PinRegistry {
+    /// mcu name -> alias -> real pin name
+    aliases: HashMap<String, HashMap<String, String>>,
+    claims: HashMap<PinRef, Claim>,
+}
+
+impl PinRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make `name` a valid MCU for pin descriptions to point at
+    pub fn register_mcu(&mut self, name: &str) {
+        self.aliases.entry(name.into()).or_default();
+    }
+
+    /// Add aliases in the `[board_pins]` format: `EXP1_1=PB5, EXP1_2=PB6`
+    pub fn add_aliases(&mut self, mcu: &str, aliases: &str) -> Result<(), PinError> {
+        let table = self
+            .aliases
+            .get_mut(mcu)
+            .ok_or_else(|| PinError::UnknownMcu(mcu.into()))?;
+        for entry in aliases.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (alias, pin) = entry
+                .split_once('=')
+                .map(|(a, p)| (a.trim(), p.trim()))
+                .filter(|(a, p)| !a.is_empty() && !p.is_empty())
+                .ok_or_else(|| PinError::InvalidAlias(entry.into()))?;
+            table.insert(alias.into(), pin.into());
+        }
+        Ok(())
+    }
+
+    /// Find the physical pin behind a description, looking through aliases
+    pub fn resolve(&self, pin: &McuPin) -> Result<PinRef, PinError> {
+        let table = self
+            .aliases
+            .get(&pin.mcu)
+            .ok_or_else(|| PinError::UnknownMcu(pin.mcu.clone()))?;
+        let name = table.get(&pin.pin).unwrap_or(&pin.pin);
+        Ok(PinRef {
+            mcu: pin.mcu.clone(),
+            pin: name.clone(),
+        })
+    }
+
+    /// Reserve the pin for `section`, failing if anyone else already has it
+    pub fn claim(&mut self, section: &str, pin: &McuPin) -> Result<PinRef, PinError> {
+        self.claim_shared(section, pin, None)
+    }
+
+    /// Reserve the pin for `section`. Sections passing the same `share_type`
+    /// (say, two endstops on one switch) may hold it at the same time
+    pub fn claim_shared(
+        &mut self,
+        section: &str,
+        pin: &McuPin,
+        share_type: Option<&str>,
+    ) -> Result<PinRef, PinError> {
+        let pin_ref = self.resolve(pin)?;
+        if let Some(claim) = self.claims.get(&pin_ref) {
+            if share_type.is_none() || claim.share_type.as_deref() != share_type {
+                return Err(PinError::AlreadyClaimed {
+                    pin: pin_ref,
+                    owner: claim.section.clone(),
+                    section: section.into(),
+                });
+            }
+            if claim.invert != pin.invert || claim.pull != pin.pull {
+                return Err(PinError::SharedMismatch(pin_ref));
+            }
+            return Ok(pin_ref);
+        }
+        self.claims.insert(
+            pin_ref.clone(),
+            Claim {
+                section: section.into(),
+                share_type: share_type.map(Into::into),
+                invert: pin.invert,
+                pull: pin.pull,
+            },
+        );
+        Ok(pin_ref)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let pin: McuPin = "^!toolboard:PB3".parse().unwrap();
+        assert_eq!(pin.mcu, "toolboard");
+        assert_eq!(pin.pin, "PB3");
+        assert!(pin.invert);
+        assert_eq!(pin.pull, Pull::Up);
+
+        let pin: McuPin = " ~ PA1 ".parse().unwrap();
+        assert_eq!(pin.mcu, DEFAULT_MCU);
+        assert_eq!(pin.pin, "PA1");
+        assert!(!pin.invert);
+        assert_eq!(pin.pull, Pull::Down);
+
+        for bad in ["", "!", "!^PA1", "mcu:", ":PA1", "a:b:c", "PA 1", "^^PA1"] {
+            assert_eq!(
+                bad.parse::<McuPin>(),
+                Err(PinError::InvalidDescription(bad.into())),
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_claims() {
+        let mut pins = PinRegistry::new();
+        pins.register_mcu(DEFAULT_MCU);
+        pins.add_aliases(DEFAULT_MCU, "EXP1_1=PB5, EXP1_2 = PB6,")
+            .unwrap();
+
+        let fan = pins.claim("fan", &"!EXP1_1".parse().unwrap()).unwrap();
+        assert_eq!(fan.pin, "PB5");
+        // same physical pin by its real name
+        let err = pins.claim("heater_bed", &"PB5".parse().unwrap());
+        assert!(matches!(err, Err(PinError::AlreadyClaimed { owner, .. }) if owner == "fan"));
+
+        let z = "^PA2".parse().unwrap();
+        pins.claim_shared("stepper_z", &z, Some("endstop")).unwrap();
+        pins.claim_shared("stepper_z1", &z, Some("endstop"))
+            .unwrap();
+        let inverted = "^!PA2".parse().unwrap();
+        assert!(matches!(
+            pins.claim_shared("stepper_z2", &inverted, Some("endstop")),
+            Err(PinError::SharedMismatch(_))
+        ));
+
+        assert_eq!(
+            pins.claim("probe", &"nope:PA3".parse().unwrap()),
+            Err(PinError::UnknownMcu("nope".into()))
+        );
+    }
+}
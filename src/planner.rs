@@ -0,0 +1,780 @@
+//! Look-ahead motion planning, klipper's `toolhead.py` `Move` and `LookAheadQueue`
+//!
+//! Moves are queued up until there's enough of them to plan a sensible speed through
+//! every junction, then turned into trapezoids and handed to the [`TrapQ`]
+
+use crate::kinematics::extruder::Extruder;
+use crate::kinematics::{Kinematics, KinematicsError};
+use crate::trapq::{Coord, TrapQ, Trapezoid};
+use crate::units::{
+    Millimeters, MillimetersPerSecond, MillimetersPerSecondSquared,
+    MillimetersSquaredPerSecondSquared, Seconds, SquareCornerVelocity,
+};
+
+/// Plan at least this far ahead before committing to junction speeds
+const LOOKAHEAD_FLUSH_TIME: Seconds = Seconds(0.250);
+/// Anything shorter than this doesn't go anywhere
+const MIN_MOVE_DISTANCE: Millimeters = Millimeters(0.000_000_001);
+/// Extrude-only moves are left for the extruder to limit
+const EXTRUDE_ONLY_ACCEL: MillimetersPerSecondSquared = MillimetersPerSecondSquared(99_999_999.9);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlannerConfig {
+    pub max_velocity: MillimetersPerSecond,
+    pub max_accel: MillimetersPerSecondSquared,
+    /// Fraction of each move that must be spent cruising, tames zig-zag infill
+    pub minimum_cruise_ratio: f64,
+    /// Speed a 90 degree corner can be taken at
+    pub square_corner_velocity: SquareCornerVelocity,
+    /// Most the extruder's speed may jump by at a junction
+    pub instant_corner_velocity: MillimetersPerSecond,
+}
+
+impl PlannerConfig {
+    /// Klipper's defaults for everything that has one
+    pub fn new(max_velocity: MillimetersPerSecond, max_accel: MillimetersPerSecondSquared) -> Self {
+        Self {
+            max_velocity,
+            max_accel,
+            minimum_cruise_ratio: 0.5,
+            square_corner_velocity: MillimetersPerSecond(5.0),
+            instant_corner_velocity: MillimetersPerSecond(1.0),
+        }
+    }
+
+    /// How far the toolhead may cut a corner, in the approximated-circle model
+    fn junction_deviation(&self) -> Millimeters {
+        self.square_corner_velocity.squared() * (2f64.sqrt() - 1.0) / self.max_accel
+    }
+
+    /// Pretend acceleration used to make sure moves keep some cruise
+    fn max_accel_to_decel(&self) -> MillimetersPerSecondSquared {
+        self.max_accel * (1.0 - self.minimum_cruise_ratio)
+    }
+}
+
+/// A straight line move on its way through the planner.
+/// Speeds are tracked squared, because that's what's linear in distance under constant accel
+#[derive(Clone, Debug, PartialEq)]
+pub struct Move {
+    pub start_pos: Coord,
+    pub end_pos: Coord,
+    pub move_d: Millimeters,
+    /// Unit vector of the direction of travel
+    pub axes_r: Coord,
+    pub start_e: Millimeters,
+    pub end_e: Millimeters,
+    /// Filament per unit of `move_d`, so plus or minus one for extrude-only moves
+    pub extrude_r: f64,
+    /// Whether the toolhead goes anywhere, rather than just the extruder
+    pub is_kinematic: bool,
+    pub accel: MillimetersPerSecondSquared,
+    junction_deviation: Millimeters,
+    instant_corner_velocity: MillimetersPerSecond,
+    /// Fastest the move could possibly be done in
+    pub min_move_t: Seconds,
+    max_start_v2: MillimetersSquaredPerSecondSquared,
+    max_cruise_v2: MillimetersSquaredPerSecondSquared,
+    /// Most the squared speed can change over the length of the move
+    delta_v2: MillimetersSquaredPerSecondSquared,
+    max_smoothed_v2: MillimetersSquaredPerSecondSquared,
+    smooth_delta_v2: MillimetersSquaredPerSecondSquared,
+    next_junction_v2: MillimetersSquaredPerSecondSquared,
+    // Filled in once the move is planned
+    pub start_v: MillimetersPerSecond,
+    pub cruise_v: MillimetersPerSecond,
+    pub end_v: MillimetersPerSecond,
+    pub accel_t: Seconds,
+    pub cruise_t: Seconds,
+    pub decel_t: Seconds,
+}
+
+impl Move {
+    fn new(
+        config: &PlannerConfig,
+        start_pos: Coord,
+        end_pos: Coord,
+        start_e: Millimeters,
+        end_e: Millimeters,
+        speed: MillimetersPerSecond,
+    ) -> Self {
+        let mut velocity = speed.min(config.max_velocity);
+        let mut accel = config.max_accel;
+        let mut axes_d = end_pos - start_pos;
+        let mut move_d = Millimeters(axes_d.norm());
+        let e_d = end_e - start_e;
+        let is_kinematic = move_d >= MIN_MOVE_DISTANCE;
+        if !is_kinematic {
+            // extrude-only, or going nowhere at all
+            axes_d = Coord::default();
+            move_d = e_d.abs();
+            velocity = speed;
+            accel = EXTRUDE_ONLY_ACCEL;
+        }
+        let inv_move_d = if move_d > MIN_MOVE_DISTANCE {
+            1.0 / move_d.0
+        } else {
+            0.0
+        };
+        Self {
+            start_pos,
+            end_pos: start_pos + axes_d,
+            move_d,
+            axes_r: axes_d * inv_move_d,
+            start_e,
+            end_e,
+            extrude_r: e_d.0 * inv_move_d,
+            is_kinematic,
+            accel,
+            junction_deviation: config.junction_deviation(),
+            instant_corner_velocity: config.instant_corner_velocity,
+            min_move_t: move_d / velocity,
+            max_start_v2: MillimetersSquaredPerSecondSquared::ZERO,
+            max_cruise_v2: velocity.squared(),
+            delta_v2: 2.0 * (move_d * accel),
+            max_smoothed_v2: MillimetersSquaredPerSecondSquared::ZERO,
+            smooth_delta_v2: 2.0 * (move_d * config.max_accel_to_decel()),
+            next_junction_v2: MillimetersSquaredPerSecondSquared(f64::MAX),
+            start_v: MillimetersPerSecond::ZERO,
+            cruise_v: MillimetersPerSecond::ZERO,
+            end_v: MillimetersPerSecond::ZERO,
+            accel_t: Seconds::ZERO,
+            cruise_t: Seconds::ZERO,
+            decel_t: Seconds::ZERO,
+        }
+    }
+
+    /// Cap the speed and acceleration of the move, e.g. for a slow Z axis
+    pub fn limit_speed(&mut self, speed: MillimetersPerSecond, accel: MillimetersPerSecondSquared) {
+        let speed2 = speed.squared();
+        if speed2 < self.max_cruise_v2 {
+            self.max_cruise_v2 = speed2;
+            self.min_move_t = self.move_d / speed;
+        }
+        self.accel = self.accel.min(accel);
+        self.delta_v2 = 2.0 * (self.move_d * self.accel);
+        self.smooth_delta_v2 = self.smooth_delta_v2.min(self.delta_v2);
+    }
+
+    /// Cap the speed the move may end at
+    pub fn limit_next_junction_speed(&mut self, speed: MillimetersPerSecond) {
+        self.next_junction_v2 = self.next_junction_v2.min(speed.squared());
+    }
+
+    /// Fastest this move can start, given the one before it
+    fn calc_junction(&mut self, prev: &Move) {
+        if !self.is_kinematic || !prev.is_kinematic {
+            return;
+        }
+        // the extruder can only change speed so suddenly
+        let diff_r = self.extrude_r - prev.extrude_r;
+        let extruder_v2 = if diff_r != 0.0 {
+            (self.instant_corner_velocity / diff_r.abs()).squared()
+        } else {
+            self.max_cruise_v2
+        };
+        let mut max_start_v2 = extruder_v2
+            .min(self.max_cruise_v2)
+            .min(prev.max_cruise_v2)
+            .min(prev.next_junction_v2)
+            .min(prev.max_start_v2 + prev.delta_v2);
+
+        // "approximated centripetal velocity": fit a circle into the corner
+        let junction_cos_theta = -self.axes_r.dot(prev.axes_r);
+        let sin_theta_d2 = (0.5 * (1.0 - junction_cos_theta)).max(0.0).sqrt();
+        let cos_theta_d2 = (0.5 * (1.0 + junction_cos_theta)).max(0.0).sqrt();
+        let one_minus_sin_theta_d2 = 1.0 - sin_theta_d2;
+        if one_minus_sin_theta_d2 > 0.0 && cos_theta_d2 > 0.0 {
+            let r_jd = sin_theta_d2 / one_minus_sin_theta_d2;
+            let move_jd_v2 = r_jd * (self.junction_deviation * self.accel);
+            let prev_jd_v2 = r_jd * (prev.junction_deviation * prev.accel);
+            // the circle can't touch either move past its midpoint
+            let tan_theta_d2 = sin_theta_d2 / cos_theta_d2;
+            let move_centripetal_v2 = 0.5 * tan_theta_d2 * (self.move_d * self.accel);
+            let prev_centripetal_v2 = 0.5 * tan_theta_d2 * (prev.move_d * prev.accel);
+            max_start_v2 = max_start_v2
+                .min(move_jd_v2)
+                .min(prev_jd_v2)
+                .min(move_centripetal_v2)
+                .min(prev_centripetal_v2);
+        }
+
+        self.max_start_v2 = max_start_v2;
+        self.max_smoothed_v2 = max_start_v2.min(prev.max_smoothed_v2 + prev.smooth_delta_v2);
+    }
+
+    /// Split the move into accel/cruise/decel given the planned speeds
+    fn set_junction(
+        &mut self,
+        start_v2: MillimetersSquaredPerSecondSquared,
+        cruise_v2: MillimetersSquaredPerSecondSquared,
+        end_v2: MillimetersSquaredPerSecondSquared,
+    ) {
+        let accel_d = (cruise_v2 - start_v2) / self.accel * 0.5;
+        let decel_d = (cruise_v2 - end_v2) / self.accel * 0.5;
+        let cruise_d = self.move_d - accel_d - decel_d;
+
+        self.start_v = start_v2.sqrt();
+        self.cruise_v = cruise_v2.sqrt();
+        self.end_v = end_v2.sqrt();
+        // distance over average speed
+        self.accel_t = accel_d / ((self.start_v + self.cruise_v) * 0.5);
+        self.cruise_t = cruise_d / self.cruise_v;
+        self.decel_t = decel_d / ((self.end_v + self.cruise_v) * 0.5);
+    }
+
+    /// Distance travelled along each axis
+    pub fn axes_d(&self) -> Coord {
+        self.axes_r * self.move_d
+    }
+
+    pub fn total_t(&self) -> Seconds {
+        self.accel_t + self.cruise_t + self.decel_t
+    }
+
+    fn to_trapezoid(&self, print_time: Seconds) -> Trapezoid {
+        Trapezoid {
+            print_time,
+            accel_t: self.accel_t,
+            cruise_t: self.cruise_t,
+            decel_t: self.decel_t,
+            start_pos: self.start_pos,
+            axes_r: self.axes_r,
+            start_v: self.start_v,
+            cruise_v: self.cruise_v,
+            accel: self.accel,
+        }
+    }
+
+    /// Just the filament's part of the move. Pressure advance only kicks in while
+    /// extruding along with the toolhead moving in XY
+    fn to_extruder_trapezoid(&self, print_time: Seconds) -> Trapezoid {
+        let can_pressure_advance =
+            self.extrude_r > 0.0 && (self.axes_r.x != 0.0 || self.axes_r.y != 0.0);
+        Trapezoid {
+            print_time,
+            accel_t: self.accel_t,
+            cruise_t: self.cruise_t,
+            decel_t: self.decel_t,
+            start_pos: Coord::new(self.start_e.0, 0.0, 0.0),
+            axes_r: Coord::new(1.0, f64::from(u8::from(can_pressure_advance)), 0.0),
+            start_v: self.start_v * self.extrude_r,
+            cruise_v: self.cruise_v * self.extrude_r,
+            accel: self.accel * self.extrude_r,
+        }
+    }
+}
+
+/// Moves waiting for enough company to be planned
+#[derive(Debug)]
+struct LookAheadQueue {
+    queue: Vec<Move>,
+    junction_flush: Seconds,
+}
+
+impl LookAheadQueue {
+    fn new() -> Self {
+        Self {
+            queue: Vec::new(),
+            junction_flush: LOOKAHEAD_FLUSH_TIME,
+        }
+    }
+
+    /// Queue a move, returning whether there's now enough planned to lazily flush
+    fn add_move(&mut self, mut mv: Move) -> bool {
+        if let Some(prev) = self.queue.last() {
+            mv.calc_junction(prev);
+            self.junction_flush -= mv.min_move_t;
+        }
+        let first = self.queue.is_empty();
+        self.queue.push(mv);
+        !first && self.junction_flush <= Seconds::ZERO
+    }
+
+    /// Plan speeds assuming a full stop after the last queued move, and pop the moves
+    /// that are settled. With `lazy`, only moves that more moves can't speed up are taken
+    fn flush(&mut self, lazy: bool) -> Vec<Move> {
+        type V2 = MillimetersSquaredPerSecondSquared;
+        self.junction_flush = LOOKAHEAD_FLUSH_TIME;
+        let mut update_flush_count = lazy;
+        let mut flush_count = self.queue.len();
+
+        // walk backwards from the (assumed) stop at the end
+        let mut delayed: Vec<(usize, V2, V2)> = Vec::new();
+        let mut next_end_v2 = V2::ZERO;
+        let mut next_smoothed_v2 = V2::ZERO;
+        let mut peak_cruise_v2 = V2::ZERO;
+        for i in (0..flush_count).rev() {
+            let mv = &self.queue[i];
+            let reachable_start_v2 = next_end_v2 + mv.delta_v2;
+            let start_v2 = mv.max_start_v2.min(reachable_start_v2);
+            let reachable_smoothed_v2 = next_smoothed_v2 + mv.smooth_delta_v2;
+            let smoothed_v2 = mv.max_smoothed_v2.min(reachable_smoothed_v2);
+            if smoothed_v2 < reachable_smoothed_v2 {
+                // this move has room to accelerate
+                if smoothed_v2 + mv.smooth_delta_v2 > next_smoothed_v2 || !delayed.is_empty() {
+                    // it can also decelerate, or it's a full accel after a full decel
+                    if update_flush_count && peak_cruise_v2 != V2::ZERO {
+                        flush_count = i;
+                        update_flush_count = false;
+                    }
+                    peak_cruise_v2 = mv
+                        .max_cruise_v2
+                        .min((smoothed_v2 + reachable_smoothed_v2) * 0.5);
+                    if !update_flush_count && i < flush_count {
+                        // now the peak is known, the delayed moves can be planned
+                        let mut mc_v2 = peak_cruise_v2;
+                        for &(j, ms_v2, me_v2) in delayed.iter().rev() {
+                            mc_v2 = mc_v2.min(ms_v2);
+                            self.queue[j].set_junction(ms_v2.min(mc_v2), mc_v2, me_v2.min(mc_v2));
+                        }
+                    }
+                    delayed.clear();
+                }
+                if !update_flush_count && i < flush_count {
+                    let mv = &mut self.queue[i];
+                    let cruise_v2 = ((start_v2 + reachable_start_v2) * 0.5)
+                        .min(mv.max_cruise_v2)
+                        .min(peak_cruise_v2);
+                    mv.set_junction(
+                        start_v2.min(cruise_v2),
+                        cruise_v2,
+                        next_end_v2.min(cruise_v2),
+                    );
+                }
+            } else {
+                // can't plan this one until the peak speed before it is known
+                delayed.push((i, start_v2, next_end_v2));
+            }
+            next_end_v2 = start_v2;
+            next_smoothed_v2 = smoothed_v2;
+        }
+
+        if update_flush_count || flush_count == 0 {
+            return Vec::new();
+        }
+        self.queue.drain(..flush_count).collect()
+    }
+}
+
+/// One toolhead move of a transformed move: where it ends, and how far through the
+/// requested move that is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MoveSegment {
+    pub end_pos: Coord,
+    pub fraction: f64,
+}
+
+/// Reshapes moves on their way to the planner, like bed mesh leveling does: klipper's
+/// gcode move transforms
+pub trait MoveTransform {
+    /// Toolhead moves that take the toolhead at `from` to the requested position `to`
+    fn split_move(&self, from: Coord, to: Coord) -> Vec<MoveSegment>;
+
+    /// Requested position that puts the toolhead at `pos`
+    fn requested_position(&self, pos: Coord) -> Coord;
+}
+
+/// Moves go straight to the toolhead as requested
+pub struct Untransformed;
+
+impl MoveTransform for Untransformed {
+    fn split_move(&self, _from: Coord, to: Coord) -> Vec<MoveSegment> {
+        vec![MoveSegment {
+            end_pos: to,
+            fraction: 1.0,
+        }]
+    }
+
+    fn requested_position(&self, pos: Coord) -> Coord {
+        pos
+    }
+}
+
+/// One transform feeding another: requested positions go through `outer`, then whatever
+/// it asks for goes through `inner` on its way to the toolhead
+pub struct ChainedTransform<'a> {
+    pub outer: &'a dyn MoveTransform,
+    pub inner: &'a dyn MoveTransform,
+}
+
+impl MoveTransform for ChainedTransform<'_> {
+    fn split_move(&self, from: Coord, to: Coord) -> Vec<MoveSegment> {
+        let mut segments = Vec::new();
+        let mut toolhead = from;
+        let mut done = 0.0;
+        for outer in self
+            .outer
+            .split_move(self.inner.requested_position(from), to)
+        {
+            for inner in self.inner.split_move(toolhead, outer.end_pos) {
+                segments.push(MoveSegment {
+                    end_pos: inner.end_pos,
+                    fraction: done + (outer.fraction - done) * inner.fraction,
+                });
+                toolhead = inner.end_pos;
+            }
+            done = outer.fraction;
+        }
+        segments
+    }
+
+    fn requested_position(&self, pos: Coord) -> Coord {
+        self.outer
+            .requested_position(self.inner.requested_position(pos))
+    }
+}
+
+/// What a move has to get past before it's queued, as in klipper's `toolhead.move`
+pub struct MoveChecks<'a, K> {
+    pub kin: &'a K,
+    pub extruder: &'a Extruder,
+    /// Whether the extruder's heater is hot enough to extrude
+    pub can_extrude: bool,
+}
+
+impl<K: Kinematics> MoveChecks<'_, K> {
+    /// Reject `mv` if the kinematics or the extruder can't make it, and slow it down to
+    /// what they can manage
+    pub fn check(&self, mv: &mut Move) -> Result<(), KinematicsError> {
+        if mv.is_kinematic {
+            self.kin.check_move(mv)?;
+        }
+        if mv.end_e != mv.start_e {
+            self.extruder.check_move(mv, self.can_extrude)?;
+        }
+        Ok(())
+    }
+}
+
+/// Plans moves and puts them on the toolhead's trapq
+#[derive(Debug)]
+pub struct Planner {
+    config: PlannerConfig,
+    lookahead: LookAheadQueue,
+    /// Where the last queued move ends
+    position: Coord,
+    e_position: Millimeters,
+    /// When the next planned move will start
+    print_time: Seconds,
+    trapq: TrapQ,
+    /// Filament moves, with E in x and whether pressure advance applies in y
+    extruder_trapq: TrapQ,
+}
+
+impl Planner {
+    pub fn new(config: PlannerConfig) -> Self {
+        Self {
+            config,
+            lookahead: LookAheadQueue::new(),
+            position: Coord::default(),
+            e_position: Millimeters::ZERO,
+            print_time: Seconds::ZERO,
+            trapq: TrapQ::new(),
+            extruder_trapq: TrapQ::new(),
+        }
+    }
+
+    pub fn config(&self) -> &PlannerConfig {
+        &self.config
+    }
+
+    pub fn position(&self) -> Coord {
+        self.position
+    }
+
+    pub fn e_position(&self) -> Millimeters {
+        self.e_position
+    }
+
+    /// When everything planned so far will have finished
+    pub fn print_time(&self) -> Seconds {
+        self.print_time
+    }
+
+    pub fn trapq(&self) -> &TrapQ {
+        &self.trapq
+    }
+
+    pub fn trapq_mut(&mut self) -> &mut TrapQ {
+        &mut self.trapq
+    }
+
+    pub fn extruder_trapq(&self) -> &TrapQ {
+        &self.extruder_trapq
+    }
+
+    pub fn extruder_trapq_mut(&mut self) -> &mut TrapQ {
+        &mut self.extruder_trapq
+    }
+
+    /// A move from the current position to `end_pos`, ready for any extra speed limits
+    pub fn make_move(&self, end_pos: Coord, speed: MillimetersPerSecond) -> Move {
+        self.make_extrude_move(end_pos, self.e_position, speed)
+    }
+
+    /// Move that also takes the extruder to `end_e`
+    pub fn make_extrude_move(
+        &self,
+        end_pos: Coord,
+        end_e: Millimeters,
+        speed: MillimetersPerSecond,
+    ) -> Move {
+        Move::new(
+            &self.config,
+            self.position,
+            end_pos,
+            self.e_position,
+            end_e,
+            speed,
+        )
+    }
+
+    /// Straight line to `end_pos` at up to `speed`
+    pub fn move_to(&mut self, end_pos: Coord, speed: MillimetersPerSecond) {
+        self.add_move(self.make_move(end_pos, speed));
+    }
+
+    /// Straight line to `end_pos` at up to `speed`, if the kinematics allow it
+    pub fn checked_move<K: Kinematics>(
+        &mut self,
+        kin: &K,
+        end_pos: Coord,
+        speed: MillimetersPerSecond,
+    ) -> Result<(), KinematicsError> {
+        let mut mv = self.make_move(end_pos, speed);
+        if mv.is_kinematic {
+            kin.check_move(&mut mv)?;
+        }
+        self.add_move(mv);
+        Ok(())
+    }
+
+    /// Straight line to `end_pos`, taking the extruder to `end_e`, if `checks` allow it
+    pub fn checked_extrude_move<K: Kinematics>(
+        &mut self,
+        checks: &MoveChecks<K>,
+        end_pos: Coord,
+        end_e: Millimeters,
+        speed: MillimetersPerSecond,
+    ) -> Result<(), KinematicsError> {
+        let mut mv = self.make_extrude_move(end_pos, end_e, speed);
+        checks.check(&mut mv)?;
+        self.add_move(mv);
+        Ok(())
+    }
+
+    /// Move to where `transform` says `end_pos` really is, extruding to `end_e` evenly along
+    /// the way, if `checks` allow it
+    pub fn transformed_move<K: Kinematics>(
+        &mut self,
+        checks: &MoveChecks<K>,
+        transform: &dyn MoveTransform,
+        end_pos: Coord,
+        end_e: Millimeters,
+        speed: MillimetersPerSecond,
+    ) -> Result<(), KinematicsError> {
+        let (start, start_e) = (self.position, self.e_position);
+        let (mut pos, mut e_pos) = (start, start_e);
+        // every segment is checked before any is queued, so nothing moves unless all can
+        let mut moves = Vec::new();
+        for segment in transform.split_move(start, end_pos) {
+            let e = start_e + (end_e - start_e) * segment.fraction;
+            let mut mv = Move::new(&self.config, pos, segment.end_pos, e_pos, e, speed);
+            checks.check(&mut mv)?;
+            // as add_move would, dropping it without moving the start of the next
+            if mv.move_d > MIN_MOVE_DISTANCE {
+                (pos, e_pos) = (mv.end_pos, mv.end_e);
+                moves.push(mv);
+            }
+        }
+        for mv in moves {
+            self.add_move(mv);
+        }
+        Ok(())
+    }
+
+    /// Queue a move made by [`Planner::make_move`]
+    pub fn add_move(&mut self, mv: Move) {
+        if mv.move_d <= MIN_MOVE_DISTANCE {
+            return;
+        }
+        self.position = mv.end_pos;
+        self.e_position = mv.end_e;
+        if self.lookahead.add_move(mv) {
+            self.process(true);
+        }
+    }
+
+    /// Plan and emit every queued move, ending at a full stop
+    pub fn flush(&mut self) {
+        self.process(false);
+    }
+
+    /// Wait `delay` after everything currently queued
+    pub fn dwell(&mut self, delay: Seconds) {
+        self.flush();
+        self.print_time += delay.max(Seconds::ZERO);
+    }
+
+    /// Declare the toolhead to be at `pos`, without moving
+    pub fn set_position(&mut self, pos: Coord) {
+        self.flush();
+        self.trapq.set_position(self.print_time, pos);
+        self.position = pos;
+    }
+
+    /// Declare the extruder to be at `e`, like `G92 E0`
+    pub fn set_e_position(&mut self, e: Millimeters) {
+        self.flush();
+        self.extruder_trapq
+            .set_position(self.print_time, Coord::new(e.0, 0.0, 0.0));
+        self.e_position = e;
+    }
+
+    fn process(&mut self, lazy: bool) {
+        for mv in self.lookahead.flush(lazy) {
+            if mv.is_kinematic {
+                self.trapq.append(&mv.to_trapezoid(self.print_time));
+            }
+            if mv.end_e != mv.start_e {
+                self.extruder_trapq
+                    .append(&mv.to_extruder_trapezoid(self.print_time));
+            }
+            self.print_time += mv.total_t();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn planner() -> Planner {
+        Planner::new(PlannerConfig::new(
+            MillimetersPerSecond(100.0),
+            MillimetersPerSecondSquared(1000.0),
+        ))
+    }
+
+    /// Speed at the end of a trapq piece
+    fn end_v(m: &crate::trapq::Move) -> MillimetersPerSecond {
+        m.start_v + m.half_accel * m.move_t * 2.0
+    }
+
+    #[test]
+    fn test_single_move() {
+        let mut p = planner();
+        p.move_to(Coord::new(100.0, 0.0, 0.0), MillimetersPerSecond(200.0));
+        p.flush();
+        // accel to 100mm/s over 5mm, cruise 90mm, decel over 5mm
+        let end = p.print_time();
+        assert!((end - Seconds(1.1)).abs() < Seconds(1e-9), "{}", end);
+        let pos = p.trapq().position_at(end).unwrap();
+        assert!((pos.x - 100.0).abs() < 1e-9);
+        let pieces: Vec<_> = p.trapq().moves().collect();
+        assert_eq!(pieces.len(), 3);
+        assert!((end_v(pieces[0]).0 - 100.0).abs() < 1e-9);
+        assert!(end_v(pieces[2]).abs().0 < 1e-9);
+    }
+
+    #[test]
+    fn test_collinear_moves_keep_speed() {
+        let mut p = planner();
+        for i in 1..=10 {
+            p.move_to(
+                Coord::new(10.0 * i as f64, 0.0, 0.0),
+                MillimetersPerSecond(100.0),
+            );
+        }
+        p.flush();
+        // same as one 100mm move
+        assert!((p.print_time() - Seconds(1.1)).abs() < Seconds(1e-9));
+    }
+
+    #[test]
+    fn test_square_corner() {
+        let mut p = planner();
+        p.move_to(Coord::new(100.0, 0.0, 0.0), MillimetersPerSecond(100.0));
+        p.move_to(Coord::new(100.0, 100.0, 0.0), MillimetersPerSecond(100.0));
+        p.flush();
+        let pieces: Vec<_> = p.trapq().moves().copied().collect();
+        // first move decelerates to the corner, second accelerates out of it
+        let corner_in = end_v(&pieces[2]);
+        let corner_out = pieces[3].start_v;
+        assert!((corner_in.0 - 5.0).abs() < 1e-9, "{}", corner_in);
+        assert!((corner_out.0 - 5.0).abs() < 1e-9, "{}", corner_out);
+        let end = p.trapq().position_at(p.print_time()).unwrap();
+        assert!((end - Coord::new(100.0, 100.0, 0.0)).norm() < 1e-9);
+    }
+
+    #[test]
+    fn test_short_moves_never_cruise_fast() {
+        let mut p = planner();
+        // 2mm zig-zags, like infill
+        for i in 1..=20 {
+            let y = if i % 2 == 0 { 0.0 } else { 2.0 };
+            p.move_to(
+                Coord::new(0.2 * i as f64, y, 0.0),
+                MillimetersPerSecond(100.0),
+            );
+        }
+        p.flush();
+        let fastest = p
+            .trapq()
+            .moves()
+            .map(|m| end_v(m).max(m.start_v))
+            .fold(MillimetersPerSecond::ZERO, MillimetersPerSecond::max);
+        // 1000mm/s² over ~1mm (half the move) is ~44mm/s, the cruise ratio halves that accel
+        assert!(fastest.0 < 35.0, "{}", fastest);
+        assert!(fastest.0 > 5.0, "{}", fastest);
+    }
+
+    #[test]
+    fn test_limit_speed() {
+        let mut p = planner();
+        let mut mv = p.make_move(Coord::new(0.0, 0.0, 10.0), MillimetersPerSecond(100.0));
+        mv.limit_speed(
+            MillimetersPerSecond(5.0),
+            MillimetersPerSecondSquared(100.0),
+        );
+        p.add_move(mv);
+        p.flush();
+        // 0.05s each way to reach 5mm/s over 0.125mm, then 9.75mm at 5mm/s
+        assert!((p.print_time() - Seconds(2.05)).abs() < Seconds(1e-9));
+    }
+
+    #[test]
+    fn test_extrusion() {
+        let mut p = planner();
+        let speed = MillimetersPerSecond(100.0);
+        // stopping extruding mid-line means the extruder's speed has to jump
+        p.add_move(p.make_extrude_move(Coord::new(50.0, 0.0, 0.0), Millimeters(2.5), speed));
+        p.move_to(Coord::new(100.0, 0.0, 0.0), speed);
+        p.flush();
+        let pieces: Vec<_> = p.trapq().moves().collect();
+        let junction = pieces
+            .iter()
+            .find(|m| (m.start_pos.x - 50.0).abs() < 1e-9)
+            .unwrap();
+        // 1mm/s of instant change over 0.05mm of filament per mm
+        assert!((junction.start_v.0 - 20.0).abs() < 1e-9);
+        let e_end = p.extruder_trapq().position_at(p.print_time()).unwrap();
+        assert!((e_end.x - 2.5).abs() < 1e-9);
+
+        // extrude-only moves only go on the extruder's trapq
+        let before = p.trapq().moves().count();
+        let mv = p.make_extrude_move(p.position(), Millimeters(1.5), speed);
+        assert!(!mv.is_kinematic);
+        assert_eq!(mv.extrude_r, -1.0);
+        p.add_move(mv);
+        p.flush();
+        assert_eq!(p.trapq().moves().count(), before);
+        assert_eq!(p.e_position(), Millimeters(1.5));
+        let e_end = p.extruder_trapq().position_at(p.print_time()).unwrap();
+        assert!((e_end.x - 1.5).abs() < 1e-9);
+
+        p.set_e_position(Millimeters::ZERO);
+        assert_eq!(p.e_position(), Millimeters::ZERO);
+    }
+}
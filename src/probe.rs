@@ -0,0 +1,665 @@
+//! Z probes: home Z towards the bed until a probe triggers, and note where. A port of
+//! klipper's `probe.py`
+
+use crate::homing::{HomingContext, HomingEndstop, HomingError};
+use crate::kinematics::{Axis, Kinematics};
+use crate::mcu::{self, McuLink};
+use crate::trapq::Coord;
+use crate::units::{Millimeters, MillimetersPerSecond, Seconds};
+
+/// `PROBE_ACCURACY` samples, unless told otherwise
+const ACCURACY_SAMPLES: usize = 10;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ProbeError {
+    #[error("Must home before probe")]
+    NotHomed,
+    #[error("Probe triggered prior to movement")]
+    TriggeredBeforeMovement,
+    #[error("Probe samples exceed samples_tolerance")]
+    SamplesTolerance,
+    #[error("{0} out of range")]
+    InvalidParameter(&'static str),
+    #[error("horizontal_move_z can't be less than probe's z_offset")]
+    HorizontalMoveZ,
+    #[error(transparent)]
+    Homing(#[from] HomingError),
+    #[error(transparent)]
+    Mcu(#[from] mcu::Error),
+}
+
+/// How several samples at one spot become one result
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SamplesResult {
+    #[default]
+    Average,
+    Median,
+}
+
+/// A `[probe]` section
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbeConfig {
+    /// Where the probe is relative to the nozzle
+    pub x_offset: Millimeters,
+    pub y_offset: Millimeters,
+    /// Height of the nozzle above the bed when the probe triggers
+    pub z_offset: Millimeters,
+    pub speed: MillimetersPerSecond,
+    pub lift_speed: MillimetersPerSecond,
+    /// How low to go before giving up, usually the Z rail's `position_min`
+    pub z_position: Millimeters,
+    pub samples: usize,
+    /// How far to lift between samples
+    pub sample_retract_dist: Millimeters,
+    pub samples_result: SamplesResult,
+    /// Samples further apart than this get thrown away and retaken
+    pub samples_tolerance: Millimeters,
+    pub samples_tolerance_retries: usize,
+}
+
+impl ProbeConfig {
+    /// Klipper's defaults for everything but the offsets
+    pub fn new(x_offset: Millimeters, y_offset: Millimeters, z_offset: Millimeters) -> Self {
+        Self {
+            x_offset,
+            y_offset,
+            z_offset,
+            speed: MillimetersPerSecond(5.0),
+            lift_speed: MillimetersPerSecond(5.0),
+            z_position: Millimeters(0.0),
+            samples: 1,
+            sample_retract_dist: Millimeters(2.0),
+            samples_result: SamplesResult::Average,
+            samples_tolerance: Millimeters(0.100),
+            samples_tolerance_retries: 0,
+        }
+    }
+}
+
+/// `PROBE` parameters, each overriding the config when given
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProbeParams {
+    pub speed: Option<MillimetersPerSecond>,
+    pub lift_speed: Option<MillimetersPerSecond>,
+    pub samples: Option<usize>,
+    pub sample_retract_dist: Option<Millimeters>,
+    pub samples_result: Option<SamplesResult>,
+    pub samples_tolerance: Option<Millimeters>,
+    pub samples_tolerance_retries: Option<usize>,
+}
+
+/// `PROBE_ACCURACY` parameters, overriding the config when given
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProbeAccuracyParams {
+    pub speed: Option<MillimetersPerSecond>,
+    pub lift_speed: Option<MillimetersPerSecond>,
+    /// Defaults to 10, whatever the config says
+    pub samples: Option<usize>,
+    pub sample_retract_dist: Option<Millimeters>,
+}
+
+/// Config and parameters, combined and checked
+struct ProbeRun {
+    speed: MillimetersPerSecond,
+    lift_speed: MillimetersPerSecond,
+    samples: usize,
+    sample_retract_dist: Millimeters,
+    samples_result: SamplesResult,
+    samples_tolerance: Millimeters,
+    samples_tolerance_retries: usize,
+}
+
+impl ProbeRun {
+    fn new(config: &ProbeConfig, params: &ProbeParams) -> Result<Self, ProbeError> {
+        let run = Self {
+            speed: params.speed.unwrap_or(config.speed),
+            lift_speed: params.lift_speed.unwrap_or(config.lift_speed),
+            samples: params.samples.unwrap_or(config.samples),
+            sample_retract_dist: params
+                .sample_retract_dist
+                .unwrap_or(config.sample_retract_dist),
+            samples_result: params.samples_result.unwrap_or(config.samples_result),
+            samples_tolerance: params.samples_tolerance.unwrap_or(config.samples_tolerance),
+            samples_tolerance_retries: params
+                .samples_tolerance_retries
+                .unwrap_or(config.samples_tolerance_retries),
+        };
+        if run.speed <= MillimetersPerSecond::ZERO {
+            return Err(ProbeError::InvalidParameter("speed"));
+        }
+        if run.lift_speed <= MillimetersPerSecond::ZERO {
+            return Err(ProbeError::InvalidParameter("lift_speed"));
+        }
+        if run.samples == 0 {
+            return Err(ProbeError::InvalidParameter("samples"));
+        }
+        if run.sample_retract_dist <= Millimeters::ZERO {
+            return Err(ProbeError::InvalidParameter("sample_retract_dist"));
+        }
+        if run.samples_tolerance < Millimeters::ZERO {
+            return Err(ProbeError::InvalidParameter("samples_tolerance"));
+        }
+        Ok(run)
+    }
+}
+
+/// Combine samples taken at one spot
+fn calc_probe_z_average(positions: &[Coord], method: SamplesResult) -> Coord {
+    if method == SamplesResult::Average {
+        let sum = positions.iter().fold(Coord::default(), |sum, &p| sum + p);
+        return sum * (1.0 / positions.len() as f64);
+    }
+    let mut sorted = positions.to_vec();
+    sorted.sort_by(|a, b| a.z.total_cmp(&b.z));
+    let middle = sorted.len() / 2;
+    if sorted.len() % 2 == 1 {
+        return sorted[middle];
+    }
+    calc_probe_z_average(&sorted[middle - 1..=middle], SamplesResult::Average)
+}
+
+/// `PROBE_ACCURACY` results
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProbeAccuracy {
+    pub maximum: Millimeters,
+    pub minimum: Millimeters,
+    pub range: Millimeters,
+    pub average: Millimeters,
+    pub median: Millimeters,
+    pub standard_deviation: Millimeters,
+}
+
+impl ProbeAccuracy {
+    pub fn from_samples(positions: &[Coord]) -> Self {
+        let z = || positions.iter().map(|p| p.z);
+        let maximum = z().fold(f64::NEG_INFINITY, f64::max);
+        let minimum = z().fold(f64::INFINITY, f64::min);
+        let average = calc_probe_z_average(positions, SamplesResult::Average).z;
+        let median = calc_probe_z_average(positions, SamplesResult::Median).z;
+        let deviation_sum: f64 = z().map(|z| (z - average).powi(2)).sum();
+        Self {
+            maximum: Millimeters(maximum),
+            minimum: Millimeters(minimum),
+            range: Millimeters(maximum - minimum),
+            average: Millimeters(average),
+            median: Millimeters(median),
+            standard_deviation: Millimeters((deviation_sum / positions.len() as f64).sqrt()),
+        }
+    }
+}
+
+impl std::fmt::Display for ProbeAccuracy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "probe accuracy results: maximum {:.6}, minimum {:.6}, range {:.6}, \
+             average {:.6}, median {:.6}, standard deviation {:.6}",
+            self.maximum.0,
+            self.minimum.0,
+            self.range.0,
+            self.average.0,
+            self.median.0,
+            self.standard_deviation.0,
+        )
+    }
+}
+
+/// Whatever a probe needs doing besides watching its endstop. A switch needs nothing,
+/// the likes of a BLTouch deploy and stow a pin. Each hook acts at `print_time` and
+/// returns how long it takes
+pub trait ProbeHooks {
+    /// Before a run of probes
+    fn multi_probe_begin(
+        &mut self,
+        _link: &mut dyn McuLink,
+        _print_time: Seconds,
+    ) -> Result<Seconds, mcu::Error> {
+        Ok(Seconds::ZERO)
+    }
+
+    /// After a run of probes
+    fn multi_probe_end(
+        &mut self,
+        _link: &mut dyn McuLink,
+        _print_time: Seconds,
+    ) -> Result<Seconds, mcu::Error> {
+        Ok(Seconds::ZERO)
+    }
+
+    /// Before each probing move
+    fn probe_prepare(
+        &mut self,
+        _link: &mut dyn McuLink,
+        _print_time: Seconds,
+    ) -> Result<Seconds, mcu::Error> {
+        Ok(Seconds::ZERO)
+    }
+
+    /// After each probing move, whether or not it triggered
+    fn probe_finish(
+        &mut self,
+        _link: &mut dyn McuLink,
+        _print_time: Seconds,
+    ) -> Result<Seconds, mcu::Error> {
+        Ok(Seconds::ZERO)
+    }
+}
+
+/// A plain switch, nothing to do but wait for it
+pub struct SwitchProbe;
+
+impl ProbeHooks for SwitchProbe {}
+
+/// Every stepper that moves Z, which is everything a probe has to stop
+pub fn z_steppers<K: Kinematics>(kin: &K) -> Vec<usize> {
+    kin.steppers()
+        .iter()
+        .enumerate()
+        .filter(|(_, stepper)| stepper.active_axes().contains(Axis::Z))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+pub struct Probe {
+    config: ProbeConfig,
+    endstop: HomingEndstop,
+    hooks: Box<dyn ProbeHooks>,
+}
+
+impl Probe {
+    /// `endstop` has to stop every Z stepper, see [`z_steppers`]
+    pub fn new(config: ProbeConfig, endstop: HomingEndstop, hooks: Box<dyn ProbeHooks>) -> Self {
+        Self {
+            config,
+            endstop,
+            hooks,
+        }
+    }
+
+    pub fn config(&self) -> &ProbeConfig {
+        &self.config
+    }
+
+    /// Where the probe is relative to the nozzle, and the nozzle's height when it triggers
+    pub fn offsets(&self) -> Coord {
+        Coord::new(
+            self.config.x_offset.0,
+            self.config.y_offset.0,
+            self.config.z_offset.0,
+        )
+    }
+
+    /// Run a hook, then wait for it
+    fn hook<K: Kinematics<Position = Coord>, L: McuLink>(
+        &mut self,
+        ctx: &mut HomingContext<K, L>,
+        hook: impl FnOnce(&mut dyn ProbeHooks, &mut dyn McuLink, Seconds) -> Result<Seconds, mcu::Error>,
+    ) -> Result<(), ProbeError> {
+        let delay = hook(&mut *self.hooks, &mut *ctx.link, ctx.planner.print_time())?;
+        ctx.planner.dwell(delay);
+        Ok(())
+    }
+
+    /// Get ready for a run of [`Probe::run_probe`]s
+    pub fn begin<K: Kinematics<Position = Coord>, L: McuLink>(
+        &mut self,
+        ctx: &mut HomingContext<K, L>,
+    ) -> Result<(), ProbeError> {
+        self.hook(ctx, |hooks, link, t| hooks.multi_probe_begin(link, t))
+    }
+
+    pub fn end<K: Kinematics<Position = Coord>, L: McuLink>(
+        &mut self,
+        ctx: &mut HomingContext<K, L>,
+    ) -> Result<(), ProbeError> {
+        self.hook(ctx, |hooks, link, t| hooks.multi_probe_end(link, t))
+    }
+
+    /// Move down until the probe triggers, returning the toolhead position when it did
+    fn probe_once<K: Kinematics<Position = Coord>, L: McuLink>(
+        &mut self,
+        ctx: &mut HomingContext<K, L>,
+        speed: MillimetersPerSecond,
+    ) -> Result<Coord, ProbeError> {
+        if !ctx.kin.homed_axes().contains(Axis::Z) {
+            return Err(ProbeError::NotHomed);
+        }
+        let mut pos = ctx.planner.position();
+        pos.z = self.config.z_position.0;
+        self.hook(ctx, |hooks, link, t| hooks.probe_prepare(link, t))?;
+        let hmove = ctx.homing_move(&mut [&mut self.endstop], pos, speed, true);
+        self.hook(ctx, |hooks, link, t| hooks.probe_finish(link, t))?;
+        let hmove = hmove?;
+        if hmove.no_movement().is_some() {
+            return Err(ProbeError::TriggeredBeforeMovement);
+        }
+        Ok(hmove.trigger_pos)
+    }
+
+    /// Probe the spot under the probe, as many times as asked, retrying while the samples
+    /// are too far apart. Returns the toolhead position the probe triggers at
+    pub fn run_probe<K: Kinematics<Position = Coord>, L: McuLink>(
+        &mut self,
+        ctx: &mut HomingContext<K, L>,
+        params: &ProbeParams,
+    ) -> Result<Coord, ProbeError> {
+        let run = ProbeRun::new(&self.config, params)?;
+        let probexy = ctx.planner.position();
+        let mut retries = 0;
+        let mut positions = Vec::new();
+        while positions.len() < run.samples {
+            let pos = self.probe_once(ctx, run.speed)?;
+            positions.push(pos);
+            let z = || positions.iter().map(|p| p.z);
+            let spread = z().fold(f64::NEG_INFINITY, f64::max) - z().fold(f64::INFINITY, f64::min);
+            if spread > run.samples_tolerance.0 {
+                if retries >= run.samples_tolerance_retries {
+                    return Err(ProbeError::SamplesTolerance);
+                }
+                retries += 1;
+                positions.clear();
+            }
+            if positions.len() < run.samples {
+                let lift = Coord::new(probexy.x, probexy.y, pos.z + run.sample_retract_dist.0);
+                ctx.move_to(lift, run.lift_speed)?;
+            }
+        }
+        Ok(calc_probe_z_average(&positions, run.samples_result))
+    }
+
+    /// `PROBE`: one [`Probe::run_probe`] on its own
+    pub fn probe<K: Kinematics<Position = Coord>, L: McuLink>(
+        &mut self,
+        ctx: &mut HomingContext<K, L>,
+        params: &ProbeParams,
+    ) -> Result<Coord, ProbeError> {
+        self.begin(ctx)?;
+        let pos = self.run_probe(ctx, params);
+        self.end(ctx)?;
+        pos
+    }
+
+    /// `PROBE_ACCURACY`: probe the same spot over and over, and see how much it varies
+    pub fn probe_accuracy<K: Kinematics<Position = Coord>, L: McuLink>(
+        &mut self,
+        ctx: &mut HomingContext<K, L>,
+        params: &ProbeAccuracyParams,
+    ) -> Result<ProbeAccuracy, ProbeError> {
+        let run = ProbeRun::new(
+            &self.config,
+            &ProbeParams {
+                speed: params.speed,
+                lift_speed: params.lift_speed,
+                samples: Some(params.samples.unwrap_or(ACCURACY_SAMPLES)),
+                sample_retract_dist: params.sample_retract_dist,
+                ..ProbeParams::default()
+            },
+        )?;
+        self.begin(ctx)?;
+        let mut positions = Vec::new();
+        while positions.len() < run.samples {
+            let pos = match self.probe_once(ctx, run.speed) {
+                Ok(pos) => pos,
+                Err(err) => {
+                    self.end(ctx)?;
+                    return Err(err);
+                }
+            };
+            positions.push(pos);
+            let lift = Coord::new(pos.x, pos.y, pos.z + run.sample_retract_dist.0);
+            ctx.move_to(lift, run.lift_speed)?;
+        }
+        self.end(ctx)?;
+        Ok(ProbeAccuracy::from_samples(&positions))
+    }
+}
+
+/// Probe one point after another, lifting to `horizontal_move_z` in between, klipper's
+/// `ProbePointsHelper`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbePoints {
+    pub points: Vec<(f64, f64)>,
+    pub horizontal_move_z: Millimeters,
+    /// Travel speed between points
+    pub speed: MillimetersPerSecond,
+    /// Whether `points` are where the probe goes, rather than the nozzle
+    pub use_offsets: bool,
+}
+
+impl ProbePoints {
+    /// Probe every point, between [`Probe::begin`] and [`Probe::end`]. Returns the
+    /// toolhead position each one triggered at
+    pub fn run<K: Kinematics<Position = Coord>, L: McuLink>(
+        &self,
+        ctx: &mut HomingContext<K, L>,
+        probe: &mut Probe,
+    ) -> Result<Vec<Coord>, ProbeError> {
+        let config = probe.config().clone();
+        if self.horizontal_move_z < config.z_offset {
+            return Err(ProbeError::HorizontalMoveZ);
+        }
+        let lift_z = self.horizontal_move_z.0;
+        let mut positions = Vec::with_capacity(self.points.len());
+        for &(x, y) in &self.points {
+            let mut pos = ctx.planner.position();
+            pos.z = lift_z;
+            // full speed up to the first point, lift speed from then on
+            let lift_speed = match positions.is_empty() {
+                true => self.speed,
+                false => config.lift_speed,
+            };
+            ctx.move_to(pos, lift_speed)?;
+            let nozzle = match self.use_offsets {
+                true => Coord::new(x - config.x_offset.0, y - config.y_offset.0, lift_z),
+                false => Coord::new(x, y, lift_z),
+            };
+            ctx.move_to(nozzle, self.speed)?;
+            positions.push(probe.run_probe(ctx, &ProbeParams::default())?);
+        }
+        let mut pos = ctx.planner.position();
+        pos.z = lift_z;
+        ctx.move_to(pos, config.lift_speed)?;
+        Ok(positions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::kinematics::cartesian::{Cartesian, CartesianConfig};
+    use crate::kinematics::RailConfig;
+    use crate::mcu::endstop::Endstop;
+    use crate::mcu::fake::FakeLink;
+    use crate::mcu::pin::PinRef;
+    use crate::planner::{Planner, PlannerConfig};
+    use crate::stepcompress::StepCompressor;
+    use crate::units::{Hertz, MillimetersPerSecondSquared};
+
+    const FREQ: Hertz = Hertz(1_000_000.0);
+    const Z_STEP: f64 = 0.0025;
+    /// Where the toolhead gets declared to be, X and Y and Z
+    const START: Coord = Coord::new(100.0, 100.0, 3.0);
+
+    /// Moves the bed to the next height in line before each probe
+    struct ShiftingBed {
+        bed: Rc<Cell<f64>>,
+        heights: VecDeque<f64>,
+    }
+
+    impl ProbeHooks for ShiftingBed {
+        fn probe_prepare(
+            &mut self,
+            _link: &mut dyn McuLink,
+            _print_time: Seconds,
+        ) -> Result<Seconds, mcu::Error> {
+            if let Some(height) = self.heights.pop_front() {
+                self.bed.set(height);
+            }
+            Ok(Seconds::ZERO)
+        }
+    }
+
+    struct Printer {
+        kin: Cartesian,
+        planner: Planner,
+        link: FakeLink,
+        /// Where the nozzle really is
+        z: Rc<dyn Fn() -> f64>,
+        bed: Rc<Cell<f64>>,
+    }
+
+    impl Printer {
+        fn ctx(&mut self) -> HomingContext<'_, Cartesian, FakeLink> {
+            HomingContext {
+                kin: &mut self.kin,
+                planner: &mut self.planner,
+                link: &mut self.link,
+            }
+        }
+    }
+
+    /// Cartesian printer with a switch probe right on the nozzle, above a bed at
+    /// `heights`, one per probe, then staying at the last
+    fn setup(config: ProbeConfig, heights: &[f64]) -> (Printer, Probe) {
+        let rail = |step: f64, max: f64| {
+            RailConfig::new(
+                Millimeters(step),
+                Millimeters(0.0),
+                Millimeters(max),
+                Millimeters(0.0),
+            )
+            .unwrap()
+        };
+        let mut kin = Cartesian::new(
+            CartesianConfig {
+                x: rail(0.0125, 200.0),
+                y: rail(0.0125, 200.0),
+                z: rail(Z_STEP, 150.0),
+                max_z_velocity: MillimetersPerSecond(10.0),
+                max_z_accel: MillimetersPerSecondSquared(100.0),
+            },
+            FREQ,
+        );
+        let planner = Planner::new(PlannerConfig::new(
+            MillimetersPerSecond(300.0),
+            MillimetersPerSecondSquared(3000.0),
+        ));
+        let mut link = FakeLink::new(&["mcu"], FREQ);
+        let mcu = link.mcu("mcu").unwrap();
+        let oid = mcu.create_oid();
+        let sc = StepCompressor::new(oid, FREQ, Seconds(0.000_025), false);
+        kin.steppers_mut()[2].set_stepcompress(mcu, sc);
+        let z_steps = link.fake("mcu").stepper_position(oid);
+        let z: Rc<dyn Fn() -> f64> = Rc::new(move || START.z + z_steps.get() as f64 * Z_STEP);
+
+        let pin = PinRef {
+            mcu: "mcu".into(),
+            pin: "PB7".into(),
+        };
+        let endstop = Endstop::new(link.mcu("mcu").unwrap(), pin, false, true);
+        let bed = Rc::new(Cell::new(heights[0]));
+        let (nozzle, surface) = (z.clone(), bed.clone());
+        link.fake("mcu")
+            .set_pin("PB7", move || nozzle() <= surface.get());
+        let steppers = z_steppers(&kin);
+        let endstop = HomingEndstop::new(&mut link, &kin, "probe", endstop, steppers).unwrap();
+        let hooks = ShiftingBed {
+            bed: bed.clone(),
+            heights: heights.iter().copied().collect(),
+        };
+        let probe = Probe::new(config, endstop, Box::new(hooks));
+        let printer = Printer {
+            kin,
+            planner,
+            link,
+            z,
+            bed,
+        };
+        (printer, probe)
+    }
+
+    fn config() -> ProbeConfig {
+        ProbeConfig::new(Millimeters(-25.0), Millimeters(5.0), Millimeters(1.5))
+    }
+
+    #[test]
+    fn test_probe() {
+        let (mut printer, mut probe) = setup(config(), &[1.234]);
+        let err = probe.probe(&mut printer.ctx(), &ProbeParams::default());
+        assert!(matches!(err, Err(ProbeError::NotHomed)));
+
+        printer.ctx().set_position(START, Axis::XYZ).unwrap();
+        let pos = probe
+            .probe(&mut printer.ctx(), &ProbeParams::default())
+            .unwrap();
+        assert_eq!((pos.x, pos.y), (START.x, START.y));
+        assert!((pos.z - 1.234).abs() <= Z_STEP, "probed {}", pos.z);
+        // the host knows where Z stopped, a little past the trigger
+        let z = (printer.z)();
+        assert!((printer.planner.position().z - z).abs() < 1e-9);
+        assert!(z <= 1.234 && z > 1.234 - 0.1);
+        assert_eq!(probe.offsets(), Coord::new(-25.0, 5.0, 1.5));
+    }
+
+    #[test]
+    fn test_samples_tolerance() {
+        // second sample is way off, so the lot gets retaken
+        let heights = [1.0, 1.5, 1.0, 1.0, 1.02];
+        let params = ProbeParams {
+            samples: Some(3),
+            samples_result: Some(SamplesResult::Median),
+            ..ProbeParams::default()
+        };
+        let mut config = config();
+        config.samples_tolerance_retries = 1;
+        let (mut printer, mut probe) = setup(config.clone(), &heights);
+        printer.ctx().set_position(START, Axis::XYZ).unwrap();
+        let pos = probe.probe(&mut printer.ctx(), &params).unwrap();
+        assert!((pos.z - 1.0).abs() <= Z_STEP, "probed {}", pos.z);
+        assert_eq!(printer.bed.get(), 1.02);
+
+        config.samples_tolerance_retries = 0;
+        let (mut printer, mut probe) = setup(config, &heights);
+        printer.ctx().set_position(START, Axis::XYZ).unwrap();
+        let err = probe.probe(&mut printer.ctx(), &params);
+        assert!(matches!(err, Err(ProbeError::SamplesTolerance)));
+
+        let params = ProbeParams {
+            samples: Some(0),
+            ..ProbeParams::default()
+        };
+        let err = probe.probe(&mut printer.ctx(), &params);
+        assert!(matches!(err, Err(ProbeError::InvalidParameter("samples"))));
+    }
+
+    #[test]
+    fn test_probe_accuracy() {
+        let heights = [1.0, 1.1, 1.0, 1.2];
+        let (mut printer, mut probe) = setup(config(), &heights);
+        printer.ctx().set_position(START, Axis::XYZ).unwrap();
+        let params = ProbeAccuracyParams {
+            samples: Some(4),
+            ..ProbeAccuracyParams::default()
+        };
+        let acc = probe.probe_accuracy(&mut printer.ctx(), &params).unwrap();
+        assert!((acc.maximum.0 - 1.2).abs() <= Z_STEP);
+        assert!((acc.minimum.0 - 1.0).abs() <= Z_STEP);
+        assert!((acc.median.0 - 1.05).abs() <= Z_STEP);
+
+        let exact = [1.0, 2.0, 3.0, 4.0].map(|z| Coord::new(0.0, 0.0, z));
+        let acc = ProbeAccuracy::from_samples(&exact);
+        assert_eq!(acc.range, Millimeters(3.0));
+        assert_eq!(acc.average, Millimeters(2.5));
+        assert_eq!(acc.median, Millimeters(2.5));
+        assert!((acc.standard_deviation.0 - 1.25f64.sqrt()).abs() < 1e-12);
+        assert_eq!(
+            acc.to_string(),
+            "probe accuracy results: maximum 4.000000, minimum 1.000000, range 3.000000, \
+             average 2.500000, median 2.500000, standard deviation 1.118034"
+        );
+    }
+}
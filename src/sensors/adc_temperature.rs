@@ -0,0 +1,224 @@
+//! Table driven sensors, straight from klipper's `adc_temperature.py`:
+//! amplifier boards that output a voltage, and RTDs read through a pullup
+
+use super::{AdcConversion, SensorConfig};
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum TableError {
+    #[error("Duplicate sample at {0}")]
+    Duplicate(f64),
+    #[error("Need at least two usable samples")]
+    TooFewSamples,
+}
+
+/// Piecewise linear curve through a set of samples, that can be walked both ways
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinearInterpolate {
+    /// Upper bound of each segment, the last one being effectively infinite
+    keys: Vec<f64>,
+    /// `(gain, offset)` of each segment
+    slopes: Vec<(f64, f64)>,
+}
+
+impl LinearInterpolate {
+    pub fn new(samples: impl IntoIterator<Item = (f64, f64)>) -> Result<Self, TableError> {
+        let mut samples: Vec<_> = samples.into_iter().collect();
+        samples.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut keys = Vec::new();
+        let mut slopes: Vec<(f64, f64)> = Vec::new();
+        let mut iter = samples.into_iter();
+        let (mut last_key, mut last_value) = iter.next().ok_or(TableError::TooFewSamples)?;
+        for (key, value) in iter {
+            if key <= last_key {
+                return Err(TableError::Duplicate(key));
+            }
+            let gain = (value - last_value) / (key - last_key);
+            let offset = last_value - last_key * gain;
+            last_key = key;
+            last_value = value;
+            // collinear points just extend the previous segment
+            match keys.last_mut() {
+                Some(last) if slopes.last() == Some(&(gain, offset)) => *last = key,
+                _ => {
+                    keys.push(key);
+                    slopes.push((gain, offset));
+                }
+            }
+        }
+        let last_slope = *slopes.last().ok_or(TableError::TooFewSamples)?;
+        keys.push(f64::MAX);
+        slopes.push(last_slope);
+        Ok(Self { keys, slopes })
+    }
+
+    pub fn interpolate(&self, key: f64) -> f64 {
+        let pos = self
+            .keys
+            .partition_point(|k| *k <= key)
+            .min(self.keys.len() - 1);
+        let (gain, offset) = self.slopes[pos];
+        key * gain + offset
+    }
+
+    pub fn reverse_interpolate(&self, value: f64) -> f64 {
+        let values: Vec<f64> = self
+            .keys
+            .iter()
+            .zip(&self.slopes)
+            .map(|(key, (gain, offset))| key * gain + offset)
+            .collect();
+        let rising = values[0] < values[values.len().saturating_sub(2)];
+        let pos = values
+            .iter()
+            .position(|v| if rising { *v >= value } else { *v <= value })
+            .unwrap_or(values.len() - 1);
+        let (gain, offset) = self.slopes[pos];
+        (value - offset) / gain
+    }
+}
+
+/// Callendar-Van Dusen resistances for a platinum RTD, every 10°C from 0 to 490
+fn calc_pt100(base: f64) -> Vec<(f64, f64)> {
+    const A: f64 = 3.9083e-3;
+    const B: f64 = -5.775e-7;
+    (0..500)
+        .step_by(10)
+        .map(|t| {
+            let t = f64::from(t);
+            (t, base * (1.0 + A * t + B * t * t))
+        })
+        .collect()
+}
+
+/// PT100 on the usual INA826 board: 4400 ohm pullup to 5V, amplified 10x
+fn calc_ina826_pt100() -> Vec<(f64, f64)> {
+    calc_pt100(100.0)
+        .into_iter()
+        .map(|(t, r)| (t, 10.0 * 5.0 * r / (4400.0 + r)))
+        .collect()
+}
+
+/// Sensor that outputs a voltage, given as `(temperature, volts)` samples
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinearVoltage {
+    /// normalised adc -> temperature
+    table: LinearInterpolate,
+}
+
+impl LinearVoltage {
+    pub fn new(samples: &[(f64, f64)], config: &SensorConfig) -> Result<Self, TableError> {
+        let samples = samples
+            .iter()
+            .map(|(temp, volts)| ((volts - config.voltage_offset) / config.adc_voltage, *temp))
+            // the ADC can't see outside its own range, so those samples are no use
+            .filter(|(adc, _)| (0.0..=1.0).contains(adc));
+        Ok(Self {
+            table: LinearInterpolate::new(samples)?,
+        })
+    }
+
+    pub fn preset(name: &str, config: &SensorConfig) -> Option<Self> {
+        let samples = match name {
+            "PT100 INA826" => calc_ina826_pt100(),
+            _ => return None,
+        };
+        Self::new(&samples, config).ok()
+    }
+}
+
+impl AdcConversion for LinearVoltage {
+    fn calc_temp(&self, adc: f64) -> f64 {
+        self.table.interpolate(adc)
+    }
+
+    fn calc_adc(&self, temp: f64) -> f64 {
+        self.table.reverse_interpolate(temp)
+    }
+}
+
+/// Sensor whose resistance changes, given as `(temperature, ohms)` samples
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinearResistance {
+    pullup: f64,
+    /// ohms -> temperature
+    table: LinearInterpolate,
+}
+
+impl LinearResistance {
+    pub fn new(samples: &[(f64, f64)], config: &SensorConfig) -> Result<Self, TableError> {
+        Ok(Self {
+            pullup: config.pullup_resistor,
+            table: LinearInterpolate::new(samples.iter().map(|(t, r)| (*r, *t)))?,
+        })
+    }
+
+    pub fn preset(name: &str, config: &SensorConfig) -> Option<Self> {
+        let samples = match name {
+            "PT1000" => calc_pt100(1000.0),
+            _ => return None,
+        };
+        Self::new(&samples, config).ok()
+    }
+}
+
+impl AdcConversion for LinearResistance {
+    fn calc_temp(&self, adc: f64) -> f64 {
+        let adc = adc.clamp(0.00001, 0.99999);
+        let r = self.pullup * adc / (1.0 - adc);
+        self.table.interpolate(r)
+    }
+
+    fn calc_adc(&self, temp: f64) -> f64 {
+        let r = self.table.reverse_interpolate(temp);
+        r / (self.pullup + r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interpolate() {
+        let li =
+            LinearInterpolate::new([(0.0, 0.0), (20.0, 10.0), (10.0, 5.0), (30.0, 30.0)]).unwrap();
+        // the two collinear segments got merged
+        assert_eq!(li.keys.len(), 3);
+        assert_eq!(li.interpolate(15.0), 7.5);
+        assert_eq!(li.interpolate(25.0), 20.0);
+        assert_eq!(li.interpolate(40.0), 50.0);
+        assert_eq!(li.reverse_interpolate(20.0), 25.0);
+        assert_eq!(li.reverse_interpolate(7.5), 15.0);
+
+        // falling curves work too
+        let li = LinearInterpolate::new([(0.0, 100.0), (1.0, 0.0), (2.0, -50.0)]).unwrap();
+        assert_eq!(li.reverse_interpolate(50.0), 0.5);
+        assert_eq!(li.reverse_interpolate(-25.0), 1.5);
+
+        assert_eq!(
+            LinearInterpolate::new([(1.0, 1.0), (1.0, 2.0)]),
+            Err(TableError::Duplicate(1.0))
+        );
+        assert_eq!(
+            LinearInterpolate::new([(1.0, 1.0)]),
+            Err(TableError::TooFewSamples)
+        );
+    }
+
+    #[test]
+    fn test_custom_voltage_table() {
+        let config = SensorConfig {
+            adc_voltage: 3.3,
+            ..Default::default()
+        };
+        // the 10V sample is out of the ADC's reach and gets dropped
+        let sensor = LinearVoltage::new(
+            &[(0.0, 0.0), (100.0, 1.0), (200.0, 2.0), (1000.0, 10.0)],
+            &config,
+        )
+        .unwrap();
+        assert!((sensor.calc_temp(1.5 / 3.3) - 150.0).abs() < 1e-9);
+        assert!((sensor.calc_adc(50.0) - 0.5 / 3.3).abs() < 1e-12);
+    }
+}
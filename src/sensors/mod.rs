@@ -0,0 +1,106 @@
+//! Turning ADC readings into temperatures, and temperatures back into ADC limits
+//!
+//! Temperatures are plain `f64` degrees Celsius, readings are normalised to `0.0..=1.0`
+//! of the ADC's full scale, same as [`AdcReading`](crate::mcu::adc::AdcReading)
+
+use crate::mcu::adc::AdcConfig;
+
+pub mod adc_temperature;
+pub mod thermistor;
+
+use adc_temperature::{LinearResistance, LinearVoltage};
+use thermistor::Thermistor;
+
+/// Consecutive out of range reports before the MCU pulls the plug
+const RANGE_CHECK_COUNT: u8 = 4;
+
+/// Something that maps a normalised ADC reading to a temperature and back
+pub trait AdcConversion {
+    /// Temperature for a normalised ADC reading
+    fn calc_temp(&self, adc: f64) -> f64;
+
+    /// Normalised ADC reading expected at `temp`
+    fn calc_adc(&self, temp: f64) -> f64;
+
+    /// ADC sampling that makes the MCU shut down if the sensor
+    /// leaves `min_temp..=max_temp`, whichever way the curve slopes
+    fn adc_config(&self, min_temp: f64, max_temp: f64) -> AdcConfig {
+        let a = self.calc_adc(min_temp);
+        let b = self.calc_adc(max_temp);
+        AdcConfig {
+            min_value: a.min(b),
+            max_value: a.max(b),
+            range_check_count: RANGE_CHECK_COUNT,
+            ..Default::default()
+        }
+    }
+}
+
+/// The electrical bits around a sensor that aren't part of its curve
+#[derive(Clone, Debug, PartialEq)]
+pub struct SensorConfig {
+    /// Ohms between the ADC pin and the reference voltage
+    pub pullup_resistor: f64,
+    /// Ohms in series with a thermistor
+    pub inline_resistor: f64,
+    /// Voltage the ADC reads as full scale
+    pub adc_voltage: f64,
+    /// Voltage added to the amplifier output before it reaches the ADC
+    pub voltage_offset: f64,
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        Self {
+            pullup_resistor: 4700.0,
+            inline_resistor: 0.0,
+            adc_voltage: 5.0,
+            voltage_offset: 0.0,
+        }
+    }
+}
+
+/// Look up one of the sensors klipper ships with by its `sensor_type` name
+pub fn preset(name: &str, config: &SensorConfig) -> Option<Box<dyn AdcConversion>> {
+    if let Some(t) = Thermistor::preset(name, config) {
+        return Some(Box::new(t));
+    }
+    if let Some(s) = LinearVoltage::preset(name, config) {
+        return Some(Box::new(s));
+    }
+    LinearResistance::preset(name, config).map(|s| Box::new(s) as _)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets() {
+        let config = SensorConfig::default();
+        for name in [
+            "EPCOS 100K B57560G104F",
+            "ATC Semitec 104GT-2",
+            "Generic 3950",
+            "PT1000",
+            "PT100 INA826",
+        ] {
+            let sensor = preset(name, &config).unwrap_or_else(|| panic!("missing {}", name));
+            for temp in [25.0, 80.0, 150.0, 240.0] {
+                let back = sensor.calc_temp(sensor.calc_adc(temp));
+                assert!((back - temp).abs() < 0.01, "{}: {} -> {}", name, temp, back);
+            }
+        }
+        assert!(preset("Definitely a thermistor", &config).is_none());
+    }
+
+    #[test]
+    fn test_adc_limits() {
+        // NTC thermistors read lower as they heat up, so max_temp sets the low limit
+        let sensor = preset("EPCOS 100K B57560G104F", &SensorConfig::default()).unwrap();
+        let config = sensor.adc_config(0.0, 300.0);
+        assert_eq!(config.min_value, sensor.calc_adc(300.0));
+        assert_eq!(config.max_value, sensor.calc_adc(0.0));
+        assert_eq!(config.range_check_count, RANGE_CHECK_COUNT);
+    }
+}
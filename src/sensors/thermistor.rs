@@ -0,0 +1,172 @@
+//! Steinhart-Hart thermistors, straight from klipper's `thermistor.py`
+
+use super::{AdcConversion, SensorConfig};
+
+const KELVIN_TO_CELSIUS: f64 = -273.15;
+
+/// How a thermistor's curve was characterised
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Calibration {
+    /// Three `(temperature, resistance)` points
+    ThreePoint([(f64, f64); 3]),
+    /// One `(temperature, resistance)` point and the beta value
+    Beta { t1: f64, r1: f64, beta: f64 },
+}
+
+/// Thermistor definitions from klipper's `temperature_sensors.cfg`
+const PRESETS: &[(&str, Calibration)] = &[
+    (
+        "EPCOS 100K B57560G104F",
+        Calibration::ThreePoint([(25.0, 100_000.0), (150.0, 1641.9), (250.0, 226.15)]),
+    ),
+    (
+        "ATC Semitec 104GT-2",
+        Calibration::ThreePoint([(20.0, 126_800.0), (150.0, 1360.0), (300.0, 80.65)]),
+    ),
+    (
+        "ATC Semitec 104NT-4-R025H42G",
+        Calibration::ThreePoint([(25.0, 100_000.0), (160.0, 1074.0), (300.0, 82.78)]),
+    ),
+    (
+        "Generic 3950",
+        Calibration::Beta {
+            t1: 25.0,
+            r1: 100_000.0,
+            beta: 3950.0,
+        },
+    ),
+    (
+        "Honeywell 100K 135-104LAG-J01",
+        Calibration::Beta {
+            t1: 25.0,
+            r1: 100_000.0,
+            beta: 3974.0,
+        },
+    ),
+    (
+        "NTC 100K MGB18-104F39050L32",
+        Calibration::Beta {
+            t1: 25.0,
+            r1: 100_000.0,
+            beta: 4100.0,
+        },
+    ),
+    (
+        "SliceEngineering 450",
+        Calibration::ThreePoint([(25.0, 500_000.0), (200.0, 3734.0), (400.0, 240.0)]),
+    ),
+    (
+        "TDK NTCG104LH104JT1",
+        Calibration::ThreePoint([(25.0, 100_000.0), (50.0, 31_230.0), (125.0, 2066.0)]),
+    ),
+];
+
+/// NTC thermistor on the low side of a pullup divider
+#[derive(Clone, Debug, PartialEq)]
+pub struct Thermistor {
+    pullup: f64,
+    inline_resistor: f64,
+    // 1/T = c1 + c2*ln(R) + c3*ln(R)^3
+    c1: f64,
+    c2: f64,
+    c3: f64,
+}
+
+impl Thermistor {
+    pub fn new(calibration: Calibration, config: &SensorConfig) -> Self {
+        let mut t = Self {
+            pullup: config.pullup_resistor,
+            inline_resistor: config.inline_resistor,
+            c1: 0.0,
+            c2: 0.0,
+            c3: 0.0,
+        };
+        match calibration {
+            Calibration::ThreePoint(points) => t.setup_coefficients(points),
+            Calibration::Beta { t1, r1, beta } => t.setup_coefficients_beta(t1, r1, beta),
+        }
+        t
+    }
+
+    /// Look up a thermistor from `temperature_sensors.cfg` by name
+    pub fn preset(name: &str, config: &SensorConfig) -> Option<Self> {
+        PRESETS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, calibration)| Self::new(*calibration, config))
+    }
+
+    fn setup_coefficients(&mut self, [(t1, r1), (t2, r2), (t3, r3)]: [(f64, f64); 3]) {
+        let inv_t1 = 1.0 / (t1 - KELVIN_TO_CELSIUS);
+        let inv_t2 = 1.0 / (t2 - KELVIN_TO_CELSIUS);
+        let inv_t3 = 1.0 / (t3 - KELVIN_TO_CELSIUS);
+        let (ln_r1, ln_r2, ln_r3) = (r1.ln(), r2.ln(), r3.ln());
+        let (ln3_r1, ln3_r2, ln3_r3) = (ln_r1.powi(3), ln_r2.powi(3), ln_r3.powi(3));
+
+        let (inv_t12, inv_t13) = (inv_t1 - inv_t2, inv_t1 - inv_t3);
+        let (ln_r12, ln_r13) = (ln_r1 - ln_r2, ln_r1 - ln_r3);
+        let (ln3_r12, ln3_r13) = (ln3_r1 - ln3_r2, ln3_r1 - ln3_r3);
+
+        self.c3 = (inv_t12 - inv_t13 * ln_r12 / ln_r13) / (ln3_r12 - ln3_r13 * ln_r12 / ln_r13);
+        if self.c3 <= 0.0 {
+            // the points don't make a sane curve, klipper falls back to a beta fit
+            let beta = ln_r13 / inv_t13;
+            return self.setup_coefficients_beta(t1, r1, beta);
+        }
+        self.c2 = (inv_t12 - self.c3 * ln3_r12) / ln_r12;
+        self.c1 = inv_t1 - self.c2 * ln_r1 - self.c3 * ln3_r1;
+    }
+
+    fn setup_coefficients_beta(&mut self, t1: f64, r1: f64, beta: f64) {
+        let inv_t1 = 1.0 / (t1 - KELVIN_TO_CELSIUS);
+        self.c3 = 0.0;
+        self.c2 = 1.0 / beta;
+        self.c1 = inv_t1 - self.c2 * r1.ln();
+    }
+}
+
+impl AdcConversion for Thermistor {
+    fn calc_temp(&self, adc: f64) -> f64 {
+        let adc = adc.clamp(0.00001, 0.99999);
+        let r = self.pullup * adc / (1.0 - adc);
+        let ln_r = (r - self.inline_resistor).ln();
+        let inv_t = self.c1 + self.c2 * ln_r + self.c3 * ln_r.powi(3);
+        1.0 / inv_t + KELVIN_TO_CELSIUS
+    }
+
+    fn calc_adc(&self, temp: f64) -> f64 {
+        if temp <= KELVIN_TO_CELSIUS {
+            return 1.0;
+        }
+        let inv_t = 1.0 / (temp - KELVIN_TO_CELSIUS);
+        let ln_r = if self.c3 != 0.0 {
+            // solve the cubic for ln(R)
+            let y = (self.c1 - inv_t) / (2.0 * self.c3);
+            let x = ((self.c2 / (3.0 * self.c3)).powi(3) + y * y).sqrt();
+            (x - y).cbrt() - (x + y).cbrt()
+        } else {
+            (inv_t - self.c1) / self.c2
+        };
+        let r = ln_r.exp() + self.inline_resistor;
+        r / (self.pullup + r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibration_points() {
+        let config = SensorConfig::default();
+        let epcos = Thermistor::preset("EPCOS 100K B57560G104F", &config).unwrap();
+        for (temp, r) in [(25.0, 100_000.0), (150.0, 1641.9), (250.0, 226.15)] {
+            let adc = r / (config.pullup_resistor + r);
+            assert!((epcos.calc_temp(adc) - temp).abs() < 1e-6);
+            assert!((epcos.calc_adc(temp) - adc).abs() < 1e-9);
+        }
+
+        let generic = Thermistor::preset("Generic 3950", &config).unwrap();
+        assert!((generic.calc_temp(100_000.0 / 104_700.0) - 25.0).abs() < 1e-9);
+    }
+}
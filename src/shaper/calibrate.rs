@@ -0,0 +1,709 @@
+//! Pick an input shaper from accelerometer recordings, klipper's `shaper_calibrate.py`
+//!
+//! The recordings are turned into a power spectral density with Welch's method, then every
+//! shaper is tried at every frequency against it. Each shaper keeps the frequency that best
+//! trades vibration left over against how much it rounds off corners
+
+use std::f64::consts::PI;
+use std::path::Path;
+use std::{fmt, fs};
+
+use super::{
+    estimate_remaining_vibrations, AxisShaper, ShaperType, DEFAULT_DAMPING_RATIO,
+    SHAPER_VIBRATION_REDUCTION,
+};
+use crate::trapq::Coord;
+use crate::units::{Hertz, MillimetersPerSecondSquared, Seconds};
+
+/// Length of each Welch window
+const WINDOW_T_SEC: f64 = 0.5;
+const KAISER_BETA: f64 = 6.0;
+/// Anything below this is noise, not resonance
+const MIN_FREQ: f64 = 5.0;
+/// Highest frequency the vibrations are scored over
+const MAX_FREQ: f64 = 200.0;
+const MAX_SHAPER_FREQ: f64 = 150.0;
+const FREQ_STEP: f64 = 0.2;
+/// Nobody knows the printer's damping ratio, so shapers are judged on the worst of these
+const TEST_DAMPING_RATIOS: [f64; 3] = [0.075, 0.1, 0.15];
+/// ZVD isn't tried, MZV does the same job with less smoothing
+pub const AUTOTUNE_SHAPERS: [ShaperType; 5] = [
+    ShaperType::Zv,
+    ShaperType::Mzv,
+    ShaperType::Ei,
+    ShaperType::TwoHumpEi,
+    ShaperType::ThreeHumpEi,
+];
+/// Acceleration and square corner velocity smoothing is compared at
+const SMOOTHING_ACCEL: f64 = 5000.0;
+const SMOOTHING_SCV: f64 = 5.0;
+/// Smoothing the suggested max_accel is allowed
+const TARGET_SMOOTHING: f64 = 0.12;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum CalibrateError {
+    #[error("Invalid accelerometer data on line {line}: `{text}`")]
+    Parse { line: usize, text: String },
+    #[error("Need at least {needed} accelerometer samples, only have {got}")]
+    TooFewSamples { needed: usize, got: usize },
+    #[error("Accelerometer samples at {0:.1}Hz are too sparse or out of order to analyse")]
+    InvalidSamplingRate(f64),
+    #[error("Unable to read {path}: {error}")]
+    Io { path: String, error: String },
+}
+
+/// One accelerometer reading, mm/s² along each axis
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AccelSample {
+    pub time: Seconds,
+    pub accel: Coord,
+}
+
+/// Read a `time,accel_x,accel_y,accel_z` file as klipper's accelerometers write them.
+/// Blank lines and `#` comments are skipped
+pub fn parse_accel_csv(text: &str) -> Result<Vec<AccelSample>, CalibrateError> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| {
+            let line = line.trim();
+            !line.is_empty() && !line.starts_with('#')
+        })
+        .map(|(i, line)| {
+            let invalid = || CalibrateError::Parse {
+                line: i + 1,
+                text: line.to_string(),
+            };
+            let values = line
+                .split(',')
+                .map(|v| v.trim().parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| invalid())?;
+            match values[..] {
+                [t, x, y, z] => Ok(AccelSample {
+                    time: Seconds(t),
+                    accel: Coord::new(x, y, z),
+                }),
+                _ => Err(invalid()),
+            }
+        })
+        .collect()
+}
+
+/// [`parse_accel_csv`] on a file, like the `/tmp/calibration_data_*.csv` klipper leaves
+pub fn read_accel_csv(path: impl AsRef<Path>) -> Result<Vec<AccelSample>, CalibrateError> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path).map_err(|e| CalibrateError::Io {
+        path: path.display().to_string(),
+        error: e.to_string(),
+    })?;
+    parse_accel_csv(&text)
+}
+
+/// Power spectral density of one or more recordings, per axis and summed
+#[derive(Clone, Debug, PartialEq)]
+pub struct CalibrationData {
+    pub freq_bins: Vec<f64>,
+    pub psd_sum: Vec<f64>,
+    pub psd_x: Vec<f64>,
+    pub psd_y: Vec<f64>,
+    pub psd_z: Vec<f64>,
+    /// How many recordings have been averaged together
+    data_sets: usize,
+}
+
+impl CalibrationData {
+    pub fn from_samples(samples: &[AccelSample]) -> Result<Self, CalibrateError> {
+        let got = samples.len();
+        if got < 2 {
+            return Err(CalibrateError::TooFewSamples { needed: 2, got });
+        }
+        let sampling_freq = got as f64 / (samples[got - 1].time - samples[0].time).0;
+        // a window has to hold at least two samples
+        if !(sampling_freq.is_finite() && sampling_freq * WINDOW_T_SEC >= 2.0) {
+            return Err(CalibrateError::InvalidSamplingRate(sampling_freq));
+        }
+        let nfft = window_size(sampling_freq);
+        if got <= nfft {
+            return Err(CalibrateError::TooFewSamples { needed: nfft, got });
+        }
+        let psd = |axis: fn(&Coord) -> f64| {
+            let values: Vec<_> = samples.iter().map(|s| axis(&s.accel)).collect();
+            welch_psd(&values, sampling_freq, nfft)
+        };
+        let (psd_x, psd_y, psd_z) = (psd(|c| c.x), psd(|c| c.y), psd(|c| c.z));
+        let psd_sum = (0..psd_x.len())
+            .map(|i| psd_x[i] + psd_y[i] + psd_z[i])
+            .collect();
+        Ok(Self {
+            freq_bins: (0..=nfft / 2)
+                .map(|i| i as f64 * sampling_freq / nfft as f64)
+                .collect(),
+            psd_sum,
+            psd_x,
+            psd_y,
+            psd_z,
+            data_sets: 1,
+        })
+    }
+
+    fn psds_mut(&mut self) -> [&mut Vec<f64>; 4] {
+        [
+            &mut self.psd_sum,
+            &mut self.psd_x,
+            &mut self.psd_y,
+            &mut self.psd_z,
+        ]
+    }
+
+    /// Average in another recording, which might have been sampled a bit differently
+    pub fn add(&mut self, other: &CalibrationData) {
+        let joined = (self.data_sets + other.data_sets) as f64;
+        let (mine, theirs) = (self.data_sets as f64, other.data_sets as f64);
+        let freq_bins = self.freq_bins.clone();
+        let other_psds = [&other.psd_sum, &other.psd_x, &other.psd_y, &other.psd_z];
+        for (psd, other_psd) in self.psds_mut().into_iter().zip(other_psds) {
+            for (p, &f) in psd.iter_mut().zip(&freq_bins) {
+                let other_p = interpolate(f, &other.freq_bins, other_psd);
+                *p = (*p * mine + other_p * theirs) / joined;
+            }
+        }
+        self.data_sets += other.data_sets;
+    }
+
+    /// Turn acceleration into something closer to displacement, which is what's seen in
+    /// prints, and drop the low frequency noise
+    pub fn normalize_to_frequencies(&mut self) {
+        let freq_bins = self.freq_bins.clone();
+        for psd in self.psds_mut() {
+            for (p, &f) in psd.iter_mut().zip(&freq_bins) {
+                *p = if f < MIN_FREQ { 0.0 } else { *p / (f + 0.1) };
+            }
+        }
+    }
+}
+
+/// Smallest power of two covering `WINDOW_T_SEC` of samples
+fn window_size(sampling_freq: f64) -> usize {
+    let samples = (sampling_freq * WINDOW_T_SEC - 1.0) as u64;
+    1 << (u64::BITS - samples.leading_zeros())
+}
+
+/// `np.interp`: linear between points, flat past either end
+fn interpolate(x: f64, xs: &[f64], ys: &[f64]) -> f64 {
+    match xs.iter().position(|&xi| xi >= x) {
+        None => ys[ys.len() - 1],
+        Some(0) => ys[0],
+        Some(i) => {
+            let frac = (x - xs[i - 1]) / (xs[i] - xs[i - 1]);
+            ys[i - 1] + frac * (ys[i] - ys[i - 1])
+        }
+    }
+}
+
+/// Modified Bessel function of the first kind, order zero
+fn bessel_i0(x: f64) -> f64 {
+    let (mut sum, mut term) = (1.0, 1.0);
+    for k in 1.. {
+        term *= (x / (2.0 * k as f64)).powi(2);
+        sum += term;
+        if term < sum * 1e-17 {
+            break;
+        }
+    }
+    sum
+}
+
+fn kaiser_window(len: usize, beta: f64) -> Vec<f64> {
+    if len == 1 {
+        return vec![1.0];
+    }
+    let norm = bessel_i0(beta);
+    (0..len)
+        .map(|n| {
+            let r = 2.0 * n as f64 / (len - 1) as f64 - 1.0;
+            bessel_i0(beta * (1.0 - r * r).sqrt()) / norm
+        })
+        .collect()
+}
+
+/// In-place radix-2 FFT, `re.len()` has to be a power of two
+fn fft(re: &mut [f64], im: &mut [f64]) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let (sin, cos) = (-2.0 * PI / len as f64).sin_cos();
+        for start in (0..n).step_by(len) {
+            let (mut wr, mut wi) = (1.0, 0.0);
+            for k in 0..len / 2 {
+                let (a, b) = (start + k, start + k + len / 2);
+                let tr = re[b] * wr - im[b] * wi;
+                let ti = re[b] * wi + im[b] * wr;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+                (wr, wi) = (wr * cos - wi * sin, wr * sin + wi * cos);
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// One-sided PSD averaged over half-overlapping, detrended, Kaiser windowed chunks
+fn welch_psd(values: &[f64], sampling_freq: f64, nfft: usize) -> Vec<f64> {
+    let window = kaiser_window(nfft, KAISER_BETA);
+    // make up for the power the window takes away
+    let scale = 1.0 / window.iter().map(|w| w * w).sum::<f64>();
+    let overlap = nfft / 2;
+    let step = nfft - overlap;
+    let windows = (values.len() - overlap) / step;
+    let mut psd = vec![0.0; nfft / 2 + 1];
+    let (mut re, mut im) = (vec![0.0; nfft], vec![0.0; nfft]);
+    for chunk in (0..windows).map(|w| &values[w * step..w * step + nfft]) {
+        let mean = chunk.iter().sum::<f64>() / nfft as f64;
+        for (i, (v, w)) in chunk.iter().zip(&window).enumerate() {
+            (re[i], im[i]) = ((v - mean) * w, 0.0);
+        }
+        fft(&mut re, &mut im);
+        for (i, p) in psd.iter_mut().enumerate() {
+            let mut power = (re[i] * re[i] + im[i] * im[i]) * scale / sampling_freq;
+            // negative frequencies fold onto positive ones, bar DC and Nyquist
+            if i != 0 && i != nfft / 2 {
+                power *= 2.0;
+            }
+            *p += power / windows as f64;
+        }
+    }
+    psd
+}
+
+/// How far corners get rounded off, in mm, at `accel` and square corner velocity `scv`
+fn shaper_smoothing(a: &[f64], t: &[Seconds], accel: f64, scv: f64) -> f64 {
+    let half_accel = accel * 0.5;
+    let inv_d = 1.0 / a.iter().sum::<f64>();
+    let ts = a.iter().zip(t).map(|(a, t)| a * t.0).sum::<f64>() * inv_d;
+    let (mut offset_90, mut offset_180) = (0.0, 0.0);
+    for (a, t) in a.iter().zip(t) {
+        let dt = t.0 - ts;
+        if dt >= 0.0 {
+            offset_90 += a * (scv + half_accel * dt) * dt;
+        }
+        offset_180 += a * half_accel * dt * dt;
+    }
+    (offset_90 * inv_d * 2f64.sqrt()).max(offset_180 * inv_d)
+}
+
+/// Largest value `func` holds for, assuming it holds for everything smaller
+fn bisect(func: impl Fn(f64) -> bool) -> f64 {
+    if !func(1e-9) {
+        return 0.0;
+    }
+    let (mut left, mut right) = (1.0, 1.0);
+    while !func(left) {
+        right = left;
+        left *= 0.5;
+    }
+    if right == left {
+        while func(right) {
+            right *= 2.0;
+        }
+    }
+    while right - left > 1e-8 {
+        let middle = (left + right) * 0.5;
+        if func(middle) {
+            left = middle;
+        } else {
+            right = middle;
+        }
+    }
+    left
+}
+
+fn max_accel(a: &[f64], t: &[Seconds]) -> MillimetersPerSecondSquared {
+    MillimetersPerSecondSquared(bisect(|accel| {
+        shaper_smoothing(a, t, accel, SMOOTHING_SCV) <= TARGET_SMOOTHING
+    }))
+}
+
+/// Share of the vibration above the noise floor the shaper doesn't get rid of
+fn remaining_vibrations(
+    a: &[f64],
+    t: &[Seconds],
+    damping_ratio: f64,
+    freq_bins: &[f64],
+    psd: &[f64],
+) -> f64 {
+    let threshold = psd.iter().fold(0.0, |max: f64, &p| max.max(p)) / SHAPER_VIBRATION_REDUCTION;
+    let remaining: f64 = freq_bins
+        .iter()
+        .zip(psd)
+        .map(|(&f, p)| {
+            let left = estimate_remaining_vibrations(a, t, damping_ratio, Hertz(f));
+            (left * p - threshold).max(0.0)
+        })
+        .sum();
+    let all: f64 = psd.iter().map(|p| (p - threshold).max(0.0)).sum();
+    remaining / all
+}
+
+/// Lowest frequency worth trying each shaper at, below it the smoothing is hopeless
+fn min_freq(shaper_type: ShaperType) -> f64 {
+    match shaper_type {
+        ShaperType::Zv => 21.0,
+        ShaperType::Mzv => 23.0,
+        ShaperType::Zvd | ShaperType::Ei => 29.0,
+        ShaperType::TwoHumpEi => 39.0,
+        ShaperType::ThreeHumpEi => 48.0,
+    }
+}
+
+/// A shaper tuned to a recording
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShaperFit {
+    pub shaper_type: ShaperType,
+    pub freq: Hertz,
+    /// Share of the recorded vibration left, with the least favourable damping ratio
+    pub vibrations: f64,
+    /// How far corners get rounded off at 5000mm/s², in mm
+    pub smoothing: f64,
+    /// Lower is better, weighing vibrations against smoothing
+    pub score: f64,
+    /// Fastest acceleration before the smoothing gets noticeable
+    pub max_accel: MillimetersPerSecondSquared,
+}
+
+impl ShaperFit {
+    pub fn axis_shaper(&self) -> AxisShaper {
+        AxisShaper {
+            shaper_type: self.shaper_type,
+            freq: self.freq,
+            damping_ratio: DEFAULT_DAMPING_RATIO,
+        }
+    }
+}
+
+impl fmt::Display for ShaperFit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Fitted shaper '{}' frequency = {:.1} Hz (vibrations = {:.1}%, smoothing ~= {:.3})",
+            self.shaper_type,
+            self.freq.0,
+            self.vibrations * 100.0,
+            self.smoothing
+        )?;
+        write!(
+            f,
+            "To avoid too much smoothing with '{}', suggested max_accel <= {:.0} mm/sec^2",
+            self.shaper_type,
+            (self.max_accel.0 / 100.0).round() * 100.0
+        )
+    }
+}
+
+/// Best frequency for `shaper_type` against `data.psd_sum`. With `max_smoothing`, only
+/// frequencies smoothing less than that are considered, if any manage it
+pub fn fit_shaper(
+    shaper_type: ShaperType,
+    data: &CalibrationData,
+    max_smoothing: Option<f64>,
+) -> Option<ShaperFit> {
+    let (freq_bins, psd): (Vec<f64>, Vec<f64>) = data
+        .freq_bins
+        .iter()
+        .zip(&data.psd_sum)
+        .filter(|(&f, _)| f <= MAX_FREQ)
+        .unzip();
+    let min_freq = min_freq(shaper_type);
+    let steps = ((MAX_SHAPER_FREQ - min_freq) / FREQ_STEP).ceil() as usize;
+
+    let mut results: Vec<ShaperFit> = Vec::with_capacity(steps);
+    let mut best: Option<usize> = None;
+    // highest first, smoothing only gets worse from there
+    for step in (0..steps).rev() {
+        let freq = Hertz(min_freq + step as f64 * FREQ_STEP);
+        let (a, t) = shaper_type.pulses(freq, DEFAULT_DAMPING_RATIO);
+        let smoothing = shaper_smoothing(&a, &t, SMOOTHING_ACCEL, SMOOTHING_SCV);
+        if let (Some(max_smoothing), Some(best)) = (max_smoothing, best) {
+            if smoothing > max_smoothing {
+                return Some(with_max_accel(results.swap_remove(best)));
+            }
+        }
+        let vibrations = TEST_DAMPING_RATIOS
+            .iter()
+            .map(|&dr| remaining_vibrations(&a, &t, dr, &freq_bins, &psd))
+            .fold(0.0, f64::max);
+        // mostly about vibrations, but not at any cost in smoothing
+        let score = smoothing * (vibrations.powf(1.5) + vibrations * 0.2 + 0.01);
+        results.push(ShaperFit {
+            shaper_type,
+            freq,
+            vibrations,
+            smoothing,
+            score,
+            max_accel: MillimetersPerSecondSquared::ZERO,
+        });
+        if best.is_none_or(|best| results[best].vibrations > vibrations) {
+            best = Some(results.len() - 1);
+        }
+    }
+    // something barely worse than the best, but much smoother, is better
+    let best = &results[best?];
+    let selected = results
+        .iter()
+        .rev()
+        .filter(|res| res.vibrations < best.vibrations * 1.1 + 0.0005)
+        .fold(best, |selected, res| {
+            if res.score < selected.score {
+                res
+            } else {
+                selected
+            }
+        });
+    Some(with_max_accel(selected.clone()))
+}
+
+fn with_max_accel(mut fit: ShaperFit) -> ShaperFit {
+    let (a, t) = fit.shaper_type.pulses(fit.freq, DEFAULT_DAMPING_RATIO);
+    fit.max_accel = max_accel(&a, &t);
+    fit
+}
+
+/// [`fit_shaper`] for each of [`AUTOTUNE_SHAPERS`]
+pub fn fit_shapers(data: &CalibrationData, max_smoothing: Option<f64>) -> Vec<ShaperFit> {
+    AUTOTUNE_SHAPERS
+        .iter()
+        .filter_map(|&shaper_type| fit_shaper(shaper_type, data, max_smoothing))
+        .collect()
+}
+
+/// The one to recommend. A later shaper in the list has to score clearly better to win,
+/// or a bit better while smoothing less
+pub fn best_shaper(fits: &[ShaperFit]) -> Option<&ShaperFit> {
+    fits.iter().fold(None, |best, fit| match best {
+        Some(best)
+            if fit.score * 1.2 >= best.score
+                && (fit.score * 1.05 >= best.score || fit.smoothing * 1.1 >= best.smoothing) =>
+        {
+            Some(best)
+        }
+        _ => Some(fit),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+
+    const SAMPLING_FREQ: f64 = 3200.0;
+    const RESONANCE: f64 = 42.0;
+
+    fn samples(accel: impl Fn(f64) -> Coord, seconds: f64) -> Vec<AccelSample> {
+        (0..(seconds * SAMPLING_FREQ) as usize)
+            .map(|i| {
+                let time = i as f64 / SAMPLING_FREQ;
+                AccelSample {
+                    time: Seconds(time),
+                    accel: accel(time),
+                }
+            })
+            .collect()
+    }
+
+    /// A toolhead on a springy frame, shaken about at random
+    fn resonating_samples() -> Vec<AccelSample> {
+        let mut rng = StdRng::seed_from_u64(0x5ba4e);
+        let omega = 2.0 * PI * RESONANCE;
+        let substeps = 10;
+        let dt = 1.0 / (SAMPLING_FREQ * substeps as f64);
+        let (mut pos, mut vel) = (0.0, 0.0);
+        let mut accels = Vec::new();
+        for _ in 0..(10.0 * SAMPLING_FREQ) as usize {
+            let force: f64 = rng.gen_range(-1.0..1.0) * 1e5;
+            let mut accel = 0.0;
+            for _ in 0..substeps {
+                accel = force - omega * omega * pos - 2.0 * DEFAULT_DAMPING_RATIO * omega * vel;
+                vel += accel * dt;
+                pos += vel * dt;
+            }
+            accels.push(accel);
+        }
+        samples(
+            |t| Coord::new(accels[(t * SAMPLING_FREQ) as usize], 0.0, 0.0),
+            10.0,
+        )
+    }
+
+    #[test]
+    fn test_parse_accel_csv() {
+        let text = "#time,accel_x,accel_y,accel_z\n1.5,10,-20,9800.5\n\n1.5003, 11, -21, 9801\n";
+        let samples = parse_accel_csv(text).unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[1].time, Seconds(1.5003));
+        assert_eq!(samples[0].accel, Coord::new(10.0, -20.0, 9800.5));
+        assert_eq!(
+            parse_accel_csv("#time,accel_x,accel_y,accel_z\n1.5,10,-20\n"),
+            Err(CalibrateError::Parse {
+                line: 2,
+                text: "1.5,10,-20".into()
+            })
+        );
+        // 0.3ms apart, so the window is 4096 samples
+        assert_eq!(
+            CalibrationData::from_samples(&samples),
+            Err(CalibrateError::TooFewSamples {
+                needed: 4096,
+                got: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_read_accel_csv() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/shaper/testdata");
+        let samples = read_accel_csv(dir.join("calibration_data_x.csv")).unwrap();
+        assert_eq!(samples.len(), 8);
+        assert_eq!(samples[0].time, Seconds(102.3851));
+        assert_eq!(samples[7].accel, Coord::new(-812.4, 40.9, 9790.2));
+        assert!(matches!(
+            read_accel_csv(dir.join("missing.csv")),
+            Err(CalibrateError::Io { .. })
+        ));
+    }
+
+    #[test]
+    fn test_bad_timestamps() {
+        // all at once, backwards, and too far apart for even a two sample window
+        let at = |times: &[f64]| -> Vec<_> {
+            times
+                .iter()
+                .map(|&t| AccelSample {
+                    time: Seconds(t),
+                    accel: Coord::default(),
+                })
+                .collect()
+        };
+        for times in [&[1.0, 1.0, 1.0][..], &[2.0, 1.0], &[0.0, 100.0]] {
+            assert!(matches!(
+                CalibrationData::from_samples(&at(times)),
+                Err(CalibrateError::InvalidSamplingRate(_))
+            ));
+        }
+        // exactly a window's worth isn't enough for welch
+        let window = samples(|_| Coord::default(), 2048.0 / SAMPLING_FREQ);
+        assert_eq!(window.len(), 2048);
+        assert!(matches!(
+            CalibrationData::from_samples(&window),
+            Err(CalibrateError::TooFewSamples { needed: 2048, .. })
+        ));
+    }
+
+    #[test]
+    fn test_fft() {
+        let signal: Vec<f64> = (0..16).map(|i| ((i * 7) % 5) as f64 - 1.5).collect();
+        let (mut re, mut im) = (signal.clone(), vec![0.0; 16]);
+        fft(&mut re, &mut im);
+        for k in 0..16 {
+            let (dre, dim) = signal
+                .iter()
+                .enumerate()
+                .fold((0.0, 0.0), |(r, i), (n, x)| {
+                    let (sin, cos) = (-2.0 * PI * (k * n) as f64 / 16.0).sin_cos();
+                    (r + x * cos, i + x * sin)
+                });
+            assert!((re[k] - dre).abs() < 1e-9 && (im[k] - dim).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_psd_power() {
+        // a 2mm/s² sine has a mean square of 2, all of it near 50Hz
+        let sine = samples(
+            |t| Coord::new((2.0 * PI * 50.0 * t).sin() * 2.0, 0.0, 0.0),
+            4.0,
+        );
+        let data = CalibrationData::from_samples(&sine).unwrap();
+        let df = data.freq_bins[1];
+        assert_eq!(data.freq_bins.len(), 1025);
+        let total: f64 = data.psd_x.iter().sum::<f64>() * df;
+        assert!((total - 2.0).abs() < 0.02, "{total}");
+        let near: f64 = data
+            .freq_bins
+            .iter()
+            .zip(&data.psd_sum)
+            .filter(|(&f, _)| (f - 50.0).abs() < 5.0)
+            .map(|(_, p)| p * df)
+            .sum();
+        assert!(near > 0.99 * total);
+
+        // averaging in the same thing changes nothing
+        let mut doubled = data.clone();
+        doubled.add(&data);
+        for (a, b) in doubled.psd_sum.iter().zip(&data.psd_sum) {
+            assert!((a - b).abs() <= 1e-12 * b.abs().max(1.0));
+        }
+    }
+
+    #[test]
+    fn test_smoothing() {
+        let (a, t) = ShaperType::Mzv.pulses(Hertz(50.0), DEFAULT_DAMPING_RATIO);
+        let smoothing = shaper_smoothing(&a, &t, SMOOTHING_ACCEL, SMOOTHING_SCV);
+        // lower frequencies spread the impulses further apart
+        let (a_low, t_low) = ShaperType::Mzv.pulses(Hertz(30.0), DEFAULT_DAMPING_RATIO);
+        assert!(shaper_smoothing(&a_low, &t_low, SMOOTHING_ACCEL, SMOOTHING_SCV) > smoothing);
+        let max = max_accel(&a, &t);
+        let at_max = shaper_smoothing(&a, &t, max.0, SMOOTHING_SCV);
+        assert!((at_max - TARGET_SMOOTHING).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_recommends_shaper() {
+        let mut data = CalibrationData::from_samples(&resonating_samples()).unwrap();
+        data.normalize_to_frequencies();
+        let peak = data
+            .freq_bins
+            .iter()
+            .zip(&data.psd_sum)
+            .fold(
+                (0.0, 0.0),
+                |best, (&f, &p)| if p > best.1 { (f, p) } else { best },
+            );
+        assert!((peak.0 - RESONANCE).abs() < 2.0, "peak at {}Hz", peak.0);
+
+        let fits = fit_shapers(&data, None);
+        assert_eq!(fits.len(), AUTOTUNE_SHAPERS.len());
+        for fit in &fits {
+            assert!(fit.vibrations < 0.15, "{fit}");
+            assert!(fit.max_accel > MillimetersPerSecondSquared::ZERO);
+        }
+        let best = best_shaper(&fits).unwrap();
+        // whatever it picked has to actually deal with the resonance
+        let (a, t) = best.shaper_type.pulses(best.freq, DEFAULT_DAMPING_RATIO);
+        let left = estimate_remaining_vibrations(&a, &t, DEFAULT_DAMPING_RATIO, Hertz(RESONANCE));
+        assert!(left < 0.15, "{best} leaves {left}");
+        assert_eq!(best.axis_shaper().freq, best.freq);
+
+        // asking for less smoothing costs some vibration
+        let smooth = fit_shaper(ShaperType::Ei, &data, Some(0.05)).unwrap();
+        let ei = fits
+            .iter()
+            .find(|f| f.shaper_type == ShaperType::Ei)
+            .unwrap();
+        assert!(smooth.smoothing <= 0.05);
+        assert!(smooth.vibrations >= ei.vibrations);
+    }
+}
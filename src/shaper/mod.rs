@@ -0,0 +1,422 @@
+//! Input shapers and `SET_INPUT_SHAPER`, klipper's `shaper_defs.py` and `input_shaper.py`
+//!
+//! Each shaper is a handful of impulses tuned to a resonant frequency. The steppers follow
+//! the toolhead convolved with those impulses, see [`crate::kinematics::shaper`]
+
+pub mod calibrate;
+
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::kinematics::shaper::ShaperPulses;
+use crate::kinematics::{Axis, Kinematics};
+use crate::units::{Hertz, Seconds};
+
+pub const DEFAULT_DAMPING_RATIO: f64 = 0.1;
+/// The EI shapers leave 1/20th of the vibration at their design frequency, in exchange
+/// for coping better when it's off
+pub(crate) const SHAPER_VIBRATION_REDUCTION: f64 = 20.0;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum ShaperError {
+    #[error("Unsupported shaper type: {0}")]
+    UnknownType(String),
+    #[error("damping_ratio must be at least 0 and below 1, not {0}")]
+    DampingRatio(f64),
+    #[error("shaper_freq can't be negative, not {0}")]
+    NegativeFrequency(Hertz),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShaperType {
+    Zv,
+    Mzv,
+    Zvd,
+    Ei,
+    TwoHumpEi,
+    ThreeHumpEi,
+}
+
+impl ShaperType {
+    pub const ALL: [ShaperType; 6] = [
+        ShaperType::Zv,
+        ShaperType::Mzv,
+        ShaperType::Zvd,
+        ShaperType::Ei,
+        ShaperType::TwoHumpEi,
+        ShaperType::ThreeHumpEi,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ShaperType::Zv => "zv",
+            ShaperType::Mzv => "mzv",
+            ShaperType::Zvd => "zvd",
+            ShaperType::Ei => "ei",
+            ShaperType::TwoHumpEi => "2hump_ei",
+            ShaperType::ThreeHumpEi => "3hump_ei",
+        }
+    }
+
+    /// Amplitudes and times of the impulses, for a resonance at `freq`
+    pub fn pulses(self, freq: Hertz, damping_ratio: f64) -> (Vec<f64>, Vec<Seconds>) {
+        let v_tol = 1.0 / SHAPER_VIBRATION_REDUCTION;
+        let df = (1.0 - damping_ratio * damping_ratio).sqrt();
+        let k = (-damping_ratio * PI / df).exp();
+        let t_d = 1.0 / (freq.0 * df);
+        let (a, t): (Vec<f64>, Vec<f64>) = match self {
+            ShaperType::Zv => (vec![1.0, k], vec![0.0, 0.5 * t_d]),
+            ShaperType::Mzv => {
+                let k = (-0.75 * damping_ratio * PI / df).exp();
+                let a1 = 1.0 - 1.0 / 2f64.sqrt();
+                let a2 = (2f64.sqrt() - 1.0) * k;
+                let a3 = a1 * k * k;
+                (vec![a1, a2, a3], vec![0.0, 0.375 * t_d, 0.75 * t_d])
+            }
+            ShaperType::Zvd => (vec![1.0, 2.0 * k, k * k], vec![0.0, 0.5 * t_d, t_d]),
+            ShaperType::Ei => {
+                let a1 = 0.25 * (1.0 + v_tol);
+                let a2 = 0.5 * (1.0 - v_tol) * k;
+                let a3 = a1 * k * k;
+                (vec![a1, a2, a3], vec![0.0, 0.5 * t_d, t_d])
+            }
+            ShaperType::TwoHumpEi => {
+                let v2 = v_tol * v_tol;
+                let x = (v2 * ((1.0 - v2).sqrt() + 1.0)).cbrt();
+                let a1 = (3.0 * x * x + 2.0 * x + 3.0 * v2) / (16.0 * x);
+                let a2 = (0.5 - a1) * k;
+                let a3 = a2 * k;
+                let a4 = a1 * k * k * k;
+                (vec![a1, a2, a3, a4], vec![0.0, 0.5 * t_d, t_d, 1.5 * t_d])
+            }
+            ShaperType::ThreeHumpEi => {
+                let k2 = k * k;
+                let a1 = 0.0625 * (1.0 + 3.0 * v_tol + 2.0 * (2.0 * (v_tol + 1.0) * v_tol).sqrt());
+                let a2 = 0.25 * (1.0 - v_tol) * k;
+                let a3 = (0.5 * (1.0 + v_tol) - 2.0 * a1) * k2;
+                let a4 = a2 * k2;
+                let a5 = a1 * k2 * k2;
+                (
+                    vec![a1, a2, a3, a4, a5],
+                    vec![0.0, 0.5 * t_d, t_d, 1.5 * t_d, 2.0 * t_d],
+                )
+            }
+        };
+        (a, t.into_iter().map(Seconds).collect())
+    }
+}
+
+impl fmt::Display for ShaperType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ShaperType {
+    type Err = ShaperError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let name = name.to_lowercase();
+        Self::ALL
+            .into_iter()
+            .find(|t| t.name() == name)
+            .ok_or(ShaperError::UnknownType(name))
+    }
+}
+
+/// Fraction of the vibration at `freq` that's left after shaping with impulses `a` at
+/// times `t`, for something resonating with `damping_ratio`
+pub fn estimate_remaining_vibrations(
+    a: &[f64],
+    t: &[Seconds],
+    damping_ratio: f64,
+    freq: Hertz,
+) -> f64 {
+    let omega = 2.0 * PI * freq.0;
+    let damping = damping_ratio * omega;
+    let omega_d = omega * (1.0 - damping_ratio * damping_ratio).sqrt();
+    let last = t.last().map_or(0.0, |t| t.0);
+    let (mut s, mut c) = (0.0, 0.0);
+    for (&a, t) in a.iter().zip(t) {
+        let w = a * (-damping * (last - t.0)).exp();
+        s += w * (omega_d * t.0).sin();
+        c += w * (omega_d * t.0).cos();
+    }
+    (s * s + c * c).sqrt() / a.iter().sum::<f64>()
+}
+
+/// How one axis is shaped, what klipper has in `[input_shaper]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct AxisShaper {
+    pub shaper_type: ShaperType,
+    /// Zero turns shaping off
+    pub freq: Hertz,
+    pub damping_ratio: f64,
+}
+
+impl Default for AxisShaper {
+    fn default() -> Self {
+        Self {
+            shaper_type: ShaperType::Mzv,
+            freq: Hertz(0.0),
+            damping_ratio: DEFAULT_DAMPING_RATIO,
+        }
+    }
+}
+
+impl AxisShaper {
+    pub fn pulses(&self) -> ShaperPulses {
+        if self.freq == Hertz(0.0) {
+            return ShaperPulses::default();
+        }
+        let (a, t) = self.shaper_type.pulses(self.freq, self.damping_ratio);
+        ShaperPulses::new(&a, &t)
+    }
+}
+
+/// `SET_INPUT_SHAPER` parameters, anything left out stays as it was.
+/// `shaper_type` sets both axes, and wins over `shaper_type_x`/`shaper_type_y` like klipper
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SetInputShaper {
+    pub shaper_type: Option<ShaperType>,
+    pub shaper_type_x: Option<ShaperType>,
+    pub shaper_type_y: Option<ShaperType>,
+    pub shaper_freq_x: Option<Hertz>,
+    pub shaper_freq_y: Option<Hertz>,
+    pub damping_ratio_x: Option<f64>,
+    pub damping_ratio_y: Option<f64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InputShaper {
+    x: AxisShaper,
+    y: AxisShaper,
+}
+
+impl InputShaper {
+    pub fn new(x: AxisShaper, y: AxisShaper) -> Self {
+        Self { x, y }
+    }
+
+    pub fn x(&self) -> &AxisShaper {
+        &self.x
+    }
+
+    pub fn y(&self) -> &AxisShaper {
+        &self.y
+    }
+
+    /// Update the shapers and apply them to `kin`. Steps should be flushed first, anything
+    /// already generated keeps the old shaping
+    pub fn set_input_shaper<K: Kinematics>(
+        &mut self,
+        kin: &mut K,
+        params: &SetInputShaper,
+    ) -> Result<(), ShaperError> {
+        let update = |current: &AxisShaper,
+                      shaper_type: Option<ShaperType>,
+                      freq: Option<Hertz>,
+                      damping_ratio: Option<f64>| {
+            let shaper = AxisShaper {
+                shaper_type: params
+                    .shaper_type
+                    .or(shaper_type)
+                    .unwrap_or(current.shaper_type),
+                freq: freq.unwrap_or(current.freq),
+                damping_ratio: damping_ratio.unwrap_or(current.damping_ratio),
+            };
+            if !(0.0..1.0).contains(&shaper.damping_ratio) {
+                return Err(ShaperError::DampingRatio(shaper.damping_ratio));
+            }
+            if shaper.freq < Hertz(0.0) {
+                return Err(ShaperError::NegativeFrequency(shaper.freq));
+            }
+            Ok(shaper)
+        };
+        let x = update(
+            &self.x,
+            params.shaper_type_x,
+            params.shaper_freq_x,
+            params.damping_ratio_x,
+        )?;
+        let y = update(
+            &self.y,
+            params.shaper_type_y,
+            params.shaper_freq_y,
+            params.damping_ratio_y,
+        )?;
+        (self.x, self.y) = (x, y);
+        self.apply(kin);
+        Ok(())
+    }
+
+    /// Shape every stepper in `kin` that moves with X or Y
+    pub fn apply<K: Kinematics>(&self, kin: &mut K) {
+        let (x, y) = (self.x.pulses(), self.y.pulses());
+        for stepper in kin.steppers_mut() {
+            stepper.set_input_shaper(Axis::X, x.clone());
+            stepper.set_input_shaper(Axis::Y, y.clone());
+        }
+    }
+
+    /// What `SET_INPUT_SHAPER` reports back
+    pub fn status(&self) -> String {
+        [("x", &self.x), ("y", &self.y)]
+            .iter()
+            .map(|(axis, s)| {
+                format!(
+                    "shaper_type_{axis}:{} shaper_freq_{axis}:{:.3} damping_ratio_{axis}:{:.6}",
+                    s.shaper_type, s.freq.0, s.damping_ratio
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kinematics::cartesian::{Cartesian, CartesianConfig};
+    use crate::kinematics::itersolve::MoveCursor;
+    use crate::kinematics::RailConfig;
+    use crate::planner::{Planner, PlannerConfig};
+    use crate::trapq::Coord;
+    use crate::units::{Millimeters, MillimetersPerSecond, MillimetersPerSecondSquared};
+
+    const FREQ: Hertz = Hertz(40.0);
+
+    #[test]
+    fn test_remaining_vibrations() {
+        let unshaped = estimate_remaining_vibrations(&[1.0], &[Seconds(0.0)], 0.1, FREQ);
+        assert!((unshaped - 1.0).abs() < 1e-12);
+        for shaper in ShaperType::ALL {
+            let (a, t) = shaper.pulses(FREQ, DEFAULT_DAMPING_RATIO);
+            let left = estimate_remaining_vibrations(&a, &t, DEFAULT_DAMPING_RATIO, FREQ);
+            assert!(left <= 0.05, "{shaper} leaves {left}");
+        }
+        // the more impulses, the less fussy about being tuned just right
+        let mistuned = |shaper: ShaperType| {
+            let (a, t) = shaper.pulses(FREQ, DEFAULT_DAMPING_RATIO);
+            estimate_remaining_vibrations(&a, &t, DEFAULT_DAMPING_RATIO, Hertz(30.0))
+        };
+        assert!(mistuned(ShaperType::ThreeHumpEi) < mistuned(ShaperType::Ei));
+        assert!(mistuned(ShaperType::Ei) < mistuned(ShaperType::Zv));
+    }
+
+    /// Peak ringing of a spring with `FREQ` resonance dragged along behind `x(t)`, once
+    /// `settle` has passed
+    fn residual_vibration(x: impl Fn(f64) -> f64, settle: f64) -> f64 {
+        let omega = 2.0 * PI * FREQ.0;
+        let dt = 0.000_01;
+        let (mut y, mut v, mut peak) = (x(0.0), 0.0, 0.0_f64);
+        let end = x(settle);
+        for i in 0..((settle + 0.1) / dt) as usize {
+            let t = i as f64 * dt;
+            let (xt, xv) = (x(t), (x(t + dt) - x(t)) / dt);
+            let accel = -omega * omega * (y - xt) - 2.0 * DEFAULT_DAMPING_RATIO * omega * (v - xv);
+            v += accel * dt;
+            y += v * dt;
+            if t > settle {
+                peak = peak.max((y - end).abs());
+            }
+        }
+        peak
+    }
+
+    #[test]
+    fn test_shaped_moves_ring_less() {
+        let mut planner = Planner::new(PlannerConfig::new(
+            MillimetersPerSecond(200.0),
+            MillimetersPerSecondSquared(3000.0),
+        ));
+        planner.move_to(Coord::new(50.0, 0.0, 0.0), MillimetersPerSecond(200.0));
+        planner.flush();
+        let moves: Vec<_> = planner.trapq().moves().copied().collect();
+        // long enough for every shaper to have finished moving
+        let settle = planner.print_time().0 + 0.05;
+        let shaped_x = |pulses: &ShaperPulses, t: f64| {
+            let i = moves.iter().rposition(|m| m.print_time.0 <= t).unwrap_or(0);
+            pulses.position(
+                MoveCursor::new(&moves, i),
+                Axis::X,
+                Seconds(t) - moves[i].print_time,
+            )
+        };
+
+        let identity = ShaperPulses::new(&[1.0], &[Seconds(0.0)]);
+        let unshaped = residual_vibration(|t| shaped_x(&identity, t), settle);
+        assert!(unshaped > 0.005, "{unshaped}");
+        for shaper in ShaperType::ALL {
+            let pulses = AxisShaper {
+                shaper_type: shaper,
+                freq: FREQ,
+                damping_ratio: DEFAULT_DAMPING_RATIO,
+            }
+            .pulses();
+            let shaped = residual_vibration(|t| shaped_x(&pulses, t), settle);
+            assert!(shaped < 0.1 * unshaped, "{shaper}: {shaped} vs {unshaped}");
+        }
+    }
+
+    #[test]
+    fn test_set_input_shaper() {
+        let rail = RailConfig::new(
+            Millimeters(0.01),
+            Millimeters(0.0),
+            Millimeters(200.0),
+            Millimeters(0.0),
+        )
+        .unwrap();
+        let mut kin = Cartesian::new(
+            CartesianConfig {
+                x: rail.clone(),
+                y: rail.clone(),
+                z: rail,
+                max_z_velocity: MillimetersPerSecond(10.0),
+                max_z_accel: MillimetersPerSecondSquared(100.0),
+            },
+            Hertz(1_000_000.0),
+        );
+        let mut shaper = InputShaper::default();
+        shaper
+            .set_input_shaper(
+                &mut kin,
+                &SetInputShaper {
+                    shaper_type: Some("EI".parse().unwrap()),
+                    shaper_freq_x: Some(Hertz(50.0)),
+                    shaper_freq_y: Some(Hertz(40.0)),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(shaper.x().shaper_type, ShaperType::Ei);
+        assert_eq!(shaper.y().freq, Hertz(40.0));
+        assert_eq!(
+            shaper.status().lines().next(),
+            Some("shaper_type_x:ei shaper_freq_x:50.000 damping_ratio_x:0.100000")
+        );
+
+        let bad = SetInputShaper {
+            damping_ratio_y: Some(1.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            shaper.set_input_shaper(&mut kin, &bad),
+            Err(ShaperError::DampingRatio(1.0))
+        );
+        assert_eq!(
+            "nope".parse::<ShaperType>(),
+            Err(ShaperError::UnknownType("nope".into()))
+        );
+        // turning one axis off leaves the other alone
+        let off = SetInputShaper {
+            shaper_freq_x: Some(Hertz(0.0)),
+            ..Default::default()
+        };
+        shaper.set_input_shaper(&mut kin, &off).unwrap();
+        assert!(shaper.x().pulses().is_empty());
+        assert_eq!(shaper.y().pulses().pulses().len(), 3);
+    }
+}
@@ -0,0 +1,342 @@
+//! Skew correction: squares up a frame whose axes aren't quite at right angles, by
+//! shearing every requested position before it reaches the toolhead. A port of klipper's
+//! `skew_correction.py`
+//!
+//! Each plane's skew comes from a calibration print's measured diagonals, AC and BD, and
+//! side AD. Positions reported back get the exact inverse shear, so they read as requested
+
+use std::collections::BTreeMap;
+use std::f64::consts::FRAC_PI_2;
+
+use crate::planner::{MoveSegment, MoveTransform};
+use crate::trapq::Coord;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum SkewError {
+    #[error("skew_correction: Invalid measurements AC={ac:.4} BD={bd:.4} AD={ad:.4}")]
+    InvalidMeasurements { ac: f64, bd: f64, ad: f64 },
+    #[error("skew_correction: Load failed, unknown profile [{0}]")]
+    UnknownProfile(String),
+    #[error("skew_correction: No profile named [{0}] to remove")]
+    NoProfileToRemove(String),
+}
+
+/// Lengths measured off a calibration print in one plane
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Diagonals {
+    pub ac: f64,
+    pub bd: f64,
+    pub ad: f64,
+}
+
+impl Diagonals {
+    /// `CALC_MEASURED_SKEW`: tangent of how far the plane's axes are off square
+    pub fn skew_factor(&self) -> Result<f64, SkewError> {
+        let Self { ac, bd, ad } = *self;
+        let side = (2.0 * ac * ac + 2.0 * bd * bd - 4.0 * ad * ad).sqrt() / 2.0;
+        let factor =
+            (FRAC_PI_2 - ((ac * ac - side * side - ad * ad) / (2.0 * side * ad)).acos()).tan();
+        if !factor.is_finite() {
+            return Err(SkewError::InvalidMeasurements { ac, bd, ad });
+        }
+        Ok(factor)
+    }
+}
+
+/// Skew in each plane, as the tangent of its angle off square
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SkewFactors {
+    pub xy: f64,
+    pub xz: f64,
+    pub yz: f64,
+}
+
+impl SkewFactors {
+    /// Where the toolhead goes for requested position `pos`
+    pub fn skew(&self, pos: Coord) -> Coord {
+        Coord::new(
+            pos.x - pos.y * self.xy - pos.z * (self.xz - self.xy * self.yz),
+            pos.y - pos.z * self.yz,
+            pos.z,
+        )
+    }
+
+    /// Requested position that puts the toolhead at `pos`
+    pub fn unskew(&self, pos: Coord) -> Coord {
+        Coord::new(
+            pos.x + pos.y * self.xy + pos.z * self.xz,
+            pos.y + pos.z * self.yz,
+            pos.z,
+        )
+    }
+}
+
+/// `SET_SKEW` parameters: a plane's measurements replace its skew, other planes keep theirs
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SetSkewParams {
+    pub xy: Option<Diagonals>,
+    pub xz: Option<Diagonals>,
+    pub yz: Option<Diagonals>,
+    /// Zero every plane, ignoring any measurements
+    pub clear: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct SkewCorrection {
+    factors: SkewFactors,
+    profiles: BTreeMap<String, SkewFactors>,
+}
+
+impl SkewCorrection {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `GET_SKEW`
+    pub fn factors(&self) -> SkewFactors {
+        self.factors
+    }
+
+    pub fn set_factors(&mut self, factors: SkewFactors) {
+        self.factors = factors;
+    }
+
+    /// `SET_SKEW`. Nothing changes unless every given plane's measurements make sense
+    pub fn set_skew(&mut self, params: &SetSkewParams) -> Result<(), SkewError> {
+        if params.clear {
+            self.factors = SkewFactors::default();
+            return Ok(());
+        }
+        let mut factors = self.factors;
+        for (diagonals, factor) in [
+            (params.xy, &mut factors.xy),
+            (params.xz, &mut factors.xz),
+            (params.yz, &mut factors.yz),
+        ] {
+            if let Some(diagonals) = diagonals {
+                *factor = diagonals.skew_factor()?;
+            }
+        }
+        self.factors = factors;
+        Ok(())
+    }
+
+    /// Names of every saved profile, in order
+    pub fn profiles(&self) -> impl Iterator<Item = &str> {
+        self.profiles.keys().map(String::as_str)
+    }
+
+    pub fn profile(&self, name: &str) -> Option<SkewFactors> {
+        self.profiles.get(name).copied()
+    }
+
+    /// `SKEW_PROFILE SAVE`: keep the current skew under `name`
+    pub fn save_profile(&mut self, name: &str) {
+        self.profiles.insert(name.into(), self.factors);
+    }
+
+    /// `SKEW_PROFILE LOAD`: correct for the skew saved under `name`
+    pub fn load_profile(&mut self, name: &str) -> Result<(), SkewError> {
+        self.factors = self
+            .profile(name)
+            .ok_or_else(|| SkewError::UnknownProfile(name.into()))?;
+        Ok(())
+    }
+
+    /// `SKEW_PROFILE REMOVE`
+    pub fn remove_profile(&mut self, name: &str) -> Result<(), SkewError> {
+        self.profiles
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| SkewError::NoProfileToRemove(name.into()))
+    }
+}
+
+/// A shear keeps straight lines straight, so moves never need splitting
+impl MoveTransform for SkewCorrection {
+    fn split_move(&self, _from: Coord, to: Coord) -> Vec<MoveSegment> {
+        vec![MoveSegment {
+            end_pos: self.factors.skew(to),
+            fraction: 1.0,
+        }]
+    }
+
+    fn requested_position(&self, pos: Coord) -> Coord {
+        self.factors.unskew(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bed_mesh::zmesh::{Algorithm, MeshParams, ZMesh};
+    use crate::bed_mesh::{BedMesh, BedMeshConfig};
+    use crate::planner::ChainedTransform;
+
+    /// What a square print `side` across comes out as, with Y leaning `angle` toward +X
+    fn measured(side: f64, angle: f64) -> Diagonals {
+        let ad = Coord::new(side, 0.0, 0.0);
+        let ab = Coord::new(side * angle.sin(), side * angle.cos(), 0.0);
+        Diagonals {
+            ac: (ad + ab).norm(),
+            bd: (ad - ab).norm(),
+            ad: ad.norm(),
+        }
+    }
+
+    fn skewed() -> SkewFactors {
+        SkewFactors {
+            xy: 0.01,
+            xz: -0.02,
+            yz: 0.005,
+        }
+    }
+
+    #[test]
+    fn test_skew_factor() {
+        assert!(measured(100.0, 0.0).skew_factor().unwrap().abs() < 1e-12);
+        for angle in [0.01_f64, -0.003, 0.02] {
+            let factor = measured(100.0, angle).skew_factor().unwrap();
+            assert!((factor - angle.tan()).abs() < 1e-9, "{factor} for {angle}");
+        }
+        let impossible = Diagonals {
+            ac: 10.0,
+            bd: 10.0,
+            ad: 100.0,
+        };
+        assert!(matches!(
+            impossible.skew_factor(),
+            Err(SkewError::InvalidMeasurements { .. })
+        ));
+    }
+
+    #[test]
+    fn test_skew_and_unskew() {
+        let factors = skewed();
+        // leaning Y toward +X means X has to come back the same
+        assert_eq!(
+            factors.skew(Coord::new(0.0, 100.0, 0.0)),
+            Coord::new(-1.0, 100.0, 0.0)
+        );
+        for pos in [
+            Coord::new(0.0, 0.0, 0.0),
+            Coord::new(120.0, 30.0, 5.0),
+            Coord::new(-10.0, 250.0, 180.0),
+        ] {
+            let toolhead = factors.skew(pos);
+            assert!((factors.unskew(toolhead) - pos).norm() < 1e-12);
+            assert!((factors.skew(factors.unskew(pos)) - pos).norm() < 1e-12);
+        }
+
+        let mut skew = SkewCorrection::new();
+        skew.set_factors(factors);
+        let to = Coord::new(120.0, 30.0, 5.0);
+        let segments = skew.split_move(Coord::default(), to);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].end_pos, factors.skew(to));
+        assert!((skew.requested_position(segments[0].end_pos) - to).norm() < 1e-12);
+    }
+
+    #[test]
+    fn test_set_skew_and_profiles() {
+        let mut skew = SkewCorrection::new();
+        skew.set_factors(skewed());
+        let params = SetSkewParams {
+            xy: Some(measured(100.0, 0.02)),
+            ..SetSkewParams::default()
+        };
+        skew.set_skew(&params).unwrap();
+        assert!((skew.factors().xy - 0.02_f64.tan()).abs() < 1e-9);
+        assert_eq!(skew.factors().xz, -0.02);
+
+        // bad measurements leave things as they were
+        let before = skew.factors();
+        let params = SetSkewParams {
+            xz: Some(measured(100.0, 0.01)),
+            yz: Some(Diagonals {
+                ac: 1.0,
+                bd: 1.0,
+                ad: 10.0,
+            }),
+            ..SetSkewParams::default()
+        };
+        assert!(skew.set_skew(&params).is_err());
+        assert_eq!(skew.factors(), before);
+
+        skew.save_profile("printed");
+        let params = SetSkewParams {
+            xy: Some(measured(100.0, 0.01)),
+            clear: true,
+            ..SetSkewParams::default()
+        };
+        skew.set_skew(&params).unwrap();
+        assert_eq!(skew.factors(), SkewFactors::default());
+        assert_eq!(skew.profiles().collect::<Vec<_>>(), ["printed"]);
+        skew.load_profile("printed").unwrap();
+        assert_eq!(skew.factors(), before);
+        skew.remove_profile("printed").unwrap();
+        assert_eq!(
+            skew.load_profile("printed"),
+            Err(SkewError::UnknownProfile("printed".into()))
+        );
+        assert_eq!(
+            skew.remove_profile("printed"),
+            Err(SkewError::NoProfileToRemove("printed".into()))
+        );
+    }
+
+    #[test]
+    fn test_ahead_of_bed_mesh() {
+        // a bed sloping up along X, mesh following it wherever the skewed move goes
+        let slope = |x: f64| 0.002 * x;
+        let params = MeshParams {
+            min_x: 0.0,
+            max_x: 200.0,
+            min_y: 0.0,
+            max_y: 200.0,
+            x_count: 3,
+            y_count: 3,
+            mesh_x_pps: 0,
+            mesh_y_pps: 0,
+            algo: Algorithm::Direct,
+            tension: 0.2,
+        };
+        let probed = vec![vec![slope(0.0), slope(100.0), slope(200.0)]; 3];
+        let mut bed_mesh = BedMesh::new(BedMeshConfig::new((0.0, 0.0), (200.0, 200.0))).unwrap();
+        bed_mesh
+            .set_mesh(Some(ZMesh::new(params, probed).unwrap()))
+            .unwrap();
+        let mut skew = SkewCorrection::new();
+        skew.set_factors(skewed());
+        let chain = ChainedTransform {
+            outer: &skew,
+            inner: &bed_mesh,
+        };
+
+        let to = Coord::new(10.0, 20.0, 0.3);
+        let from = chain
+            .split_move(Coord::new(0.0, 0.0, 0.3), to)
+            .last()
+            .unwrap()
+            .end_pos;
+        assert!((chain.requested_position(from) - to).norm() < 1e-9);
+        let to = Coord::new(190.0, 180.0, 0.3);
+        let segments = chain.split_move(from, to);
+        assert!(segments.len() > 1);
+        let mut fraction = 0.0;
+        for segment in &segments {
+            let pos = segment.end_pos;
+            assert!((pos.z - 0.3 - slope(pos.x)).abs() < 1e-9, "{pos:?}");
+            assert!(segment.fraction > fraction);
+            fraction = segment.fraction;
+            // each lands where it should along the skewed move
+            let requested = chain.requested_position(pos);
+            let expected =
+                Coord::new(10.0, 20.0, 0.3) + (to - Coord::new(10.0, 20.0, 0.3)) * fraction;
+            assert!((requested - expected).norm() < 1e-9, "{requested:?}");
+        }
+        assert_eq!(fraction, 1.0);
+        let end = segments.last().unwrap().end_pos;
+        assert!((Coord::new(end.x, end.y, 0.3) - skewed().skew(to)).norm() < 1e-9);
+    }
+}
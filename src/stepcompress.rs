@@ -0,0 +1,545 @@
+//! Step compression, a port of klipper's `stepcompress.c`
+//!
+//! Sending every step to the MCU would never keep up, so runs of steps get approximated
+//! by `queue_step interval count add` sequences: the first step `interval` ticks after the
+//! last one, then `count - 1` more, the gap between them changing by `add` each time.
+//! Each step may land up to `max_error` early, but never late
+
+use std::collections::VecDeque;
+
+use crate::kinematics::itersolve::Step;
+use crate::mcu::{Command, Oid};
+use crate::units::{Hertz, Seconds, Ticks};
+
+/// Furthest ahead of the last step the next one can be and still be compressed
+const CLOCK_DIFF_MAX: u64 = 3 << 28;
+/// Most the `add` of two valid sequences can differ by is `(6 + 4 * sqrt(2)) * max_error /
+/// count²`. 11 instead of 11.66 works well in practice
+const QUADRATIC_DEV: i64 = 11;
+/// A step then an immediate step back this close together just cancel out
+const SDS_FILTER_TIME: Seconds = Seconds(0.000_750);
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum StepCompressError {
+    #[error("stepcompress o={} {step_move:?}: Invalid sequence", oid.0)]
+    InvalidSequence { oid: Oid, step_move: StepMove },
+    #[error("stepcompress o={} {step_move:?}: Point {point}: {clock} not in {min}:{max}", oid.0)]
+    PointOutOfRange {
+        oid: Oid,
+        step_move: StepMove,
+        point: usize,
+        clock: i64,
+        min: i64,
+        max: i64,
+    },
+}
+
+/// One `queue_step`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StepMove {
+    pub interval: u32,
+    pub count: u16,
+    pub add: i16,
+}
+
+impl StepMove {
+    /// Ticks from the first step to the last
+    fn span(&self) -> u64 {
+        let count = i64::from(self.count);
+        let ticks = i64::from(self.add) * (count * (count - 1) / 2)
+            + i64::from(self.interval) * (count - 1);
+        ticks as u64
+    }
+}
+
+/// Earliest and latest acceptable clock for a step, relative to the last sent step
+#[derive(Clone, Copy)]
+struct Points {
+    min: i64,
+    max: i64,
+}
+
+/// Division rounding towards positive infinity
+fn idiv_up(n: i64, d: i64) -> i64 {
+    if n >= 0 {
+        (n + d - 1) / d
+    } else {
+        n / d
+    }
+}
+
+/// Division rounding towards negative infinity
+fn idiv_down(n: i64, d: i64) -> i64 {
+    if n >= 0 {
+        n / d
+    } else {
+        (n - d + 1) / d
+    }
+}
+
+/// Turns one stepper's steps into MCU commands
+#[derive(Debug)]
+pub struct StepCompressor {
+    oid: Oid,
+    clock_freq: Hertz,
+    max_error: i64,
+    /// Dir pin is wired backwards
+    invert_dir: bool,
+    /// Step clocks waiting to be compressed
+    queue: VecDeque<u64>,
+    last_step_clock: u64,
+    /// Direction the MCU was last told to step in, if it's been told
+    sdir: Option<bool>,
+    /// Newest step, held back in case the next one cancels it out
+    next_step: Option<Step>,
+    commands: Vec<Command>,
+}
+
+impl StepCompressor {
+    pub fn new(oid: Oid, clock_freq: Hertz, max_error: Seconds, invert_dir: bool) -> Self {
+        Self {
+            oid,
+            clock_freq,
+            max_error: max_error.to_ticks(clock_freq).0 as i64,
+            invert_dir,
+            queue: VecDeque::new(),
+            last_step_clock: 0,
+            sdir: None,
+            next_step: None,
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn oid(&self) -> Oid {
+        self.oid
+    }
+
+    /// Whether the dir pin is wired backwards
+    pub fn invert_dir(&self) -> bool {
+        self.invert_dir
+    }
+
+    /// Clock of the last step sent
+    pub fn last_step_clock(&self) -> Ticks {
+        Ticks(self.last_step_clock)
+    }
+
+    /// Commands generated since last asked
+    pub fn drain_commands(&mut self) -> Vec<Command> {
+        std::mem::take(&mut self.commands)
+    }
+
+    fn minmax_point(&self, index: usize) -> Points {
+        let lsc = self.last_step_clock;
+        let point = (self.queue[index] - lsc) as i64;
+        let prev = match index {
+            0 => 0,
+            _ => (self.queue[index - 1] - lsc) as i64,
+        };
+        let max_error = ((point - prev) / 2).min(self.max_error);
+        Points {
+            min: point - max_error,
+            max: point,
+        }
+    }
+
+    /// Find the `queue_step` that covers the most of the queue
+    fn compress_bisect_add(&self) -> StepMove {
+        let qlast = self.queue.len().min(65535);
+        let point = self.minmax_point(0);
+        let (mut outer_mininterval, mut outer_maxinterval) = (point.min, point.max);
+        let (mut add, mut minadd, mut maxadd) = (0i64, -0x8000i64, 0x7fffi64);
+        let (mut bestinterval, mut bestcount, mut bestadd, mut bestreach) = (0, 1, 1, i64::MIN);
+        let (mut zerointerval, mut zerocount) = (0, 0);
+        let step_move = |interval: i64, count: i64, add: i64| StepMove {
+            interval: interval as u32,
+            count: count as u16,
+            add: add as i16,
+        };
+
+        loop {
+            // longest valid sequence with this `add`
+            let mut nextpoint;
+            let mut nextmininterval = outer_mininterval;
+            let mut nextmaxinterval = outer_maxinterval;
+            let mut interval = nextmaxinterval;
+            let mut nextcount = 1;
+            loop {
+                nextcount += 1;
+                if nextcount as usize > qlast {
+                    return step_move(interval, nextcount - 1, add);
+                }
+                nextpoint = self.minmax_point(nextcount as usize - 1);
+                let c = add * (nextcount * (nextcount - 1) / 2);
+                if nextmininterval * nextcount < nextpoint.min - c {
+                    nextmininterval = idiv_up(nextpoint.min - c, nextcount);
+                }
+                if nextmaxinterval * nextcount > nextpoint.max - c {
+                    nextmaxinterval = idiv_down(nextpoint.max - c, nextcount);
+                }
+                if nextmininterval > nextmaxinterval {
+                    break;
+                }
+                interval = nextmaxinterval;
+            }
+
+            let count = nextcount - 1;
+            let reach = add * (count * (count - 1) / 2) + interval * count;
+            if reach > bestreach || (reach == bestreach && interval > bestinterval) {
+                bestinterval = interval;
+                bestcount = count;
+                bestadd = add;
+                bestreach = reach;
+                if add == 0 {
+                    zerointerval = interval;
+                    zerocount = count;
+                }
+                if count > 0x200 {
+                    // no `add` is going to beat this
+                    break;
+                }
+            }
+
+            // would a bigger or smaller `add` get further?
+            let nextaddfactor = nextcount * (nextcount - 1) / 2;
+            let nextreach = add * nextaddfactor + interval * nextcount;
+            if nextreach < nextpoint.min {
+                minadd = add + 1;
+                outer_maxinterval = nextmaxinterval;
+            } else {
+                maxadd = add - 1;
+                outer_mininterval = nextmininterval;
+            }
+
+            // two valid quadratics can't be too far apart
+            if count > 1 {
+                let errdelta = self.max_error * QUADRATIC_DEV / (count * count);
+                minadd = minadd.max(add - errdelta);
+                maxadd = maxadd.min(add + errdelta);
+            }
+
+            // the next point might narrow things down further
+            let c = outer_maxinterval * nextcount;
+            if minadd * nextaddfactor < nextpoint.min - c {
+                minadd = idiv_up(nextpoint.min - c, nextaddfactor);
+            }
+            let c = outer_mininterval * nextcount;
+            if maxadd * nextaddfactor > nextpoint.max - c {
+                maxadd = idiv_down(nextpoint.max - c, nextaddfactor);
+            }
+
+            if minadd > maxadd {
+                break;
+            }
+            add = maxadd - (maxadd - minadd) / 4;
+        }
+        if zerocount + zerocount / 16 >= bestcount {
+            // no `add` is nicer if it does about as well
+            return step_move(zerointerval, zerocount, 0);
+        }
+        step_move(bestinterval, bestcount, bestadd)
+    }
+
+    /// Make sure a `queue_step` really does match the queued steps
+    fn check_line(&self, step_move: StepMove) -> Result<(), StepCompressError> {
+        let invalid = step_move.count == 0
+            || (step_move.interval == 0 && step_move.add == 0 && step_move.count > 1)
+            || step_move.interval >= 0x8000_0000;
+        if invalid {
+            return Err(StepCompressError::InvalidSequence {
+                oid: self.oid,
+                step_move,
+            });
+        }
+        let mut interval = i64::from(step_move.interval);
+        let mut p = 0;
+        for i in 0..usize::from(step_move.count) {
+            let point = self.minmax_point(i);
+            p += interval;
+            if p < point.min || p > point.max || interval >= 0x8000_0000 {
+                return Err(StepCompressError::PointOutOfRange {
+                    oid: self.oid,
+                    step_move,
+                    point: i + 1,
+                    clock: p,
+                    min: point.min,
+                    max: point.max,
+                });
+            }
+            interval += i64::from(step_move.add);
+        }
+        Ok(())
+    }
+
+    fn add_move(&mut self, first_clock: u64, step_move: StepMove) {
+        self.commands.push(Command::QueueStep {
+            oid: self.oid,
+            interval: step_move.interval,
+            count: step_move.count,
+            add: step_move.add,
+        });
+        self.last_step_clock = first_clock + step_move.span();
+    }
+
+    /// Compress queued steps up to `move_clock`
+    fn queue_flush(&mut self, move_clock: u64) -> Result<(), StepCompressError> {
+        while !self.queue.is_empty() && self.last_step_clock < move_clock {
+            let step_move = self.compress_bisect_add();
+            self.check_line(step_move)?;
+            self.add_move(
+                self.last_step_clock + u64::from(step_move.interval),
+                step_move,
+            );
+            self.queue.drain(..usize::from(step_move.count));
+        }
+        Ok(())
+    }
+
+    /// A lone step too far after the last one to be part of any sequence
+    fn flush_far(&mut self, step_clock: u64) {
+        let step_move = StepMove {
+            interval: (step_clock - self.last_step_clock) as u32,
+            count: 1,
+            add: 0,
+        };
+        self.add_move(step_clock, step_move);
+    }
+
+    fn set_next_step_dir(&mut self, dir: bool) -> Result<(), StepCompressError> {
+        if self.sdir == Some(dir) {
+            return Ok(());
+        }
+        self.queue_flush(u64::MAX)?;
+        self.sdir = Some(dir);
+        self.commands.push(Command::SetNextStepDir {
+            oid: self.oid,
+            dir: u8::from(dir ^ self.invert_dir),
+        });
+        Ok(())
+    }
+
+    fn queue_append(&mut self, step: Step) -> Result<(), StepCompressError> {
+        self.set_next_step_dir(step.dir)?;
+        let clock = step.clock.0;
+        if clock >= self.last_step_clock + CLOCK_DIFF_MAX {
+            self.queue_flush(clock - CLOCK_DIFF_MAX + 1)?;
+            if clock >= self.last_step_clock + CLOCK_DIFF_MAX {
+                self.flush_far(clock);
+                return Ok(());
+            }
+        }
+        self.queue.push_back(clock);
+        Ok(())
+    }
+
+    /// Queue up a step. Steps must come in time order
+    pub fn append(&mut self, step: Step) -> Result<(), StepCompressError> {
+        if let Some(pending) = self.next_step.take() {
+            if step.dir != pending.dir {
+                let diff = step.clock.0 as i64 - pending.clock.0 as i64;
+                if (diff as f64) < SDS_FILTER_TIME.to_fractional_ticks(self.clock_freq) {
+                    // step, dir change, step straight back: skip the lot
+                    return Ok(());
+                }
+            }
+            self.queue_append(pending)?;
+        }
+        self.next_step = Some(step);
+        Ok(())
+    }
+
+    /// Stop the newest step from being cancelled out
+    pub fn commit(&mut self) -> Result<(), StepCompressError> {
+        match self.next_step.take() {
+            Some(pending) => self.queue_append(pending),
+            None => Ok(()),
+        }
+    }
+
+    /// Send everything up to `move_clock` to the MCU
+    pub fn flush(&mut self, move_clock: Ticks) -> Result<(), StepCompressError> {
+        if matches!(self.next_step, Some(pending) if pending.clock <= move_clock) {
+            self.commit()?;
+        }
+        self.queue_flush(move_clock.0)
+    }
+
+    /// Flush everything and restart from `last_step_clock`, with the direction unknown
+    pub fn reset(&mut self, last_step_clock: Ticks) -> Result<(), StepCompressError> {
+        self.flush(Ticks(u64::MAX))?;
+        self.last_step_clock = last_step_clock.0;
+        self.sdir = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const FREQ: Hertz = Hertz(1_000_000.0);
+    /// 25us, klipper's default
+    const MAX_ERROR: Seconds = Seconds(0.000_025);
+
+    fn compressor() -> StepCompressor {
+        StepCompressor::new(Oid(3), FREQ, MAX_ERROR, false)
+    }
+
+    /// Play the commands back into the steps they'd make the MCU take
+    fn replay(commands: &[Command]) -> Vec<Step> {
+        let (mut clock, mut dir) = (0i64, false);
+        let mut steps = Vec::new();
+        for command in commands {
+            match *command {
+                Command::SetNextStepDir { dir: d, .. } => dir = d == 1,
+                Command::QueueStep {
+                    interval,
+                    count,
+                    add,
+                    ..
+                } => {
+                    let mut interval = i64::from(interval);
+                    for _ in 0..count {
+                        clock += interval;
+                        steps.push(Step {
+                            clock: Ticks(clock as u64),
+                            dir,
+                        });
+                        interval += i64::from(add);
+                    }
+                }
+                _ => panic!("unexpected {command:?}"),
+            }
+        }
+        steps
+    }
+
+    fn compress(steps: &[Step]) -> Vec<Command> {
+        let mut sc = compressor();
+        for &step in steps {
+            sc.append(step).unwrap();
+        }
+        sc.flush(Ticks(u64::MAX)).unwrap();
+        sc.drain_commands()
+    }
+
+    fn assert_within_tolerance(original: &[Step], replayed: &[Step]) {
+        let max_error = MAX_ERROR.to_ticks(FREQ).0;
+        assert_eq!(original.len(), replayed.len());
+        for (orig, got) in original.iter().zip(replayed) {
+            assert_eq!(orig.dir, got.dir);
+            assert!(got.clock <= orig.clock, "{got:?} late for {orig:?}");
+            assert!(
+                got.clock.0 + max_error >= orig.clock.0,
+                "{got:?} early for {orig:?}"
+            );
+        }
+    }
+
+    /// Steps from a list of (gap, direction) pairs
+    fn steps_from(gaps: &[(u64, bool)]) -> Vec<Step> {
+        gaps.iter()
+            .scan(0, |clock, &(gap, dir)| {
+                *clock += gap;
+                Some(Step {
+                    clock: Ticks(*clock),
+                    dir,
+                })
+            })
+            .collect()
+    }
+
+    proptest! {
+        /// Any steps in one direction come back within tolerance
+        #[test]
+        fn round_trip_one_direction(gaps in prop::collection::vec(1u64..20_000, 1..500)) {
+            let steps = steps_from(&gaps.iter().map(|&g| (g, true)).collect::<Vec<_>>());
+            assert_within_tolerance(&steps, &replay(&compress(&steps)));
+        }
+
+        /// Direction changes far enough apart not to be filtered out survive too
+        #[test]
+        fn round_trip_direction_changes(
+            gaps in prop::collection::vec((1u64..5_000, any::<bool>()), 1..500)
+        ) {
+            let mut last_dir = None;
+            let gaps: Vec<_> = gaps
+                .into_iter()
+                .map(|(gap, dir)| {
+                    let changed = last_dir.is_some_and(|last| last != dir);
+                    last_dir = Some(dir);
+                    (if changed { gap + 1_000 } else { gap }, dir)
+                })
+                .collect();
+            let steps = steps_from(&gaps);
+            assert_within_tolerance(&steps, &replay(&compress(&steps)));
+        }
+    }
+
+    #[test]
+    fn test_acceleration_compresses() {
+        // constant acceleration from rest, in steps/s²
+        let accel = 1_000_000.0;
+        let steps: Vec<_> = (1..=1000)
+            .map(|n| Step {
+                clock: Seconds((2.0 * n as f64 / accel).sqrt()).to_ticks(FREQ),
+                dir: true,
+            })
+            .collect();
+        let commands = compress(&steps);
+        assert!(commands.len() < 40, "{} commands", commands.len());
+        assert_eq!(
+            commands[0],
+            Command::SetNextStepDir {
+                oid: Oid(3),
+                dir: 1
+            }
+        );
+        assert_within_tolerance(&steps, &replay(&commands));
+    }
+
+    #[test]
+    fn test_rapid_reversal_cancels() {
+        let mut sc = StepCompressor::new(Oid(3), FREQ, MAX_ERROR, true);
+        let steps = steps_from(&[(1000, true), (1000, true), (100, false), (2000, false)]);
+        for &step in &steps {
+            sc.append(step).unwrap();
+        }
+        sc.flush(Ticks(u64::MAX)).unwrap();
+        let commands = sc.drain_commands();
+        // the second forward step and the quick step back cancel out
+        assert_eq!(
+            commands[0],
+            Command::SetNextStepDir {
+                oid: Oid(3),
+                dir: 0
+            }
+        );
+        let replayed = replay(&commands);
+        assert_eq!(replayed.len(), 2);
+        assert_eq!(replayed[0].clock, Ticks(1000));
+        assert_eq!(replayed[1].clock, Ticks(4100));
+        // replay sees the pin level, which is inverted
+        assert!(!replayed[0].dir && replayed[1].dir);
+    }
+
+    #[test]
+    fn test_far_step() {
+        let mut sc = compressor();
+        let far = Ticks(CLOCK_DIFF_MAX * 2);
+        sc.append(Step {
+            clock: Ticks(10),
+            dir: true,
+        })
+        .unwrap();
+        sc.append(Step {
+            clock: far,
+            dir: true,
+        })
+        .unwrap();
+        sc.flush(Ticks(u64::MAX)).unwrap();
+        assert_eq!(sc.last_step_clock(), far);
+        assert_eq!(replay(&sc.drain_commands()).len(), 2);
+    }
+}
@@ -1,6 +1,20 @@
+use std::cell::Cell;
 use std::path::PathBuf;
 use std::process::Command;
 use std::process::{ChildStdin, ChildStdout, Stdio};
+use std::rc::Rc;
+
+use crate::homing::HomingContext;
+use crate::kinematics::cartesian::{Cartesian, CartesianConfig};
+use crate::kinematics::extruder::{Extruder, ExtruderConfig};
+use crate::kinematics::{Kinematics, RailConfig};
+use crate::mcu::fake::FakeLink;
+use crate::mcu::McuLink;
+use crate::planner::{Planner, PlannerConfig};
+use crate::stepcompress::StepCompressor;
+use crate::units::{
+    Hertz, Millimeters, MillimetersPerSecond, MillimetersPerSecondSquared, Seconds,
+};
 
 #[cfg(test)]
 fn in_simulator(test_fn: impl Fn(ChildStdin, ChildStdout)) {
@@ -22,6 +36,95 @@ fn in_simulator(test_fn: impl Fn(ChildStdin, ChildStdout)) {
     // simulator dies because stdin closes, not because Drop killed process
 }
 
+/// A cartesian printer 200mm square and 150mm tall with `z_count` Z steppers, all
+/// stepping through a fake MCU called "mcu", and an extruder for a 0.4mm nozzle
+pub struct FakePrinter {
+    pub kin: Cartesian,
+    pub extruder: Extruder,
+    pub planner: Planner,
+    pub link: FakeLink,
+    /// Each stepper's step count on the MCU, and how far a step goes
+    steps: Vec<(Rc<Cell<i64>>, f64)>,
+}
+
+impl FakePrinter {
+    pub const FREQ: Hertz = Hertz(1_000_000.0);
+    pub const XY_STEP: f64 = 0.0125;
+    pub const Z_STEP: f64 = 0.0025;
+
+    pub fn new(z_count: usize) -> Self {
+        let rail = |step: f64, max: f64| {
+            RailConfig::new(
+                Millimeters(step),
+                Millimeters(0.0),
+                Millimeters(max),
+                Millimeters(0.0),
+            )
+            .unwrap()
+        };
+        let mut kin = Cartesian::new(
+            CartesianConfig {
+                x: rail(Self::XY_STEP, 200.0),
+                y: rail(Self::XY_STEP, 200.0),
+                z: rail(Self::Z_STEP, 150.0),
+                max_z_velocity: MillimetersPerSecond(10.0),
+                max_z_accel: MillimetersPerSecondSquared(100.0),
+            },
+            Self::FREQ,
+        );
+        for _ in 1..z_count {
+            kin.add_z_stepper(Millimeters(Self::Z_STEP));
+        }
+        let mut link = FakeLink::new(&["mcu"], Self::FREQ);
+        let mut steps = Vec::new();
+        for (i, stepper) in kin.steppers_mut().into_iter().enumerate() {
+            let mcu = link.mcu("mcu").unwrap();
+            let oid = mcu.create_oid();
+            let sc = StepCompressor::new(oid, Self::FREQ, Seconds(0.000_025), false);
+            stepper.set_stepcompress(mcu, sc);
+            let step = if i < 2 { Self::XY_STEP } else { Self::Z_STEP };
+            steps.push((link.fake("mcu").stepper_position(oid), step));
+        }
+        let planner = Planner::new(PlannerConfig::new(
+            MillimetersPerSecond(300.0),
+            MillimetersPerSecondSquared(3000.0),
+        ));
+        let extruder = extruder(planner.config());
+        FakePrinter {
+            kin,
+            extruder,
+            planner,
+            link,
+            steps,
+        }
+    }
+
+    pub fn ctx(&mut self) -> HomingContext<'_, Cartesian, FakeLink> {
+        HomingContext {
+            kin: &mut self.kin,
+            planner: &mut self.planner,
+            link: &mut self.link,
+        }
+    }
+
+    /// How far each stepper has gone, by its place in the kinematics' steppers, as far as
+    /// the MCU has got
+    pub fn travel(&self) -> impl Fn(usize) -> f64 + Clone + 'static {
+        let steps = self.steps.clone();
+        move |i| steps[i].0.get() as f64 * steps[i].1
+    }
+}
+
+/// An extruder for a 0.4mm nozzle and 1.75mm filament, to go with `planner`
+pub fn extruder(planner: &PlannerConfig) -> Extruder {
+    let config = ExtruderConfig::new(
+        Millimeters(0.002),
+        Millimeters(0.4),
+        Millimeters(1.75),
+        planner,
+    );
+    Extruder::new(config, FakePrinter::FREQ).unwrap()
+}
 
 #[cfg(test)]
 mod tests {
@@ -49,18 +152,17 @@ mod tests {
 
             reader.read_exact(&mut buf[1..total_len]).unwrap();
             hexdump(&buf[..total_len]);
-            let (crc, payload) = if let [
-                payload @ ..,
-                crc1,
-                crc2,
-                0x7e
-            ] = &buf[..total_len] {
+            let (crc, payload) = if let [payload @ .., crc1, crc2, 0x7e] = &buf[..total_len] {
                 let crc = u16::from_be_bytes([*crc1, *crc2]);
                 (crc, payload)
             } else {
                 panic!("invalid trailer!")
             };
-            assert_eq!(payload[1] & 0xF0, 0x10, "Expected a seq of 0 and magic of 0x10");
+            assert_eq!(
+                payload[1] & 0xF0,
+                0x10,
+                "Expected a seq of 0 and magic of 0x10"
+            );
             let k_crc = klipper_ffi_crc(payload);
             let rust_crc = klipper_crc(payload);
             assert_eq!(k_crc, crc);
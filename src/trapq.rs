@@ -0,0 +1,373 @@
+//! Trapezoidal motion queue, a port of klipper's `trapq.c`
+//!
+//! Every move is split into up to three constant-acceleration pieces (accelerate,
+//! cruise, decelerate) so the toolhead position at any print time is a quadratic away
+
+use std::collections::VecDeque;
+use std::ops::{Add, Mul, Neg, Sub};
+
+use crate::units::{Millimeters, MillimetersPerSecond, MillimetersPerSecondSquared, Seconds};
+
+/// Null moves filling the gap before the very first move are capped at this long,
+/// so step generation doesn't have to wade through hours of nothing
+const MAX_NULL_MOVE: Seconds = Seconds(1.0);
+
+/// Position (or direction) in cartesian space, in millimetres
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Coord {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl Coord {
+    pub const fn new(x: f64, y: f64, z: f64) -> Self {
+        Self { x, y, z }
+    }
+
+    pub fn dot(self, other: Self) -> f64 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn cross(self, other: Self) -> Self {
+        Self::new(
+            self.y * other.z - self.z * other.y,
+            self.z * other.x - self.x * other.z,
+            self.x * other.y - self.y * other.x,
+        )
+    }
+
+    /// Length of the vector
+    pub fn norm(self) -> f64 {
+        self.dot(self).sqrt()
+    }
+}
+
+impl Add for Coord {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self::new(self.x + rhs.x, self.y + rhs.y, self.z + rhs.z)
+    }
+}
+
+impl Sub for Coord {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self::new(self.x - rhs.x, self.y - rhs.y, self.z - rhs.z)
+    }
+}
+
+impl Neg for Coord {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Self::new(-self.x, -self.y, -self.z)
+    }
+}
+
+impl Mul<f64> for Coord {
+    type Output = Self;
+
+    fn mul(self, rhs: f64) -> Self {
+        Self::new(self.x * rhs, self.y * rhs, self.z * rhs)
+    }
+}
+
+/// Travelling along `axes_r` by a distance
+impl Mul<Millimeters> for Coord {
+    type Output = Self;
+
+    fn mul(self, rhs: Millimeters) -> Self {
+        self * rhs.0
+    }
+}
+
+/// One constant-acceleration piece of a move
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Move {
+    pub print_time: Seconds,
+    pub move_t: Seconds,
+    pub start_v: MillimetersPerSecond,
+    pub half_accel: MillimetersPerSecondSquared,
+    pub start_pos: Coord,
+    /// Unit vector of the direction of travel
+    pub axes_r: Coord,
+}
+
+impl Move {
+    /// Move that sits still at `pos` from `print_time` for `move_t`
+    pub(crate) fn null(print_time: Seconds, move_t: Seconds, pos: Coord) -> Self {
+        Self {
+            print_time,
+            move_t,
+            start_v: MillimetersPerSecond::ZERO,
+            half_accel: MillimetersPerSecondSquared::ZERO,
+            start_pos: pos,
+            axes_r: Coord::default(),
+        }
+    }
+
+    pub fn end_time(&self) -> Seconds {
+        self.print_time + self.move_t
+    }
+
+    /// Whether the toolhead is actually going anywhere
+    pub fn is_null(&self) -> bool {
+        self.start_v == MillimetersPerSecond::ZERO
+            && self.half_accel == MillimetersPerSecondSquared::ZERO
+    }
+
+    /// Distance travelled `move_time` into this piece
+    pub fn get_distance(&self, move_time: Seconds) -> Millimeters {
+        (self.start_v + self.half_accel * move_time) * move_time
+    }
+
+    /// Position `move_time` into this piece
+    pub fn get_coord(&self, move_time: Seconds) -> Coord {
+        self.start_pos + self.axes_r * self.get_distance(move_time)
+    }
+}
+
+/// A whole trapezoidal move, before it gets chopped into [`Move`]s
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Trapezoid {
+    pub print_time: Seconds,
+    pub accel_t: Seconds,
+    pub cruise_t: Seconds,
+    pub decel_t: Seconds,
+    pub start_pos: Coord,
+    /// Unit vector of the direction of travel
+    pub axes_r: Coord,
+    pub start_v: MillimetersPerSecond,
+    pub cruise_v: MillimetersPerSecond,
+    pub accel: MillimetersPerSecondSquared,
+}
+
+/// Queue of moves yet to be turned into steps, plus a little history of ones that have been
+#[derive(Debug, Default)]
+pub struct TrapQ {
+    /// Oldest first
+    moves: VecDeque<Move>,
+    /// Newest first, null moves don't bother getting kept
+    history: VecDeque<Move>,
+}
+
+impl TrapQ {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a trapezoidal move, one piece per non-empty phase
+    pub fn append(&mut self, trap: &Trapezoid) {
+        let mut print_time = trap.print_time;
+        let mut start_pos = trap.start_pos;
+        let phases = [
+            (trap.accel_t, trap.start_v, trap.accel * 0.5),
+            (
+                trap.cruise_t,
+                trap.cruise_v,
+                MillimetersPerSecondSquared::ZERO,
+            ),
+            (trap.decel_t, trap.cruise_v, -trap.accel * 0.5),
+        ];
+        for (move_t, start_v, half_accel) in phases {
+            if move_t == Seconds::ZERO {
+                continue;
+            }
+            let m = Move {
+                print_time,
+                move_t,
+                start_v,
+                half_accel,
+                start_pos,
+                axes_r: trap.axes_r,
+            };
+            self.add_move(m);
+            print_time += move_t;
+            start_pos = m.get_coord(move_t);
+        }
+    }
+
+    fn add_move(&mut self, m: Move) {
+        let prev_end = self
+            .moves
+            .back()
+            .or_else(|| self.history.front())
+            .map(Move::end_time);
+        let gap_start = match prev_end {
+            Some(end) if end < m.print_time => Some(end),
+            Some(_) => None,
+            None if m.print_time > Seconds::ZERO => {
+                Some((m.print_time - MAX_NULL_MOVE).max(Seconds::ZERO))
+            }
+            None => None,
+        };
+        if let Some(start) = gap_start {
+            self.moves
+                .push_back(Move::null(start, m.print_time - start, m.start_pos));
+        }
+        self.moves.push_back(m);
+    }
+
+    /// Moves that haven't been finalized yet, oldest first
+    pub fn moves(&self) -> impl Iterator<Item = &Move> + '_ {
+        self.moves.iter()
+    }
+
+    /// Moves that haven't been finalized yet, oldest first, in the two pieces they happen
+    /// to be stored in
+    pub fn move_slices(&self) -> (&[Move], &[Move]) {
+        self.moves.as_slices()
+    }
+
+    /// Finalized moves still being remembered, newest first
+    pub fn history(&self) -> impl Iterator<Item = &Move> + '_ {
+        self.history.iter()
+    }
+
+    /// Retire every move that has completed by `print_time`, and forget
+    /// history that ended before `clear_history_time`
+    pub fn finalize_moves(&mut self, print_time: Seconds, clear_history_time: Seconds) {
+        while let Some(m) = self.moves.front() {
+            if m.end_time() > print_time {
+                break;
+            }
+            let m = self.moves.pop_front().unwrap();
+            if !m.is_null() {
+                self.history.push_front(m);
+            }
+        }
+        // the newest piece of history sticks around regardless, it's the current position
+        while self.history.len() > 1 {
+            match self.history.back() {
+                Some(m) if m.end_time() <= clear_history_time => self.history.pop_back(),
+                _ => break,
+            };
+        }
+    }
+
+    /// Forget everything after `print_time` and declare the toolhead to be at `pos`
+    pub fn set_position(&mut self, print_time: Seconds, pos: Coord) {
+        self.finalize_moves(Seconds(f64::INFINITY), Seconds(f64::NEG_INFINITY));
+        // anything that hasn't happened by now never will
+        self.history.retain(|m| m.print_time < print_time);
+        if let Some(latest) = self.history.front_mut() {
+            if latest.end_time() > print_time {
+                latest.move_t = print_time - latest.print_time;
+            }
+        }
+        self.history
+            .push_front(Move::null(print_time, Seconds(0.0), pos));
+    }
+
+    /// Toolhead position at `print_time`, looking through history if need be.
+    /// Before the first known move it's wherever that move starts,
+    /// after the last one it's wherever that move ended
+    pub fn position_at(&self, print_time: Seconds) -> Option<Coord> {
+        let mut all = self.history.iter().rev().chain(&self.moves).peekable();
+        let first = **all.peek()?;
+        if print_time <= first.print_time {
+            return Some(first.start_pos);
+        }
+        let mut last = first;
+        for m in all {
+            if print_time < m.end_time() {
+                let t = print_time.max(m.print_time) - m.print_time;
+                return Some(m.get_coord(t));
+            }
+            last = *m;
+        }
+        Some(last.get_coord(last.move_t))
+    }
+
+    /// When the last queued move finishes
+    pub fn end_time(&self) -> Option<Seconds> {
+        self.moves
+            .back()
+            .or_else(|| self.history.front())
+            .map(Move::end_time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 100mm along X at 100mm/s, 1000mm/s² both ways, starting from rest at `print_time`
+    fn x_move(print_time: f64, start_x: f64) -> Trapezoid {
+        Trapezoid {
+            print_time: Seconds(print_time),
+            accel_t: Seconds(0.1),
+            cruise_t: Seconds(0.9),
+            decel_t: Seconds(0.1),
+            start_pos: Coord::new(start_x, 0.0, 0.0),
+            axes_r: Coord::new(1.0, 0.0, 0.0),
+            start_v: MillimetersPerSecond(0.0),
+            cruise_v: MillimetersPerSecond(100.0),
+            accel: MillimetersPerSecondSquared(1000.0),
+        }
+    }
+
+    fn x_at(tq: &TrapQ, t: f64) -> f64 {
+        tq.position_at(Seconds(t)).unwrap().x
+    }
+
+    #[test]
+    fn test_phases() {
+        let mut tq = TrapQ::new();
+        tq.append(&x_move(2.0, 0.0));
+        // null move filling the time before, then accel, cruise, decel
+        let moves: Vec<_> = tq.moves().copied().collect();
+        assert_eq!(moves.len(), 4);
+        assert!(moves[0].is_null());
+        assert_eq!(moves[0].print_time, Seconds(1.0));
+
+        assert_eq!(x_at(&tq, 0.0), 0.0);
+        assert!((x_at(&tq, 2.05) - 1.25).abs() < 1e-9);
+        assert!((x_at(&tq, 2.1) - 5.0).abs() < 1e-9);
+        assert!((x_at(&tq, 2.6) - 55.0).abs() < 1e-9);
+        assert!((x_at(&tq, 3.1) - 100.0).abs() < 1e-9);
+        assert!((x_at(&tq, 10.0) - 100.0).abs() < 1e-9);
+        assert!((tq.end_time().unwrap() - Seconds(3.1)).abs() < Seconds(1e-9));
+    }
+
+    #[test]
+    fn test_gaps_and_history() {
+        let mut tq = TrapQ::new();
+        tq.append(&x_move(0.0, 0.0));
+        tq.append(&x_move(2.0, 100.0));
+        // the pause between the two got filled in
+        let gap = tq.moves().nth(3).unwrap();
+        assert!(gap.is_null());
+        assert!((gap.start_pos.x - 100.0).abs() < 1e-9);
+        assert!((x_at(&tq, 1.5) - 100.0).abs() < 1e-9);
+
+        tq.finalize_moves(Seconds(2.5), Seconds(1.0));
+        // first move's accel and cruise have expired, its decel is the oldest history left
+        assert_eq!(tq.history().count(), 2);
+        assert_eq!(tq.moves().count(), 2);
+        assert!((x_at(&tq, 1.05) - 98.75).abs() < 1e-9);
+        assert!((x_at(&tq, 3.1) - 200.0).abs() < 1e-9);
+
+        tq.finalize_moves(Seconds(10.0), Seconds(10.0));
+        assert_eq!(tq.moves().count(), 0);
+        assert_eq!(tq.history().count(), 1);
+        assert!((x_at(&tq, 20.0) - 200.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_set_position() {
+        let mut tq = TrapQ::new();
+        tq.append(&x_move(0.0, 0.0));
+        // endstop hit partway through the cruise
+        tq.set_position(Seconds(0.5), Coord::new(0.0, 0.0, 0.0));
+        assert_eq!(tq.moves().count(), 0);
+        assert_eq!(x_at(&tq, 0.6), 0.0);
+        assert!((x_at(&tq, 0.3) - 25.0).abs() < 1e-9);
+
+        tq.append(&x_move(1.0, 0.0));
+        assert!((x_at(&tq, 2.1) - 100.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,303 @@
+//! Dimensioned quantities used throughout the motion system
+//!
+//! Everything is a thin `f64` newtype, so the compiler yells when millimetres get
+//! added to seconds, but the generated code is the same as shuffling raw floats.
+//! Only the combinations that make physical sense get an operator,
+//! e.g. [`Millimeters`] / [`Seconds`] = [`MillimetersPerSecond`]
+
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+
+/// Declare an `f64` newtype with the arithmetic any quantity supports:
+/// adding/subtracting itself, scaling by a bare number, and dividing by itself into a ratio
+macro_rules! quantity {
+    ($(#[$meta:meta])* $name:ident, $suffix:literal) => {
+        $(#[$meta])*
+        #[repr(transparent)]
+        #[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd, derive_more::From)]
+        pub struct $name(pub f64);
+
+        impl $name {
+            pub const ZERO: Self = Self(0.0);
+
+            pub fn abs(self) -> Self {
+                Self(self.0.abs())
+            }
+
+            pub fn min(self, other: Self) -> Self {
+                Self(self.0.min(other.0))
+            }
+
+            pub fn max(self, other: Self) -> Self {
+                Self(self.0.max(other.0))
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}{}", self.0, $suffix)
+            }
+        }
+
+        impl Add for $name {
+            type Output = Self;
+
+            fn add(self, rhs: Self) -> Self {
+                Self(self.0 + rhs.0)
+            }
+        }
+
+        impl AddAssign for $name {
+            fn add_assign(&mut self, rhs: Self) {
+                self.0 += rhs.0;
+            }
+        }
+
+        impl Sub for $name {
+            type Output = Self;
+
+            fn sub(self, rhs: Self) -> Self {
+                Self(self.0 - rhs.0)
+            }
+        }
+
+        impl SubAssign for $name {
+            fn sub_assign(&mut self, rhs: Self) {
+                self.0 -= rhs.0;
+            }
+        }
+
+        impl Neg for $name {
+            type Output = Self;
+
+            fn neg(self) -> Self {
+                Self(-self.0)
+            }
+        }
+
+        impl Mul<f64> for $name {
+            type Output = Self;
+
+            fn mul(self, rhs: f64) -> Self {
+                Self(self.0 * rhs)
+            }
+        }
+
+        impl Mul<$name> for f64 {
+            type Output = $name;
+
+            fn mul(self, rhs: $name) -> $name {
+                $name(self * rhs.0)
+            }
+        }
+
+        impl Div<f64> for $name {
+            type Output = Self;
+
+            fn div(self, rhs: f64) -> Self {
+                Self(self.0 / rhs)
+            }
+        }
+
+        impl Div for $name {
+            type Output = f64;
+
+            fn div(self, rhs: Self) -> f64 {
+                self.0 / rhs.0
+            }
+        }
+
+        impl Sum for $name {
+            fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+                Self(iter.map(|q| q.0).sum())
+            }
+        }
+    };
+}
+
+/// `$lhs * $rhs = $out`, in whichever order the operands show up
+macro_rules! product {
+    ($lhs:ident * $rhs:ident = $out:ident) => {
+        impl Mul<$rhs> for $lhs {
+            type Output = $out;
+
+            fn mul(self, rhs: $rhs) -> $out {
+                $out(self.0 * rhs.0)
+            }
+        }
+
+        impl Mul<$lhs> for $rhs {
+            type Output = $out;
+
+            fn mul(self, rhs: $lhs) -> $out {
+                $out(self.0 * rhs.0)
+            }
+        }
+
+        impl Div<$rhs> for $out {
+            type Output = $lhs;
+
+            fn div(self, rhs: $rhs) -> $lhs {
+                $lhs(self.0 / rhs.0)
+            }
+        }
+
+        impl Div<$lhs> for $out {
+            type Output = $rhs;
+
+            fn div(self, rhs: $lhs) -> $rhs {
+                $rhs(self.0 / rhs.0)
+            }
+        }
+    };
+}
+
+quantity!(
+    /// Length along an axis
+    Millimeters,
+    "mm"
+);
+quantity!(
+    /// Speed, also used for `square_corner_velocity`
+    MillimetersPerSecond,
+    "mm/s"
+);
+quantity!(
+    /// Acceleration
+    MillimetersPerSecondSquared,
+    "mm/s^2"
+);
+quantity!(
+    /// Jerk, the rate of change of acceleration
+    MillimetersPerSecondCubed,
+    "mm/s^3"
+);
+quantity!(
+    /// Squared speed, what the look-ahead planner actually juggles (v² = 2·a·d)
+    MillimetersSquaredPerSecondSquared,
+    "mm^2/s^2"
+);
+quantity!(
+    /// Host or print time
+    Seconds,
+    "s"
+);
+quantity!(
+    /// Frequency, mostly of an MCU clock
+    Hertz,
+    "Hz"
+);
+
+/// Klipper's name for the corner speed limit is just a velocity
+pub type SquareCornerVelocity = MillimetersPerSecond;
+
+product!(MillimetersPerSecond * Seconds = Millimeters);
+product!(MillimetersPerSecondSquared * Seconds = MillimetersPerSecond);
+product!(MillimetersPerSecondCubed * Seconds = MillimetersPerSecondSquared);
+product!(MillimetersPerSecondSquared * Millimeters = MillimetersSquaredPerSecondSquared);
+
+impl Mul for MillimetersPerSecond {
+    type Output = MillimetersSquaredPerSecondSquared;
+
+    fn mul(self, rhs: Self) -> MillimetersSquaredPerSecondSquared {
+        MillimetersSquaredPerSecondSquared(self.0 * rhs.0)
+    }
+}
+
+impl MillimetersPerSecond {
+    pub fn squared(self) -> MillimetersSquaredPerSecondSquared {
+        self * self
+    }
+}
+
+impl MillimetersSquaredPerSecondSquared {
+    pub fn sqrt(self) -> MillimetersPerSecond {
+        MillimetersPerSecond(self.0.sqrt())
+    }
+}
+
+/// Seconds times cycles-per-second is a bare (fractional) number of cycles
+impl Mul<Hertz> for Seconds {
+    type Output = f64;
+
+    fn mul(self, rhs: Hertz) -> f64 {
+        self.0 * rhs.0
+    }
+}
+
+/// Count of MCU clock ticks
+#[repr(transparent)]
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    derive_more::From,
+    derive_more::Add,
+    derive_more::Sub,
+    derive_more::AddAssign,
+    derive_more::SubAssign,
+    derive_more::Display,
+)]
+#[display(fmt = "{} ticks", _0)]
+pub struct Ticks(pub u64);
+
+impl Ticks {
+    /// How long these ticks take on a clock running at `freq`
+    pub fn to_seconds(self, freq: Hertz) -> Seconds {
+        Seconds(self.0 as f64 / freq.0)
+    }
+}
+
+impl Seconds {
+    /// Nearest whole clock tick for a clock running at `freq`.
+    /// Negative times saturate to tick 0
+    pub fn to_ticks(self, freq: Hertz) -> Ticks {
+        Ticks((self * freq).round() as u64)
+    }
+
+    /// Clock ticks for a clock running at `freq`, without rounding
+    pub fn to_fractional_ticks(self, freq: Hertz) -> f64 {
+        self * freq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dimensions() {
+        let d = Millimeters(100.0);
+        let t = Seconds(2.0);
+        let v: MillimetersPerSecond = d / t;
+        assert_eq!(v, MillimetersPerSecond(50.0));
+        let a: MillimetersPerSecondSquared = v / t;
+        assert_eq!(a, MillimetersPerSecondSquared(25.0));
+        let back: Millimeters = v * t;
+        assert_eq!(back, d);
+        assert_eq!(d / v, t);
+        // v² = 2·a·d
+        let v2 = 2.0 * (a * d);
+        assert_eq!(
+            v2.sqrt(),
+            MillimetersPerSecond((2.0f64 * 25.0 * 100.0).sqrt())
+        );
+        assert_eq!(d / Millimeters(25.0), 4.0);
+    }
+
+    #[test]
+    fn test_ticks() {
+        let freq = Hertz(16_000_000.0);
+        assert_eq!(Seconds(0.001).to_ticks(freq), Ticks(16_000));
+        assert_eq!(Ticks(8_000_000).to_seconds(freq), Seconds(0.5));
+        assert_eq!(Seconds(1.5e-7).to_fractional_ticks(freq), 2.4);
+        assert_eq!(Seconds(-1.0).to_ticks(freq), Ticks(0));
+    }
+}
@@ -3,3 +3,8 @@
 
 extern uint8_t* encode_int(uint8_t p[], uint32_t v);
 extern uint32_t parse_int(uint8_t *pp[]);
+
+#include "klipper/klippy/chelper/itersolve.h"
+#include "klipper/klippy/chelper/trapq.h"
+#include "src/ffi/chelper.h"
+#include "src/ffi/stepcapture.h"